@@ -1,4 +1,7 @@
-pub(crate) use crate::command::{auth, gateway, ping, quick, seed, serve, stun, tunnel};
+pub(crate) use crate::command::{
+    auth, enroll, gateway, gateways, invite, keygen, ping, quick, revoke, rotate, seed,
+    serve, stun, tunnel,
+};
 use clap::{Parser, Subcommand};
 
 #[derive(Debug, Parser)]
@@ -23,10 +26,34 @@ pub enum RoxiCli {
     Gateway(gateway::Args),
     #[clap(name = "quick", about = "Run wg-quick.")]
     Quick(quick::Args),
-    #[clap(name = "seed", about = "Seed a client against the server.")]
+    #[clap(
+        name = "seed",
+        about = "Seed a client against the server and serve as a gateway."
+    )]
     Seed(seed::Args),
     #[clap(name = "tunnel", about = "Create tunnel a tunnel between two peers.")]
     Tunnel(tunnel::Args),
+    #[clap(
+        name = "keygen",
+        about = "Generate a control channel or identity key pair."
+    )]
+    Keygen(keygen::Args),
+    #[clap(name = "invite", about = "Create and list invites for new clients.")]
+    Invite(invite::Args),
+    #[clap(
+        name = "enroll",
+        about = "Redeem an invite for a client identity and config."
+    )]
+    Enroll(enroll::Args),
+    #[clap(name = "revoke", about = "Revoke an enrolled client.")]
+    Revoke(revoke::Args),
+    #[clap(name = "rotate", about = "Replace this client's identity key.")]
+    Rotate(rotate::Args),
+    #[clap(
+        name = "gateways",
+        about = "List seeders and the load of their gateways."
+    )]
+    Gateways(gateways::Args),
 }
 
 pub async fn run_cli() -> Result<(), anyhow::Error> {
@@ -41,5 +68,11 @@ pub async fn run_cli() -> Result<(), anyhow::Error> {
         RoxiCli::Quick(command) => quick::exec(command).await,
         RoxiCli::Seed(command) => seed::exec(command).await,
         RoxiCli::Tunnel(command) => tunnel::exec(command).await,
+        RoxiCli::Keygen(command) => keygen::exec(command).await,
+        RoxiCli::Invite(command) => invite::exec(command).await,
+        RoxiCli::Enroll(command) => enroll::exec(command).await,
+        RoxiCli::Revoke(command) => revoke::exec(command).await,
+        RoxiCli::Rotate(command) => rotate::exec(command).await,
+        RoxiCli::Gateways(command) => gateways::exec(command).await,
     }
 }
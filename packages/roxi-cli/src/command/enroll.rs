@@ -0,0 +1,66 @@
+use clap::Parser;
+use roxi_client::Config;
+use roxi_crypto::{IdentityKeyPair, NoiseKeyPair};
+use roxi_proto::WireGuardProtoConfigBuilder;
+use std::{fs, path::PathBuf};
+
+#[derive(Debug, Parser, Clone)]
+#[clap(name = "Roxi enroll", about = "Roxi enroll", version)]
+pub struct Args {
+    /// Invite token from `roxi invite create`.
+    #[clap(short, long, help = "Invite token.")]
+    pub token: String,
+
+    /// Server control channel address.
+    #[clap(short, long, help = "Server address, e.g. 203.0.113.7:8080.")]
+    pub server: String,
+
+    /// Server control channel public key to check the server against.
+    #[clap(long)]
+    pub server_key: Option<String>,
+
+    /// Where to write client.yaml and wg0.conf.
+    #[clap(short, long, default_value = ".")]
+    pub dir: PathBuf,
+
+    /// Tunnel address, unless the invite assigns one.
+    #[clap(long, default_value = "10.0.0.2/32")]
+    pub address: String,
+
+    /// WireGuard listen port.
+    #[clap(long, default_value_t = 51820)]
+    pub listen_port: u16,
+}
+
+pub async fn exec(args: Args) -> anyhow::Result<()> {
+    let identity = IdentityKeyPair::generate()?;
+    let (enrollment, local_ip) = roxi_client::enroll(
+        &args.server,
+        args.server_key.as_deref(),
+        &args.token,
+        &identity,
+    )
+    .await?;
+
+    fs::create_dir_all(&args.dir)?;
+    let config = Config::from_enrollment(&args.dir, &enrollment, &identity, local_ip);
+
+    // WireGuard keys are X25519 keys, as are those of the control channel
+    let wireguard_keys = NoiseKeyPair::generate()?;
+    WireGuardProtoConfigBuilder::builder()
+        .private_key(wireguard_keys.private_base64())
+        .address(enrollment.address.clone().unwrap_or(args.address))
+        .port(args.listen_port)
+        .build()
+        .save(config.wireguard_filepath())?;
+    config.save()?;
+
+    println!("Enrolled as {}", enrollment.label);
+    println!("Client config: {}", config.path().display());
+    println!(
+        "WireGuard config: {}",
+        config.wireguard_filepath().display()
+    );
+    println!("WireGuard public key: {}", wireguard_keys.public_base64());
+    Ok(())
+}
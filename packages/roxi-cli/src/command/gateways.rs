@@ -0,0 +1,67 @@
+use clap::Parser;
+use roxi_crypto::NoiseKeyPair;
+use roxi_lib::types::InterfaceKind;
+use roxi_server::Config;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser, Clone)]
+#[clap(name = "Roxi gateways", about = "Roxi gateways", version)]
+pub struct Args {
+    /// Server config file, whose private key proves we are its operator.
+    #[clap(short, long, help = "Server config file.")]
+    pub config: PathBuf,
+
+    /// Server control channel address. Defaults to the one in the config.
+    #[clap(short, long)]
+    pub server: Option<String>,
+}
+
+pub async fn exec(args: Args) -> anyhow::Result<()> {
+    let config = Config::try_from(&args.config)?;
+    let key = config.private_key().ok_or_else(|| {
+        anyhow::anyhow!(
+            "Listing gateways requires the server's network.server.private_key"
+        )
+    })?;
+    let keys = NoiseKeyPair::try_from(key)?;
+    let server = args
+        .server
+        .unwrap_or_else(|| config.remote_addr(InterfaceKind::Tcp));
+
+    let statuses = roxi_client::gateway_statuses(&server, &keys).await?;
+    if statuses.is_empty() {
+        println!("No seeders");
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:<24} {:>8} {:>8} {:>12} {:>12} {:>8}",
+        "PEER", "GATEWAY", "ASSIGNED", "TUNNELS", "BYTES IN", "BYTES OUT", "HEALTH"
+    );
+    for status in statuses {
+        let (tunnels, bytes_in, bytes_out, health) = match status.load {
+            Some(load) => (
+                format!("{}/{}", load.active_tunnels, load.max_clients),
+                load.bytes_in.to_string(),
+                load.bytes_out.to_string(),
+                match (load.healthy, load.is_full()) {
+                    (false, _) => "down",
+                    (true, true) => "full",
+                    (true, false) => "ok",
+                },
+            ),
+            None => ("-".into(), "-".into(), "-".into(), "unknown"),
+        };
+        println!(
+            "{:<24} {:<24} {:>8} {:>8} {:>12} {:>12} {:>8}",
+            status.peer.to_string(),
+            status.gateway.to_string(),
+            status.assigned,
+            tunnels,
+            bytes_in,
+            bytes_out,
+            health
+        );
+    }
+    Ok(())
+}
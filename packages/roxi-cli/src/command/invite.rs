@@ -0,0 +1,131 @@
+use clap::{Parser, Subcommand};
+use roxi_crypto::NoiseKeyPair;
+use roxi_lib::types::InterfaceKind;
+use roxi_server::{Config, Invite, InviteStore};
+use std::{
+    path::PathBuf,
+    time::{Duration, UNIX_EPOCH},
+};
+
+#[derive(Debug, Parser, Clone)]
+#[clap(name = "Roxi invite", about = "Roxi invite", version)]
+pub struct Args {
+    #[clap(subcommand)]
+    pub command: InviteCommand,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum InviteCommand {
+    #[clap(name = "create", about = "Mint a single-use invite token.")]
+    Create(CreateArgs),
+    #[clap(name = "list", about = "List invites and their usage.")]
+    List(ListArgs),
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct CreateArgs {
+    /// Server config file.
+    #[clap(short, long, help = "Server config file.")]
+    pub config: PathBuf,
+
+    /// How long the invite stays valid, e.g. `30m` or `7d`.
+    #[clap(long, default_value = "24h", value_parser = humantime::parse_duration)]
+    pub ttl: Duration,
+
+    /// How many clients may enroll with the invite.
+    #[clap(long, default_value_t = 1)]
+    pub uses: u32,
+
+    /// Label for the enrolled client.
+    #[clap(long)]
+    pub label: Option<String>,
+
+    /// Tunnel address to assign the enrolled client, e.g. `10.0.0.2/32`.
+    #[clap(long)]
+    pub address: Option<String>,
+
+    /// Who the invite is recorded as created by. Defaults to the current user.
+    #[clap(long)]
+    pub created_by: Option<String>,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct ListArgs {
+    /// Server config file.
+    #[clap(short, long, help = "Server config file.")]
+    pub config: PathBuf,
+}
+
+pub async fn exec(args: Args) -> anyhow::Result<()> {
+    match args.command {
+        InviteCommand::Create(args) => create(args),
+        InviteCommand::List(args) => list(args),
+    }
+}
+
+fn create(args: CreateArgs) -> anyhow::Result<()> {
+    let config = Config::try_from(&args.config)?;
+    let path = config.invites_path();
+    let created_by = args.created_by.unwrap_or_else(whoami::username);
+
+    let (_, (token, invite)) = InviteStore::update(&path, |store| {
+        let (token, invite) = store.create(
+            &created_by,
+            args.ttl.as_secs(),
+            args.uses,
+            args.label,
+            args.address,
+        );
+        Ok((token, invite.clone()))
+    })?;
+    println!(
+        "Invite {} for {} use(s), expires {}",
+        invite.id,
+        invite.max_uses,
+        expiry(&invite)
+    );
+
+    let server_key = match config.private_key() {
+        Some(key) => format!(
+            " --server-key {}",
+            NoiseKeyPair::try_from(key)?.public_base64()
+        ),
+        None => String::new(),
+    };
+    println!("Enroll with:");
+    println!(
+        "  roxi enroll --server {}{server_key} --token {token}",
+        config.remote_addr(InterfaceKind::Tcp)
+    );
+    Ok(())
+}
+
+fn list(args: ListArgs) -> anyhow::Result<()> {
+    let config = Config::try_from(&args.config)?;
+    let store = InviteStore::load(&config.invites_path())?;
+
+    for invite in store.invites() {
+        let state = if invite.is_used_up() {
+            "used"
+        } else if invite.is_expired() {
+            "expired"
+        } else {
+            "active"
+        };
+        println!(
+            "{}  {:<7}  {}/{} uses  expires {}  created by {}  {}",
+            invite.id,
+            state,
+            invite.uses,
+            invite.max_uses,
+            expiry(invite),
+            invite.created_by,
+            invite.label.as_deref().unwrap_or("-"),
+        );
+    }
+    Ok(())
+}
+
+fn expiry(invite: &Invite) -> humantime::Rfc3339Timestamp {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(invite.expires_at))
+}
@@ -0,0 +1,24 @@
+use clap::Parser;
+use roxi_crypto::{IdentityKeyPair, NoiseKeyPair};
+
+#[derive(Debug, Parser, Clone)]
+#[clap(name = "Roxi keygen", about = "Roxi keygen", version)]
+pub struct Args {
+    /// Generate a client identity key instead of a control channel key.
+    #[clap(long, help = "Generate a client identity key.")]
+    pub identity: bool,
+}
+
+pub async fn exec(args: Args) -> anyhow::Result<()> {
+    if args.identity {
+        let keys = IdentityKeyPair::generate()?;
+        println!("private_key: \"{}\"", keys.seed_base64());
+        println!("public_key: \"{}\"", keys.public_base64());
+        return Ok(());
+    }
+
+    let keys = NoiseKeyPair::generate()?;
+    println!("private_key: \"{}\"", keys.private_base64());
+    println!("public_key: \"{}\"", keys.public_base64());
+    Ok(())
+}
@@ -1,7 +1,13 @@
 pub(crate) mod auth;
+pub(crate) mod enroll;
 pub(crate) mod gateway;
+pub(crate) mod gateways;
+pub(crate) mod invite;
+pub(crate) mod keygen;
 pub(crate) mod ping;
 pub(crate) mod quick;
+pub(crate) mod revoke;
+pub(crate) mod rotate;
 pub(crate) mod seed;
 pub(crate) mod serve;
 pub(crate) mod stun;
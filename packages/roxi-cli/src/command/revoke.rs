@@ -0,0 +1,36 @@
+use clap::Parser;
+use roxi_crypto::NoiseKeyPair;
+use roxi_lib::types::InterfaceKind;
+use roxi_server::Config;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser, Clone)]
+#[clap(name = "Roxi revoke", about = "Roxi revoke", version)]
+pub struct Args {
+    /// Server config file, whose private key proves we are its operator.
+    #[clap(short, long, help = "Server config file.")]
+    pub config: PathBuf,
+
+    /// Label of the enrolled client to revoke.
+    #[clap(short, long)]
+    pub label: String,
+
+    /// Server control channel address. Defaults to the one in the config.
+    #[clap(short, long)]
+    pub server: Option<String>,
+}
+
+pub async fn exec(args: Args) -> anyhow::Result<()> {
+    let config = Config::try_from(&args.config)?;
+    let key = config.private_key().ok_or_else(|| {
+        anyhow::anyhow!("Revoking requires the server's network.server.private_key")
+    })?;
+    let keys = NoiseKeyPair::try_from(key)?;
+    let server = args
+        .server
+        .unwrap_or_else(|| config.remote_addr(InterfaceKind::Tcp));
+
+    roxi_client::revoke(&server, &keys, &args.label).await?;
+    println!("Revoked {}", args.label);
+    Ok(())
+}
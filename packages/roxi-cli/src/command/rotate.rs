@@ -0,0 +1,19 @@
+use clap::Parser;
+use roxi_client::{Client, Config};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser, Clone)]
+#[clap(name = "Roxi rotate", about = "Roxi rotate", version)]
+pub struct Args {
+    /// Config file, updated with the new identity key.
+    #[clap(short, long, help = "Config file.")]
+    pub config: PathBuf,
+}
+
+pub async fn exec(args: Args) -> anyhow::Result<()> {
+    let config = Config::try_from(&args.config)?;
+    let mut client = Client::new(config).await?;
+    client.rotate_identity().await?;
+    println!("Rotated identity key, now {:?}", client.client_id());
+    Ok(())
+}
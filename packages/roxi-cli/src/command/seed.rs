@@ -1,6 +1,7 @@
 use clap::Parser;
 use roxi_client::{Client, Config};
 use roxi_lib::util::init_logging;
+use roxi_server::Seeder;
 use std::path::PathBuf;
 
 #[derive(Debug, Parser, Clone)]
@@ -18,10 +19,12 @@ pub async fn exec(args: Args) -> anyhow::Result<()> {
 
     init_logging().await?;
 
-    let mut client = Client::new(config).await?;
-    if let Err(e) = client.seed().await {
-        tracing::error!("Could not seed client: {e}");
+    // Stays connected, serving as the gateway of the peers the server assigns
+    let mut seeder = Seeder::new(Client::new(config).await?);
+    if let Err(e) = seeder.run().await {
+        tracing::error!("Seeding ended: {e}");
     }
+    seeder.stop().await?;
 
     Ok(())
 }
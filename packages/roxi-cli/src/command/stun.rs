@@ -9,6 +9,10 @@ pub struct Args {
     /// Config file.
     #[clap(short, long, help = "Config file.")]
     pub config: PathBuf,
+
+    /// Ask the server which address it observed.
+    #[clap(long, help = "Ask the server which address it observed.")]
+    pub check: bool,
 }
 
 pub async fn exec(args: Args) -> anyhow::Result<()> {
@@ -19,8 +23,20 @@ pub async fn exec(args: Args) -> anyhow::Result<()> {
     init_logging().await?;
 
     let mut client = Client::new(config).await?;
-    if let Err(e) = client.stun().await {
-        tracing::error!("Could not contact stun server: {e}");
+    match client.stun().await {
+        Ok(addr) => {
+            tracing::info!("Reflexive address: {addr}");
+            client.config().save()?;
+
+            if args.check {
+                client.authenticate().await?;
+                match client.request_stun_info().await? {
+                    Some(info) => tracing::info!("Server observed: {}", info.addr()),
+                    None => tracing::warn!("Server has not observed a STUN binding"),
+                }
+            }
+        }
+        Err(e) => tracing::error!("Could not contact stun server: {e}"),
     }
 
     Ok(())
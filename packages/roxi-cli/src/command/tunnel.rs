@@ -22,6 +22,12 @@ pub async fn exec(args: Args) -> anyhow::Result<()> {
 
     if let Err(e) = client.tunnel().await {
         tracing::error!("Tunnel error: {e}");
+        return Ok(());
+    }
+
+    // Keep the session alive for as long as the tunnel is up
+    if let Err(e) = client.heartbeat().await {
+        tracing::error!("Session ended: {e}");
     }
 
     Ok(())
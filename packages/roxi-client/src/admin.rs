@@ -0,0 +1,113 @@
+use crate::{error::ClientError, ClientResult};
+use futures::{SinkExt, StreamExt};
+use roxi_crypto::NoiseKeyPair;
+use roxi_proto::{
+    GatewayStatus, Message, MessageFramed, MessageKind, MessageStatus, PeerRevocation,
+    RevokeRequest, SecureSession, DEFAULT_MAX_FRAME_SIZE,
+};
+use std::net::SocketAddr;
+use tokio::{
+    net::TcpStream,
+    time::{timeout, Duration},
+};
+
+/// How long to wait on the remote at each step of a one-off request.
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+/// Revokes the enrolled client labelled `label`. Only the server's own control
+/// channel `keys` are allowed to.
+pub async fn revoke(server: &str, keys: &NoiseKeyPair, label: &str) -> ClientResult<()> {
+    let request = RevokeRequest {
+        label: label.to_string(),
+    };
+    let (msg, _) = exchange(
+        server,
+        keys,
+        Some(keys.public()),
+        MessageKind::RevokeRequest,
+        bincode::serialize(&request)?,
+    )
+    .await?;
+    expect(&msg, MessageKind::RevokeResponse)
+}
+
+/// The seeders of the server at `server` and the load they last reported, as
+/// its operator holding `keys`.
+pub async fn gateway_statuses(
+    server: &str,
+    keys: &NoiseKeyPair,
+) -> ClientResult<Vec<GatewayStatus>> {
+    let (msg, _) = exchange(
+        server,
+        keys,
+        Some(keys.public()),
+        MessageKind::GatewayStatusRequest,
+        bincode::serialize(&())?,
+    )
+    .await?;
+    expect(&msg, MessageKind::GatewayStatusResponse)?;
+    Ok(bincode::deserialize(&msg.data())?)
+}
+
+/// Tells the gateway at `gateway` to drop a revoked client's WireGuard peer,
+/// as the server holding `keys`.
+pub async fn drop_gateway_peer(
+    gateway: &str,
+    keys: &NoiseKeyPair,
+    revocation: &PeerRevocation,
+) -> ClientResult<()> {
+    let (msg, _) = exchange(
+        gateway,
+        keys,
+        None,
+        MessageKind::PeerRevokeRequest,
+        bincode::serialize(revocation)?,
+    )
+    .await?;
+    expect(&msg, MessageKind::PeerRevokeResponse)
+}
+
+fn expect(msg: &Message, kind: MessageKind) -> ClientResult<()> {
+    if *msg.kind() != kind {
+        return Err(ClientError::UnexpectedMessage(*msg.kind()));
+    }
+    match msg.status() {
+        MessageStatus::r#Ok => Ok(()),
+        status => Err(ClientError::UnexpectedStatus(*status)),
+    }
+}
+
+/// Sends a single message over a fresh secure connection to `remote` and
+/// returns the reply, along with the local address the connection came from.
+pub(crate) async fn exchange(
+    remote: &str,
+    keys: &NoiseKeyPair,
+    pinned: Option<&[u8]>,
+    kind: MessageKind,
+    data: Vec<u8>,
+) -> ClientResult<(Message, SocketAddr)> {
+    let remote: SocketAddr = remote.parse()?;
+    let wait = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    let mut stream = timeout(wait, TcpStream::connect(remote)).await??;
+    let local = stream.local_addr()?;
+
+    let session =
+        timeout(wait, SecureSession::initiate(&mut stream, keys, pinned)).await??;
+    let mut framed = MessageFramed::new(stream, session.codec(DEFAULT_MAX_FRAME_SIZE));
+
+    timeout(
+        wait,
+        framed.send(Message::new(
+            kind,
+            MessageStatus::Pending,
+            remote.to_string(),
+            Some(data),
+        )),
+    )
+    .await??;
+
+    let msg = timeout(wait, framed.next())
+        .await?
+        .ok_or(ClientError::NoResponse)??;
+    Ok((msg, local))
+}
@@ -1,54 +1,188 @@
-use crate::{config::Config, ClientResult};
-use bytes::BytesMut;
-use roxi_lib::types::{Address, ClientId, InterfaceKind};
+use crate::{
+    config::{Config, Stun},
+    error::ClientError,
+    event::Events,
+    mux::Multiplexer,
+    ClientResult,
+};
+use futures::future::BoxFuture;
+use roxi_crypto::{decode_public_key, IdentityKeyPair, NoiseKeyPair};
+use roxi_lib::types::{
+    config::WireGuardConfPeer, Address, ClientId, InterfaceKind, StunInfo,
+};
 use roxi_proto::{
-    command, Message, MessageKind, MessageStatus, WireGuardProtoConfig,
+    AuthenticationChallenge, AuthenticationRequest, Candidate, CandidateKind,
+    DisconnectReason, Features, GatewayInfo, GatewayLoad, GatewayReady, Hello, HelloAck,
+    KeyRotationRequest, Message, MessageKind, MessageStatus, PeerTunnelClosed, PunchInfo,
+    PunchProbe, PunchProbeKind, PunchRequest, RelayInfo, RelayRequest, ResumeRequest,
+    SecureSession, SessionTicket, StunClass, StunMessage, WireGuardProtoConfig,
     WireGuardProtoPeer,
 };
-use std::sync::Arc;
+use std::{fs, net::SocketAddr, sync::Arc};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, UdpSocket},
+    net::{TcpSocket, UdpSocket},
     sync::Mutex,
-    time::{sleep, timeout, Duration},
+    time::{self, timeout, timeout_at, Duration, Instant},
 };
 
-const STUN_BINDING_REQUEST_TYPE: u16 = 0x0001;
-const STUN_MAGIC_COOKIE: u32 = 0x2112A442;
+const STUN_INITIAL_RTO_MS: u64 = 500;
+const STUN_MAX_RETRANSMITS: usize = 3;
+const NAT_PUNCH_INITIAL_INTERVAL_MS: u64 = 100;
 
 pub struct Client {
     config: Config,
+    identity: Option<IdentityKeyPair>,
     wireguard_config: Arc<Mutex<WireGuardProtoConfig>>,
-    tcp: TcpStream,
-    udp: UdpSocket,
-    peer_stream: Option<(ClientId, Address, Arc<Mutex<TcpStream>>)>,
+    tcp: Option<Arc<Multiplexer>>,
+    /// Bound to WireGuard's listen port while punching, so that the mapping
+    /// punched is the one WireGuard sends from once it binds the port again.
+    udp: Option<Arc<UdpSocket>>,
+    /// Held while a detached punch answer runs, as answers share WireGuard's port.
+    punching: Arc<Mutex<()>>,
+    peer_stream: Option<(ClientId, Address, Multiplexer)>,
+    protocol: Option<HelloAck>,
+    peer_endpoint: Option<Address>,
+    /// Ticket for resuming the session on a new connection.
+    ticket: Option<SessionTicket>,
 }
 
 impl Client {
+    /// Derived from the identity key if there is one, else the gateway address.
     pub fn client_id(&self) -> ClientId {
-        ClientId::from(self.config.gateway_remote_addr(InterfaceKind::Tcp))
+        match &self.identity {
+            Some(identity) => ClientId::from_public_key(identity.public()),
+            None => ClientId::from(self.config.gateway_remote_addr(InterfaceKind::Tcp)),
+        }
     }
 
     pub fn config(&self) -> &Config {
         &self.config
     }
 
-    pub async fn new(config: Config) -> ClientResult<Self> {
+    pub async fn new(mut config: Config) -> ClientResult<Self> {
         let wireguard_config = WireGuardProtoConfig::try_from(config.wireguard())?;
+        // Generated now rather than by the gateway, so that it can be announced
+        // for peers to pin before the gateway is launched
+        if config.gateway_private_key().is_none() {
+            config.set_gateway_private_key(NoiseKeyPair::generate()?.private_base64());
+        }
 
         Ok(Self {
+            identity: config.identity()?,
             config: config.clone(),
             wireguard_config: Arc::new(Mutex::new(wireguard_config)),
-            tcp: TcpStream::connect(&config.remote_addr(InterfaceKind::Tcp))
-                .await
-                .expect("Failed to connect to TCP server"),
-            udp: UdpSocket::bind(&config.addr(InterfaceKind::Udp))
-                .await
-                .expect("Failed to bind to UDP socket"),
+            tcp: None,
+            udp: None,
+            punching: Arc::new(Mutex::new(())),
             peer_stream: None,
+            protocol: None,
+            peer_endpoint: None,
+            ticket: Self::load_ticket(&config),
         })
     }
 
+    /// The ticket saved by an earlier run, if it is still valid.
+    fn load_ticket(config: &Config) -> Option<SessionTicket> {
+        let data = fs::read(config.ticket_path()).ok()?;
+        match bincode::deserialize::<SessionTicket>(&data) {
+            Ok(ticket) if !ticket.is_expired() => Some(ticket),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!("Ignoring unreadable session ticket: {e}");
+                None
+            }
+        }
+    }
+
+    /// Keeps the ticket in `msg` for resuming the session later.
+    fn store_ticket(&mut self, msg: &Message) -> ClientResult<()> {
+        let data = msg.data();
+        if data.is_empty() {
+            return Ok(());
+        }
+        let ticket: SessionTicket = bincode::deserialize(&data)?;
+        if let Err(e) = fs::write(self.config.ticket_path(), &data) {
+            tracing::warn!("Failed to save session ticket: {e}");
+        }
+        self.ticket = Some(ticket);
+        Ok(())
+    }
+
+    fn forget_ticket(&mut self) {
+        if self.ticket.take().is_some() {
+            let _ = fs::remove_file(self.config.ticket_path());
+        }
+    }
+
+    /// The control connection to the server, established on first use.
+    async fn control(&mut self) -> ClientResult<Arc<Multiplexer>> {
+        let tcp = match self.tcp.take() {
+            Some(tcp) => tcp,
+            None => Arc::new(Self::connect(&self.config).await?),
+        };
+        Ok(self.tcp.insert(tcp).clone())
+    }
+
+    /// The socket punched from, bound to WireGuard's listen port on first use.
+    async fn udp(&mut self) -> ClientResult<Arc<UdpSocket>> {
+        if let Some(udp) = &self.udp {
+            return Ok(udp.clone());
+        }
+        let port = self.wireguard_config.lock().await.interface.port;
+        let udp = Self::bind_wireguard_port(&self.config, port).await?;
+        Ok(self.udp.insert(Arc::new(udp)).clone())
+    }
+
+    async fn bind_wireguard_port(config: &Config, port: u16) -> ClientResult<UdpSocket> {
+        UdpSocket::bind(config.wireguard_bind_addr(port))
+            .await
+            .map_err(|e| ClientError::WireGuardPortInUse(port, e))
+    }
+
+    /// Connects to the server from the configured interface, so that hosts with
+    /// several addresses present the one the config names, and secures the
+    /// connection before any message is sent.
+    async fn connect(config: &Config) -> ClientResult<Multiplexer> {
+        let remote: SocketAddr = config.remote_addr(InterfaceKind::Tcp).parse()?;
+        let pinned = match config.server_public_key() {
+            Some(key) => Some(decode_public_key(key)?),
+            None => {
+                tracing::warn!("No server public key configured, server is not verified");
+                None
+            }
+        };
+        Self::connect_to(config, remote, pinned.as_deref()).await
+    }
+
+    /// Opens a secure connection to `remote`, verifying its static key against
+    /// `pinned` if given.
+    async fn connect_to(
+        config: &Config,
+        remote: SocketAddr,
+        pinned: Option<&[u8]>,
+    ) -> ClientResult<Multiplexer> {
+        let local: SocketAddr = config.bind_addr().parse()?;
+        let socket = match remote {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if !local.ip().is_unspecified() {
+            socket.bind(local)?;
+        }
+        let mut stream = socket.connect(remote).await?;
+
+        let session = timeout(
+            Duration::from_secs(config.response_timeout()),
+            SecureSession::initiate(&mut stream, &NoiseKeyPair::generate()?, pinned),
+        )
+        .await??;
+
+        Ok(Multiplexer::new(
+            stream,
+            session.codec(config.max_frame_size()),
+        ))
+    }
+
     pub async fn ping(&mut self) -> ClientResult<Option<Message>> {
         match self
             .send(Message::new(
@@ -70,18 +204,136 @@ impl Client {
         }
     }
 
+    /// Pings the server every `heartbeat_interval` seconds so the session does
+    /// not go idle, until the server ends it.
+    pub async fn heartbeat(&mut self) -> ClientResult<()> {
+        let mut interval =
+            time::interval(Duration::from_secs(self.config.heartbeat_interval()));
+        // The first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if self.ping().await?.is_none() {
+                return Err(ClientError::NoResponse);
+            }
+        }
+    }
+
+    /// Negotiates a protocol version and feature set with the server.
+    pub async fn hello(&mut self) -> ClientResult<HelloAck> {
+        let msg = self
+            .send(Message::new(
+                MessageKind::Hello,
+                MessageStatus::Pending,
+                self.config.remote_addr(InterfaceKind::Tcp),
+                Some(bincode::serialize(&Hello::local())?),
+            ))
+            .await?
+            .ok_or(ClientError::NoResponse)?;
+
+        match msg.status() {
+            MessageStatus::r#Ok => {
+                let ack: HelloAck = bincode::deserialize(&msg.data())?;
+                tracing::info!("Negotiated protocol with server: {ack:?}");
+                if let Some(tcp) = &self.tcp {
+                    tcp.set_version(ack.version).await;
+                }
+                self.protocol = Some(ack.clone());
+                Ok(ack)
+            }
+            _ => {
+                let server: Hello = bincode::deserialize(&msg.data())?;
+                tracing::error!(
+                    "Server rejected protocol versions {:?}",
+                    Hello::local().versions
+                );
+                Err(ClientError::IncompatibleVersion(server.versions))
+            }
+        }
+    }
+
+    pub fn protocol(&self) -> Option<&HelloAck> {
+        self.protocol.as_ref()
+    }
+
+    /// What the server is told about this client when authenticating.
+    pub fn authentication_request(&self) -> ClientResult<AuthenticationRequest> {
+        let wireguard_public_key = match self.config.wireguard_public_key() {
+            Ok(key) => Some(key.to_string()),
+            Err(e) => {
+                tracing::warn!("No WireGuard public key to announce: {e}");
+                None
+            }
+        };
+
+        let request = AuthenticationRequest::new(
+            self.client_id(),
+            Address::try_from(self.config.gateway_remote_addr(InterfaceKind::Tcp))?,
+            wireguard_public_key,
+            self.protocol
+                .as_ref()
+                .map(|ack| ack.features)
+                .unwrap_or(Features::local()),
+        )
+        .with_tags(self.config.gateway_tags().to_vec());
+        Ok(match self.config.gateway_private_key() {
+            Some(key) => request
+                .with_gateway_public_key(NoiseKeyPair::try_from(key)?.public_base64()),
+            None => request,
+        })
+    }
+
+    /// Asks the server for a nonce to prove our credentials against.
+    async fn request_challenge(&mut self) -> ClientResult<AuthenticationChallenge> {
+        let msg = self
+            .send(Message::new(
+                MessageKind::AuthenticationChallengeRequest,
+                MessageStatus::Pending,
+                self.config.remote_addr(InterfaceKind::Tcp),
+                None,
+            ))
+            .await?
+            .ok_or(ClientError::NoResponse)?;
+
+        if *msg.kind() != MessageKind::AuthenticationChallengeResponse {
+            return Err(ClientError::UnexpectedMessage(*msg.kind()));
+        }
+
+        Ok(bincode::deserialize(&msg.data())?)
+    }
+
+    /// Authenticates the control connection. A new connection resumes the
+    /// previous session with its ticket when the server supports it, falling
+    /// back to a full challenge-response otherwise.
     pub async fn authenticate(&mut self) -> ClientResult<Option<Message>> {
-        let secret = self.config.clone().try_into()?;
+        if self.protocol.is_none() {
+            self.hello().await?;
+            if let Some(msg) = self.resume().await? {
+                return Ok(Some(msg));
+            }
+        }
+
+        let challenge = self.request_challenge().await?;
+        let mut request = self.authentication_request()?;
+        match (&self.identity, self.config.shared_key()) {
+            (Some(identity), _) => request.sign_with_identity(identity, &challenge)?,
+            (None, Some(key)) => request.sign(&key, &challenge)?,
+            (None, None) => return Err(ClientError::NoCredentials),
+        }
+        let request = bincode::serialize(&request)?;
         match self
             .send(Message::new(
                 MessageKind::AuthenticationRequest,
                 MessageStatus::Pending,
                 self.config.remote_addr(InterfaceKind::Tcp),
-                Some(secret),
+                Some(request),
             ))
             .await?
         {
             Some(msg) => {
+                if *msg.status() == MessageStatus::r#Ok {
+                    self.store_ticket(&msg)?;
+                }
                 tracing::info!("Successfully authenticated client connection");
                 Ok(Some(msg))
             }
@@ -92,29 +344,95 @@ impl Client {
         }
     }
 
-    pub async fn stun(&mut self) -> ClientResult<()> {
-        let mut request = BytesMut::with_capacity(20);
-        request.extend_from_slice(&u16::to_be_bytes(STUN_BINDING_REQUEST_TYPE));
-        request.extend_from_slice(&u16::to_be_bytes(0)); // Length
-        request.extend_from_slice(&u32::to_be_bytes(STUN_MAGIC_COOKIE));
+    /// Picks the previous session back up with its ticket. Returns `None` if
+    /// there is nothing to resume or the server declined.
+    async fn resume(&mut self) -> ClientResult<Option<Message>> {
+        let supported = self
+            .protocol
+            .as_ref()
+            .is_some_and(|ack| ack.features.contains(Features::RESUME));
+        let token = match &self.ticket {
+            Some(ticket) if supported && !ticket.is_expired() => ticket.token.clone(),
+            _ => return Ok(None),
+        };
+
+        let msg = self
+            .send(Message::new(
+                MessageKind::ResumeRequest,
+                MessageStatus::Pending,
+                self.config.remote_addr(InterfaceKind::Tcp),
+                Some(bincode::serialize(&ResumeRequest { token })?),
+            ))
+            .await?
+            .ok_or(ClientError::NoResponse)?;
 
-        for _ in 0..12 {
-            request.extend_from_slice(&[rand::random::<u8>()]);
+        match msg.status() {
+            MessageStatus::r#Ok => {
+                self.store_ticket(&msg)?;
+                tracing::info!("Resumed session");
+                Ok(Some(msg))
+            }
+            status => {
+                tracing::info!("Server declined to resume the session ({status:?})");
+                self.forget_ticket();
+                Ok(None)
+            }
         }
+    }
 
-        tracing::info!("Sending info to STUN server");
-        let send_result = timeout(
-            Duration::from_secs(1),
-            self.udp
-                .send_to(&request, self.config.remote_addr(InterfaceKind::Udp)),
-        )
-        .await;
+    /// Discovers this client's reflexive address with a STUN Binding request.
+    ///
+    /// The request is retransmitted with a doubling timeout (RFC 5389, 7.2.1) until
+    /// a response with a matching transaction ID arrives. The discovered address is
+    /// stored in the client's config.
+    pub async fn stun(&mut self) -> ClientResult<Address> {
+        let udp = self.udp().await?;
+        let addr =
+            Self::stun_on(&udp, &self.config.remote_addr(InterfaceKind::Udp)).await?;
+        self.config.set_stun(Stun::from(addr.clone()));
+        Ok(addr)
+    }
+
+    async fn stun_on(udp: &UdpSocket, server: &str) -> ClientResult<Address> {
+        let request = StunMessage::binding_request();
+        let encoded = request.encode();
+        let mut rto = Duration::from_millis(STUN_INITIAL_RTO_MS);
+        let mut buff = [0u8; 1024];
 
-        if let Ok(Ok(_)) = send_result {
-            tracing::info!("Successfully sent info to STUN server");
+        for _ in 0..=STUN_MAX_RETRANSMITS {
+            tracing::info!("Sending binding request to STUN server {server}");
+            udp.send_to(&encoded, server).await?;
+
+            let deadline = Instant::now() + rto;
+            while let Ok(result) = timeout_at(deadline, udp.recv_from(&mut buff)).await {
+                let (len, _) = result?;
+                let response = match StunMessage::decode(&buff[..len]) {
+                    Ok(response)
+                        if response.transaction_id() == request.transaction_id() =>
+                    {
+                        response
+                    }
+                    _ => continue,
+                };
+
+                if response.class() == StunClass::ErrorResponse {
+                    let (code, reason) = response.error_code().unwrap_or((0, ""));
+                    return Err(ClientError::StunError(code, reason.to_string()));
+                }
+
+                let addr = Address::from(
+                    response
+                        .mapped_address()
+                        .ok_or(ClientError::NotAStunBindingResponse)?,
+                );
+                tracing::info!("Discovered reflexive address {addr}");
+                return Ok(addr);
+            }
+
+            rto *= 2;
         }
 
-        Ok(())
+        Err(ClientError::NoResponse)
     }
 
     pub async fn seed(&mut self) -> ClientResult<Option<Message>> {
@@ -139,8 +457,46 @@ impl Client {
         }
     }
 
-    pub async fn request_stun_info(&mut self) -> ClientResult<Option<Message>> {
-        match self
+    /// Replaces this client's identity key with a fresh one, keeping its
+    /// enrollment, and saves the new key to the config. The server closes the
+    /// connection afterwards, so the next request reconnects with the new key.
+    pub async fn rotate_identity(&mut self) -> ClientResult<()> {
+        let old = match &self.identity {
+            Some(identity) => identity.public().to_vec(),
+            None => return Err(ClientError::NoIdentity),
+        };
+        self.authenticate().await?;
+
+        let identity = IdentityKeyPair::generate()?;
+        let request = KeyRotationRequest::new(&old, &identity);
+        let msg = self
+            .send(Message::new(
+                MessageKind::KeyRotationRequest,
+                MessageStatus::Pending,
+                self.config.remote_addr(InterfaceKind::Tcp),
+                Some(bincode::serialize(&request)?),
+            ))
+            .await?
+            .ok_or(ClientError::NoResponse)?;
+        match msg.status() {
+            MessageStatus::r#Ok => {}
+            status => return Err(ClientError::UnexpectedStatus(*status)),
+        }
+
+        self.config.set_identity(&identity);
+        self.config.save()?;
+        self.identity = Some(identity);
+        self.tcp = None;
+        self.protocol = None;
+        self.forget_ticket();
+        tracing::info!("Rotated identity key, now {:?}", self.client_id());
+        Ok(())
+    }
+
+    /// Asks the server which reflexive address it observed for this client's last
+    /// STUN Binding request. Returns `None` if the server has not seen one.
+    pub async fn request_stun_info(&mut self) -> ClientResult<Option<StunInfo>> {
+        let msg = self
             .send(Message::new(
                 MessageKind::StunInfoRequest,
                 MessageStatus::Pending,
@@ -148,15 +504,22 @@ impl Client {
                 None,
             ))
             .await?
-        {
-            Some(msg) => {
-                tracing::info!("Successfully received STUN info");
-                Ok(Some(msg))
+            .ok_or(ClientError::NoResponse)?;
+
+        match msg.status() {
+            MessageStatus::r#Ok => {
+                let info: StunInfo = bincode::deserialize(&msg.data())?;
+                tracing::info!("Server observed reflexive address {}", info.addr());
+                Ok(Some(info))
             }
-            None => {
-                tracing::error!("Failed to receive STUN info");
+            MessageStatus::NotFound => {
+                tracing::warn!("Server has not seen a STUN binding from this client");
                 Ok(None)
             }
+            status => {
+                tracing::error!("Failed to receive STUN info: {status:?}");
+                Err(ClientError::UnexpectedStatus(*status))
+            }
         }
     }
 
@@ -181,74 +544,464 @@ impl Client {
         }
     }
 
-    pub async fn nat_punch(&mut self, addr: Address) -> ClientResult<()> {
-        tracing::info!("Attempting NAT punch to {addr:?}");
+    /// Tells the server that our gateway now serves `peer`, which the server
+    /// assigned to us.
+    pub async fn report_ready(&mut self, peer: ClientId) -> ClientResult<()> {
+        let msg = self
+            .send(Message::new(
+                MessageKind::GatewayReadyRequest,
+                MessageStatus::Pending,
+                self.config.remote_addr(InterfaceKind::Tcp),
+                Some(bincode::serialize(&GatewayReady { peer })?),
+            ))
+            .await?
+            .ok_or(ClientError::NoResponse)?;
 
-        // TODO: What is the timeout on this?
-        match TcpStream::connect(&addr.to_string()).await {
-            Ok(stream) => {
-                tracing::info!(
-                    "NAT punch received response. Connection to {addr:?} is open!"
-                );
-                let client_id = ClientId::from(addr.clone());
-                self.peer_stream = Some((client_id, addr, Arc::new(Mutex::new(stream))));
+        match msg.status() {
+            MessageStatus::r#Ok => Ok(()),
+            status => {
+                tracing::error!("Server refused gateway readiness: {status:?}");
+                Err(ClientError::UnexpectedStatus(*status))
             }
-            Err(e) => {
-                tracing::info!("NAT punch failed. Will try again: {e}");
-                let max_attempts = self.config.nat_punch_attempts();
-                let mut attempts = 0;
-                while attempts < max_attempts {
-                    if let Err(e) = TcpStream::connect(&addr.to_string()).await {
-                        tracing::warn!(
-                            "NAT punch attempt {}/{} failed: {}",
-                            attempts,
-                            attempts + 1,
-                            e
-                        );
-                        attempts += 1;
-                    }
+        }
+    }
+
+    /// Tells the server that our gateway no longer serves `peer`.
+    pub async fn report_tunnel_closed(&mut self, peer: ClientId) -> ClientResult<()> {
+        let msg = self
+            .send(Message::new(
+                MessageKind::PeerTunnelClosedRequest,
+                MessageStatus::Pending,
+                self.config.remote_addr(InterfaceKind::Tcp),
+                Some(bincode::serialize(&PeerTunnelClosed { peer })?),
+            ))
+            .await?
+            .ok_or(ClientError::NoResponse)?;
+
+        match msg.status() {
+            MessageStatus::r#Ok => Ok(()),
+            status => {
+                tracing::error!("Server refused closed tunnel report: {status:?}");
+                Err(ClientError::UnexpectedStatus(*status))
+            }
+        }
+    }
+
+    /// Tells the server how busy our gateway is.
+    pub async fn report_load(&mut self, load: GatewayLoad) -> ClientResult<()> {
+        let msg = self
+            .send(Message::new(
+                MessageKind::GatewayLoadRequest,
+                MessageStatus::Pending,
+                self.config.remote_addr(InterfaceKind::Tcp),
+                Some(bincode::serialize(&load)?),
+            ))
+            .await?
+            .ok_or(ClientError::NoResponse)?;
+
+        match msg.status() {
+            MessageStatus::r#Ok => Ok(()),
+            status => {
+                tracing::error!("Server refused gateway load report: {status:?}");
+                Err(ClientError::UnexpectedStatus(*status))
+            }
+        }
+    }
+
+    /// Gathers the addresses this client may be reachable at: the host addresses
+    /// of its UDP socket and its server-reflexive address. The latter is taken from
+    /// the config if `stun` has already run, and discovered otherwise.
+    pub async fn gather_candidates(&mut self) -> ClientResult<Vec<Candidate>> {
+        let udp = self.udp().await?;
+        let reflexive = match self.config.stun_addr() {
+            Ok(addr) => Some(Address::try_from(addr)?),
+            Err(_) => self.stun().await.ok(),
+        };
+        Self::candidates_on(&udp, reflexive)
+    }
+
+    fn candidates_on(
+        udp: &UdpSocket,
+        reflexive: Option<Address>,
+    ) -> ClientResult<Vec<Candidate>> {
+        let local = udp.local_addr()?;
+        let ips = if local.ip().is_unspecified() {
+            if_addrs::get_if_addrs()?
+                .into_iter()
+                .filter(|iface| {
+                    !iface.is_loopback() && iface.ip().is_ipv4() == local.is_ipv4()
+                })
+                .map(|iface| iface.ip())
+                .collect()
+        } else {
+            vec![local.ip()]
+        };
+
+        let mut candidates = ips
+            .into_iter()
+            .zip((0..=u16::MAX).rev())
+            .map(|(ip, pref)| {
+                Candidate::new(CandidateKind::Host, Address::new(ip, local.port()), pref)
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(addr) = reflexive {
+            if !candidates.iter().any(|c| c.addr == addr) {
+                candidates.push(Candidate::new(
+                    CandidateKind::ServerReflexive,
+                    addr,
+                    u16::MAX,
+                ));
+            }
+        }
+
+        tracing::info!("Gathered candidates: {candidates:?}");
+        Ok(candidates)
+    }
+
+    /// Offers our candidates to `peer` through the server, then runs connectivity
+    /// checks against the candidates it answers with. Returns the address of the
+    /// best working candidate.
+    ///
+    /// Punches from WireGuard's listen port, which must be free until then and is
+    /// let go afterwards for WireGuard to bind.
+    pub async fn nat_punch(&mut self, peer: ClientId) -> ClientResult<Address> {
+        let punched = self.nat_punch_inner(peer).await;
+        self.udp = None;
+        punched
+    }
+
+    async fn nat_punch_inner(&mut self, peer: ClientId) -> ClientResult<Address> {
+        tracing::info!("Requesting NAT punch to {peer:?}");
+
+        let candidates = self.gather_candidates().await?;
+        // Answered once the peer has sent its own candidates
+        let response_timeout = Duration::from_secs(self.config.response_timeout());
+        let msg = timeout(
+            response_timeout,
+            self.send(Message::new(
+                MessageKind::NATPunchRequest,
+                MessageStatus::Pending,
+                self.config.remote_addr(InterfaceKind::Tcp),
+                Some(bincode::serialize(&PunchRequest { peer, candidates })?),
+            )),
+        )
+        .await??
+        .ok_or(ClientError::NoResponse)?;
+        if *msg.status() != MessageStatus::r#Ok {
+            tracing::error!("Server could not coordinate NAT punch: {:?}", msg.status());
+            return Err(ClientError::UnexpectedStatus(*msg.status()));
+        }
+
+        let info: PunchInfo = bincode::deserialize(&msg.data())?;
+        self.punch(info).await
+    }
+
+    /// Waits for the server to relay another peer's `NATPunchRequest`, answers with
+    /// our own candidates, then runs connectivity checks. This is the counterpart of
+    /// `nat_punch` on the seeding side.
+    pub async fn accept_punch(&mut self) -> ClientResult<Address> {
+        let msg = self.wait_for(MessageKind::NATPunchRequest).await?;
+        self.answer_punch(bincode::deserialize(&msg.data())?).await
+    }
+
+    /// Answers a punch offer already received, e.g. as an `Event::Punch`.
+    pub async fn answer_punch(&mut self, info: PunchInfo) -> ClientResult<Address> {
+        let punched = self.answer_punch_inner(info).await;
+        self.udp = None;
+        punched
+    }
+
+    async fn answer_punch_inner(&mut self, info: PunchInfo) -> ClientResult<Address> {
+        let candidates = self.gather_candidates().await?;
+        self.notify(Self::punch_response(&self.config, &info, candidates)?)
+            .await?;
+
+        self.punch(info).await
+    }
+
+    /// Like `answer_punch`, but returns the answer as a future that does not
+    /// borrow the client, so that the caller can go on handling other messages
+    /// while the connectivity checks run. Answers run one at a time, as they
+    /// all punch from WireGuard's listen port.
+    pub async fn answer_punch_detached(
+        &mut self,
+        info: PunchInfo,
+    ) -> ClientResult<BoxFuture<'static, ClientResult<Address>>> {
+        let control = self.control().await?;
+        let config = self.config.clone();
+        let port = self.wireguard_config.lock().await.interface.port;
+        let punching = Arc::clone(&self.punching);
+        self.udp = None;
+
+        Ok(Box::pin(async move {
+            let _punching = punching.lock().await;
+            let udp = Self::bind_wireguard_port(&config, port).await?;
+            let reflexive = match config.stun_addr() {
+                Ok(addr) => Some(Address::try_from(addr)?),
+                Err(_) => Self::stun_on(&udp, &config.remote_addr(InterfaceKind::Udp))
+                    .await
+                    .ok(),
+            };
+            let candidates = Self::candidates_on(&udp, reflexive)?;
+
+            let request_timeout = Duration::from_secs(config.request_timeout());
+            let response = Self::punch_response(&config, &info, candidates)?;
+            timeout(request_timeout, control.notify(response)).await??;
+
+            Self::punch_on(&udp, &config, &info).await
+        }))
+    }
+
+    fn punch_response(
+        config: &Config,
+        info: &PunchInfo,
+        candidates: Vec<Candidate>,
+    ) -> ClientResult<Message> {
+        Ok(Message::new(
+            MessageKind::NATPunchResponse,
+            MessageStatus::r#Ok,
+            config.remote_addr(InterfaceKind::Tcp),
+            Some(bincode::serialize(&PunchInfo {
+                peer: info.peer.clone(),
+                candidates,
+                token: info.token,
+            })?),
+        ))
+    }
+
+    /// Asks the server to relay a tunnel with `peer`, for when a hole punch fails.
+    /// Returns the relay address to use as the peer's endpoint.
+    pub async fn request_relay(&mut self, peer: ClientId) -> ClientResult<Address> {
+        tracing::info!("Requesting relay to {peer:?}");
+
+        let msg = self
+            .send(Message::new(
+                MessageKind::RelayRequest,
+                MessageStatus::Pending,
+                self.config.remote_addr(InterfaceKind::Tcp),
+                Some(bincode::serialize(&RelayRequest { peer })?),
+            ))
+            .await?
+            .ok_or(ClientError::NoResponse)?;
+
+        if *msg.status() != MessageStatus::r#Ok {
+            tracing::error!("Server could not relay tunnel: {:?}", msg.status());
+            return Err(ClientError::UnexpectedStatus(*msg.status()));
+        }
+
+        let info: RelayInfo = bincode::deserialize(&msg.data())?;
+        Ok(self.use_relay(info))
+    }
+
+    /// Waits for the server to announce a relayed tunnel requested by another peer.
+    pub async fn accept_relay(&mut self) -> ClientResult<Address> {
+        let msg = self.wait_for(MessageKind::RelayRequest).await?;
+        self.join_relay(bincode::deserialize(&msg.data())?).await
+    }
+
+    /// Takes up a relayed tunnel already announced, e.g. as an `Event::Relay`.
+    /// Registers WireGuard's listen port with the relay, which cannot forward the
+    /// other side's traffic to us before it has heard from that port. If that
+    /// fails, the relay learns the port from WireGuard's own keepalives instead.
+    pub async fn join_relay(&mut self, info: RelayInfo) -> ClientResult<Address> {
+        let relay = self.use_relay(info);
+        let registered = match self.udp().await {
+            Ok(udp) => udp
+                .send_to(&[], relay.to_socket_addr())
+                .await
+                .map_err(ClientError::from),
+            Err(e) => Err(e),
+        };
+        self.udp = None;
+        if let Err(e) = registered {
+            tracing::warn!("Cannot register with the relay at {relay}: {e}");
+        }
+        Ok(relay)
+    }
+
+    fn use_relay(&mut self, info: RelayInfo) -> Address {
+        tracing::info!("Tunnel to {:?} is relayed via {}", info.peer, info.endpoint);
+        self.peer_endpoint = Some(info.endpoint.clone());
+        info.endpoint
+    }
+
+    /// Sends a message that the server does not answer directly.
+    async fn notify(&mut self, m: Message) -> ClientResult<()> {
+        let request_timeout = Duration::from_secs(self.config.request_timeout());
+        timeout(request_timeout, self.control().await?.notify(m)).await??;
+        Ok(())
+    }
+
+    /// Waits for a message of `kind` pushed by the server outside of a request,
+    /// passing over pushes of any other kind.
+    async fn wait_for(&mut self, kind: MessageKind) -> ClientResult<Message> {
+        let deadline =
+            Instant::now() + Duration::from_secs(self.config.response_timeout());
+        loop {
+            let msg = timeout_at(deadline, self.control().await?.pushed())
+                .await?
+                .ok_or(ClientError::NoResponse)?;
+
+            if *msg.kind() == MessageKind::DisconnectSessionResponse {
+                return Err(self.session_ended(&msg));
+            }
+            if *msg.kind() == kind {
+                return Ok(msg);
+            }
+            tracing::debug!("Passing over {:?} while waiting for {kind:?}", msg.kind());
+        }
+    }
+
+    /// What the server tells this client outside of a request from now on,
+    /// such as gateway assignments, punch offers or the end of the session.
+    /// Connects to the server if there is no connection yet.
+    pub async fn events(&mut self) -> ClientResult<Events> {
+        Ok(Events::new(self.control().await?.subscribe().await))
+    }
+
+    /// Runs connectivity checks against the peer's candidates.
+    ///
+    /// Every round probes each candidate in priority order, then listens until the
+    /// round's deadline; rounds back off exponentially, capped at the configured NAT
+    /// punch delay. A candidate works once a probe or ack carrying the server's
+    /// token arrives from it. Probes from an address the peer did not offer are
+    /// treated as peer-reflexive candidates. At the end of the first round in which
+    /// anything worked, the highest-priority working candidate is selected.
+    async fn punch(&mut self, info: PunchInfo) -> ClientResult<Address> {
+        let udp = self.udp().await?;
+        let addr = Self::punch_on(&udp, &self.config, &info).await?;
+        self.peer_endpoint = Some(addr.clone());
+        Ok(addr)
+    }
+
+    async fn punch_on(
+        udp: &UdpSocket,
+        config: &Config,
+        info: &PunchInfo,
+    ) -> ClientResult<Address> {
+        let mut remotes = info.candidates.clone();
+        remotes.sort_by_key(|c| std::cmp::Reverse(c.priority));
+        remotes.dedup_by(|a, b| a.addr == b.addr);
+
+        let probe = PunchProbe::new(PunchProbeKind::Probe, info.token).to_vec();
+        let ack = PunchProbe::new(PunchProbeKind::Ack, info.token).to_vec();
+        let max_attempts = config.nat_punch_attempts();
+        let max_interval = Duration::from_secs(config.nat_punch_delay().into());
+        let mut interval = Duration::from_millis(NAT_PUNCH_INITIAL_INTERVAL_MS);
+        let mut working: Vec<Candidate> = Vec::new();
+        let mut buff = [0u8; 1024];
+
+        for attempt in 1..=max_attempts {
+            tracing::info!("NAT punch attempt {attempt}/{max_attempts} to {remotes:?}");
+            for remote in &remotes {
+                if let Err(e) = udp.send_to(&probe, remote.addr.to_socket_addr()).await {
+                    tracing::debug!("Cannot probe {}: {e}", remote.addr);
+                }
+            }
+
+            let deadline = Instant::now() + interval;
+            while let Ok(result) = timeout_at(deadline, udp.recv_from(&mut buff)).await {
+                let (len, from) = result?;
+                let reply = match PunchProbe::from_slice(&buff[..len]) {
+                    Some(reply) if reply.token == info.token => reply,
+                    _ => continue,
+                };
+
+                // Hearing from the peer means its NAT now lets our packets in, so ack
+                // in case our earlier probes were dropped before its mapping existed.
+                if reply.kind == PunchProbeKind::Probe {
+                    udp.send_to(&ack, from).await?;
                 }
 
-                if attempts == max_attempts {
-                    tracing::error!("NAT punch max attempts reached");
-                    return Ok(());
+                let addr = Address::from(from);
+                let candidate = remotes
+                    .iter()
+                    .find(|c| c.addr == addr)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        Candidate::new(CandidateKind::PeerReflexive, addr, 0)
+                    });
+                if !working.contains(&candidate) {
+                    working.push(candidate);
+                }
+            }
+
+            if let Some(best) = working.iter().max_by_key(|c| c.priority) {
+                match best.kind {
+                    CandidateKind::Relay => tracing::info!(
+                        "Tunnel to {:?} is relayed via {}",
+                        info.peer,
+                        best.addr
+                    ),
+                    kind => tracing::info!(
+                        "Tunnel to {:?} is direct via {} ({kind:?})",
+                        info.peer,
+                        best.addr
+                    ),
                 }
+                return Ok(best.addr.clone());
             }
+
+            interval = (interval * 2).min(max_interval);
         }
 
-        tracing::info!("NAT punch attempt(s) finished.");
-        sleep(Duration::from_secs(self.config.nat_punch_delay().into())).await;
+        tracing::error!("NAT punch max attempts reached");
+        Err(ClientError::NatPunchFailed(max_attempts))
+    }
+
+    /// Opens the control connection to `peer`'s gateway at `addr`, which the
+    /// tunnel is set up over and lives as long as. The gateway must prove it
+    /// holds `public_key`, the key its seeder announced.
+    async fn connect_gateway(
+        &mut self,
+        peer: ClientId,
+        addr: Address,
+        public_key: Option<&str>,
+    ) -> ClientResult<()> {
+        let remote: SocketAddr = addr.to_string().parse()?;
+        let pinned = match public_key {
+            Some(key) => Some(decode_public_key(key)?),
+            None => {
+                tracing::warn!(
+                    "No gateway public key announced, gateway is not verified"
+                );
+                None
+            }
+        };
+        let gateway = Self::connect_to(&self.config, remote, pinned.as_deref()).await?;
+        self.peer_stream = Some((peer, addr, gateway));
         Ok(())
     }
 
     async fn request_tunnel_info(&mut self) -> ClientResult<Option<Message>> {
-        let pubkey = command::cat_wireguard_pubkey()?;
+        let pubkey = self.config.wireguard_public_key()?;
         let endpoint = None;
         let allowed_ips = "".to_string();
         let persistent_keepalive = 1;
 
-        let data = bincode::serialize(&WireGuardProtoPeer {
-            public_key: pubkey.to_owned(),
+        let data = bincode::serialize(&WireGuardConfPeer {
+            public_key: pubkey.to_string(),
             allowed_ips,
             endpoint,
             persistent_keepalive: Some(persistent_keepalive),
         })?;
 
         match self
-            .send(Message::new(
-                MessageKind::PeerTunnelInitRequest,
-                MessageStatus::Pending,
-                self.config.remote_addr(InterfaceKind::Tcp),
-                Some(data),
-            ))
+            .send_to_gateway(MessageKind::PeerTunnelInitRequest, Some(data))
             .await?
         {
             Some(msg) => {
                 tracing::info!("Received tunnel info: {msg:?}");
-                let peer: WireGuardProtoPeer = bincode::deserialize(&msg.data())?;
-                self.wireguard_config.lock().await.add_peer(peer);
-
-                //            self.wireguard_config.save();
+                let peer: WireGuardConfPeer = bincode::deserialize(&msg.data())?;
+                let mut peer = WireGuardProtoPeer::from(peer);
+                if peer.endpoint.is_none() {
+                    peer.endpoint = self.peer_endpoint.as_ref().map(|a| a.to_string());
+                }
+                let mut wireguard_config = self.wireguard_config.lock().await;
+                wireguard_config.remove_peer(&peer.public_key.to_string());
+                wireguard_config.add_peer(peer);
+                wireguard_config.save(self.config.wireguard_filepath())?;
 
                 Ok(Some(msg))
             }
@@ -261,12 +1014,7 @@ impl Client {
 
     pub async fn setup_peer_tunnel(&mut self, addr: Address) -> ClientResult<()> {
         let _msg = self
-            .send(Message::new(
-                MessageKind::PeerTunnelRequest,
-                MessageStatus::Pending,
-                self.config.remote_addr(InterfaceKind::Tcp),
-                addr.into(),
-            ))
+            .send_to_gateway(MessageKind::PeerTunnelRequest, addr.into())
             .await?;
 
         Ok(())
@@ -274,61 +1022,115 @@ impl Client {
 
     pub async fn tunnel(&mut self) -> ClientResult<()> {
         self.authenticate().await?;
-        let msg = self.request_gateway().await?;
-        if let Some(msg) = msg {
-            let addr = Address::try_from(msg.data())?;
-            if let Err(e) = timeout(
-                Duration::from_secs(self.config.request_timeout()),
-                self.nat_punch(addr.clone()),
-            )
-            .await
-            {
-                tracing::error!("NAT punch failed: {e}");
+        let msg = self
+            .request_gateway()
+            .await?
+            .ok_or(ClientError::NoResponse)?;
+        if *msg.status() != MessageStatus::r#Ok {
+            tracing::error!("Server assigned no gateway: {:?}", msg.status());
+            return Err(ClientError::UnexpectedStatus(*msg.status()));
+        }
+
+        let info: GatewayInfo = bincode::deserialize(&msg.data())?;
+        let (peer, addr) = (info.peer, info.gateway);
+        let public_key = info.gateway_public_key;
+        if let Err(e) = self.nat_punch(peer.clone()).await {
+            tracing::warn!("NAT punch failed, falling back to relay: {e}");
+            if let Err(e) = self.request_relay(peer.clone()).await {
+                tracing::error!("Relay failed: {e}");
                 return Ok(());
             }
-            self.request_tunnel_info().await?;
-            self.setup_peer_tunnel(addr).await?;
         }
+        self.connect_gateway(peer, addr.clone(), public_key.as_deref())
+            .await?;
+        self.setup_peer_tunnel(addr).await?;
+        self.request_tunnel_info().await?;
         Ok(())
     }
 
+    /// Sends a request to the gateway we tunnel through and waits for its
+    /// response. Returns `None` like `send` does.
+    async fn send_to_gateway(
+        &self,
+        kind: MessageKind,
+        data: Option<Vec<u8>>,
+    ) -> ClientResult<Option<Message>> {
+        let (_, addr, gateway) = match &self.peer_stream {
+            Some(peer_stream) => peer_stream,
+            None => return Err(ClientError::NoGateway),
+        };
+        let request_timeout = Duration::from_secs(self.config.request_timeout());
+        let m = Message::new(kind, MessageStatus::Pending, addr.to_string(), data);
+        let response = match timeout(request_timeout, gateway.request(m)).await {
+            Ok(result) => result?,
+            Err(e) => {
+                tracing::error!("Gateway request timeout: {e}");
+                return Ok(None);
+            }
+        };
+        match timeout(request_timeout, response).await {
+            Ok(Ok(msg)) => {
+                tracing::info!("Received gateway response: {msg:?}");
+                Ok(Some(msg))
+            }
+            _ => {
+                tracing::info!("No response from gateway");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Sends a request and waits for the response correlated with it. Returns
+    /// `None` if the request cannot be sent in time or the connection closes
+    /// before it is answered.
     async fn send(&mut self, m: Message) -> ClientResult<Option<Message>> {
-        tracing::info!("Sending message: {m:?}");
-        let data = m.serialize()?;
-        tracing::info!("Sending {} bytes", data.len());
+        let request_timeout = Duration::from_secs(self.config.request_timeout());
+        let tcp = self.control().await?;
+        let response = match timeout(request_timeout, tcp.request(m)).await {
+            Ok(result) => result?,
+            Err(e) => {
+                tracing::error!("Request timeout: {e}");
+                return Ok(None);
+            }
+        };
+        let msg = match response.await {
+            Ok(msg) => msg,
+            Err(_) => {
+                tracing::info!("No data in response");
+                return Ok(None);
+            }
+        };
 
-        match timeout(
-            Duration::from_secs(self.config.request_timeout()),
-            self.tcp.write_all(&data),
-        )
-        .await
-        {
-            Ok(_) => {
-                let mut buff = vec![0u8; 1024];
-                let n = self.tcp.read(&mut buff).await?;
-                if n > 0 {
-                    let data = buff[..n].to_vec();
-                    let msg = Message::deserialize(&data)?;
-                    tracing::info!("Received response: {msg:?}");
-                    match msg.status() {
-                        MessageStatus::r#Ok | MessageStatus::Created => {
-                            tracing::info!("Recevied successful response");
-                        }
-                        _ => {
-                            tracing::warn!("Received non-success response");
-                        }
-                    }
-                    return Ok(Some(msg));
-                }
+        tracing::info!("Received response: {msg:?}");
+        if *msg.kind() == MessageKind::DisconnectSessionResponse {
+            return Err(self.session_ended(&msg));
+        }
 
-                tracing::info!("No data in response");
+        match msg.status() {
+            MessageStatus::r#Ok | MessageStatus::Created => {
+                tracing::info!("Recevied successful response");
             }
-            Err(e) => {
-                tracing::error!("Request timeout: {e}");
+            _ => {
+                tracing::warn!("Received non-success response");
             }
         }
+        Ok(Some(msg))
+    }
 
-        Ok(None)
+    /// Drops the connection the server closes after ending the session.
+    fn session_ended(&mut self, msg: &Message) -> ClientError {
+        let reason: DisconnectReason = match bincode::deserialize(&msg.data()) {
+            Ok(reason) => reason,
+            Err(e) => return ClientError::Bincode(e),
+        };
+        tracing::warn!("Server ended the session: {reason:?}");
+        self.tcp = None;
+        self.protocol = None;
+        // Only a session that went idle can be resumed
+        if reason != DisconnectReason::Idle {
+            self.forget_ticket();
+        }
+        ClientError::SessionEnded(reason)
     }
 
     pub async fn stop(&mut self) -> ClientResult<()> {
@@ -347,17 +1149,14 @@ impl Client {
 
     pub async fn stop_inner(&mut self) -> ClientResult<()> {
         tracing::info!("Stopping client");
-        if let Some((client_id, addr, stream)) = self.peer_stream.take() {
+        if self.peer_stream.is_some() {
+            // The gateway tears the tunnel down and hangs up
             let _msg = self
-                .send(Message::new(
-                    MessageKind::PeerTunnelClose,
-                    MessageStatus::Pending,
-                    addr.to_string(),
-                    Some(client_id.to_vec()),
-                ))
-                .await?;
-
-            stream.lock().await.shutdown().await?;
+                .send_to_gateway(MessageKind::PeerTunnelClose, None)
+                .await;
+            if let Some((peer, _, _)) = self.peer_stream.take() {
+                tracing::info!("Closed the tunnel through {peer:?}");
+            }
         }
 
         Ok(())
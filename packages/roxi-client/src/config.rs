@@ -1,34 +1,53 @@
 use crate::{error::ClientError, ClientResult};
-use roxi_lib::types::{config::WireGuardConf, InterfaceKind, Ports, SharedKey};
+use roxi_crypto::IdentityKeyPair;
+use roxi_lib::{
+    constant,
+    types::{config::WireGuardConf, Address, InterfaceKind, Ports, SharedKey},
+};
+use roxi_proto::{command, Enrollment, WireGuardProtoKey, DEFAULT_MAX_FRAME_SIZE};
 use serde::{Deserialize, Serialize};
 use std::{
     fs::File,
     io::Write,
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::{Path, PathBuf},
 };
 
+/// Gateway ports written into enrolled configs.
+const DEFAULT_GATEWAY_PORTS: Ports = Ports {
+    tcp: 8081,
+    udp: 5677,
+};
+
+const DEFAULT_GATEWAY_MAX_CLIENTS: u16 = 10;
+
+const DEFAULT_NAT_ATTEMPTS: u8 = 3;
+
+/// Seconds between NAT punch attempts.
+const DEFAULT_NAT_DELAY: u8 = 2;
+
+/// Seconds between heartbeat pings on the control connection.
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 30;
+
 #[derive(Debug, Serialize, Deserialize, Clone, Hash)]
 pub struct Stun {
     ip: Option<IpAddr>,
     port: Option<u16>,
 }
 
-impl From<[u8; 6]> for Stun {
-    fn from(d: [u8; 6]) -> Self {
-        let ip = IpAddr::V4(Ipv4Addr::new(d[0], d[1], d[2], d[3]));
-        let port = u16::from_be_bytes([d[4], d[5]]);
+impl From<Address> for Stun {
+    fn from(a: Address) -> Self {
         Self {
-            ip: Some(ip),
-            port: Some(port),
+            ip: Some(a.ip()),
+            port: Some(a.port()),
         }
     }
 }
 
 impl Stun {
     pub fn addr(&self) -> Option<String> {
-        if self.ip.is_some() && self.port.is_some() {
-            return Some(format!("{}:{}", self.ip.unwrap(), self.port.unwrap()));
+        if let (Some(ip), Some(port)) = (self.ip, self.port) {
+            return Some(SocketAddr::new(ip, port).to_string());
         }
         None
     }
@@ -36,7 +55,10 @@ impl Stun {
 
 #[derive(Debug, Serialize, Deserialize, Clone, Hash)]
 pub struct Auth {
-    shared_key: SharedKey,
+    shared_key: Option<SharedKey>,
+    /// Base64 Ed25519 seed identifying this client, from `roxi keygen --identity`.
+    /// Used instead of the shared key when set.
+    private_key: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Hash)]
@@ -51,22 +73,29 @@ pub struct Gateway {
     ip: IpAddr,
     ports: Ports,
     max_clients: u16,
+    /// Base64 static key for the control channel. Generated at startup if unset.
+    private_key: Option<String>,
+    /// Labels, such as a region, the server matches gateways to clients by.
+    #[serde(default)]
+    tags: Vec<String>,
 }
 
 // FIXME: Maybe bind these common methods with a tait?
 impl Gateway {
     pub fn addr(&self, k: InterfaceKind) -> String {
         match k {
-            InterfaceKind::Tcp => format!("{}:{}", self.interface, self.ports.tcp),
-            InterfaceKind::Udp => format!("{}:{}", self.interface, self.ports.udp),
+            InterfaceKind::Tcp => SocketAddr::new(self.interface, self.ports.tcp),
+            InterfaceKind::Udp => SocketAddr::new(self.interface, self.ports.udp),
         }
+        .to_string()
     }
 
     pub fn remote_addr(&self, k: InterfaceKind) -> String {
         match k {
-            InterfaceKind::Tcp => format!("{}:{}", self.ip, self.ports.tcp),
-            InterfaceKind::Udp => format!("{}:{}", self.ip, self.ports.udp),
+            InterfaceKind::Tcp => SocketAddr::new(self.ip, self.ports.tcp),
+            InterfaceKind::Udp => SocketAddr::new(self.ip, self.ports.udp),
         }
+        .to_string()
     }
 }
 
@@ -77,23 +106,38 @@ pub struct Server {
     ports: Ports,
     request_timeout: u64,
     response_timeout: u64,
+    max_frame_size: Option<usize>,
+    /// Seconds between heartbeat pings that keep the session from going idle.
+    heartbeat_interval: Option<u64>,
+    /// Base64 static key the server must prove it holds. Not checked if unset.
+    public_key: Option<String>,
 }
 
 impl Server {
     pub fn addr(&self, k: InterfaceKind) -> String {
         match k {
-            InterfaceKind::Tcp => format!("{}:{}", self.interface, self.ports.tcp),
-            InterfaceKind::Udp => {
-                format!("{}:{}", self.interface, self.ports.udp)
-            }
+            InterfaceKind::Tcp => SocketAddr::new(self.interface, self.ports.tcp),
+            InterfaceKind::Udp => SocketAddr::new(self.interface, self.ports.udp),
         }
+        .to_string()
     }
 
     pub fn remote_addr(&self, k: InterfaceKind) -> String {
         match k {
-            InterfaceKind::Tcp => format!("{}:{}", self.ip, self.ports.tcp),
-            InterfaceKind::Udp => format!("{}:{}", self.ip, self.ports.udp),
+            InterfaceKind::Tcp => SocketAddr::new(self.ip, self.ports.tcp),
+            InterfaceKind::Udp => SocketAddr::new(self.ip, self.ports.udp),
         }
+        .to_string()
+    }
+
+    /// `ports` name the server's ports, so the client binds ephemeral ones.
+    pub fn bind_addr(&self) -> String {
+        SocketAddr::new(self.interface, 0).to_string()
+    }
+
+    /// WireGuard's listen `port` on the configured interface.
+    pub fn wireguard_bind_addr(&self, port: u16) -> String {
+        SocketAddr::new(self.interface, port).to_string()
     }
 }
 
@@ -124,6 +168,57 @@ pub struct Config {
 }
 
 impl Config {
+    /// The config of a client enrolled by `enrollment`, kept in `dir` next to
+    /// its WireGuard config. The client identifies itself with `identity` and
+    /// serves as a gateway at `gateway_ip`.
+    pub fn from_enrollment(
+        dir: &Path,
+        enrollment: &Enrollment,
+        identity: &IdentityKeyPair,
+        gateway_ip: IpAddr,
+    ) -> Self {
+        let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        Self {
+            auth: Auth {
+                shared_key: None,
+                private_key: Some(identity.seed_base64()),
+            },
+            path: dir.join(format!("{}.yaml", constant::PEER_FILENAME)),
+            network: Network {
+                server: Server {
+                    interface: unspecified,
+                    ip: enrollment.server_ip,
+                    ports: enrollment.server_ports.clone(),
+                    request_timeout: enrollment.response_timeout,
+                    response_timeout: enrollment.response_timeout,
+                    max_frame_size: None,
+                    heartbeat_interval: None,
+                    public_key: enrollment.server_public_key.clone(),
+                },
+                gateway: Gateway {
+                    interface: unspecified,
+                    ip: gateway_ip,
+                    ports: DEFAULT_GATEWAY_PORTS,
+                    max_clients: DEFAULT_GATEWAY_MAX_CLIENTS,
+                    private_key: None,
+                    tags: vec![],
+                },
+                stun: Stun {
+                    ip: None,
+                    port: None,
+                },
+                wireguard: WireGuardConf {
+                    config: dir.join(format!("{}.conf", constant::WIREGUARD_INTERFACE)),
+                    public_key: None,
+                },
+                nat: Nat {
+                    attempts: DEFAULT_NAT_ATTEMPTS,
+                    delay: DEFAULT_NAT_DELAY,
+                },
+            },
+        }
+    }
+
     pub fn request_timeout(&self) -> u64 {
         self.network.server.request_timeout
     }
@@ -132,14 +227,33 @@ impl Config {
         self.network.server.response_timeout
     }
 
+    pub fn max_frame_size(&self) -> usize {
+        self.network
+            .server
+            .max_frame_size
+            .unwrap_or(DEFAULT_MAX_FRAME_SIZE)
+    }
+
     pub fn wireguard_filepath(&self) -> &PathBuf {
         self.network.wireguard_filepath()
     }
 
+    pub fn heartbeat_interval(&self) -> u64 {
+        self.network
+            .server
+            .heartbeat_interval
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS)
+    }
+
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
 
+    /// Where the ticket for resuming the last session is kept.
+    pub fn ticket_path(&self) -> PathBuf {
+        self.path.with_extension("ticket")
+    }
+
     pub fn set_stun(&mut self, stun: Stun) {
         self.network.set_stun(stun);
     }
@@ -152,6 +266,14 @@ impl Config {
         self.network.server.remote_addr(k)
     }
 
+    pub fn bind_addr(&self) -> String {
+        self.network.server.bind_addr()
+    }
+
+    pub fn wireguard_bind_addr(&self, port: u16) -> String {
+        self.network.server.wireguard_bind_addr(port)
+    }
+
     pub fn stun_addr(&self) -> anyhow::Result<String> {
         match self.network.stun.addr() {
             Some(addr) => Ok(addr),
@@ -159,10 +281,21 @@ impl Config {
         }
     }
 
-    pub fn shared_key(&self) -> SharedKey {
+    pub fn shared_key(&self) -> Option<SharedKey> {
         self.auth.shared_key.clone()
     }
 
+    pub fn set_identity(&mut self, identity: &IdentityKeyPair) {
+        self.auth.private_key = Some(identity.seed_base64());
+    }
+
+    pub fn identity(&self) -> ClientResult<Option<IdentityKeyPair>> {
+        match &self.auth.private_key {
+            Some(key) => Ok(Some(IdentityKeyPair::try_from(key.as_str())?)),
+            None => Ok(None),
+        }
+    }
+
     pub fn gateway_addr(&self, k: InterfaceKind) -> String {
         self.network.gateway.addr(k)
     }
@@ -171,6 +304,22 @@ impl Config {
         self.network.gateway.remote_addr(k)
     }
 
+    pub fn gateway_tags(&self) -> &[String] {
+        &self.network.gateway.tags
+    }
+
+    pub fn server_public_key(&self) -> Option<&str> {
+        self.network.server.public_key.as_deref()
+    }
+
+    pub fn gateway_private_key(&self) -> Option<&str> {
+        self.network.gateway.private_key.as_deref()
+    }
+
+    pub fn set_gateway_private_key(&mut self, key: String) {
+        self.network.gateway.private_key = Some(key);
+    }
+
     pub fn max_gateway_clients(&self) -> u16 {
         self.network.gateway.max_clients
     }
@@ -187,6 +336,14 @@ impl Config {
         self.network.wireguard.clone()
     }
 
+    /// Our WireGuard public key, as configured or else as installed.
+    pub fn wireguard_public_key(&self) -> ClientResult<WireGuardProtoKey> {
+        match &self.network.wireguard.public_key {
+            Some(key) => Ok(WireGuardProtoKey::from_public(key.clone())),
+            None => Ok(command::cat_wireguard_pubkey()?),
+        }
+    }
+
     pub fn save(&self) -> ClientResult<()> {
         let content = serde_yaml::to_string(&self)?;
         let mut f = File::create(&self.path)?;
@@ -0,0 +1,43 @@
+use crate::{admin::exchange, error::ClientError, ClientResult};
+use roxi_crypto::{decode_public_key, IdentityKeyPair, NoiseKeyPair};
+use roxi_proto::{Enrollment, EnrollmentRequest, MessageKind, MessageStatus};
+use std::net::IpAddr;
+
+/// Redeems invite `token` with the server at `server`, enrolling `identity` as a
+/// client. `server_public_key`, if given, is the key the server must hold.
+///
+/// Returns what the server told us along with the local address we reached it
+/// from, which other peers can use to reach this client's gateway.
+pub async fn enroll(
+    server: &str,
+    server_public_key: Option<&str>,
+    token: &str,
+    identity: &IdentityKeyPair,
+) -> ClientResult<(Enrollment, IpAddr)> {
+    let pinned = match server_public_key {
+        Some(key) => Some(decode_public_key(key)?),
+        None => None,
+    };
+    let request = EnrollmentRequest::new(token, identity);
+    let (msg, local) = exchange(
+        server,
+        &NoiseKeyPair::generate()?,
+        pinned.as_deref(),
+        MessageKind::EnrollRequest,
+        bincode::serialize(&request)?,
+    )
+    .await?;
+    if *msg.kind() != MessageKind::EnrollResponse {
+        return Err(ClientError::UnexpectedMessage(*msg.kind()));
+    }
+
+    match msg.status() {
+        MessageStatus::Created => {
+            let enrollment: Enrollment = bincode::deserialize(&msg.data())?;
+            tracing::info!("Enrolled as {}", enrollment.label);
+            Ok((enrollment, local.ip()))
+        }
+        MessageStatus::Unauthorized => Err(ClientError::InviteRejected),
+        status => Err(ClientError::UnexpectedStatus(*status)),
+    }
+}
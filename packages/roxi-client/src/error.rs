@@ -11,15 +11,30 @@ pub enum ClientError {
     #[error("Invalid shared key")]
     InvalidSharedKey,
 
+    #[error("No shared key or identity key configured")]
+    NoCredentials,
+
+    #[error("No identity key configured")]
+    NoIdentity,
+
     #[error("Serde yaml error: {0}")]
     Yaml(#[from] serde_yaml::Error),
 
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] roxi_crypto::CryptoError),
+
     #[error("Protocol error: {0}")]
     Proto(#[from] roxi_proto::ProtoError),
 
     #[error("Not a stun binding request")]
     NotAStunBindingRequest,
 
+    #[error("Not a stun binding response")]
+    NotAStunBindingResponse,
+
+    #[error("STUN error response {0}: {1}")]
+    StunError(u16, String),
+
     #[error("From utf8 error: {0}")]
     FromUtf8(#[from] std::string::FromUtf8Error),
 
@@ -29,6 +44,33 @@ pub enum ClientError {
     #[error("Bincode error: {0}")]
     Bincode(#[from] bincode::Error),
 
+    #[error("Server supports none of our protocol versions (server supports {0:?})")]
+    IncompatibleVersion(Vec<u8>),
+
+    #[error("Server rejected the invite: it is invalid, expired or used up")]
+    InviteRejected,
+
+    #[error("Server ended the session: {0:?}")]
+    SessionEnded(roxi_proto::DisconnectReason),
+
+    #[error("No response from server")]
+    NoResponse,
+
+    #[error("No gateway connection")]
+    NoGateway,
+
+    #[error("WireGuard port {0} is in use, bring the interface down to punch: {1}")]
+    WireGuardPortInUse(u16, std::io::Error),
+
+    #[error("NAT punch failed after {0} attempts")]
+    NatPunchFailed(u8),
+
+    #[error("Unexpected message kind: {0:?}")]
+    UnexpectedMessage(roxi_proto::MessageKind),
+
+    #[error("Unexpected response status: {0:?}")]
+    UnexpectedStatus(roxi_proto::MessageStatus),
+
     #[error("Elapsed error: {0}")]
     Elapsed(#[from] tokio::time::error::Elapsed),
 }
@@ -0,0 +1,76 @@
+use roxi_proto::{
+    DisconnectReason, GatewayInfo, GatewayReady, Message, MessageKind, PunchInfo,
+    RelayInfo,
+};
+use tokio::sync::broadcast;
+
+/// Something the server told this client outside of a request.
+#[derive(Debug)]
+pub enum Event {
+    /// This client was picked as the gateway of `GatewayInfo::peer`.
+    Gateway(GatewayInfo),
+    /// The gateway `GatewayReady::peer` is ready to serve this client.
+    GatewayReady(GatewayReady),
+    /// A peer offers its candidates for a hole punch.
+    Punch(PunchInfo),
+    /// A peer set up a relayed tunnel with this client.
+    Relay(RelayInfo),
+    /// The server ended the session and closes the connection.
+    SessionEnded(DisconnectReason),
+    /// The server is shutting down and closes the connection.
+    Shutdown,
+    /// A message this client does not know as an event.
+    Other(Message),
+}
+
+impl Event {
+    fn from_message(msg: Message) -> Self {
+        let data = msg.data();
+        let event = match msg.kind() {
+            MessageKind::GatewayResponse => {
+                bincode::deserialize(&data).map(Event::Gateway)
+            }
+            MessageKind::GatewayReadyResponse => {
+                bincode::deserialize(&data).map(Event::GatewayReady)
+            }
+            MessageKind::NATPunchRequest => bincode::deserialize(&data).map(Event::Punch),
+            MessageKind::RelayRequest => bincode::deserialize(&data).map(Event::Relay),
+            MessageKind::DisconnectSessionResponse => {
+                bincode::deserialize(&data).map(Event::SessionEnded)
+            }
+            MessageKind::ServerShutdown => Ok(Event::Shutdown),
+            _ => return Event::Other(msg),
+        };
+        match event {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!("Malformed {:?} from server: {e}", msg.kind());
+                Event::Other(msg)
+            }
+        }
+    }
+}
+
+/// The events of a control connection, from the moment they were asked for.
+pub struct Events {
+    rx: broadcast::Receiver<Message>,
+}
+
+impl Events {
+    pub(crate) fn new(rx: broadcast::Receiver<Message>) -> Self {
+        Self { rx }
+    }
+
+    /// The next event, or `None` once the connection is closed.
+    pub async fn next(&mut self) -> Option<Event> {
+        loop {
+            match self.rx.recv().await {
+                Ok(msg) => return Some(Event::from_message(msg)),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("Missed {n} events from the server");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
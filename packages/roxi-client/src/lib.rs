@@ -1,9 +1,16 @@
+pub(crate) mod admin;
 pub(crate) mod client;
 pub(crate) mod config;
+pub(crate) mod enroll;
 pub(crate) mod error;
+pub(crate) mod event;
+pub(crate) mod mux;
 
 pub type ClientResult<T> = core::result::Result<T, error::ClientError>;
 
+pub use admin::{drop_gateway_peer, gateway_statuses, revoke};
 pub use client::Client;
 pub use config::Config;
+pub use enroll::enroll;
 pub use error::ClientError;
+pub use event::{Event, Events};
@@ -0,0 +1,229 @@
+use crate::ClientResult;
+use futures::{SinkExt, StreamExt};
+use roxi_proto::{
+    Message, MessageKind, MessageSink, MessageStatus, MessageStream, SecureCodec,
+    PROTOCOL_VERSION,
+};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+};
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{broadcast, oneshot, Mutex},
+    task::JoinHandle,
+};
+use tokio_util::codec::FramedRead;
+
+/// How many server-initiated messages are buffered for a subscriber that is
+/// not reading.
+const PUSHED_CAPACITY: usize = 64;
+
+/// The first header version that carries request IDs.
+const REQUEST_ID_VERSION: u8 = 3;
+
+/// A control connection shared by concurrent requests.
+///
+/// Every request is sent with a fresh ID and its response is routed back by
+/// the ID the server echoes. The server's pings are answered right away, and
+/// anything else it sends, such as relayed punch offers or the end of the
+/// session, goes to subscribers.
+pub struct Multiplexer {
+    sink: Arc<Mutex<MessageSink<OwnedWriteHalf>>>,
+    state: Arc<StdMutex<State>>,
+    next_id: AtomicU32,
+    /// Subscribed before the reader starts, so no pushed message is missed.
+    inbox: Mutex<broadcast::Receiver<Message>>,
+    reader: JoinHandle<()>,
+}
+
+struct State {
+    pending: BTreeMap<u32, oneshot::Sender<Message>>,
+    version: u8,
+    closed: bool,
+    /// The server's `DisconnectSessionResponse`, if it ended the session.
+    ended: Option<Message>,
+}
+
+impl State {
+    /// The request `msg` answers. Servers older than request IDs answer in
+    /// order, so their responses go to the oldest request.
+    fn waiter(&mut self, msg: &Message) -> Option<oneshot::Sender<Message>> {
+        match msg.id() {
+            0 if self.version < REQUEST_ID_VERSION => {
+                self.pending.pop_first().map(|(_, tx)| tx)
+            }
+            0 => None,
+            id => self.pending.remove(&id),
+        }
+    }
+}
+
+impl Multiplexer {
+    pub fn new(stream: TcpStream, codec: SecureCodec) -> Self {
+        let (reader, writer) = stream.into_split();
+        let reader = FramedRead::new(reader, codec.clone());
+        let (pushed, inbox) = broadcast::channel(PUSHED_CAPACITY);
+        let state = Arc::new(StdMutex::new(State {
+            pending: BTreeMap::new(),
+            version: PROTOCOL_VERSION,
+            closed: false,
+            ended: None,
+        }));
+
+        let sink = Arc::new(Mutex::new(MessageSink::new(writer, codec)));
+
+        Self {
+            reader: tokio::spawn(Self::read(reader, sink.clone(), state.clone(), pushed)),
+            sink,
+            state,
+            next_id: AtomicU32::new(1),
+            inbox: Mutex::new(inbox),
+        }
+    }
+
+    /// Routes what the server sends until the connection closes, then settles
+    /// the requests still waiting: with the end of the session if the server
+    /// ended it, else by dropping them.
+    async fn read(
+        mut reader: MessageStream<OwnedReadHalf>,
+        sink: Arc<Mutex<MessageSink<OwnedWriteHalf>>>,
+        state: Arc<StdMutex<State>>,
+        pushed: broadcast::Sender<Message>,
+    ) {
+        while let Some(result) = reader.next().await {
+            let msg = match result {
+                Ok(msg) => msg,
+                Err(e) => {
+                    tracing::error!("Failed to read from server: {e}");
+                    break;
+                }
+            };
+            tracing::info!("Received message: {msg:?}");
+
+            // The server times its round trip to us, so its pings are never
+            // taken for the answer to one of our requests
+            if *msg.kind() == MessageKind::Ping {
+                let pong = Message::new(
+                    MessageKind::Pong,
+                    MessageStatus::r#Ok,
+                    msg.sender_addr().to_string(),
+                    None,
+                )
+                .with_id(msg.id());
+                if let Err(e) = sink.lock().await.send(pong).await {
+                    tracing::warn!("Failed to answer the server's ping: {e}");
+                }
+                continue;
+            }
+
+            let waiter = {
+                let mut state = state.lock().unwrap();
+                if *msg.kind() == MessageKind::DisconnectSessionResponse {
+                    state.ended = Some(msg.clone());
+                }
+                state.waiter(&msg)
+            };
+            match waiter {
+                Some(tx) => {
+                    let _ = tx.send(msg);
+                }
+                None => {
+                    // Nobody listening is fine, pushes are advisory
+                    let _ = pushed.send(msg);
+                }
+            }
+        }
+
+        let mut state = state.lock().unwrap();
+        state.closed = true;
+        let ended = state.ended.clone();
+        for (_, tx) in std::mem::take(&mut state.pending) {
+            if let Some(ended) = &ended {
+                let _ = tx.send(ended.clone());
+            }
+        }
+    }
+
+    /// Header version messages are sent in.
+    pub async fn set_version(&self, version: u8) {
+        self.state.lock().unwrap().version = version;
+        self.sink.lock().await.encoder_mut().set_version(version);
+    }
+
+    fn next_id(&self) -> u32 {
+        loop {
+            // Zero marks messages that answer nothing, so it is skipped on wrap
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
+    /// Sends `msg` as a request. The receiver yields its response, and fails if
+    /// the connection closes first.
+    pub async fn request(
+        &self,
+        msg: Message,
+    ) -> ClientResult<oneshot::Receiver<Message>> {
+        let id = self.next_id();
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                if let Some(ended) = &state.ended {
+                    let _ = tx.send(ended.clone());
+                }
+                return Ok(rx);
+            }
+            state.pending.insert(id, tx);
+        }
+
+        if let Err(e) = self.notify(msg.with_id(id)).await {
+            self.state.lock().unwrap().pending.remove(&id);
+            return Err(e);
+        }
+        Ok(rx)
+    }
+
+    /// Sends `msg` without waiting for a response.
+    pub async fn notify(&self, msg: Message) -> ClientResult<()> {
+        tracing::info!("Sending message: {msg:?}");
+        self.sink.lock().await.send(msg).await?;
+        Ok(())
+    }
+
+    /// The next message the server sent outside of a request, or `None` once
+    /// the connection is closed and everything pushed before has been read.
+    pub async fn pushed(&self) -> Option<Message> {
+        let mut inbox = self.inbox.lock().await;
+        loop {
+            match inbox.recv().await {
+                Ok(msg) => return Some(msg),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("Missed {n} messages pushed by the server");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// A receiver of the messages the server sends from now on outside of a
+    /// request.
+    pub async fn subscribe(&self) -> broadcast::Receiver<Message> {
+        self.inbox.lock().await.resubscribe()
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
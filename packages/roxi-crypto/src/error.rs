@@ -4,6 +4,15 @@ use thiserror::Error;
 pub enum CryptoError {
     #[error("Unspecified ring error")]
     Unspecified,
+
+    #[error("Noise error: {0}")]
+    Noise(#[from] snow::Error),
+
+    #[error("Base64 decode error: {0}")]
+    Base64(#[from] base64::DecodeError),
+
+    #[error("Invalid key length: {0}")]
+    InvalidKeyLength(usize),
 }
 
 impl From<ring::error::Unspecified> for CryptoError {
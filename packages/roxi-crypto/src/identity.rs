@@ -0,0 +1,66 @@
+use crate::{CryptoError, CryptoResult};
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{self, Ed25519KeyPair, KeyPair},
+};
+
+pub const IDENTITY_SEED_LEN: usize = 32;
+
+/// A client's long-term Ed25519 identity, stored as its 32-byte seed.
+pub struct IdentityKeyPair {
+    seed: [u8; IDENTITY_SEED_LEN],
+    keypair: Ed25519KeyPair,
+}
+
+impl IdentityKeyPair {
+    pub fn generate() -> CryptoResult<Self> {
+        let mut seed = [0u8; IDENTITY_SEED_LEN];
+        SystemRandom::new().fill(&mut seed)?;
+        Self::from_seed(&seed)
+    }
+
+    pub fn from_seed(seed: &[u8]) -> CryptoResult<Self> {
+        let seed: [u8; IDENTITY_SEED_LEN] = seed
+            .try_into()
+            .map_err(|_| CryptoError::InvalidKeyLength(seed.len()))?;
+        let keypair = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|_| CryptoError::Unspecified)?;
+        Ok(Self { seed, keypair })
+    }
+
+    pub fn public(&self) -> &[u8] {
+        self.keypair.public_key().as_ref()
+    }
+
+    pub fn seed_base64(&self) -> String {
+        base64::encode(self.seed)
+    }
+
+    pub fn public_base64(&self) -> String {
+        base64::encode(self.public())
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        self.keypair.sign(msg).as_ref().to_vec()
+    }
+}
+
+impl std::fmt::Debug for IdentityKeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("IdentityKeyPair")
+            .field("public", &self.public_base64())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TryFrom<&str> for IdentityKeyPair {
+    type Error = CryptoError;
+    fn try_from(seed: &str) -> CryptoResult<Self> {
+        Self::from_seed(&base64::decode(seed)?)
+    }
+}
+
+pub fn verify_ed25519(public: &[u8], msg: &[u8], sig: &[u8]) -> CryptoResult<()> {
+    signature::UnparsedPublicKey::new(&signature::ED25519, public).verify(msg, sig)?;
+    Ok(())
+}
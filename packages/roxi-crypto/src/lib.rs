@@ -1,10 +1,19 @@
 pub(crate) mod error;
+pub(crate) mod identity;
+pub(crate) mod noise;
+
 use ring::{
     agreement::{self, EphemeralPrivateKey},
+    hmac,
     signature::ED25519_PUBLIC_KEY_LEN,
 };
 
 pub use crate::error::CryptoError;
+pub use crate::identity::{verify_ed25519, IdentityKeyPair, IDENTITY_SEED_LEN};
+pub use crate::noise::{
+    decode_public_key, NoiseHandshake, NoiseKeyPair, NoiseTransport, NOISE_KEY_LEN,
+    NOISE_MAX_MESSAGE_LEN, NOISE_PARAMS, NOISE_TAG_LEN,
+};
 
 pub type CryptoResult<T> = core::result::Result<T, CryptoError>;
 
@@ -35,3 +44,15 @@ pub fn gen_keypair() -> CryptoResult<KeyPair> {
         pubkey: pubkey_bytes,
     })
 }
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data).as_ref().to_vec()
+}
+
+/// Checks `tag` against `data` in constant time.
+pub fn verify_hmac_sha256(key: &[u8], data: &[u8], tag: &[u8]) -> CryptoResult<()> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::verify(&key, data, tag)?;
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use crate::{CryptoError, CryptoResult};
+use snow::{
+    params::DHChoice,
+    resolvers::{CryptoResolver, DefaultResolver},
+};
+
+/// Noise protocol used for the control channel.
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+pub const NOISE_KEY_LEN: usize = 32;
+
+/// Largest Noise message, including the AEAD tag.
+pub const NOISE_MAX_MESSAGE_LEN: usize = 65535;
+
+pub const NOISE_TAG_LEN: usize = 16;
+
+/// A static X25519 key pair identifying one end of a Noise control channel.
+#[derive(Clone)]
+pub struct NoiseKeyPair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl NoiseKeyPair {
+    pub fn generate() -> CryptoResult<Self> {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+        Ok(Self {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    pub fn from_private(private: &[u8]) -> CryptoResult<Self> {
+        if private.len() != NOISE_KEY_LEN {
+            return Err(CryptoError::InvalidKeyLength(private.len()));
+        }
+
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .ok_or(CryptoError::Unspecified)?;
+        dh.set(private);
+
+        Ok(Self {
+            private: private.to_vec(),
+            public: dh.pubkey().to_vec(),
+        })
+    }
+
+    pub fn private(&self) -> &[u8] {
+        &self.private
+    }
+
+    pub fn public(&self) -> &[u8] {
+        &self.public
+    }
+
+    pub fn private_base64(&self) -> String {
+        base64::encode(&self.private)
+    }
+
+    pub fn public_base64(&self) -> String {
+        base64::encode(&self.public)
+    }
+}
+
+impl std::fmt::Debug for NoiseKeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("NoiseKeyPair")
+            .field("public", &self.public_base64())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TryFrom<&str> for NoiseKeyPair {
+    type Error = CryptoError;
+    fn try_from(private: &str) -> CryptoResult<Self> {
+        Self::from_private(&base64::decode(private)?)
+    }
+}
+
+/// One side of an in-progress Noise handshake.
+pub struct NoiseHandshake(snow::HandshakeState);
+
+impl NoiseHandshake {
+    pub fn initiator(keys: &NoiseKeyPair, prologue: &[u8]) -> CryptoResult<Self> {
+        Ok(Self(
+            snow::Builder::new(NOISE_PARAMS.parse()?)
+                .local_private_key(keys.private())
+                .prologue(prologue)
+                .build_initiator()?,
+        ))
+    }
+
+    pub fn responder(keys: &NoiseKeyPair, prologue: &[u8]) -> CryptoResult<Self> {
+        Ok(Self(
+            snow::Builder::new(NOISE_PARAMS.parse()?)
+                .local_private_key(keys.private())
+                .prologue(prologue)
+                .build_responder()?,
+        ))
+    }
+
+    pub fn write_message(
+        &mut self,
+        payload: &[u8],
+        out: &mut [u8],
+    ) -> CryptoResult<usize> {
+        Ok(self.0.write_message(payload, out)?)
+    }
+
+    pub fn read_message(&mut self, msg: &[u8], out: &mut [u8]) -> CryptoResult<usize> {
+        Ok(self.0.read_message(msg, out)?)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.is_handshake_finished()
+    }
+
+    /// The remote static key, once the handshake has revealed it.
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.0.get_remote_static()
+    }
+
+    pub fn into_transport(self) -> CryptoResult<NoiseTransport> {
+        Ok(NoiseTransport(self.0.into_stateless_transport_mode()?))
+    }
+}
+
+/// Cipher state of a completed handshake. Nonces are tracked by the caller, so
+/// the read and write halves of a connection can share one transport.
+pub struct NoiseTransport(snow::StatelessTransportState);
+
+impl NoiseTransport {
+    pub fn encrypt(
+        &self,
+        nonce: u64,
+        plain: &[u8],
+        out: &mut [u8],
+    ) -> CryptoResult<usize> {
+        Ok(self.0.write_message(nonce, plain, out)?)
+    }
+
+    pub fn decrypt(
+        &self,
+        nonce: u64,
+        cipher: &[u8],
+        out: &mut [u8],
+    ) -> CryptoResult<usize> {
+        Ok(self.0.read_message(nonce, cipher, out)?)
+    }
+}
+
+/// Decodes a base64 public key, e.g. one pinned in a config file.
+pub fn decode_public_key(public: &str) -> CryptoResult<Vec<u8>> {
+    let key = base64::decode(public)?;
+    if key.len() != NOISE_KEY_LEN {
+        return Err(CryptoError::InvalidKeyLength(key.len()));
+    }
+    Ok(key)
+}
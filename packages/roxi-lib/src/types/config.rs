@@ -12,4 +12,7 @@ pub struct WireGuardConfPeer {
 #[derive(Debug, Serialize, Deserialize, Clone, Hash)]
 pub struct WireGuardConf {
     pub config: PathBuf,
+    /// Our public key, read from the installed WireGuard when unset.
+    #[serde(default)]
+    pub public_key: Option<String>,
 }
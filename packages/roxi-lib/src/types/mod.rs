@@ -3,11 +3,12 @@ pub mod config;
 use serde::{Deserialize, Serialize};
 use std::{
     fmt,
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
 };
 use tokio::net::TcpStream;
 
-#[derive(Debug, Serialize, Deserialize, Clone, Hash)]
+#[derive(Debug, Serialize, Deserialize, Clone, Hash, Eq, PartialEq)]
 pub struct Ports {
     pub tcp: u16,
     pub udp: u16,
@@ -19,14 +20,24 @@ pub enum InterfaceKind {
     Udp,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+const ADDR_FAMILY_V4: u8 = 4;
+const ADDR_FAMILY_V6: u8 = 6;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
 pub struct Address {
-    ip: Ipv4Addr,
+    ip: IpAddr,
     port: u16,
 }
 
 impl Address {
-    pub fn ip(&self) -> Ipv4Addr {
+    pub fn new(ip: IpAddr, port: u16) -> Self {
+        Self {
+            ip: ip.to_canonical(),
+            port,
+        }
+    }
+
+    pub fn ip(&self) -> IpAddr {
         self.ip
     }
 
@@ -34,31 +45,111 @@ impl Address {
         self.port
     }
 
+    pub fn is_ipv6(&self) -> bool {
+        self.ip.is_ipv6()
+    }
+
+    pub fn to_socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+
+    /// Encodes the address as a family byte (4 or 6), the IP octets and the
+    /// big-endian port, i.e. 7 bytes for IPv4 and 19 bytes for IPv6.
     pub fn to_vec(&self) -> Vec<u8> {
         let mut result = Vec::new();
-        result.extend(self.ip.octets());
+        match self.ip {
+            IpAddr::V4(ip) => {
+                result.push(ADDR_FAMILY_V4);
+                result.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                result.push(ADDR_FAMILY_V6);
+                result.extend(ip.octets());
+            }
+        }
         result.extend(self.port.to_be_bytes());
 
         result
     }
+
+    /// Number of bytes an encoded address starting with `family` occupies.
+    pub fn encoded_len(family: u8) -> anyhow::Result<usize> {
+        match family {
+            ADDR_FAMILY_V4 => Ok(7),
+            ADDR_FAMILY_V6 => Ok(19),
+            _ => Err(anyhow::anyhow!("Unknown address family: {family}")),
+        }
+    }
+
+    /// Decodes an address produced by `Address::to_vec`, returning it along with
+    /// the number of bytes consumed.
+    pub fn decode(d: &[u8]) -> anyhow::Result<(Self, usize)> {
+        let family = *d
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("Address expected"))?;
+        let len = Self::encoded_len(family)?;
+        if d.len() < len {
+            return Err(anyhow::anyhow!("Truncated address"));
+        }
+
+        let ip = match family {
+            ADDR_FAMILY_V4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&d[1..5]);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            _ => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&d[1..17]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        };
+        let port = u16::from_be_bytes([d[len - 2], d[len - 1]]);
+
+        Ok((Self { ip, port }, len))
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(s: SocketAddr) -> Self {
+        Self::new(s.ip(), s.port())
+    }
+}
+
+impl From<&SocketAddr> for Address {
+    fn from(s: &SocketAddr) -> Self {
+        Self::new(s.ip(), s.port())
+    }
+}
+
+impl TryFrom<&[u8]> for Address {
+    type Error = anyhow::Error;
+    fn try_from(d: &[u8]) -> Result<Self, Self::Error> {
+        let (addr, _) = Self::decode(d)?;
+        Ok(addr)
+    }
 }
 
 impl TryFrom<Vec<u8>> for Address {
     type Error = anyhow::Error;
     fn try_from(d: Vec<u8>) -> Result<Self, Self::Error> {
-        let ip = Ipv4Addr::new(d[0], d[1], d[2], d[3]);
-        let port = u16::from_be_bytes([d[4], d[5]]);
-        Ok(Self { ip, port })
+        Self::try_from(d.as_slice())
     }
 }
 
 impl TryFrom<String> for Address {
     type Error = anyhow::Error;
     fn try_from(s: String) -> Result<Self, Self::Error> {
-        let mut parts = s.split(":");
-        let ip: Ipv4Addr = parts.next().unwrap().parse().unwrap();
-        let port: u16 = parts.next().unwrap().parse().unwrap();
-        Ok(Self { ip, port })
+        Self::try_from(s.as_str())
+    }
+}
+
+impl TryFrom<&str> for Address {
+    type Error = anyhow::Error;
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let addr = SocketAddr::from_str(s)
+            .map_err(|e| anyhow::anyhow!("Invalid address {s}: {e}"))?;
+        Ok(Self::from(addr))
     }
 }
 
@@ -72,7 +163,7 @@ impl TryFrom<&ClientId> for Address {
 
 impl std::fmt::Display for Address {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.ip, self.port)
+        write!(f, "{}", self.to_socket_addr())
     }
 }
 
@@ -80,11 +171,7 @@ impl TryFrom<Option<Vec<u8>>> for Address {
     type Error = anyhow::Error;
     fn try_from(d: Option<Vec<u8>>) -> Result<Self, Self::Error> {
         match d {
-            Some(d) => {
-                let ip = Ipv4Addr::new(d[0], d[1], d[2], d[3]);
-                let port = u16::from_be_bytes([d[4], d[5]]);
-                Ok(Self { ip, port })
-            }
+            Some(d) => Self::try_from(d),
             None => Err(anyhow::anyhow!("Address expected")),
         }
     }
@@ -96,14 +183,6 @@ impl From<Address> for Option<Vec<u8>> {
     }
 }
 
-impl From<[u8; 6]> for Address {
-    fn from(d: [u8; 6]) -> Self {
-        let ip = Ipv4Addr::new(d[0], d[1], d[2], d[3]);
-        let port = u16::from_be_bytes([d[4], d[5]]);
-        Self { ip, port }
-    }
-}
-
 #[repr(u8)]
 #[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
 pub enum StunAddressKind {
@@ -114,14 +193,30 @@ pub enum StunAddressKind {
 #[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
 pub struct StunInfo {
     kind: StunAddressKind,
-    ip: Ipv4Addr,
+    ip: IpAddr,
     port: u16,
 }
 
 impl StunInfo {
-    pub fn new(kind: StunAddressKind, ip: Ipv4Addr, port: u16) -> Self {
+    pub fn new(kind: StunAddressKind, ip: IpAddr, port: u16) -> Self {
         Self { kind, ip, port }
     }
+
+    pub fn kind(&self) -> &StunAddressKind {
+        &self.kind
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn addr(&self) -> Address {
+        Address::new(self.ip, self.port)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
@@ -131,6 +226,12 @@ impl ClientId {
     pub fn to_vec(self) -> Vec<u8> {
         self.0.into_bytes()
     }
+
+    /// The identity of a client that authenticates with a key pair, which stays
+    /// the same wherever the client connects from.
+    pub fn from_public_key(key: &[u8]) -> Self {
+        Self(format!("rx-{}", &crate::util::sha256(key)[..32]))
+    }
 }
 
 impl std::fmt::Display for ClientId {
@@ -155,13 +256,13 @@ impl TryFrom<&TcpStream> for ClientId {
     type Error = std::io::Error;
     fn try_from(s: &TcpStream) -> Result<Self, Self::Error> {
         let addr = s.peer_addr()?;
-        Ok(Self::from(addr.ip().to_string()))
+        Ok(Self::from(&addr))
     }
 }
 
 impl From<&SocketAddr> for ClientId {
     fn from(s: &SocketAddr) -> Self {
-        Self::from(s.ip().to_string())
+        Self::from(s.ip().to_canonical().to_string())
     }
 }
 
@@ -178,6 +279,10 @@ impl SharedKey {
     pub fn to_vec(self) -> Vec<u8> {
         self.0.into_bytes()
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
 }
 
 impl std::fmt::Display for SharedKey {
@@ -206,3 +311,35 @@ impl TryFrom<Vec<u8>> for SharedKey {
         Ok(Self(res))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_roundtrips_ipv4_and_ipv6() {
+        for s in ["192.168.1.2:8080", "[2001:db8::1]:51820"] {
+            let addr = Address::try_from(s).unwrap();
+            assert_eq!(addr.to_string(), s);
+
+            let encoded = addr.to_vec();
+            let (decoded, n) = Address::decode(&encoded).unwrap();
+            assert_eq!(n, encoded.len());
+            assert_eq!(decoded, addr);
+        }
+    }
+
+    #[test]
+    fn test_address_canonicalizes_ipv4_mapped_ipv6() {
+        let addr = Address::try_from("[::ffff:10.0.0.1]:80").unwrap();
+        assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(!addr.is_ipv6());
+    }
+
+    #[test]
+    fn test_address_rejects_truncated_input() {
+        let encoded = Address::try_from("[::1]:80").unwrap().to_vec();
+        assert!(Address::decode(&encoded[..10]).is_err());
+        assert!(Address::decode(&[9, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+use crate::{hello::Features, ProtoError, ProtoResult};
+use rand::RngCore;
+use roxi_crypto::IdentityKeyPair;
+use roxi_lib::types::{Address, ClientId, SharedKey};
+use serde::{Deserialize, Serialize};
+
+pub const AUTH_NONCE_LEN: usize = 32;
+
+/// Domain separation for the bytes a shared key HMAC covers.
+const AUTH_CONTEXT_LABEL: &[u8] = b"roxi-auth-v1";
+
+/// Sent by the server in an `AuthenticationChallengeResponse`. The client proves
+/// it holds the shared key, or its identity key, by answering with an HMAC or a
+/// signature over the nonce.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct AuthenticationChallenge {
+    pub nonce: [u8; AUTH_NONCE_LEN],
+}
+
+impl AuthenticationChallenge {
+    pub fn new() -> Self {
+        let mut nonce = [0u8; AUTH_NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        Self { nonce }
+    }
+}
+
+impl Default for AuthenticationChallenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Proof of identity presented in an `AuthenticationRequest`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+pub enum Credentials {
+    None,
+    /// HMAC-SHA256, keyed by the shared key, over the challenge nonce and the
+    /// rest of the request.
+    SharedKeyHmac {
+        nonce: [u8; AUTH_NONCE_LEN],
+        mac: Vec<u8>,
+    },
+    /// Ed25519 signature by the client's identity key over the same bytes.
+    Ed25519 {
+        nonce: [u8; AUTH_NONCE_LEN],
+        public_key: Vec<u8>,
+        signature: Vec<u8>,
+    },
+}
+
+/// Payload of an `AuthenticationRequest`: what the server needs to know about a
+/// client to admit it as a peer, and nothing about its local setup.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+pub struct AuthenticationRequest {
+    /// The identity the client claims for itself.
+    pub client_id: ClientId,
+    pub credentials: Credentials,
+    /// Where other peers reach this client's gateway.
+    pub gateway: Address,
+    /// The client's WireGuard public key, if it has one yet.
+    pub wireguard_public_key: Option<String>,
+    /// Base64 static key of the gateway's control channel, which peers pin.
+    pub gateway_public_key: Option<String>,
+    pub features: Features,
+    /// Labels, such as a region, that gateways are matched to clients by.
+    pub tags: Vec<String>,
+}
+
+impl AuthenticationRequest {
+    pub fn new(
+        client_id: ClientId,
+        gateway: Address,
+        wireguard_public_key: Option<String>,
+        features: Features,
+    ) -> Self {
+        Self {
+            client_id,
+            credentials: Credentials::None,
+            gateway,
+            wireguard_public_key,
+            gateway_public_key: None,
+            features,
+            tags: vec![],
+        }
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn with_gateway_public_key(mut self, key: String) -> Self {
+        self.gateway_public_key = Some(key);
+        self
+    }
+
+    /// Answers `challenge` with `key`, binding everything else in the request.
+    pub fn sign(
+        &mut self,
+        key: &SharedKey,
+        challenge: &AuthenticationChallenge,
+    ) -> ProtoResult<()> {
+        let mac =
+            roxi_crypto::hmac_sha256(key.as_bytes(), &self.context(&challenge.nonce)?);
+        self.credentials = Credentials::SharedKeyHmac {
+            nonce: challenge.nonce,
+            mac,
+        };
+        Ok(())
+    }
+
+    /// Answers `challenge` by signing with the client's identity key.
+    pub fn sign_with_identity(
+        &mut self,
+        identity: &IdentityKeyPair,
+        challenge: &AuthenticationChallenge,
+    ) -> ProtoResult<()> {
+        let signature = identity.sign(&self.context(&challenge.nonce)?);
+        self.credentials = Credentials::Ed25519 {
+            nonce: challenge.nonce,
+            public_key: identity.public().to_vec(),
+            signature,
+        };
+        Ok(())
+    }
+
+    /// The identity key the request claims to be signed with, if any.
+    pub fn public_key(&self) -> Option<&[u8]> {
+        match &self.credentials {
+            Credentials::Ed25519 { public_key, .. } => Some(public_key),
+            _ => None,
+        }
+    }
+
+    /// Checks that the request answers `challenge` with the private half of the
+    /// identity key it carries. Whether that key is trusted is up to the caller.
+    pub fn verify_identity(
+        &self,
+        challenge: &AuthenticationChallenge,
+    ) -> ProtoResult<()> {
+        match &self.credentials {
+            Credentials::Ed25519 {
+                nonce,
+                public_key,
+                signature,
+            } if *nonce == challenge.nonce => {
+                roxi_crypto::verify_ed25519(public_key, &self.context(nonce)?, signature)
+                    .map_err(|_| ProtoError::InvalidCredentials)
+            }
+            _ => Err(ProtoError::InvalidCredentials),
+        }
+    }
+
+    /// Checks, in constant time, that the request answers `challenge` with `key`.
+    pub fn verify(
+        &self,
+        key: &SharedKey,
+        challenge: &AuthenticationChallenge,
+    ) -> ProtoResult<()> {
+        match &self.credentials {
+            Credentials::SharedKeyHmac { nonce, mac } if *nonce == challenge.nonce => {
+                roxi_crypto::verify_hmac_sha256(
+                    key.as_bytes(),
+                    &self.context(nonce)?,
+                    mac,
+                )
+                .map_err(|_| ProtoError::InvalidCredentials)
+            }
+            _ => Err(ProtoError::InvalidCredentials),
+        }
+    }
+
+    /// The bytes credentials answering `challenge` cover, for verifiers that
+    /// hold the key themselves.
+    pub fn signed_data(
+        &self,
+        challenge: &AuthenticationChallenge,
+    ) -> ProtoResult<Vec<u8>> {
+        self.context(&challenge.nonce)
+    }
+
+    fn context(&self, nonce: &[u8]) -> ProtoResult<Vec<u8>> {
+        let claims = bincode::serialize(&(
+            &self.client_id,
+            &self.gateway,
+            &self.wireguard_public_key,
+            &self.gateway_public_key,
+            &self.features,
+            &self.tags,
+        ))?;
+        Ok([AUTH_CONTEXT_LABEL, nonce, &claims].concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> AuthenticationRequest {
+        AuthenticationRequest::new(
+            ClientId::from("192.168.1.2"),
+            Address::try_from("192.168.1.2:8081").unwrap(),
+            None,
+            Features::local(),
+        )
+    }
+
+    #[test]
+    fn test_authentication_request_verifies_signed_challenge() {
+        let key = SharedKey::from("roxi-XXX");
+        let challenge = AuthenticationChallenge::new();
+        let mut request = request();
+        request.sign(&key, &challenge).unwrap();
+
+        let data = bincode::serialize(&request).unwrap();
+        let decoded: AuthenticationRequest = bincode::deserialize(&data).unwrap();
+        assert!(decoded.verify(&key, &challenge).is_ok());
+    }
+
+    #[test]
+    fn test_authentication_request_rejects_bad_answers() {
+        let key = SharedKey::from("roxi-XXX");
+        let challenge = AuthenticationChallenge::new();
+        assert!(request().verify(&key, &challenge).is_err());
+
+        let mut signed = request();
+        signed.sign(&key, &challenge).unwrap();
+
+        // Replayed against a fresh challenge
+        assert!(signed
+            .verify(&key, &AuthenticationChallenge::new())
+            .is_err());
+
+        // Wrong key
+        assert!(signed
+            .verify(&SharedKey::from("roxi-YYY"), &challenge)
+            .is_err());
+
+        // Tampered claims
+        let mut tampered = signed.clone();
+        tampered.gateway = Address::try_from("10.0.0.1:8081").unwrap();
+        assert!(tampered.verify(&key, &challenge).is_err());
+
+        let mut tampered = signed.clone();
+        tampered.tags = vec!["eu-west".to_string()];
+        assert!(tampered.verify(&key, &challenge).is_err());
+
+        let mut tampered = signed.clone();
+        tampered.gateway_public_key = Some("forged".to_string());
+        assert!(tampered.verify(&key, &challenge).is_err());
+    }
+
+    #[test]
+    fn test_authentication_request_verifies_identity_signature() {
+        let identity = IdentityKeyPair::generate().unwrap();
+        let challenge = AuthenticationChallenge::new();
+        let mut request = request();
+        request.sign_with_identity(&identity, &challenge).unwrap();
+
+        assert_eq!(request.public_key(), Some(identity.public()));
+        assert!(request.verify_identity(&challenge).is_ok());
+        assert!(request
+            .verify_identity(&AuthenticationChallenge::new())
+            .is_err());
+        assert!(request
+            .verify(&SharedKey::from("roxi-XXX"), &challenge)
+            .is_err());
+
+        // A signature does not carry over to another key
+        let other = IdentityKeyPair::generate().unwrap();
+        if let Credentials::Ed25519 { public_key, .. } = &mut request.credentials {
+            *public_key = other.public().to_vec();
+        }
+        assert!(request.verify_identity(&challenge).is_err());
+    }
+}
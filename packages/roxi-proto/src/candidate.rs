@@ -0,0 +1,76 @@
+use roxi_lib::types::Address;
+use serde::{Deserialize, Serialize};
+
+/// Where a candidate address came from, in the sense of RFC 8445.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum CandidateKind {
+    /// An address of one of the peer's own interfaces.
+    Host,
+    /// The peer's address as seen by the STUN server.
+    ServerReflexive,
+    /// An address learned from a connectivity check rather than exchanged up front.
+    PeerReflexive,
+    /// An address on the roxi server's relay.
+    Relay,
+}
+
+impl CandidateKind {
+    /// RFC 8445 recommended type preferences; direct paths beat relayed ones.
+    pub fn type_preference(&self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            CandidateKind::PeerReflexive => 110,
+            CandidateKind::ServerReflexive => 100,
+            CandidateKind::Relay => 0,
+        }
+    }
+}
+
+/// An address at which a peer might be reachable.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub addr: Address,
+    pub priority: u32,
+}
+
+impl Candidate {
+    /// Builds a candidate with an RFC 8445 (5.1.2.1) priority. `local_preference`
+    /// orders candidates of the same kind, e.g. one per interface.
+    pub fn new(kind: CandidateKind, addr: Address, local_preference: u16) -> Self {
+        let priority =
+            (kind.type_preference() << 24) | ((local_preference as u32) << 8) | 255;
+        Self {
+            kind,
+            addr,
+            priority,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidate_priority_prefers_direct_paths() {
+        let addr = Address::try_from("10.0.0.1:5000").unwrap();
+        let host = Candidate::new(CandidateKind::Host, addr.clone(), 0);
+        let prflx = Candidate::new(CandidateKind::PeerReflexive, addr.clone(), u16::MAX);
+        let srflx =
+            Candidate::new(CandidateKind::ServerReflexive, addr.clone(), u16::MAX);
+        let relay = Candidate::new(CandidateKind::Relay, addr, u16::MAX);
+
+        assert!(host.priority > prflx.priority);
+        assert!(prflx.priority > srflx.priority);
+        assert!(srflx.priority > relay.priority);
+    }
+
+    #[test]
+    fn test_candidate_local_preference_orders_same_kind() {
+        let addr = Address::try_from("10.0.0.1:5000").unwrap();
+        let first = Candidate::new(CandidateKind::Host, addr.clone(), 2);
+        let second = Candidate::new(CandidateKind::Host, addr, 1);
+        assert!(first.priority > second.priority);
+    }
+}
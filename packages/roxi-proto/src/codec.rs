@@ -0,0 +1,277 @@
+use crate::secure::SecureCodec;
+use crate::{
+    message::{MIN_HEADER_LEN, PROTOCOL_VERSION},
+    Message, ProtoError, ProtoResult,
+};
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead, FramedWrite};
+
+/// Default upper bound on a single frame's payload (8 MiB).
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+pub type MessageFramed<T> = Framed<T, SecureCodec>;
+
+pub type MessageStream<R> = FramedRead<R, SecureCodec>;
+
+pub type MessageSink<W> = FramedWrite<W, SecureCodec>;
+
+/// Length-delimited codec for `Message`.
+///
+/// Frames are delimited using the payload length already carried in the message
+/// header, so partial reads are buffered until a full frame is available, and
+/// coalesced frames are split back into individual messages.
+///
+/// Outgoing messages are stamped with the codec's protocol version, which starts
+/// out as `PROTOCOL_VERSION` and is updated once a `Hello` exchange completes.
+#[derive(Debug, Clone)]
+pub struct MessageCodec {
+    max_frame_size: usize,
+    version: u8,
+}
+
+impl MessageCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            version: PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = ProtoError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> ProtoResult<Option<Message>> {
+        let header_len = match Message::header_len(src)? {
+            Some(n) => n,
+            None => {
+                src.reserve(MIN_HEADER_LEN - src.len());
+                return Ok(None);
+            }
+        };
+
+        if src.len() < header_len {
+            src.reserve(header_len - src.len());
+            return Ok(None);
+        }
+
+        let size = Message::payload_len(&src[..header_len])?;
+        if size > self.max_frame_size {
+            return Err(ProtoError::FrameTooLarge(size, self.max_frame_size));
+        }
+
+        let frame_len = header_len + size;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(frame_len);
+        Message::deserialize(&frame).map(Some)
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = ProtoError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> ProtoResult<()> {
+        let size = msg.data_len();
+        if size > self.max_frame_size {
+            return Err(ProtoError::FrameTooLarge(size, self.max_frame_size));
+        }
+
+        let data = msg.with_version(self.version).serialize()?;
+        dst.extend_from_slice(&data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageKind, MessageStatus};
+
+    fn message(kind: MessageKind, data: Option<Vec<u8>>) -> Message {
+        Message::new(
+            kind,
+            MessageStatus::Pending,
+            "127.0.0.1:8080".to_string(),
+            data,
+        )
+    }
+
+    #[test]
+    fn test_codec_roundtrips_ipv6_sender() {
+        let mut codec = MessageCodec::default();
+        let mut src = BytesMut::new();
+        codec
+            .encode(
+                Message::new(
+                    MessageKind::SeedRequest,
+                    MessageStatus::Pending,
+                    "[2001:db8::2]:8080".to_string(),
+                    Some(b"seed".to_vec()),
+                ),
+                &mut src,
+            )
+            .unwrap();
+
+        let msg = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(msg.version(), PROTOCOL_VERSION);
+        assert_eq!(msg.sender_addr().to_string(), "[2001:db8::2]:8080");
+        assert_eq!(msg.data(), b"seed".to_vec());
+    }
+
+    #[test]
+    fn test_codec_speaks_legacy_v1_headers() {
+        let mut codec = MessageCodec::default();
+        codec.set_version(1);
+
+        let mut src = BytesMut::new();
+        codec
+            .encode(
+                message(MessageKind::SeedRequest, Some(b"seed".to_vec())),
+                &mut src,
+            )
+            .unwrap();
+
+        let msg = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(msg.version(), 1);
+        assert_eq!(msg.sender_addr().to_string(), "127.0.0.1:8080");
+        assert_eq!(msg.data(), b"seed".to_vec());
+
+        let result = codec.encode(
+            Message::new(
+                MessageKind::Ping,
+                MessageStatus::Pending,
+                "[::1]:8080".to_string(),
+                None,
+            ),
+            &mut src,
+        );
+        assert!(matches!(result, Err(ProtoError::UnsupportedAddress(1))));
+    }
+
+    #[test]
+    fn test_codec_carries_request_ids() {
+        let mut codec = MessageCodec::default();
+        let mut src = BytesMut::new();
+        codec
+            .encode(
+                message(MessageKind::GatewayRequest, Some(b"abc".to_vec())).with_id(7),
+                &mut src,
+            )
+            .unwrap();
+        let msg = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(msg.id(), 7);
+        assert_eq!(msg.data(), b"abc".to_vec());
+
+        // Peers speaking version 2 do not correlate their requests
+        codec.set_version(2);
+        codec
+            .encode(message(MessageKind::Ping, None).with_id(7), &mut src)
+            .unwrap();
+        let msg = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(msg.version(), 2);
+        assert_eq!(msg.id(), 0);
+    }
+
+    #[test]
+    fn test_codec_buffers_partial_frames() {
+        let mut codec = MessageCodec::default();
+        let mut full = BytesMut::new();
+        codec
+            .encode(
+                message(MessageKind::SeedRequest, Some(vec![7u8; 4096])),
+                &mut full,
+            )
+            .unwrap();
+
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&full[..10]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(&full[10..2048]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(&full[2048..]);
+        let msg = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(*msg.kind(), MessageKind::SeedRequest);
+        assert_eq!(msg.data(), vec![7u8; 4096]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_codec_splits_coalesced_frames() {
+        let mut codec = MessageCodec::default();
+        let mut src = BytesMut::new();
+        codec
+            .encode(message(MessageKind::Ping, None), &mut src)
+            .unwrap();
+        codec
+            .encode(
+                message(MessageKind::GatewayRequest, Some(b"abc".to_vec())),
+                &mut src,
+            )
+            .unwrap();
+
+        let first = codec.decode(&mut src).unwrap().unwrap();
+        let second = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(*first.kind(), MessageKind::Ping);
+        assert_eq!(*second.kind(), MessageKind::GatewayRequest);
+        assert_eq!(second.data(), b"abc".to_vec());
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_codec_rejects_oversized_frames() {
+        let mut codec = MessageCodec::new(16);
+        let mut dst = BytesMut::new();
+        let result = codec.encode(
+            message(MessageKind::SeedRequest, Some(vec![0u8; 17])),
+            &mut dst,
+        );
+        assert!(matches!(result, Err(ProtoError::FrameTooLarge(17, 16))));
+
+        let mut src = BytesMut::new();
+        MessageCodec::default()
+            .encode(
+                message(MessageKind::SeedRequest, Some(vec![0u8; 17])),
+                &mut src,
+            )
+            .unwrap();
+        let result = codec.decode(&mut src);
+        assert!(matches!(result, Err(ProtoError::FrameTooLarge(17, 16))));
+    }
+
+    #[test]
+    fn test_codec_rejects_bad_magic() {
+        let mut codec = MessageCodec::default();
+        let mut src = BytesMut::new();
+        codec
+            .encode(message(MessageKind::Ping, None), &mut src)
+            .unwrap();
+        src[0] = b'X';
+
+        assert!(matches!(codec.decode(&mut src), Err(ProtoError::BadMagic)));
+    }
+}
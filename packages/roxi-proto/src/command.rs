@@ -39,6 +39,50 @@ pub fn reload_wireguard(interface: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Drops the peer with `public_key` from the live `interface`, which keeps it
+/// until it is reloaded otherwise.
+pub fn remove_wireguard_peer(interface: &str, public_key: &str) -> ProtoResult<()> {
+    let output = Command::new("wg")
+        .arg("set")
+        .arg(interface)
+        .arg("peer")
+        .arg(public_key)
+        .arg("remove")
+        .output()?;
+
+    if !output.status.success() {
+        return Err(ProtoError::Io(io::Error::other(format!(
+            "Failed to remove peer from {interface}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    Ok(())
+}
+
+/// Bytes received and sent by all peers of `interface`, per `wg show transfer`.
+pub fn wireguard_transfer(interface: &str) -> ProtoResult<(u64, u64)> {
+    let output = Command::new("wg")
+        .arg("show")
+        .arg(interface)
+        .arg("transfer")
+        .output()?;
+
+    if !output.status.success() {
+        return Err(ProtoError::Io(io::Error::other(format!(
+            "Failed to read transfer of {interface}"
+        ))));
+    }
+
+    // One `<public key>\t<received>\t<sent>` line per peer
+    let stdout = String::from_utf8(output.stdout)?;
+    let transfer = stdout.lines().fold((0, 0), |(rx, tx), line| {
+        let mut fields = line.split_whitespace().skip(1);
+        let mut next = || fields.next().and_then(|f| f.parse::<u64>().ok());
+        (rx + next().unwrap_or(0), tx + next().unwrap_or(0))
+    });
+    Ok(transfer)
+}
+
 pub fn wireguard_keypair() -> ProtoResult<WireGuardProtoKeyPair> {
     let privkey = Command::new("wg").arg("genkey").output()?;
     let privkey = String::from_utf8(privkey.stdout)
@@ -82,8 +126,7 @@ pub fn derive_wireguard_pubkey(
             "Failed to generate public key: {}",
             String::from_utf8_lossy(&output.stderr)
         );
-        return Err(ProtoError::Io(io::Error::new(
-            io::ErrorKind::Other,
+        return Err(ProtoError::Io(io::Error::other(
             "Failed to generate public key",
         )));
     }
@@ -101,10 +144,7 @@ pub fn cat_wireguard_pubkey() -> ProtoResult<WireGuardProtoKey> {
         return Ok(WireGuardProtoKey::from_public(k));
     }
 
-    Err(ProtoError::Io(io::Error::new(
-        io::ErrorKind::Other,
-        "Failed to read publickey",
-    )))
+    Err(ProtoError::Io(io::Error::other("Failed to read publickey")))
 }
 
 pub fn cat_wireguard_key<P: AsRef<Path>>(p: P) -> ProtoResult<WireGuardProtoKey> {
@@ -0,0 +1,23 @@
+use crate::MessageStatus;
+use serde::{Deserialize, Serialize};
+
+/// Payload of a `DisconnectSessionResponse` the server sends when it ends a
+/// client's session.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum DisconnectReason {
+    /// The session outlived `auth.session_ttl`.
+    Expired,
+    /// No message, heartbeats included, arrived within the idle timeout.
+    Idle,
+    /// The client's credentials were revoked.
+    Revoked,
+}
+
+impl DisconnectReason {
+    pub fn status(&self) -> MessageStatus {
+        match self {
+            Self::Expired | Self::Idle => MessageStatus::Unauthorized,
+            Self::Revoked => MessageStatus::Forbidden,
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{ProtoError, ProtoResult};
+use roxi_crypto::IdentityKeyPair;
+use roxi_lib::types::Ports;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Domain separation for the bytes an enrollment signature covers.
+const ENROLL_CONTEXT_LABEL: &[u8] = b"roxi-enroll-v1";
+
+/// Payload of an `EnrollRequest`: redeems an invite token for the identity key
+/// the client just generated, signed to prove it holds the private half.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct EnrollmentRequest {
+    pub token: String,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl EnrollmentRequest {
+    pub fn new(token: &str, identity: &IdentityKeyPair) -> Self {
+        Self {
+            token: token.to_string(),
+            public_key: identity.public().to_vec(),
+            signature: identity.sign(&Self::context(token)),
+        }
+    }
+
+    /// Checks that the request is signed by the key it enrolls.
+    pub fn verify(&self) -> ProtoResult<()> {
+        roxi_crypto::verify_ed25519(
+            &self.public_key,
+            &Self::context(&self.token),
+            &self.signature,
+        )
+        .map_err(|_| ProtoError::InvalidCredentials)
+    }
+
+    fn context(token: &str) -> Vec<u8> {
+        [ENROLL_CONTEXT_LABEL, token.as_bytes()].concat()
+    }
+}
+
+/// Payload of an `EnrollResponse`: what a newly enrolled client needs to write
+/// its config.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct Enrollment {
+    /// The name the server knows the client by.
+    pub label: String,
+    pub server_ip: IpAddr,
+    pub server_ports: Ports,
+    /// The server's control channel key, if it is configured rather than
+    /// generated each run, and so can be pinned.
+    pub server_public_key: Option<String>,
+    pub response_timeout: u64,
+    /// The tunnel address the invite assigned the client, e.g. `10.0.0.2/32`.
+    pub address: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enrollment_request_verifies_signature() {
+        let identity = IdentityKeyPair::generate().unwrap();
+        let request = EnrollmentRequest::new("rxi-token", &identity);
+        assert!(request.verify().is_ok());
+
+        let mut stolen = request.clone();
+        stolen.token = "rxi-other".to_string();
+        assert!(stolen.verify().is_err());
+
+        let mut swapped = request;
+        swapped.public_key = IdentityKeyPair::generate().unwrap().public().to_vec();
+        assert!(swapped.verify().is_err());
+    }
+}
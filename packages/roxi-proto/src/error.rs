@@ -8,6 +8,12 @@ pub enum ProtoError {
     #[error("Malformed message")]
     MalformedMessage,
 
+    #[error("Bad magic bytes in message header")]
+    BadMagic,
+
+    #[error("Sender address cannot be encoded in protocol version {0}")]
+    UnsupportedAddress(u8),
+
     #[error("Utf8 error: {0}")]
     Utf8(#[from] std::string::FromUtf8Error),
 
@@ -26,6 +32,18 @@ pub enum ProtoError {
     #[error("Malformed config")]
     MalformedConfig,
 
+    #[error("Frame of {0} bytes exceeds maximum frame size of {1} bytes")]
+    FrameTooLarge(usize, usize),
+
+    #[error("Malformed STUN message: {0}")]
+    Stun(&'static str),
+
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+
+    #[error("Peer presented an untrusted static key")]
+    UntrustedPeerKey,
+
     #[error("Missing wireguard config file: {0}")]
     MissingWireGuardField(String),
 }
@@ -0,0 +1,89 @@
+use roxi_lib::types::{Address, ClientId};
+use serde::{Deserialize, Serialize};
+
+/// Sent by the server in a `GatewayResponse` to both sides of a tunnel: who the
+/// other side is, where its gateway listens and the keys it announced.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct GatewayInfo {
+    pub peer: ClientId,
+    pub gateway: Address,
+    pub wireguard_public_key: Option<String>,
+    /// Static key its gateway's control channel must prove it holds.
+    pub gateway_public_key: Option<String>,
+}
+
+/// Payload of a `GatewayReadyRequest`, in which a seeder tells the server that
+/// its gateway serves `peer`, and of the `GatewayReadyResponse` the server then
+/// sends `peer`, naming the seeder.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct GatewayReady {
+    pub peer: ClientId,
+}
+
+/// Payload of a `PeerTunnelClosedRequest`, in which a seeder tells the server
+/// that its gateway no longer serves `peer`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct PeerTunnelClosed {
+    pub peer: ClientId,
+}
+
+/// Payload of a `GatewayLoadRequest`, in which a seeder reports how busy its
+/// gateway is so the server can route clients around it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct GatewayLoad {
+    /// Peers the gateway holds a tunnel for.
+    pub active_tunnels: u32,
+    pub max_clients: u16,
+    /// Bytes received and sent over WireGuard since its interface came up.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Whether the gateway is running and accepting peers.
+    pub healthy: bool,
+}
+
+impl GatewayLoad {
+    /// Whether the gateway cannot take another peer.
+    pub fn is_full(&self) -> bool {
+        self.active_tunnels >= u32::from(self.max_clients)
+    }
+
+    /// Whether the server should assign the gateway new peers.
+    pub fn is_available(&self) -> bool {
+        self.healthy && !self.is_full()
+    }
+}
+
+/// One seeder in the `GatewayStatusResponse` the server sends its operator.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct GatewayStatus {
+    pub peer: ClientId,
+    pub gateway: Address,
+    /// Connected clients the server assigned to it.
+    pub assigned: u32,
+    /// Its last report, if it sent one.
+    pub load: Option<GatewayLoad>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gateway_load_availability() {
+        let mut load = GatewayLoad {
+            active_tunnels: 1,
+            max_clients: 2,
+            healthy: true,
+            ..Default::default()
+        };
+        assert!(load.is_available());
+
+        load.active_tunnels = 2;
+        assert!(load.is_full());
+        assert!(!load.is_available());
+
+        load.active_tunnels = 0;
+        load.healthy = false;
+        assert!(!load.is_available());
+    }
+}
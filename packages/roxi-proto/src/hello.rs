@@ -0,0 +1,124 @@
+use crate::message::{PROTOCOL_VERSION, SUPPORTED_PROTOCOL_VERSIONS};
+use serde::{Deserialize, Serialize};
+
+/// Optional protocol capabilities advertised during the `Hello` exchange.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    pub const STUN: Features = Features(1 << 0);
+    pub const SEED: Features = Features(1 << 1);
+    pub const TUNNEL: Features = Features(1 << 2);
+    pub const RESUME: Features = Features(1 << 3);
+
+    /// Features supported by this build.
+    pub fn local() -> Self {
+        Features::STUN | Features::SEED | Features::TUNNEL | Features::RESUME
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(&self, other: Features) -> Self {
+        Features(self.0 & other.0)
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Features;
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+/// Sent by the initiator of a connection to advertise what it can speak.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct Hello {
+    pub versions: Vec<u8>,
+    pub features: Features,
+}
+
+impl Hello {
+    pub fn local() -> Self {
+        Self {
+            versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            features: Features::local(),
+        }
+    }
+
+    /// Picks the highest protocol version both sides support, along with the
+    /// features both sides share. Returns `None` if there is no common version.
+    pub fn negotiate(&self, local: &Hello) -> Option<HelloAck> {
+        let version = self
+            .versions
+            .iter()
+            .filter(|v| local.versions.contains(v))
+            .max()
+            .copied()?;
+
+        Some(HelloAck {
+            version,
+            features: self.features.intersection(local.features),
+        })
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::local()
+    }
+}
+
+/// Sent in response to a `Hello` with the negotiated version and features.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct HelloAck {
+    pub version: u8,
+    pub features: Features,
+}
+
+impl Default for HelloAck {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            features: Features::local(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_negotiates_highest_common_version() {
+        let remote = Hello {
+            versions: vec![1, 2, 3],
+            features: Features::STUN | Features::TUNNEL,
+        };
+        let local = Hello {
+            versions: vec![1, 2],
+            features: Features::STUN | Features::SEED,
+        };
+
+        let ack = remote.negotiate(&local).unwrap();
+        assert_eq!(ack.version, 2);
+        assert!(ack.features.contains(Features::STUN));
+        assert!(!ack.features.contains(Features::SEED));
+        assert!(!ack.features.contains(Features::TUNNEL));
+    }
+
+    #[test]
+    fn test_hello_rejects_disjoint_versions() {
+        let remote = Hello {
+            versions: vec![7],
+            features: Features::local(),
+        };
+        assert!(remote.negotiate(&Hello::local()).is_none());
+    }
+}
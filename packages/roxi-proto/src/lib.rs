@@ -1,12 +1,49 @@
+pub(crate) mod auth;
+pub(crate) mod candidate;
+pub mod codec;
 pub mod command;
+pub(crate) mod disconnect;
+pub(crate) mod enroll;
 pub(crate) mod error;
+pub(crate) mod gateway;
+pub(crate) mod hello;
 pub(crate) mod message;
+pub(crate) mod punch;
+pub(crate) mod relay;
+pub(crate) mod resume;
+pub(crate) mod revoke;
+pub(crate) mod secure;
+pub mod stun;
 pub(crate) mod wireguard;
 
 pub type ProtoResult<T> = core::result::Result<T, error::ProtoError>;
 
+pub use auth::{
+    AuthenticationChallenge, AuthenticationRequest, Credentials, AUTH_NONCE_LEN,
+};
+pub use candidate::{Candidate, CandidateKind};
+pub use codec::{
+    MessageCodec, MessageFramed, MessageSink, MessageStream, DEFAULT_MAX_FRAME_SIZE,
+};
+pub use disconnect::DisconnectReason;
+pub use enroll::{Enrollment, EnrollmentRequest};
 pub use error::ProtoError;
-pub use message::{Message, MessageKind, MessageStatus};
+pub use gateway::{
+    GatewayInfo, GatewayLoad, GatewayReady, GatewayStatus, PeerTunnelClosed,
+};
+pub use hello::{Features, Hello, HelloAck};
+pub use message::{
+    Message, MessageKind, MessageStatus, MAGIC, PROTOCOL_VERSION,
+    SUPPORTED_PROTOCOL_VERSIONS,
+};
+pub use punch::{
+    PunchInfo, PunchProbe, PunchProbeKind, PunchRequest, PUNCH_MAGIC, PUNCH_TOKEN_LEN,
+};
+pub use relay::{RelayInfo, RelayRequest};
+pub use resume::{ResumeRequest, SessionTicket};
+pub use revoke::{KeyRotationRequest, PeerRevocation, RevokeRequest};
+pub use secure::{SecureCodec, SecureSession};
+pub use stun::{StunAttribute, StunClass, StunMessage, StunMethod, STUN_MAGIC_COOKIE};
 pub use wireguard::{
     WireGuardProtoConfig, WireGuardProtoConfigBuilder, WireGuardProtoKey,
     WireGuardProtoKeyPair, WireGuardProtoPeer,
@@ -1,10 +1,38 @@
 use crate::{error::ProtoError, ProtoResult};
+use roxi_lib::types::Address;
 use serde::{Deserialize, Serialize};
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use strum::{AsRefStr, Display};
 
+/// Magic bytes prefixing every message on the wire.
+pub const MAGIC: [u8; 2] = *b"RX";
+
+/// Protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Protocol versions this build can decode and respond to, oldest first.
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u8] = &[1, 2, 3];
+
+/// Version 1 header: magic (2), version (1), kind (2), status (2), IPv4 sender
+/// address (6) and payload length (8).
+const V1_HEADER_LEN: usize = 21;
+
+/// Version 2+ header prefix: magic (2), version (1), header length (1), kind (2),
+/// status (2) and payload length (8). It is followed by the variable-length
+/// sender address and any fields added by later versions, which older decoders
+/// skip using the header length.
+const V2_HEADER_PREFIX_LEN: usize = 16;
+
+/// Version 3 appends the request ID (4) to the version 2 header.
+const V3_REQUEST_ID_LEN: usize = 4;
+
+/// Number of bytes needed to determine the length of any header.
+pub(crate) const MIN_HEADER_LEN: usize = 4;
+
 #[repr(u16)]
-#[derive(Debug, AsRefStr, Display, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, Copy, AsRefStr, Display, Eq, PartialEq, Serialize, Deserialize,
+)]
 pub enum MessageStatus {
     Pending = 0,
     r#Ok = 200,
@@ -14,6 +42,7 @@ pub enum MessageStatus {
     NotFound = 404,
     BadData = 405,
     ImATeapot = 419,
+    UpgradeRequired = 426,
     InternalServerError = 500,
     ServiceUnavailable = 503,
     Unknown,
@@ -30,14 +59,18 @@ impl From<u16> for MessageStatus {
             404 => MessageStatus::NotFound,
             405 => MessageStatus::BadData,
             419 => MessageStatus::ImATeapot,
+            426 => MessageStatus::UpgradeRequired,
             500 => MessageStatus::InternalServerError,
+            503 => MessageStatus::ServiceUnavailable,
             _ => MessageStatus::Unknown,
         }
     }
 }
 
 #[repr(u16)]
-#[derive(Debug, AsRefStr, Display, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, Copy, AsRefStr, Display, Eq, PartialEq, Hash, Serialize, Deserialize,
+)]
 pub enum MessageKind {
     Ping = 0,
     Pong = 1,
@@ -62,6 +95,31 @@ pub enum MessageKind {
     SeedResponse = 20,
     ServerShutdown = 21,
     PeerTunnelClose = 22,
+    Hello = 23,
+    HelloAck = 24,
+    RelayRequest = 25,
+    RelayResponse = 26,
+    AuthenticationChallengeRequest = 27,
+    AuthenticationChallengeResponse = 28,
+    EnrollRequest = 29,
+    EnrollResponse = 30,
+    RevokeRequest = 31,
+    RevokeResponse = 32,
+    KeyRotationRequest = 33,
+    KeyRotationResponse = 34,
+    PeerRevokeRequest = 35,
+    PeerRevokeResponse = 36,
+    ResumeRequest = 37,
+    ResumeResponse = 38,
+    GatewayReadyRequest = 39,
+    GatewayReadyResponse = 40,
+    GatewayLoadRequest = 41,
+    GatewayLoadResponse = 42,
+    GatewayStatusRequest = 43,
+    GatewayStatusResponse = 44,
+    PeerTunnelCloseResponse = 45,
+    PeerTunnelClosedRequest = 46,
+    PeerTunnelClosedResponse = 47,
     Unknown,
 }
 
@@ -91,16 +149,45 @@ impl From<u16> for MessageKind {
             20 => MessageKind::SeedResponse,
             21 => MessageKind::ServerShutdown,
             22 => MessageKind::PeerTunnelClose,
+            23 => MessageKind::Hello,
+            24 => MessageKind::HelloAck,
+            25 => MessageKind::RelayRequest,
+            26 => MessageKind::RelayResponse,
+            27 => MessageKind::AuthenticationChallengeRequest,
+            28 => MessageKind::AuthenticationChallengeResponse,
+            29 => MessageKind::EnrollRequest,
+            30 => MessageKind::EnrollResponse,
+            31 => MessageKind::RevokeRequest,
+            32 => MessageKind::RevokeResponse,
+            33 => MessageKind::KeyRotationRequest,
+            34 => MessageKind::KeyRotationResponse,
+            35 => MessageKind::PeerRevokeRequest,
+            36 => MessageKind::PeerRevokeResponse,
+            37 => MessageKind::ResumeRequest,
+            38 => MessageKind::ResumeResponse,
+            39 => MessageKind::GatewayReadyRequest,
+            40 => MessageKind::GatewayReadyResponse,
+            41 => MessageKind::GatewayLoadRequest,
+            42 => MessageKind::GatewayLoadResponse,
+            43 => MessageKind::GatewayStatusRequest,
+            44 => MessageKind::GatewayStatusResponse,
+            45 => MessageKind::PeerTunnelCloseResponse,
+            46 => MessageKind::PeerTunnelClosedRequest,
+            47 => MessageKind::PeerTunnelClosedResponse,
             _ => MessageKind::Unknown,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    version: u8,
+    /// Correlates a response with its request. Zero for messages that answer
+    /// nothing, and in headers older than version 3.
+    id: u32,
     kind: MessageKind,
     status: MessageStatus,
-    sender_addr: [u8; 6],
+    sender_addr: Address,
     data: Option<Vec<u8>>,
 }
 
@@ -111,8 +198,10 @@ impl Message {
         addr: String,
         data: Option<Vec<u8>>,
     ) -> Self {
-        let sender_addr = Message::pack_addr(addr);
+        let sender_addr = Address::try_from(addr).expect("Invalid sender address");
         Self {
+            version: PROTOCOL_VERSION,
+            id: 0,
             kind,
             status,
             sender_addr,
@@ -120,27 +209,26 @@ impl Message {
         }
     }
 
-    fn pack_addr(hostname: String) -> [u8; 6] {
-        let mut parts = hostname.split(':');
-        let ip: Ipv4Addr = parts
-            .next()
-            .expect("Invalid hostname ip")
-            .parse()
-            .expect("Bad address ip");
-        let ip = ip.octets();
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
 
-        let port = parts
-            .next()
-            .expect("Invalid hostname port")
-            .parse::<u16>()
-            .expect("Bad address port")
-            .to_be_bytes();
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = id;
+        self
+    }
 
-        let mut buff = [0u8; 6];
-        buff[..4].copy_from_slice(&ip);
-        buff[4..].copy_from_slice(&port);
+    pub fn id(&self) -> u32 {
+        self.id
+    }
 
-        buff
+    pub fn is_supported_version(&self) -> bool {
+        SUPPORTED_PROTOCOL_VERSIONS.contains(&self.version)
     }
 
     pub fn into_inner(&mut self) -> Option<Vec<u8>> {
@@ -155,61 +243,164 @@ impl Message {
         &self.status
     }
 
-    pub fn sender_addr(&self) -> [u8; 6] {
-        self.sender_addr
+    pub fn sender_addr(&self) -> &Address {
+        &self.sender_addr
     }
 
     pub fn data(&self) -> Vec<u8> {
         self.data.clone().unwrap_or_default()
     }
 
+    pub(crate) fn data_len(&self) -> usize {
+        self.data.as_ref().map(|d| d.len()).unwrap_or_default()
+    }
+
     pub fn serialize(self) -> ProtoResult<Vec<u8>> {
         let mut result = Vec::new();
         let data = self.data.unwrap_or_default();
-        result.extend(&(self.kind as u16).to_be_bytes());
-        result.extend(&(self.status as u16).to_be_bytes());
-        result.extend(&self.sender_addr);
-        result.extend(&(data.len().to_be_bytes()));
+        result.extend(&MAGIC);
+        result.push(self.version);
+
+        if self.version == 1 {
+            let ip = match self.sender_addr.ip() {
+                IpAddr::V4(ip) => ip,
+                IpAddr::V6(_) => {
+                    return Err(ProtoError::UnsupportedAddress(self.version))
+                }
+            };
+            result.extend(&(self.kind as u16).to_be_bytes());
+            result.extend(&(self.status as u16).to_be_bytes());
+            result.extend(ip.octets());
+            result.extend(self.sender_addr.port().to_be_bytes());
+            result.extend(&(data.len() as u64).to_be_bytes());
+        } else {
+            let addr = self.sender_addr.to_vec();
+            let id_len = match self.version {
+                2 => 0,
+                _ => V3_REQUEST_ID_LEN,
+            };
+            let header_len = u8::try_from(V2_HEADER_PREFIX_LEN + addr.len() + id_len)
+                .map_err(|_| ProtoError::MalformedMessage)?;
+            result.push(header_len);
+            result.extend(&(self.kind as u16).to_be_bytes());
+            result.extend(&(self.status as u16).to_be_bytes());
+            result.extend(&(data.len() as u64).to_be_bytes());
+            result.extend(addr);
+            if id_len > 0 {
+                result.extend(&self.id.to_be_bytes());
+            }
+        }
+
         result.extend(&data);
 
         Ok(result)
     }
 
+    /// Returns the length of the header at the start of `buf`, or `None` if not
+    /// enough bytes have been read to tell yet.
+    pub(crate) fn header_len(buf: &[u8]) -> ProtoResult<Option<usize>> {
+        if buf.len() < MIN_HEADER_LEN {
+            return Ok(None);
+        }
+
+        if buf[..2] != MAGIC {
+            return Err(ProtoError::BadMagic);
+        }
+
+        match buf[2] {
+            1 => Ok(Some(V1_HEADER_LEN)),
+            _ => {
+                let n = buf[3] as usize;
+                if n < V2_HEADER_PREFIX_LEN {
+                    return Err(ProtoError::MalformedMessage);
+                }
+                Ok(Some(n))
+            }
+        }
+    }
+
+    /// Reads the payload length from a serialized message header.
+    pub(crate) fn payload_len(header: &[u8]) -> ProtoResult<usize> {
+        let header_len =
+            Message::header_len(header)?.ok_or(ProtoError::MalformedMessage)?;
+        if header.len() < header_len {
+            return Err(ProtoError::MalformedMessage);
+        }
+
+        let offset = match header[2] {
+            1 => 13,
+            _ => 8,
+        };
+
+        let mut sizebuff = [0u8; 8];
+        sizebuff.copy_from_slice(&header[offset..offset + 8]);
+        usize::try_from(u64::from_be_bytes(sizebuff))
+            .map_err(|_| ProtoError::MalformedMessage)
+    }
+
     pub fn deserialize(data: &[u8]) -> ProtoResult<Self> {
-        if data.len() < 10 {
+        let header_len =
+            Message::header_len(data)?.ok_or(ProtoError::MalformedMessage)?;
+        let n = Message::payload_len(data)?;
+        if data.len() < header_len + n {
             return Err(ProtoError::MalformedMessage);
         }
 
+        let version = data[2];
+        let (kind_offset, status_offset) = match version {
+            1 => (3, 5),
+            _ => (4, 6),
+        };
+
         let mut kindbuff = [0u8; 2];
-        kindbuff.copy_from_slice(&data[..2]);
+        kindbuff.copy_from_slice(&data[kind_offset..kind_offset + 2]);
         let kind: MessageKind = u16::from_be_bytes(kindbuff).into();
 
         let mut statusbuff = [0u8; 2];
-        statusbuff.copy_from_slice(&data[2..4]);
+        statusbuff.copy_from_slice(&data[status_offset..status_offset + 2]);
         let status: MessageStatus = u16::from_be_bytes(statusbuff).into();
 
-        let mut addrbuff = [0u8; 6];
-        addrbuff.copy_from_slice(&data[4..10]);
-
-        let mut sizebuff = [0u8; 8];
-        sizebuff.copy_from_slice(&data[10..18]);
-
-        let payload = match kind {
-            MessageKind::Ping
-            | MessageKind::Pong
-            | MessageKind::StunInfoRequest
-            | MessageKind::AuthenticationResponse => None,
+        let (sender_addr, id) = match version {
+            1 => {
+                let mut ip = [0u8; 4];
+                ip.copy_from_slice(&data[7..11]);
+                let port = u16::from_be_bytes([data[11], data[12]]);
+                (Address::new(IpAddr::from(ip), port), 0)
+            }
             _ => {
-                let n = usize::from_be_bytes(sizebuff);
-                let payload = data[18..18 + n].to_vec();
-                Some(payload)
+                let (addr, len) =
+                    Address::decode(&data[V2_HEADER_PREFIX_LEN..header_len])
+                        .map_err(|_| ProtoError::MalformedMessage)?;
+                let end = V2_HEADER_PREFIX_LEN + len;
+                if end > header_len {
+                    return Err(ProtoError::MalformedMessage);
+                }
+                let id = match version {
+                    2 => 0,
+                    _ => {
+                        if end + V3_REQUEST_ID_LEN > header_len {
+                            return Err(ProtoError::MalformedMessage);
+                        }
+                        let mut idbuff = [0u8; V3_REQUEST_ID_LEN];
+                        idbuff.copy_from_slice(&data[end..end + V3_REQUEST_ID_LEN]);
+                        u32::from_be_bytes(idbuff)
+                    }
+                };
+                (addr, id)
             }
         };
 
+        let payload = match kind {
+            MessageKind::Ping | MessageKind::Pong | MessageKind::StunInfoRequest => None,
+            _ => Some(data[header_len..header_len + n].to_vec()),
+        };
+
         Ok(Self {
+            version,
+            id,
             kind,
             status,
-            sender_addr: addrbuff,
+            sender_addr,
             data: payload,
         })
     }
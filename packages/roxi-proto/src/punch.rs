@@ -0,0 +1,97 @@
+use crate::candidate::Candidate;
+use roxi_lib::types::ClientId;
+use serde::{Deserialize, Serialize};
+
+/// Prefix on every UDP probe so stray datagrams (e.g. STUN) are ignored.
+pub const PUNCH_MAGIC: &[u8; 4] = b"RXPH";
+
+pub const PUNCH_TOKEN_LEN: usize = 16;
+
+const PUNCH_PROBE_LEN: usize = PUNCH_MAGIC.len() + 1 + PUNCH_TOKEN_LEN;
+
+/// Sent to the server to ask for a coordinated hole punch with `peer`, offering
+/// the candidates this side gathered.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct PunchRequest {
+    pub peer: ClientId,
+    pub candidates: Vec<Candidate>,
+}
+
+/// Relayed by the server to both sides of a hole punch: who the other side is,
+/// the candidates it offered, and a token both sides stamp on their probes.
+///
+/// The side that receives the offer in a `NATPunchRequest` answers with its own
+/// candidates in a `NATPunchResponse`, which the server forwards to the other.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct PunchInfo {
+    pub peer: ClientId,
+    pub candidates: Vec<Candidate>,
+    pub token: [u8; PUNCH_TOKEN_LEN],
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PunchProbeKind {
+    Probe = 0,
+    Ack = 1,
+}
+
+/// A UDP datagram exchanged directly between two peers while punching.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PunchProbe {
+    pub kind: PunchProbeKind,
+    pub token: [u8; PUNCH_TOKEN_LEN],
+}
+
+impl PunchProbe {
+    pub fn new(kind: PunchProbeKind, token: [u8; PUNCH_TOKEN_LEN]) -> Self {
+        Self { kind, token }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(PUNCH_PROBE_LEN);
+        result.extend(PUNCH_MAGIC);
+        result.push(self.kind as u8);
+        result.extend(self.token);
+        result
+    }
+
+    /// Parses a probe, returning `None` for anything that is not one.
+    pub fn from_slice(buf: &[u8]) -> Option<Self> {
+        if buf.len() != PUNCH_PROBE_LEN || &buf[..PUNCH_MAGIC.len()] != PUNCH_MAGIC {
+            return None;
+        }
+
+        let kind = match buf[PUNCH_MAGIC.len()] {
+            0 => PunchProbeKind::Probe,
+            1 => PunchProbeKind::Ack,
+            _ => return None,
+        };
+
+        let mut token = [0u8; PUNCH_TOKEN_LEN];
+        token.copy_from_slice(&buf[PUNCH_MAGIC.len() + 1..]);
+        Some(Self { kind, token })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_punch_probe_roundtrip() {
+        let probe = PunchProbe::new(PunchProbeKind::Ack, [9u8; PUNCH_TOKEN_LEN]);
+        assert_eq!(PunchProbe::from_slice(&probe.to_vec()), Some(probe));
+    }
+
+    #[test]
+    fn test_punch_probe_ignores_foreign_datagrams() {
+        let mut probe = PunchProbe::new(PunchProbeKind::Probe, [1u8; 16]).to_vec();
+        assert!(PunchProbe::from_slice(&probe[1..]).is_none());
+
+        probe[PUNCH_MAGIC.len()] = 7;
+        assert!(PunchProbe::from_slice(&probe).is_none());
+
+        probe[0] = b'X';
+        assert!(PunchProbe::from_slice(&probe).is_none());
+    }
+}
@@ -0,0 +1,17 @@
+use roxi_lib::types::{Address, ClientId};
+use serde::{Deserialize, Serialize};
+
+/// Sent to the server to ask for a relayed tunnel with `peer`, typically after
+/// a hole punch has failed.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct RelayRequest {
+    pub peer: ClientId,
+}
+
+/// Sent by the server to both sides of a relayed tunnel. `endpoint` is the relay
+/// address each side should use as its WireGuard `Endpoint`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct RelayInfo {
+    pub peer: ClientId,
+    pub endpoint: Address,
+}
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Payload of a successful `AuthenticationResponse` or `ResumeResponse`: an
+/// opaque token the client can present in a `ResumeRequest` on a later
+/// connection to pick its session back up without authenticating again.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SessionTicket {
+    pub token: Vec<u8>,
+    /// Unix seconds after which the server no longer honors the token.
+    pub expires_at: u64,
+}
+
+impl SessionTicket {
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        now >= self.expires_at
+    }
+}
+
+/// Payload of a `ResumeRequest`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ResumeRequest {
+    pub token: Vec<u8>,
+}
@@ -0,0 +1,75 @@
+use crate::{ProtoError, ProtoResult};
+use roxi_crypto::IdentityKeyPair;
+use roxi_lib::types::ClientId;
+use serde::{Deserialize, Serialize};
+
+/// Domain separation for the bytes a key rotation signature covers.
+const ROTATE_CONTEXT_LABEL: &[u8] = b"roxi-rotate-v1";
+
+/// Payload of a `RevokeRequest`, which only the holder of the server's control
+/// channel key may send.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct RevokeRequest {
+    /// Label of the enrolled client to revoke.
+    pub label: String,
+}
+
+/// Payload of a `PeerRevokeRequest`, sent by the server to gateways so they
+/// drop a revoked client's WireGuard peer.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct PeerRevocation {
+    pub peer: ClientId,
+    pub wireguard_public_key: String,
+}
+
+/// Payload of a `KeyRotationRequest`: an authenticated client replacing its
+/// identity key, signed with the new key over both keys.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct KeyRotationRequest {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl KeyRotationRequest {
+    pub fn new(old_public_key: &[u8], identity: &IdentityKeyPair) -> Self {
+        Self {
+            public_key: identity.public().to_vec(),
+            signature: identity.sign(&Self::context(old_public_key, identity.public())),
+        }
+    }
+
+    /// Checks that the new key signed off on replacing `old_public_key`.
+    pub fn verify(&self, old_public_key: &[u8]) -> ProtoResult<()> {
+        roxi_crypto::verify_ed25519(
+            &self.public_key,
+            &Self::context(old_public_key, &self.public_key),
+            &self.signature,
+        )
+        .map_err(|_| ProtoError::InvalidCredentials)
+    }
+
+    fn context(old_public_key: &[u8], new_public_key: &[u8]) -> Vec<u8> {
+        [ROTATE_CONTEXT_LABEL, old_public_key, new_public_key].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_rotation_request_binds_both_keys() {
+        let old = IdentityKeyPair::generate().unwrap();
+        let new = IdentityKeyPair::generate().unwrap();
+        let request = KeyRotationRequest::new(old.public(), &new);
+        assert!(request.verify(old.public()).is_ok());
+
+        // Not replayable against another client's key
+        let other = IdentityKeyPair::generate().unwrap();
+        assert!(request.verify(other.public()).is_err());
+
+        let mut swapped = request;
+        swapped.public_key = other.public().to_vec();
+        assert!(swapped.verify(old.public()).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::{codec::MessageCodec, message::MAGIC, Message, ProtoError, ProtoResult};
+use async_std::sync::Arc;
+use bytes::{Buf, BufMut, BytesMut};
+use roxi_crypto::{
+    NoiseHandshake, NoiseKeyPair, NoiseTransport, NOISE_MAX_MESSAGE_LEN, NOISE_TAG_LEN,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+const NOISE_LEN_PREFIX: usize = 2;
+
+const NOISE_MAX_PLAINTEXT_LEN: usize = NOISE_MAX_MESSAGE_LEN - NOISE_TAG_LEN;
+
+/// Binds the handshake to the roxi control protocol.
+const NOISE_PROLOGUE: &[u8] = b"roxi-control";
+
+/// A control connection that completed a Noise XX handshake.
+///
+/// The handshake runs on the raw stream before any `Message` is exchanged, with
+/// each handshake message prefixed by its length as a big-endian `u16`.
+pub struct SecureSession {
+    transport: NoiseTransport,
+    remote_static: Vec<u8>,
+}
+
+impl SecureSession {
+    /// Runs the initiator side of the handshake. If `pinned` is given, the
+    /// responder must prove it holds the matching private key.
+    pub async fn initiate<T>(
+        io: &mut T,
+        keys: &NoiseKeyPair,
+        pinned: Option<&[u8]>,
+    ) -> ProtoResult<Self>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut handshake = NoiseHandshake::initiator(keys, &prologue())?;
+
+        // -> e
+        write_handshake(io, &mut handshake).await?;
+        // <- e, ee, s, es
+        read_handshake(io, &mut handshake).await?;
+
+        let remote_static = handshake
+            .remote_static()
+            .ok_or(ProtoError::MalformedMessage)?
+            .to_vec();
+        if let Some(pinned) = pinned {
+            if pinned != remote_static.as_slice() {
+                return Err(ProtoError::UntrustedPeerKey);
+            }
+        }
+
+        // -> s, se
+        write_handshake(io, &mut handshake).await?;
+
+        Self::finish(handshake, remote_static)
+    }
+
+    /// Runs the responder side of the handshake.
+    pub async fn accept<T>(io: &mut T, keys: &NoiseKeyPair) -> ProtoResult<Self>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut handshake = NoiseHandshake::responder(keys, &prologue())?;
+
+        // -> e
+        read_handshake(io, &mut handshake).await?;
+        // <- e, ee, s, es
+        write_handshake(io, &mut handshake).await?;
+        // -> s, se
+        read_handshake(io, &mut handshake).await?;
+
+        let remote_static = handshake
+            .remote_static()
+            .ok_or(ProtoError::MalformedMessage)?
+            .to_vec();
+
+        Self::finish(handshake, remote_static)
+    }
+
+    fn finish(handshake: NoiseHandshake, remote_static: Vec<u8>) -> ProtoResult<Self> {
+        if !handshake.is_finished() {
+            return Err(ProtoError::MalformedMessage);
+        }
+
+        Ok(Self {
+            transport: handshake.into_transport()?,
+            remote_static,
+        })
+    }
+
+    /// The peer's static public key.
+    pub fn remote_static(&self) -> &[u8] {
+        &self.remote_static
+    }
+
+    pub fn codec(self, max_frame_size: usize) -> SecureCodec {
+        SecureCodec {
+            inner: MessageCodec::new(max_frame_size),
+            transport: Arc::new(self.transport),
+            send_nonce: 0,
+            recv_nonce: 0,
+            plaintext: BytesMut::new(),
+        }
+    }
+}
+
+fn prologue() -> Vec<u8> {
+    [NOISE_PROLOGUE, &MAGIC].concat()
+}
+
+async fn write_handshake<T>(io: &mut T, handshake: &mut NoiseHandshake) -> ProtoResult<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE_LEN];
+    let len = handshake.write_message(&[], &mut buf)?;
+    io.write_u16(len as u16).await?;
+    io.write_all(&buf[..len]).await?;
+    io.flush().await?;
+    Ok(())
+}
+
+async fn read_handshake<T>(io: &mut T, handshake: &mut NoiseHandshake) -> ProtoResult<()>
+where
+    T: AsyncRead + Unpin,
+{
+    let len = io.read_u16().await? as usize;
+    let mut msg = vec![0u8; len];
+    io.read_exact(&mut msg).await?;
+
+    let mut payload = vec![0u8; NOISE_MAX_MESSAGE_LEN];
+    handshake.read_message(&msg, &mut payload)?;
+    Ok(())
+}
+
+/// `MessageCodec` wrapped in the transport keys of a `SecureSession`.
+///
+/// Encoded messages are split into Noise messages of at most 64 KiB, each
+/// prefixed by its length. Clones share the keys but keep their own nonces, so
+/// the read and write halves of a split stream each take a clone.
+#[derive(Clone)]
+pub struct SecureCodec {
+    inner: MessageCodec,
+    transport: Arc<NoiseTransport>,
+    send_nonce: u64,
+    recv_nonce: u64,
+    plaintext: BytesMut,
+}
+
+impl SecureCodec {
+    pub fn version(&self) -> u8 {
+        self.inner.version()
+    }
+
+    pub fn set_version(&mut self, version: u8) {
+        self.inner.set_version(version);
+    }
+}
+
+impl std::fmt::Debug for SecureCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SecureCodec")
+            .field("inner", &self.inner)
+            .field("send_nonce", &self.send_nonce)
+            .field("recv_nonce", &self.recv_nonce)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Decoder for SecureCodec {
+    type Item = Message;
+    type Error = ProtoError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> ProtoResult<Option<Message>> {
+        while src.len() >= NOISE_LEN_PREFIX {
+            let len = u16::from_be_bytes([src[0], src[1]]) as usize;
+            if src.len() < NOISE_LEN_PREFIX + len {
+                src.reserve(NOISE_LEN_PREFIX + len - src.len());
+                break;
+            }
+
+            src.advance(NOISE_LEN_PREFIX);
+            let cipher = src.split_to(len);
+            let mut plain = vec![0u8; len];
+            let n = self
+                .transport
+                .decrypt(self.recv_nonce, &cipher, &mut plain)?;
+            self.recv_nonce += 1;
+            self.plaintext.extend_from_slice(&plain[..n]);
+        }
+
+        self.inner.decode(&mut self.plaintext)
+    }
+}
+
+impl Encoder<Message> for SecureCodec {
+    type Error = ProtoError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> ProtoResult<()> {
+        let mut plain = BytesMut::new();
+        self.inner.encode(msg, &mut plain)?;
+
+        let mut cipher = vec![0u8; NOISE_MAX_MESSAGE_LEN];
+        for chunk in plain.chunks(NOISE_MAX_PLAINTEXT_LEN) {
+            let n = self
+                .transport
+                .encrypt(self.send_nonce, chunk, &mut cipher)?;
+            self.send_nonce += 1;
+            dst.put_u16(n as u16);
+            dst.extend_from_slice(&cipher[..n]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageKind, MessageStatus, DEFAULT_MAX_FRAME_SIZE};
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    fn message(data: Vec<u8>) -> Message {
+        Message::new(
+            MessageKind::SeedRequest,
+            MessageStatus::Pending,
+            "127.0.0.1:8080".to_string(),
+            Some(data),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_secure_session_roundtrips_messages() {
+        let server_keys = NoiseKeyPair::generate().unwrap();
+        let client_keys = NoiseKeyPair::generate().unwrap();
+        let (mut a, mut b) = tokio::io::duplex(1024);
+
+        let pinned = server_keys.public().to_vec();
+        let (client, server) = tokio::join!(
+            SecureSession::initiate(&mut a, &client_keys, Some(&pinned)),
+            SecureSession::accept(&mut b, &server_keys),
+        );
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(client.remote_static(), server_keys.public());
+        assert_eq!(server.remote_static(), client_keys.public());
+
+        let mut client = Framed::new(a, client.codec(DEFAULT_MAX_FRAME_SIZE));
+        let mut server = Framed::new(b, server.codec(DEFAULT_MAX_FRAME_SIZE));
+
+        // Large enough to span several Noise messages
+        let big = vec![7u8; 3 * NOISE_MAX_PLAINTEXT_LEN];
+        let (sent, received) =
+            tokio::join!(client.send(message(big.clone())), server.next());
+        sent.unwrap();
+        assert_eq!(received.unwrap().unwrap().data(), big);
+
+        server.send(message(b"pong".to_vec())).await.unwrap();
+        server.send(message(b"again".to_vec())).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap().data(),
+            b"pong".to_vec()
+        );
+        assert_eq!(
+            client.next().await.unwrap().unwrap().data(),
+            b"again".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_secure_session_rejects_unpinned_server_key() {
+        let server_keys = NoiseKeyPair::generate().unwrap();
+        let client_keys = NoiseKeyPair::generate().unwrap();
+        let other = NoiseKeyPair::generate().unwrap();
+        let (mut a, mut b) = tokio::io::duplex(1024);
+
+        let pinned = other.public().to_vec();
+        let (client, server) = tokio::join!(
+            async {
+                let result =
+                    SecureSession::initiate(&mut a, &client_keys, Some(&pinned)).await;
+                drop(a);
+                result
+            },
+            SecureSession::accept(&mut b, &server_keys),
+        );
+        assert!(matches!(client, Err(ProtoError::UntrustedPeerKey)));
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_secure_codec_rejects_tampered_frames() {
+        let server_keys = NoiseKeyPair::generate().unwrap();
+        let client_keys = NoiseKeyPair::generate().unwrap();
+        let (mut a, mut b) = tokio::io::duplex(1024);
+
+        let (client, server) = tokio::join!(
+            SecureSession::initiate(&mut a, &client_keys, None),
+            SecureSession::accept(&mut b, &server_keys),
+        );
+        let mut client = client.unwrap().codec(DEFAULT_MAX_FRAME_SIZE);
+        let mut server = server.unwrap().codec(DEFAULT_MAX_FRAME_SIZE);
+
+        let mut frame = BytesMut::new();
+        client
+            .encode(message(b"seed".to_vec()), &mut frame)
+            .unwrap();
+        let last = frame.len() - 1;
+        frame[last] ^= 1;
+        assert!(server.decode(&mut frame).is_err());
+    }
+}
@@ -0,0 +1,504 @@
+//! Minimal RFC 5389 STUN message encoding and decoding.
+//!
+//! Only the pieces needed for Binding requests and responses are implemented:
+//! MAPPED-ADDRESS, XOR-MAPPED-ADDRESS, ERROR-CODE, UNKNOWN-ATTRIBUTES, SOFTWARE
+//! and FINGERPRINT. Any other attribute is preserved as `StunAttribute::Unknown`.
+use crate::{ProtoError, ProtoResult};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const STUN_MAGIC_COOKIE: u32 = 0x2112A442;
+
+const STUN_HEADER_LEN: usize = 20;
+const STUN_FINGERPRINT_XOR: u32 = 0x5354554e;
+const STUN_METHOD_BINDING: u16 = 0x0001;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_SOFTWARE: u16 = 0x8022;
+const ATTR_FINGERPRINT: u16 = 0x8028;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+pub const STUN_SOFTWARE: &str = concat!("roxi ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StunClass {
+    Request,
+    Indication,
+    SuccessResponse,
+    ErrorResponse,
+}
+
+impl StunClass {
+    fn bits(&self) -> u16 {
+        match self {
+            StunClass::Request => 0x0000,
+            StunClass::Indication => 0x0010,
+            StunClass::SuccessResponse => 0x0100,
+            StunClass::ErrorResponse => 0x0110,
+        }
+    }
+
+    fn from_type(t: u16) -> Self {
+        match t & 0x0110 {
+            0x0000 => StunClass::Request,
+            0x0010 => StunClass::Indication,
+            0x0100 => StunClass::SuccessResponse,
+            _ => StunClass::ErrorResponse,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StunMethod {
+    Binding,
+    Other(u16),
+}
+
+impl StunMethod {
+    fn value(&self) -> u16 {
+        match self {
+            StunMethod::Binding => STUN_METHOD_BINDING,
+            StunMethod::Other(m) => *m,
+        }
+    }
+
+    fn bits(&self) -> u16 {
+        let m = self.value();
+        (m & 0x000F) | ((m & 0x0070) << 1) | ((m & 0x0F80) << 2)
+    }
+
+    fn from_type(t: u16) -> Self {
+        let m = (t & 0x000F) | ((t & 0x00E0) >> 1) | ((t & 0x3E00) >> 2);
+        match m {
+            STUN_METHOD_BINDING => StunMethod::Binding,
+            m => StunMethod::Other(m),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StunAttribute {
+    MappedAddress(SocketAddr),
+    XorMappedAddress(SocketAddr),
+    ErrorCode { code: u16, reason: String },
+    UnknownAttributes(Vec<u16>),
+    Software(String),
+    Fingerprint(u32),
+    Unknown { kind: u16, value: Vec<u8> },
+}
+
+impl StunAttribute {
+    /// Attributes below 0x8000 must be understood by the receiver.
+    pub fn is_comprehension_required(&self) -> bool {
+        matches!(self, StunAttribute::Unknown { kind, .. } if *kind < 0x8000)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StunMessage {
+    class: StunClass,
+    method: StunMethod,
+    transaction_id: [u8; 12],
+    attributes: Vec<StunAttribute>,
+}
+
+impl StunMessage {
+    pub fn new(class: StunClass, method: StunMethod, transaction_id: [u8; 12]) -> Self {
+        Self {
+            class,
+            method,
+            transaction_id,
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn binding_request() -> Self {
+        Self::new(
+            StunClass::Request,
+            StunMethod::Binding,
+            rand::random::<[u8; 12]>(),
+        )
+    }
+
+    /// Builds a Binding success response reporting `addr` as the reflexive address.
+    pub fn binding_success(transaction_id: [u8; 12], addr: SocketAddr) -> Self {
+        Self::new(
+            StunClass::SuccessResponse,
+            StunMethod::Binding,
+            transaction_id,
+        )
+        .with_attribute(StunAttribute::XorMappedAddress(addr))
+        .with_attribute(StunAttribute::MappedAddress(addr))
+        .with_attribute(StunAttribute::Software(STUN_SOFTWARE.to_string()))
+    }
+
+    pub fn error_response(
+        method: StunMethod,
+        transaction_id: [u8; 12],
+        code: u16,
+        reason: &str,
+    ) -> Self {
+        Self::new(StunClass::ErrorResponse, method, transaction_id).with_attribute(
+            StunAttribute::ErrorCode {
+                code,
+                reason: reason.to_string(),
+            },
+        )
+    }
+
+    pub fn with_attribute(mut self, attr: StunAttribute) -> Self {
+        self.attributes.push(attr);
+        self
+    }
+
+    pub fn class(&self) -> StunClass {
+        self.class
+    }
+
+    pub fn method(&self) -> StunMethod {
+        self.method
+    }
+
+    pub fn transaction_id(&self) -> &[u8; 12] {
+        &self.transaction_id
+    }
+
+    pub fn attributes(&self) -> &[StunAttribute] {
+        &self.attributes
+    }
+
+    /// The reflexive address, preferring XOR-MAPPED-ADDRESS over MAPPED-ADDRESS.
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        let xor = self.attributes.iter().find_map(|a| match a {
+            StunAttribute::XorMappedAddress(addr) => Some(*addr),
+            _ => None,
+        });
+        xor.or_else(|| {
+            self.attributes.iter().find_map(|a| match a {
+                StunAttribute::MappedAddress(addr) => Some(*addr),
+                _ => None,
+            })
+        })
+    }
+
+    pub fn error_code(&self) -> Option<(u16, &str)> {
+        self.attributes.iter().find_map(|a| match a {
+            StunAttribute::ErrorCode { code, reason } => Some((*code, reason.as_str())),
+            _ => None,
+        })
+    }
+
+    /// Comprehension-required attributes that this implementation does not know.
+    pub fn unknown_required_attributes(&self) -> Vec<u16> {
+        self.attributes
+            .iter()
+            .filter_map(|a| match a {
+                StunAttribute::Unknown { kind, .. } if a.is_comprehension_required() => {
+                    Some(*kind)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Quickly checks whether `buf` looks like a STUN message.
+    pub fn is_stun(buf: &[u8]) -> bool {
+        buf.len() >= STUN_HEADER_LEN
+            && buf[0] & 0xC0 == 0
+            && buf[4..8] == STUN_MAGIC_COOKIE.to_be_bytes()
+    }
+
+    /// Encodes the message, always appending a FINGERPRINT attribute.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for attr in &self.attributes {
+            if matches!(attr, StunAttribute::Fingerprint(_)) {
+                continue;
+            }
+            self.encode_attribute(attr, &mut body);
+        }
+
+        let msg_type = self.method.bits() | self.class.bits();
+        let mut result = Vec::with_capacity(STUN_HEADER_LEN + body.len() + 8);
+        result.extend(msg_type.to_be_bytes());
+        // Length includes the FINGERPRINT attribute appended below
+        result.extend(((body.len() + 8) as u16).to_be_bytes());
+        result.extend(STUN_MAGIC_COOKIE.to_be_bytes());
+        result.extend(self.transaction_id);
+        result.extend(body);
+
+        let crc = crc32fast::hash(&result) ^ STUN_FINGERPRINT_XOR;
+        result.extend(ATTR_FINGERPRINT.to_be_bytes());
+        result.extend(4u16.to_be_bytes());
+        result.extend(crc.to_be_bytes());
+
+        result
+    }
+
+    fn encode_attribute(&self, attr: &StunAttribute, out: &mut Vec<u8>) {
+        let (kind, value) = match attr {
+            StunAttribute::MappedAddress(addr) => {
+                (ATTR_MAPPED_ADDRESS, encode_address(addr.ip(), addr.port()))
+            }
+            StunAttribute::XorMappedAddress(addr) => {
+                let (ip, port) = xor_address(addr, &self.transaction_id);
+                (ATTR_XOR_MAPPED_ADDRESS, encode_address(ip, port))
+            }
+            StunAttribute::ErrorCode { code, reason } => {
+                let mut value = vec![0u8, 0u8, (code / 100) as u8, (code % 100) as u8];
+                value.extend(reason.as_bytes());
+                (ATTR_ERROR_CODE, value)
+            }
+            StunAttribute::UnknownAttributes(kinds) => (
+                ATTR_UNKNOWN_ATTRIBUTES,
+                kinds.iter().flat_map(|k| k.to_be_bytes()).collect(),
+            ),
+            StunAttribute::Software(s) => (ATTR_SOFTWARE, s.as_bytes().to_vec()),
+            StunAttribute::Fingerprint(crc) => {
+                (ATTR_FINGERPRINT, crc.to_be_bytes().to_vec())
+            }
+            StunAttribute::Unknown { kind, value } => (*kind, value.clone()),
+        };
+
+        out.extend(kind.to_be_bytes());
+        out.extend((value.len() as u16).to_be_bytes());
+        out.extend(&value);
+        out.resize(out.len() + padding(value.len()), 0);
+    }
+
+    pub fn decode(buf: &[u8]) -> ProtoResult<Self> {
+        if !Self::is_stun(buf) {
+            return Err(ProtoError::Stun("Not a STUN message"));
+        }
+
+        let msg_type = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if len % 4 != 0 || buf.len() < STUN_HEADER_LEN + len {
+            return Err(ProtoError::Stun("Bad message length"));
+        }
+
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&buf[8..STUN_HEADER_LEN]);
+
+        let mut msg = Self::new(
+            StunClass::from_type(msg_type),
+            StunMethod::from_type(msg_type),
+            transaction_id,
+        );
+
+        let end = STUN_HEADER_LEN + len;
+        let mut offset = STUN_HEADER_LEN;
+        while offset + 4 <= end {
+            let kind = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+            let size = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+            let start = offset + 4;
+            if start + size > end {
+                return Err(ProtoError::Stun("Truncated attribute"));
+            }
+            let value = &buf[start..start + size];
+
+            let attr = match kind {
+                ATTR_MAPPED_ADDRESS => {
+                    let (ip, port) = decode_address(value)?;
+                    StunAttribute::MappedAddress(SocketAddr::new(ip, port))
+                }
+                ATTR_XOR_MAPPED_ADDRESS => {
+                    let (ip, port) = decode_address(value)?;
+                    let (ip, port) =
+                        xor_address(&SocketAddr::new(ip, port), &transaction_id);
+                    StunAttribute::XorMappedAddress(SocketAddr::new(ip, port))
+                }
+                ATTR_ERROR_CODE => {
+                    if value.len() < 4 {
+                        return Err(ProtoError::Stun("Bad ERROR-CODE"));
+                    }
+                    let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+                    let reason = String::from_utf8_lossy(&value[4..]).to_string();
+                    StunAttribute::ErrorCode { code, reason }
+                }
+                ATTR_UNKNOWN_ATTRIBUTES => StunAttribute::UnknownAttributes(
+                    value
+                        .chunks_exact(2)
+                        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                        .collect(),
+                ),
+                ATTR_SOFTWARE => {
+                    StunAttribute::Software(String::from_utf8_lossy(value).to_string())
+                }
+                ATTR_FINGERPRINT => {
+                    if value.len() != 4 || start + size != end {
+                        return Err(ProtoError::Stun("FINGERPRINT must be last"));
+                    }
+                    let crc =
+                        u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+                    let expected = crc32fast::hash(&buf[..offset]) ^ STUN_FINGERPRINT_XOR;
+                    if crc != expected {
+                        return Err(ProtoError::Stun("FINGERPRINT mismatch"));
+                    }
+                    StunAttribute::Fingerprint(crc)
+                }
+                kind => StunAttribute::Unknown {
+                    kind,
+                    value: value.to_vec(),
+                },
+            };
+
+            msg.attributes.push(attr);
+            offset = start + size + padding(size);
+        }
+
+        Ok(msg)
+    }
+}
+
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn encode_address(ip: IpAddr, port: u16) -> Vec<u8> {
+    let mut value = vec![0u8];
+    match ip {
+        IpAddr::V4(ip) => {
+            value.push(FAMILY_IPV4);
+            value.extend(port.to_be_bytes());
+            value.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            value.push(FAMILY_IPV6);
+            value.extend(port.to_be_bytes());
+            value.extend(ip.octets());
+        }
+    }
+    value
+}
+
+fn decode_address(value: &[u8]) -> ProtoResult<(IpAddr, u16)> {
+    if value.len() < 4 {
+        return Err(ProtoError::Stun("Bad address attribute"));
+    }
+
+    let port = u16::from_be_bytes([value[2], value[3]]);
+    let ip = match (value[1], value.len()) {
+        (FAMILY_IPV4, 8) => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&value[4..8]);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (FAMILY_IPV6, 20) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&value[4..20]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(ProtoError::Stun("Bad address family")),
+    };
+
+    Ok((ip, port))
+}
+
+/// XORs an address with the magic cookie (and transaction ID for IPv6). The
+/// operation is its own inverse, so it is used for both encoding and decoding.
+fn xor_address(addr: &SocketAddr, transaction_id: &[u8; 12]) -> (IpAddr, u16) {
+    let cookie = STUN_MAGIC_COOKIE.to_be_bytes();
+    let port = addr.port() ^ (STUN_MAGIC_COOKIE >> 16) as u16;
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut octets = ip.octets();
+            for (o, c) in octets.iter_mut().zip(cookie.iter()) {
+                *o ^= c;
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            let key = cookie.iter().chain(transaction_id.iter());
+            for (o, k) in octets.iter_mut().zip(key) {
+                *o ^= k;
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+    };
+    (ip, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sample IPv4 response from RFC 5769, section 2.2.
+    const RFC5769_IPV4_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc,
+        0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65,
+        0x73, 0x74, 0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00,
+        0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14,
+        0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9, 0x2a,
+        0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d,
+        0x4c, 0x96,
+    ];
+
+    #[test]
+    fn test_stun_decodes_rfc5769_ipv4_response() {
+        let msg = StunMessage::decode(&RFC5769_IPV4_RESPONSE).unwrap();
+        assert_eq!(msg.class(), StunClass::SuccessResponse);
+        assert_eq!(msg.method(), StunMethod::Binding);
+        assert_eq!(
+            msg.mapped_address(),
+            Some("192.0.2.1:32853".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_stun_binding_roundtrip_ipv4_and_ipv6() {
+        for addr in ["203.0.113.7:40000", "[2001:db8::1234]:51820"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let request = StunMessage::binding_request();
+            let encoded = request.encode();
+            assert!(StunMessage::is_stun(&encoded));
+
+            let decoded = StunMessage::decode(&encoded).unwrap();
+            assert_eq!(decoded.class(), StunClass::Request);
+            assert_eq!(decoded.method(), StunMethod::Binding);
+
+            let response = StunMessage::binding_success(*decoded.transaction_id(), addr);
+            let decoded = StunMessage::decode(&response.encode()).unwrap();
+            assert_eq!(decoded.transaction_id(), request.transaction_id());
+            assert_eq!(decoded.class(), StunClass::SuccessResponse);
+            assert_eq!(decoded.mapped_address(), Some(addr));
+        }
+    }
+
+    #[test]
+    fn test_stun_error_response_roundtrip() {
+        let msg = StunMessage::error_response(
+            StunMethod::Binding,
+            [1u8; 12],
+            420,
+            "Unknown Attribute",
+        )
+        .with_attribute(StunAttribute::UnknownAttributes(vec![0x0042]));
+        let decoded = StunMessage::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded.class(), StunClass::ErrorResponse);
+        assert_eq!(decoded.error_code(), Some((420, "Unknown Attribute")));
+    }
+
+    #[test]
+    fn test_stun_rejects_corrupted_fingerprint() {
+        let mut encoded = StunMessage::binding_request().encode();
+        let last = encoded.len() - 1;
+        encoded[last] ^= 0xFF;
+        assert!(StunMessage::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_stun_reports_unknown_required_attributes() {
+        let msg = StunMessage::binding_request().with_attribute(StunAttribute::Unknown {
+            kind: 0x0042,
+            value: vec![1, 2, 3],
+        });
+        let decoded = StunMessage::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded.unknown_required_attributes(), vec![0x0042]);
+    }
+}
@@ -312,6 +312,18 @@ impl WireGuardProtoConfig {
         }
     }
 
+    /// Removes the peer with `public_key`, returning whether there was one.
+    pub fn remove_peer(&mut self, public_key: &str) -> bool {
+        match self.peers.as_mut() {
+            Some(peers) => {
+                let before = peers.len();
+                peers.retain(|p| p.public_key.to_string() != public_key);
+                before != peers.len()
+            }
+            None => false,
+        }
+    }
+
     pub fn save<P: AsRef<Path>>(&self, p: P) -> ProtoResult<()> {
         let content = toml::to_string(&self)?;
         let mut f = File::create(&p)?;
@@ -349,7 +361,7 @@ impl TryFrom<&Path> for WireGuardProtoConfig {
 impl TryFrom<config::WireGuardConf> for WireGuardProtoConfig {
     type Error = ProtoError;
     fn try_from(w: config::WireGuardConf) -> Result<Self, Self::Error> {
-        let config::WireGuardConf { config } = w;
+        let config::WireGuardConf { config, .. } = w;
         let config = WireGuardProtoConfig::try_from(&config)?;
         Ok(config)
     }
@@ -546,6 +558,19 @@ SaveConfig = true
             3
         );
 
+        assert!(config.remove_peer("456"));
+        assert!(!config.remove_peer("456"));
+        let _ = config.save(name);
+
+        let updated_config = WireGuardProtoConfig::try_from(&path).unwrap();
+        let keys = updated_config
+            .peers
+            .unwrap_or_default()
+            .iter()
+            .map(|p| p.public_key.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["123", "789"]);
+
         std::fs::remove_file(name).unwrap();
     }
 }
@@ -1,6 +1,68 @@
-use crate::{ServerError, ServerResult};
-use roxi_lib::types::SharedKey;
+use crate::{
+    config::{AuthKind, Config, EnrolledClient},
+    ServerError, ServerResult,
+};
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use roxi_crypto::decode_public_key;
+use roxi_lib::types::{ClientId, SharedKey};
+use roxi_proto::{AuthenticationChallenge, AuthenticationRequest, Credentials};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    path::Path,
+    process::Stdio,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    time::{timeout, Duration},
+};
 
+/// How long an external auth command may take to decide.
+const AUTH_COMMAND_TIMEOUT_SECS: u64 = 5;
+
+/// Decides whether a client may join, and under which ID its session is kept.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Checks that `request`, from the connection identified as `origin`,
+    /// answers `challenge`, returning the ID to key the client's session by.
+    async fn authenticate(
+        &self,
+        origin: &ClientId,
+        request: &AuthenticationRequest,
+        challenge: &AuthenticationChallenge,
+    ) -> ServerResult<ClientId>;
+}
+
+/// Builds the authenticator selected by `auth.kind`.
+pub fn from_config(config: &Config) -> ServerResult<Arc<dyn Authenticator>> {
+    let auth: Arc<dyn Authenticator> = match config.auth_kind() {
+        AuthKind::SharedKey => {
+            let key = config
+                .shared_key()
+                .ok_or(ServerError::MissingAuthConfig("shared_key"))?;
+            Arc::new(SharedKeyAuthentication::new(&key))
+        }
+        AuthKind::Keys => Arc::new(KeyAuthentication::new(config.enrolled_clients())),
+        AuthKind::TokenFile => {
+            let path = config
+                .token_file()
+                .ok_or(ServerError::MissingAuthConfig("token_file"))?;
+            Arc::new(TokenFileAuthentication::load(path)?)
+        }
+        AuthKind::Command => {
+            let command = config
+                .auth_command()
+                .ok_or(ServerError::MissingAuthConfig("command"))?;
+            Arc::new(CommandAuthentication::new(command)?)
+        }
+    };
+    Ok(auth)
+}
+
+/// Admits any client that knows the shared key.
 pub struct SharedKeyAuthentication {
     shared_key: SharedKey,
 }
@@ -11,11 +73,215 @@ impl SharedKeyAuthentication {
             shared_key: shared_key.clone(),
         }
     }
-    pub fn authenticate(&self, k: &SharedKey) -> ServerResult<()> {
-        if k == &self.shared_key {
-            return Ok(());
+}
+
+#[async_trait]
+impl Authenticator for SharedKeyAuthentication {
+    async fn authenticate(
+        &self,
+        origin: &ClientId,
+        request: &AuthenticationRequest,
+        challenge: &AuthenticationChallenge,
+    ) -> ServerResult<ClientId> {
+        request
+            .verify(&self.shared_key, challenge)
+            .map_err(|_| ServerError::Unauthenticated)?;
+        Ok(origin.clone())
+    }
+}
+
+/// Admits clients whose identity key is on the enrolled allow-list.
+pub struct KeyAuthentication {
+    clients: HashMap<Vec<u8>, String>,
+}
+
+impl KeyAuthentication {
+    pub fn new(clients: &[EnrolledClient]) -> Self {
+        let clients = clients
+            .iter()
+            .filter_map(|c| match decode_public_key(&c.public_key) {
+                Ok(key) => Some((key, c.label.clone())),
+                Err(e) => {
+                    tracing::error!("Ignoring enrolled client {}: {e}", c.label);
+                    None
+                }
+            })
+            .collect();
+        Self { clients }
+    }
+
+    pub fn contains(&self, public_key: &[u8]) -> bool {
+        self.clients.contains_key(public_key)
+    }
+}
+
+#[async_trait]
+impl Authenticator for KeyAuthentication {
+    async fn authenticate(
+        &self,
+        origin: &ClientId,
+        request: &AuthenticationRequest,
+        challenge: &AuthenticationChallenge,
+    ) -> ServerResult<ClientId> {
+        let (key, label) = request
+            .public_key()
+            .and_then(|key| self.clients.get_key_value(key))
+            .ok_or(ServerError::Unauthenticated)?;
+
+        request
+            .verify_identity(challenge)
+            .map_err(|_| ServerError::Unauthenticated)?;
+
+        let client_id = ClientId::from_public_key(key);
+        tracing::info!("{origin:?} is enrolled client {label} ({client_id:?})");
+        Ok(client_id)
+    }
+}
+
+/// Admits clients answering with any token from a file of `label: token`
+/// entries. Clients send their token as their shared key.
+pub struct TokenFileAuthentication {
+    tokens: BTreeMap<String, SharedKey>,
+}
+
+impl TokenFileAuthentication {
+    pub fn load(path: &Path) -> ServerResult<Self> {
+        let tokens: BTreeMap<String, SharedKey> =
+            serde_yaml::from_reader(File::open(path)?)?;
+        tracing::info!(
+            "Loaded {} auth tokens from {}",
+            tokens.len(),
+            path.display()
+        );
+        Ok(Self { tokens })
+    }
+}
+
+#[async_trait]
+impl Authenticator for TokenFileAuthentication {
+    async fn authenticate(
+        &self,
+        origin: &ClientId,
+        request: &AuthenticationRequest,
+        challenge: &AuthenticationChallenge,
+    ) -> ServerResult<ClientId> {
+        let label = self
+            .tokens
+            .iter()
+            .find(|(_, token)| request.verify(token, challenge).is_ok())
+            .map(|(label, _)| label)
+            .ok_or(ServerError::Unauthenticated)?;
+
+        tracing::info!("{origin:?} authenticated with token {label}");
+        Ok(origin.clone())
+    }
+}
+
+/// What an auth command reads from its stdin, as JSON.
+#[derive(Debug, Serialize)]
+struct CommandRequest<'a> {
+    origin: &'a ClientId,
+    client_id: &'a ClientId,
+    gateway: String,
+    wireguard_public_key: Option<&'a str>,
+    /// Base64 bytes the credentials cover.
+    message: String,
+    /// Base64 HMAC over `message`, for clients using a shared key.
+    mac: Option<String>,
+    /// Base64 identity key, already checked to have signed `message`.
+    public_key: Option<String>,
+}
+
+/// Hands the decision to an external command, so an organization can plug in
+/// its own identity source. The command gets the request as JSON on stdin and
+/// admits the client by exiting 0, optionally printing the ID to keep its
+/// session under.
+pub struct CommandAuthentication {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandAuthentication {
+    pub fn new(command: &[String]) -> ServerResult<Self> {
+        let (program, args) = command
+            .split_first()
+            .ok_or(ServerError::MissingAuthConfig("command"))?;
+        Ok(Self {
+            program: program.clone(),
+            args: args.to_vec(),
+        })
+    }
+
+    async fn run(&self, input: &[u8]) -> ServerResult<Option<String>> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        // Commands may decide without reading the request, closing stdin early
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Err(e) = stdin.write_all(input).await {
+                tracing::warn!("Auth command did not read the request: {e}");
+            }
+        }
+
+        let output = timeout(
+            Duration::from_secs(AUTH_COMMAND_TIMEOUT_SECS),
+            child.wait_with_output(),
+        )
+        .await??;
+        if !output.status.success() {
+            tracing::info!("Auth command rejected client: {}", output.status);
+            return Err(ServerError::Unauthenticated);
         }
 
-        Err(ServerError::Unauthenticated)
+        let stdout = String::from_utf8(output.stdout)?;
+        Ok(stdout
+            .lines()
+            .next()
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(String::from))
+    }
+}
+
+#[async_trait]
+impl Authenticator for CommandAuthentication {
+    async fn authenticate(
+        &self,
+        origin: &ClientId,
+        request: &AuthenticationRequest,
+        challenge: &AuthenticationChallenge,
+    ) -> ServerResult<ClientId> {
+        let (mac, public_key) = match &request.credentials {
+            Credentials::SharedKeyHmac { mac, .. } => (Some(base64::encode(mac)), None),
+            Credentials::Ed25519 { public_key, .. } => {
+                request
+                    .verify_identity(challenge)
+                    .map_err(|_| ServerError::Unauthenticated)?;
+                (None, Some(base64::encode(public_key)))
+            }
+            Credentials::None => return Err(ServerError::Unauthenticated),
+        };
+
+        let input = serde_json::to_vec(&CommandRequest {
+            origin,
+            client_id: &request.client_id,
+            gateway: request.gateway.to_string(),
+            wireguard_public_key: request.wireguard_public_key.as_deref(),
+            message: base64::encode(request.signed_data(challenge)?),
+            mac,
+            public_key,
+        })?;
+
+        match self.run(&input).await? {
+            Some(id) => Ok(ClientId::from(id.as_str())),
+            None => Ok(match request.public_key() {
+                Some(key) => ClientId::from_public_key(key),
+                None => origin.clone(),
+            }),
+        }
     }
 }
@@ -1,18 +1,25 @@
 use crate::{error::ServerError, ServerResult};
 use roxi_lib::types::{InterfaceKind, SharedKey};
+use roxi_proto::DEFAULT_MAX_FRAME_SIZE;
 use serde::{Deserialize, Serialize};
 use std::{
     fs::File,
-    net::IpAddr,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
     path::{Path, PathBuf},
 };
 
+/// Seconds a session may go without a message before it is ended as idle; a
+/// few missed client heartbeats.
+const DEFAULT_SESSION_IDLE_TIMEOUT_SECS: u64 = 90;
+
 #[derive(Debug, Serialize, Deserialize, Clone, Hash)]
 pub struct Ports {
     tcp: u16,
     udp: u16,
 }
 
+#[allow(unused)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Tun {
     address: IpAddr,
@@ -21,10 +28,99 @@ pub struct Tun {
     destination: IpAddr,
 }
 
+/// Which source decides whether a client may join.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthKind {
+    /// Any client that knows `shared_key`.
+    #[default]
+    SharedKey,
+    /// Clients whose identity key is listed under `clients`.
+    Keys,
+    /// Clients holding a token from `token_file`.
+    TokenFile,
+    /// Whoever `command` admits.
+    Command,
+}
+
+/// Where the server keeps sessions and gateway assignments.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+    /// In memory, lost when the server stops.
+    #[default]
+    Memory,
+    /// In an embedded database at `path`, kept across restarts.
+    Sled,
+}
+
+/// How the server picks the seeder that serves as a client's gateway.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectorKind {
+    /// Any seeder.
+    #[default]
+    Random,
+    /// The seeder serving the fewest clients.
+    LeastLoaded,
+    /// The seeder with the lowest round trip to the server.
+    LowestRtt,
+    /// The seeder sharing the most tags with the client.
+    Affinity,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GatewaySelection {
+    #[serde(default)]
+    policy: SelectorKind,
+    /// Whether a client keeps the gateway it had while that one seeds.
+    #[serde(default = "default_sticky")]
+    sticky: bool,
+}
+
+impl Default for GatewaySelection {
+    fn default() -> Self {
+        Self {
+            policy: SelectorKind::default(),
+            sticky: default_sticky(),
+        }
+    }
+}
+
+fn default_sticky() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Store {
+    #[serde(default)]
+    kind: StoreKind,
+    path: Option<PathBuf>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Auth {
-    shared_key: SharedKey,
+    #[serde(default)]
+    kind: AuthKind,
+    shared_key: Option<SharedKey>,
     session_ttl: u64,
+    /// Seconds without a message, heartbeats included, after which a session ends.
+    idle_timeout: Option<u64>,
+    #[serde(default)]
+    clients: Vec<EnrolledClient>,
+    token_file: Option<PathBuf>,
+    /// Program and arguments, e.g. `["/usr/local/bin/roxi-auth", "--realm", "vpn"]`.
+    command: Option<Vec<String>>,
+    /// Where invites and the clients that redeemed them are kept.
+    invites: Option<PathBuf>,
+}
+
+/// A client allowed to authenticate with its identity key.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EnrolledClient {
+    pub label: String,
+    /// Base64 Ed25519 public key, as printed by `roxi keygen --identity`.
+    pub public_key: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -34,33 +130,57 @@ pub struct Server {
     ports: Ports,
     max_clients: u16,
     response_timeout: u64,
+    max_frame_size: Option<usize>,
+    /// Base64 static key for the control channel. Generated at startup if unset.
+    private_key: Option<String>,
 }
 
 impl Server {
     pub fn addr(&self, k: InterfaceKind) -> String {
         match k {
-            InterfaceKind::Tcp => format!("{}:{}", self.interface, self.ports.tcp),
-            InterfaceKind::Udp => format!("{}:{}", self.interface, self.ports.udp),
+            InterfaceKind::Tcp => SocketAddr::new(self.interface, self.ports.tcp),
+            InterfaceKind::Udp => SocketAddr::new(self.interface, self.ports.udp),
         }
+        .to_string()
     }
 
     pub fn remote_addr(&self, k: InterfaceKind) -> String {
         match k {
-            InterfaceKind::Tcp => format!("{}:{}", self.ip, self.ports.tcp),
-            InterfaceKind::Udp => format!("{}:{}", self.ip, self.ports.udp),
+            InterfaceKind::Tcp => SocketAddr::new(self.ip, self.ports.tcp),
+            InterfaceKind::Udp => SocketAddr::new(self.ip, self.ports.udp),
         }
+        .to_string()
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Relay {
+    enabled: bool,
+    ports: PortRange,
+    /// Per-tunnel cap in bytes per second. Unlimited if unset.
+    max_bandwidth: Option<u64>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Network {
     server: Server,
+    relay: Option<Relay>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     network: Network,
     auth: Auth,
+    #[serde(default)]
+    store: Store,
+    #[serde(default)]
+    gateway_selection: GatewaySelection,
     path: PathBuf,
 }
 
@@ -77,17 +197,102 @@ impl Config {
         self.network.server.max_clients
     }
 
-    pub fn shared_key(&self) -> SharedKey {
+    pub fn auth_kind(&self) -> AuthKind {
+        self.auth.kind
+    }
+
+    pub fn shared_key(&self) -> Option<SharedKey> {
         self.auth.shared_key.clone()
     }
 
+    pub fn enrolled_clients(&self) -> &[EnrolledClient] {
+        &self.auth.clients
+    }
+
+    pub fn token_file(&self) -> Option<&Path> {
+        self.auth.token_file.as_deref()
+    }
+
+    pub fn auth_command(&self) -> Option<&[String]> {
+        self.auth.command.as_deref()
+    }
+
+    /// `auth.invites`, or next to this config file if unset.
+    pub fn invites_path(&self) -> PathBuf {
+        self.auth
+            .invites
+            .clone()
+            .unwrap_or_else(|| self.path.with_extension("invites.yaml"))
+    }
+
+    pub fn store_kind(&self) -> StoreKind {
+        self.store.kind
+    }
+
+    pub fn gateway_selection_policy(&self) -> SelectorKind {
+        self.gateway_selection.policy
+    }
+
+    pub fn gateway_selection_sticky(&self) -> bool {
+        self.gateway_selection.sticky
+    }
+
+    /// `store.path`, or next to this config file if unset.
+    pub fn store_path(&self) -> PathBuf {
+        self.store
+            .path
+            .clone()
+            .unwrap_or_else(|| self.path.with_extension("db"))
+    }
+
     pub fn session_ttl(&self) -> u64 {
         self.auth.session_ttl
     }
 
+    pub fn session_idle_timeout(&self) -> u64 {
+        self.auth
+            .idle_timeout
+            .unwrap_or(DEFAULT_SESSION_IDLE_TIMEOUT_SECS)
+    }
+
     pub fn response_timeout(&self) -> u64 {
         self.network.server.response_timeout
     }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.network
+            .server
+            .max_frame_size
+            .unwrap_or(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn private_key(&self) -> Option<&str> {
+        self.network.server.private_key.as_deref()
+    }
+
+    pub fn relay_enabled(&self) -> bool {
+        self.network.relay.as_ref().is_some_and(|r| r.enabled)
+    }
+
+    /// Ports the relay may allocate from. Empty if no relay is configured.
+    pub fn relay_ports(&self) -> RangeInclusive<u16> {
+        match &self.network.relay {
+            Some(r) => r.ports.start..=r.ports.end,
+            None => RangeInclusive::new(1, 0),
+        }
+    }
+
+    pub fn relay_max_bandwidth(&self) -> Option<u64> {
+        self.network.relay.as_ref().and_then(|r| r.max_bandwidth)
+    }
+
+    pub fn relay_addr(&self, port: u16) -> String {
+        SocketAddr::new(self.network.server.interface, port).to_string()
+    }
+
+    pub fn relay_remote_addr(&self, port: u16) -> String {
+        SocketAddr::new(self.network.server.ip, port).to_string()
+    }
 }
 
 impl TryFrom<&PathBuf> for Config {
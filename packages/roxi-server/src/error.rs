@@ -1,3 +1,4 @@
+use roxi_proto::MessageStatus;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -29,20 +30,50 @@ pub enum ServerError {
     #[error("Connection closed")]
     ConnectionClosed,
 
+    #[error("{0:?} is not connected")]
+    NotConnected(roxi_lib::types::ClientId),
+
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] roxi_crypto::CryptoError),
+
     #[error("Proto error: {0}")]
     Proto(#[from] roxi_proto::ProtoError),
 
     #[error("Invalid message")]
     InvalidMessage,
 
+    #[error("Serde json error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("auth.{0} is required by the configured auth kind")]
+    MissingAuthConfig(&'static str),
+
+    #[error("Invite is invalid, expired or used up")]
+    InvalidInvite,
+
+    #[error("No enrolled client labelled {0}")]
+    UnknownClient(String),
+
+    #[error("Key is not enrolled")]
+    NotEnrolled,
+
+    #[error("Only the server's operator may do that")]
+    Forbidden,
+
+    #[error("Session ticket is invalid or expired")]
+    InvalidTicket,
+
     #[error("Unauthenticated")]
     Unauthenticated,
 
+    #[error("Incompatible protocol version")]
+    IncompatibleVersion,
+
     #[error("FromUt8 error: {0}")]
     FromUtf8(#[from] std::string::FromUtf8Error),
 
-    #[error("Only Ipv4 is supported")]
-    UnsupportedIpAddrType,
+    #[error("Store error: {0}")]
+    Store(#[from] sled::Error),
 
     #[error("Bincode error: {0}")]
     Bincode(#[from] bincode::Error),
@@ -50,9 +81,33 @@ pub enum ServerError {
     #[error("Client error: {0}")]
     Client(#[from] roxi_client::ClientError),
 
+    #[error("Relay is disabled")]
+    RelayDisabled,
+
+    #[error("No relay ports available")]
+    NoRelayPortsAvailable,
+
+    #[error("Relay peers must have distinct IP addresses")]
+    IndistinctRelayPeers,
+
     #[error("No available peers")]
     NoAvailablePeers,
 
     #[error("Elapsed error: {0}")]
     Elapsed(#[from] tokio::time::error::Elapsed),
 }
+
+impl ServerError {
+    /// The status a refused request is answered with before the connection is
+    /// dropped, for errors the peer should be told about.
+    pub fn status(&self) -> Option<MessageStatus> {
+        match self {
+            ServerError::Unauthenticated | ServerError::InvalidInvite => {
+                Some(MessageStatus::Unauthorized)
+            }
+            ServerError::Forbidden => Some(MessageStatus::Forbidden),
+            ServerError::NoAvailablePeers => Some(MessageStatus::ServiceUnavailable),
+            _ => None,
+        }
+    }
+}
@@ -1,25 +1,41 @@
-use crate::{error::ServerError, ServerResult};
+use crate::{
+    handler::{Authenticated, Connection, Handler, Privileged, Registry, Reply, Service},
+    outbox::Outbox,
+    ServerResult,
+};
 use async_std::sync::Arc;
+use async_trait::async_trait;
 use roxi_client::Config;
-use roxi_lib::types::{ClientId, InterfaceKind};
+use roxi_crypto::{decode_public_key, NoiseKeyPair};
+use roxi_lib::types::{config::WireGuardConfPeer, ClientId, InterfaceKind};
 use roxi_proto::{
-    command, Message, MessageKind, MessageStatus, WireGuardProtoConfig,
-    WireGuardProtoPeer,
+    command, GatewayLoad, Message, MessageKind, MessageSink, MessageStatus,
+    PeerRevocation, SecureSession, WireGuardProtoConfig, WireGuardProtoPeer,
 };
 use std::collections::HashMap;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::{Mutex, RwLock, Semaphore},
+    sync::{broadcast, Mutex, RwLock, Semaphore},
     time::{timeout, Duration},
 };
+use tokio_util::codec::FramedRead;
+
+/// How many closed tunnels are buffered for a subscriber that is not reading.
+const CLOSED_TUNNELS_CAPACITY: usize = 64;
 
 pub struct Gateway {
     tcp: TcpListener,
     client_limit: Arc<Semaphore>,
     config: Config,
+    keys: NoiseKeyPair,
     wireguard_config: Arc<Mutex<WireGuardProtoConfig>>,
-    client_streams: Arc<RwLock<HashMap<ClientId, Arc<Mutex<TcpStream>>>>>,
+    /// Keyed per connection, as several peers may connect from one IP.
+    client_streams: Arc<RwLock<HashMap<ClientId, Outbox>>>,
+    /// WireGuard key of each tunnel, by the connection that opened it or,
+    /// until it connects, the peer it was assigned for.
+    tunnels: Arc<Mutex<HashMap<ClientId, String>>>,
+    closed_tunnels: broadcast::Sender<String>,
+    handlers: Registry<Self>,
 }
 
 impl Gateway {
@@ -28,148 +44,233 @@ impl Gateway {
         let client_limit =
             Arc::new(Semaphore::new(config.max_gateway_clients() as usize));
 
+        let keys = match config.gateway_private_key() {
+            Some(key) => NoiseKeyPair::try_from(key)?,
+            None => {
+                tracing::warn!("No private key configured, generating one for this run");
+                NoiseKeyPair::generate()?
+            }
+        };
+        tracing::info!(
+            "Gateway control channel public key: {}",
+            keys.public_base64()
+        );
+
         let wireguard_config = WireGuardProtoConfig::try_from(config.wireguard())?;
         Ok(Self {
             tcp,
             client_limit,
             config: config.clone(),
+            keys,
             wireguard_config: Arc::new(Mutex::new(wireguard_config)),
             client_streams: Arc::new(RwLock::new(HashMap::new())),
+            tunnels: Arc::new(Mutex::new(HashMap::new())),
+            closed_tunnels: broadcast::channel(CLOSED_TUNNELS_CAPACITY).0,
+            handlers: handlers(),
         })
     }
 
-    pub async fn handle_conn(&self, stream: TcpStream) -> ServerResult<()> {
-        tracing::info!("Handling incoming tcp stream");
+    /// Base64 static key clients can pin to verify this listener.
+    pub fn public_key(&self) -> String {
+        self.keys.public_base64()
+    }
 
-        let client_id = ClientId::try_from(&stream)?;
-        let stream = Arc::new(Mutex::new(stream));
+    /// Adds `peer` to our WireGuard config, replacing an earlier entry with the
+    /// same key, and saves it. The earlier entry's endpoint is kept if `peer`
+    /// names none, as it may be the relay the tunnel goes through.
+    pub async fn add_peer(&self, mut peer: WireGuardProtoPeer) -> ServerResult<()> {
+        let public_key = peer.public_key.to_string();
+        let mut wireguard_config = self.wireguard_config.lock().await;
+        let previous = wireguard_config
+            .peers
+            .iter()
+            .flatten()
+            .find(|p| p.public_key.to_string() == public_key)
+            .and_then(|p| p.endpoint.clone());
+        if peer.endpoint.is_none() {
+            peer.endpoint = previous;
+        }
+        wireguard_config.remove_peer(&public_key);
+        wireguard_config.add_peer(peer);
+        wireguard_config.save(self.config.wireguard_filepath())?;
+        Ok(())
+    }
 
-        loop {
-            let mut buff = vec![0u8; 1024];
-            let n = stream.lock().await.read(&mut buff).await?;
-            if n == 0 {
-                tracing::warn!("{client_id:?} connection closed");
-                break;
-            }
+    /// Points our WireGuard at `endpoint` for the peer with `public_key`,
+    /// returning whether we have it.
+    pub async fn set_endpoint(
+        &self,
+        public_key: &str,
+        endpoint: String,
+    ) -> ServerResult<bool> {
+        let mut wireguard_config = self.wireguard_config.lock().await;
+        match wireguard_config
+            .peers
+            .iter_mut()
+            .flatten()
+            .find(|p| p.public_key.to_string() == public_key)
+        {
+            Some(peer) => peer.endpoint = Some(endpoint),
+            None => return Ok(false),
+        }
+        wireguard_config.save(self.config.wireguard_filepath())?;
+        Ok(true)
+    }
 
-            tracing::info!("Read {n} bytes");
-
-            let msg = Message::deserialize(&buff)?;
-
-            tracing::info!("Received message from {client_id:?}: {msg:?}");
-
-            match msg.kind() {
-                MessageKind::Ping => {
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::Pong,
-                            MessageStatus::r#Ok,
-                            self.config.stun_addr().expect("STUN address required"),
-                            None,
-                        ),
-                        stream.clone(),
-                    )
-                    .await?;
-                }
-                MessageKind::PeerTunnelRequest => {
-                    self.client_streams
-                        .write()
-                        .await
-                        .insert(client_id.clone(), stream.clone());
-
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::PeerTunnelResponse,
-                            MessageStatus::r#Ok,
-                            self.config.stun_addr().expect("STUN address required"),
-                            None,
-                        ),
-                        stream.clone(),
-                    )
-                    .await?;
-                }
-                MessageKind::PeerTunnelInitRequest => {
-                    let peer: WireGuardProtoPeer = bincode::deserialize(&msg.data())?;
-                    self.wireguard_config.lock().await.add_peer(peer);
-                    self.wireguard_config
-                        .lock()
-                        .await
-                        .save(self.config.wireguard_filepath())?;
-
-                    let pubkey = command::cat_wireguard_pubkey()?;
-
-                    let allowed_ips = "".to_string();
-                    let endpoint = None;
-                    let persistent_keepalive = 1;
-                    let data = bincode::serialize(&WireGuardProtoPeer {
-                        public_key: pubkey,
-                        allowed_ips,
-                        endpoint,
-                        persistent_keepalive: Some(persistent_keepalive),
-                    })?;
-
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::PeerTunnelInitResponse,
-                            MessageStatus::r#Ok,
-                            self.config.stun_addr().expect("STUN address required"),
-                            Some(data),
-                        ),
-                        stream.clone(),
-                    )
-                    .await?;
-                }
-                MessageKind::NATPunchRequest => {
-                    if !self.client_streams.read().await.contains_key(&client_id) {
-                        tracing::error!("{client_id:?} not a recognized peer");
-                        self.send(
-                            &client_id,
-                            Message::new(
-                                MessageKind::NATPunchResponse,
-                                MessageStatus::Unauthorized,
-                                self.config.stun_addr().expect("STUN address required"),
-                                None,
-                            ),
-                            stream.clone(),
-                        )
-                        .await?;
-                    }
-
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::NATPunchResponse,
-                            MessageStatus::r#Ok,
-                            self.config.stun_addr().expect("STUN address required"),
-                            None,
-                        ),
-                        stream.clone(),
-                    )
-                    .await?;
-                }
-                _ => {
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::GenericErrorResponse,
-                            MessageStatus::BadData,
-                            self.config.stun_addr().expect("STUN address required"),
-                            None,
-                        ),
-                        stream.clone(),
-                    )
-                    .await?;
-                    return Err(ServerError::InvalidMessage);
-                }
+    /// Removes the peer with `public_key` from our WireGuard config and the
+    /// live interface, returning whether we had it.
+    pub async fn remove_peer(&self, public_key: &str) -> ServerResult<bool> {
+        self.tunnels.lock().await.retain(|_, key| key != public_key);
+        let mut wireguard_config = self.wireguard_config.lock().await;
+        if !wireguard_config.remove_peer(public_key) {
+            return Ok(false);
+        }
+        wireguard_config.save(self.config.wireguard_filepath())?;
+
+        let interface = self.wireguard_interface();
+        if let Err(e) = command::remove_wireguard_peer(&interface, public_key) {
+            tracing::warn!("Peer stays on {interface} until it is reloaded: {e}");
+        }
+        Ok(true)
+    }
+
+    /// Adds the WireGuard peer the server assigned us as `peer_id`. Its tunnel
+    /// is tracked under `peer_id` until the peer connects and opens it, unless
+    /// it already has.
+    pub async fn assign_tunnel(
+        &self,
+        peer_id: &ClientId,
+        peer: WireGuardProtoPeer,
+    ) -> ServerResult<()> {
+        let public_key = peer.public_key.to_string();
+        let mut tunnels = self.tunnels.lock().await;
+        if tunnels.values().any(|key| *key == public_key) {
+            return Ok(());
+        }
+        self.add_peer(peer).await?;
+        tunnels.insert(peer_id.clone(), public_key);
+        Ok(())
+    }
+
+    /// Adds the WireGuard peer of the client on the connection `client_id`,
+    /// whose tunnel is torn down when it closes it or its connection drops.
+    pub async fn open_tunnel(
+        &self,
+        client_id: &ClientId,
+        peer: WireGuardProtoPeer,
+    ) -> ServerResult<()> {
+        let public_key = peer.public_key.to_string();
+        self.add_peer(peer).await?;
+        let previous = {
+            let mut tunnels = self.tunnels.lock().await;
+            // The connection takes over the tunnel assigned for the peer
+            tunnels.retain(|_, key| *key != public_key);
+            tunnels.insert(client_id.clone(), public_key.clone())
+        };
+        // A peer that reconnects with a new key replaces its old entry
+        match previous {
+            Some(previous) if previous != public_key => {
+                self.remove_peer(&previous).await?;
             }
+            _ => {}
         }
+        Ok(())
+    }
+
+    /// Tears down the tunnel of the client on the connection `client_id`, if it
+    /// opened one, and tells subscribers of `closed_tunnels`. The peer's tunnel
+    /// address goes with its WireGuard entry, as the gateway keeps no pool of
+    /// its own; the seeder's report frees its assignment and relay port on the
+    /// server.
+    pub async fn close_tunnel(&self, client_id: &ClientId) -> ServerResult<()> {
+        self.client_streams.write().await.remove(client_id);
+        let public_key = match self.tunnels.lock().await.remove(client_id) {
+            Some(public_key) => public_key,
+            None => return Ok(()),
+        };
 
+        self.remove_peer(&public_key).await?;
+        tracing::info!("Closed the tunnel of {client_id:?}");
+        // Nobody listening is fine, e.g. a gateway run on its own
+        let _ = self.closed_tunnels.send(public_key);
         Ok(())
     }
 
+    /// A receiver of the WireGuard keys of the tunnels closed from now on.
+    pub fn closed_tunnels(&self) -> broadcast::Receiver<String> {
+        self.closed_tunnels.subscribe()
+    }
+
+    /// Name of the WireGuard interface, after its config file.
+    fn wireguard_interface(&self) -> String {
+        self.config
+            .wireguard_filepath()
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// How busy the gateway is, as reported to the server. Transfer counts
+    /// are left at zero if WireGuard cannot be queried.
+    pub async fn load(&self) -> GatewayLoad {
+        let interface = self.wireguard_interface();
+        let (bytes_in, bytes_out) = match command::wireguard_transfer(&interface) {
+            Ok(transfer) => transfer,
+            Err(e) => {
+                tracing::debug!("No WireGuard transfer counts for {interface}: {e}");
+                (0, 0)
+            }
+        };
+
+        GatewayLoad {
+            active_tunnels: self.tunnels.lock().await.len() as u32,
+            max_clients: self.config.max_gateway_clients(),
+            bytes_in,
+            bytes_out,
+            healthy: true,
+        }
+    }
+
+    /// Whether `remote_static` is the key of the server we are pinned to. Without
+    /// a pinned key nobody can prove to be the server.
+    fn is_server(&self, remote_static: &[u8]) -> bool {
+        match self.config.server_public_key().map(decode_public_key) {
+            Some(Ok(key)) => key == remote_static,
+            Some(Err(e)) => {
+                tracing::error!("Invalid server public key: {e}");
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub async fn handle_conn(&self, mut stream: TcpStream) -> ServerResult<()> {
+        tracing::info!("Handling incoming tcp stream");
+
+        let addr = stream.peer_addr()?;
+        let session = timeout(
+            Duration::from_secs(self.config.response_timeout()),
+            SecureSession::accept(&mut stream, &self.keys),
+        )
+        .await??;
+        let from_server = self.is_server(session.remote_static());
+        let codec = session.codec(self.config.max_frame_size());
+        let (reader, writer) = stream.into_split();
+        let reader = FramedRead::new(reader, codec.clone());
+        let stream = Outbox::spawn(MessageSink::new(writer, codec));
+
+        let mut conn = Connection::new(ClientId::from(&addr), stream, from_server);
+        // Tunnels are kept per connection, not per IP
+        conn.client_id = ClientId::from(addr.to_string());
+        let result = self.handlers.serve(self, &mut conn, reader).await;
+
+        // A peer that goes away without closing its tunnel leaves nothing behind
+        if let Err(e) = self.close_tunnel(&conn.client_id).await {
+            tracing::error!("Failed to close the tunnel of {:?}: {e}", conn.client_id);
+        }
+        result
+    }
+
     pub async fn stop(self: Arc<Self>) -> ServerResult<()> {
         if let Err(e) = self.stop_with_timeout(Duration::from_secs(1)).await {
             tracing::error!("Error stopping server: {e}");
@@ -192,23 +293,14 @@ impl Gateway {
             for (client_id, stream) in clients.iter() {
                 tracing::info!("Closing connection for client: {:?}", client_id);
 
-                // FIXME: Trying to acquire a lock on the stream here causes lock contention due to
-                // `Server::handle_conn` accessing the lock infinitum, which (for now) allows clients
-                // to send multiple messages on a single stream. Might require a bit of rework later. <( '.' )>
-                let mut guard = stream.lock().await;
-
                 if let Err(e) = timeout(
                     Duration::from_secs(self.config.response_timeout()),
-                    self.send(
-                        client_id,
-                        Message::new(
-                            MessageKind::ServerShutdown,
-                            MessageStatus::ServiceUnavailable,
-                            self.config.remote_addr(InterfaceKind::Tcp),
-                            None,
-                        ),
-                        stream.clone(),
-                    ),
+                    stream.send(Message::new(
+                        MessageKind::ServerShutdown,
+                        MessageStatus::ServiceUnavailable,
+                        self.config.remote_addr(InterfaceKind::Tcp),
+                        None,
+                    )),
                 )
                 .await
                 {
@@ -217,7 +309,7 @@ impl Gateway {
                     );
                 }
 
-                let _ = AsyncWriteExt::shutdown(&mut *guard).await;
+                let _ = stream.close().await;
             }
             clients.clear();
         }
@@ -228,18 +320,6 @@ impl Gateway {
         Ok(())
     }
 
-    async fn send(
-        &self,
-        client_id: &ClientId,
-        msg: Message,
-        stream: Arc<Mutex<TcpStream>>,
-    ) -> ServerResult<()> {
-        tracing::info!("Sending message to {client_id:?}: {msg:?}");
-        let data = msg.serialize()?;
-        stream.lock().await.write_all(&data).await?;
-        Ok(())
-    }
-
     pub async fn run(self: Arc<Self>) -> ServerResult<()> {
         tracing::info!(
             "Gateway server listening at {}",
@@ -262,3 +342,155 @@ impl Gateway {
         }
     }
 }
+
+#[async_trait]
+impl Service for Gateway {
+    fn addr(&self) -> String {
+        self.config.stun_addr().expect("STUN address required")
+    }
+
+    /// Peers are recognized once they ask for a tunnel.
+    async fn is_authenticated(&self, conn: &Connection) -> bool {
+        self.client_streams
+            .read()
+            .await
+            .contains_key(&conn.client_id)
+    }
+}
+
+/// How the gateway answers each kind of message.
+fn handlers() -> Registry<Gateway> {
+    Registry::new()
+        .with(PeerTunnelHandler)
+        .with(PeerTunnelInitHandler)
+        .with(PeerTunnelCloseHandler)
+        .with(Privileged(PeerRevokeHandler))
+        .with(Authenticated(PunchHandler))
+}
+
+struct PeerTunnelHandler;
+
+#[async_trait]
+impl Handler<Gateway> for PeerTunnelHandler {
+    type Request = ();
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::PeerTunnelRequest;
+    const RESPONSE: MessageKind = MessageKind::PeerTunnelResponse;
+
+    async fn handle(
+        &self,
+        gateway: &Gateway,
+        conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<()>> {
+        gateway
+            .client_streams
+            .write()
+            .await
+            .insert(conn.client_id.clone(), conn.stream.clone());
+        Ok(Reply::status(MessageStatus::r#Ok))
+    }
+}
+
+/// Adds the peer to our WireGuard config and answers with our own.
+struct PeerTunnelInitHandler;
+
+#[async_trait]
+impl Handler<Gateway> for PeerTunnelInitHandler {
+    type Request = WireGuardConfPeer;
+    type Response = WireGuardConfPeer;
+
+    const REQUEST: MessageKind = MessageKind::PeerTunnelInitRequest;
+    const RESPONSE: MessageKind = MessageKind::PeerTunnelInitResponse;
+
+    async fn handle(
+        &self,
+        gateway: &Gateway,
+        conn: &mut Connection,
+        peer: WireGuardConfPeer,
+    ) -> ServerResult<Reply<WireGuardConfPeer>> {
+        gateway.open_tunnel(&conn.client_id, peer.into()).await?;
+
+        let pubkey = gateway.config.wireguard_public_key()?;
+
+        let allowed_ips = "".to_string();
+        let endpoint = None;
+        let persistent_keepalive = 1;
+        Ok(Reply::ok(WireGuardConfPeer {
+            public_key: pubkey.to_string(),
+            allowed_ips,
+            endpoint,
+            persistent_keepalive: Some(persistent_keepalive),
+        }))
+    }
+}
+
+/// Tears down the tunnel of the peer and closes its connection.
+struct PeerTunnelCloseHandler;
+
+#[async_trait]
+impl Handler<Gateway> for PeerTunnelCloseHandler {
+    type Request = ();
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::PeerTunnelClose;
+    const RESPONSE: MessageKind = MessageKind::PeerTunnelCloseResponse;
+
+    async fn handle(
+        &self,
+        gateway: &Gateway,
+        conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<()>> {
+        gateway.close_tunnel(&conn.client_id).await?;
+        Ok(Reply::status(MessageStatus::r#Ok).close())
+    }
+}
+
+/// Drops a peer the server revoked.
+struct PeerRevokeHandler;
+
+#[async_trait]
+impl Handler<Gateway> for PeerRevokeHandler {
+    type Request = PeerRevocation;
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::PeerRevokeRequest;
+    const RESPONSE: MessageKind = MessageKind::PeerRevokeResponse;
+
+    async fn handle(
+        &self,
+        gateway: &Gateway,
+        _conn: &mut Connection,
+        revocation: PeerRevocation,
+    ) -> ServerResult<Reply<()>> {
+        if gateway
+            .remove_peer(&revocation.wireguard_public_key)
+            .await?
+        {
+            tracing::info!("Dropped revoked peer {:?}", revocation.peer);
+        }
+        Ok(Reply::status(MessageStatus::r#Ok))
+    }
+}
+
+struct PunchHandler;
+
+#[async_trait]
+impl Handler<Gateway> for PunchHandler {
+    type Request = ();
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::NATPunchRequest;
+    const RESPONSE: MessageKind = MessageKind::NATPunchResponse;
+
+    async fn handle(
+        &self,
+        _gateway: &Gateway,
+        _conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<()>> {
+        Ok(Reply::status(MessageStatus::r#Ok))
+    }
+}
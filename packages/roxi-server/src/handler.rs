@@ -1 +1,435 @@
+use crate::{error::ServerError, outbox::Outbox, ServerResult};
+use async_trait::async_trait;
+use futures::StreamExt;
+use roxi_lib::types::ClientId;
+use roxi_proto::{
+    AuthenticationChallenge, Hello, HelloAck, Message, MessageKind, MessageStatus,
+    MessageStream, PROTOCOL_VERSION,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, marker::PhantomData};
+use tokio::{net::tcp::OwnedReadHalf, time::Instant};
 
+/// A listener that dispatches the messages of its connections to a `Registry`.
+#[async_trait]
+pub trait Service: Send + Sync + Sized + 'static {
+    /// Address replies are sent from.
+    fn addr(&self) -> String;
+
+    /// Whether the client on `conn` may reach handlers behind `Authenticated`.
+    async fn is_authenticated(&self, conn: &Connection) -> bool;
+
+    /// Called with every message before it is dispatched.
+    async fn received(&self, _conn: &Connection) {}
+
+    fn message(
+        &self,
+        kind: MessageKind,
+        status: MessageStatus,
+        data: Option<Vec<u8>>,
+    ) -> Message {
+        Message::new(kind, status, self.addr(), data)
+    }
+
+    async fn send(
+        &self,
+        client_id: &ClientId,
+        msg: Message,
+        stream: Outbox,
+    ) -> ServerResult<()> {
+        tracing::info!("Sending message to {client_id:?}: {msg:?}");
+        stream.send(msg).await
+    }
+}
+
+/// What handlers know about the connection a message arrived on.
+pub struct Connection {
+    /// ID the client is known by, re-keyed once it authenticates.
+    pub client_id: ClientId,
+    /// The address-derived ID of the connection.
+    pub origin: ClientId,
+    pub stream: Outbox,
+    /// Whether the peer holds the key that may administer the service.
+    pub privileged: bool,
+    /// Header version replies are sent in.
+    pub version: u8,
+    /// Outstanding challenge; each one is answered at most once.
+    pub challenge: Option<(AuthenticationChallenge, Instant)>,
+    /// Outstanding ping timing the round trip, by the ID it was sent with.
+    pub probe: Option<(u32, Instant)>,
+    /// ID of the request being handled, echoed in its reply.
+    pub request_id: u32,
+}
+
+impl Connection {
+    pub fn new(origin: ClientId, stream: Outbox, privileged: bool) -> Self {
+        Self {
+            client_id: origin.clone(),
+            origin,
+            stream,
+            privileged,
+            version: PROTOCOL_VERSION,
+            challenge: None,
+            probe: None,
+            request_id: 0,
+        }
+    }
+
+    pub async fn set_version(&mut self, version: u8) -> ServerResult<()> {
+        self.version = version;
+        self.stream.set_version(version).await
+    }
+}
+
+/// What becomes of the connection once a message is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Close,
+}
+
+/// A handler's answer to the sender of a request.
+pub struct Reply<T> {
+    status: Option<MessageStatus>,
+    body: Option<T>,
+    flow: Flow,
+}
+
+impl<T> Reply<T> {
+    pub fn ok(body: T) -> Self {
+        Self::with_body(MessageStatus::r#Ok, body)
+    }
+
+    pub fn with_body(status: MessageStatus, body: T) -> Self {
+        Self {
+            status: Some(status),
+            body: Some(body),
+            flow: Flow::Continue,
+        }
+    }
+
+    /// A reply without a payload.
+    pub fn status(status: MessageStatus) -> Self {
+        Self {
+            status: Some(status),
+            body: None,
+            flow: Flow::Continue,
+        }
+    }
+
+    /// Nothing to answer, e.g. because the answer comes from another peer.
+    pub fn none() -> Self {
+        Self {
+            status: None,
+            body: None,
+            flow: Flow::Continue,
+        }
+    }
+
+    /// Closes the connection once the reply is sent.
+    pub fn close(mut self) -> Self {
+        self.flow = Flow::Close;
+        self
+    }
+}
+
+/// Handles one kind of message for the service `S`.
+#[async_trait]
+pub trait Handler<S: Service>: Send + Sync {
+    /// Payload of the message handled. Messages without one use `()`.
+    type Request: DeserializeOwned + Send;
+    /// Payload of the reply. Replies without one use `()`.
+    type Response: Serialize + Send;
+
+    const REQUEST: MessageKind;
+    const RESPONSE: MessageKind;
+
+    async fn handle(
+        &self,
+        service: &S,
+        conn: &mut Connection,
+        request: Self::Request,
+    ) -> ServerResult<Reply<Self::Response>>;
+}
+
+/// A handler with its payload types erased, so that a registry can hold any.
+#[async_trait]
+trait Route<S: Service>: Send + Sync {
+    fn response_kind(&self) -> MessageKind;
+
+    async fn call(
+        &self,
+        service: &S,
+        conn: &mut Connection,
+        msg: &Message,
+    ) -> ServerResult<Flow>;
+}
+
+#[async_trait]
+impl<S: Service, H: Handler<S>> Route<S> for H {
+    fn response_kind(&self) -> MessageKind {
+        H::RESPONSE
+    }
+
+    async fn call(
+        &self,
+        service: &S,
+        conn: &mut Connection,
+        msg: &Message,
+    ) -> ServerResult<Flow> {
+        let request: H::Request = bincode::deserialize(&msg.data())?;
+        let reply = self.handle(service, conn, request).await?;
+        if let Some(status) = reply.status {
+            let data = match reply.body {
+                Some(body) => Some(bincode::serialize(&body)?),
+                None => None,
+            };
+            let msg = service
+                .message(H::RESPONSE, status, data)
+                .with_id(conn.request_id);
+            service
+                .send(&conn.client_id, msg, conn.stream.clone())
+                .await?;
+        }
+        Ok(reply.flow)
+    }
+}
+
+/// Only lets clients the service has authenticated through to `H`; anyone else
+/// is refused and disconnected.
+pub struct Authenticated<H>(pub H);
+
+#[async_trait]
+impl<S: Service, H: Handler<S>> Handler<S> for Authenticated<H> {
+    type Request = H::Request;
+    type Response = H::Response;
+
+    const REQUEST: MessageKind = H::REQUEST;
+    const RESPONSE: MessageKind = H::RESPONSE;
+
+    async fn handle(
+        &self,
+        service: &S,
+        conn: &mut Connection,
+        request: Self::Request,
+    ) -> ServerResult<Reply<Self::Response>> {
+        if !service.is_authenticated(conn).await {
+            tracing::error!("Unauthenticated client: {:?}", conn.client_id);
+            return Err(ServerError::Unauthenticated);
+        }
+        self.0.handle(service, conn, request).await
+    }
+}
+
+/// Only lets a privileged peer through to `H`; anyone else is refused.
+pub struct Privileged<H>(pub H);
+
+#[async_trait]
+impl<S: Service, H: Handler<S>> Handler<S> for Privileged<H> {
+    type Request = H::Request;
+    type Response = H::Response;
+
+    const REQUEST: MessageKind = H::REQUEST;
+    const RESPONSE: MessageKind = H::RESPONSE;
+
+    async fn handle(
+        &self,
+        service: &S,
+        conn: &mut Connection,
+        request: Self::Request,
+    ) -> ServerResult<Reply<Self::Response>> {
+        if !conn.privileged {
+            tracing::error!("{:?} is not allowed to send {}", conn.client_id, H::REQUEST);
+            return Ok(Reply::status(MessageStatus::Forbidden));
+        }
+        self.0.handle(service, conn, request).await
+    }
+}
+
+/// Negotiates the protocol version of the connection.
+pub struct HelloHandler;
+
+#[async_trait]
+impl<S: Service> Handler<S> for HelloHandler {
+    type Request = Hello;
+    type Response = HelloAck;
+
+    const REQUEST: MessageKind = MessageKind::Hello;
+    const RESPONSE: MessageKind = MessageKind::HelloAck;
+
+    async fn handle(
+        &self,
+        _service: &S,
+        conn: &mut Connection,
+        hello: Hello,
+    ) -> ServerResult<Reply<HelloAck>> {
+        let ack = match hello.negotiate(&Hello::local()) {
+            Some(ack) => ack,
+            None => {
+                tracing::error!(
+                    "{:?} supports none of our protocol versions: {:?}",
+                    conn.client_id,
+                    hello.versions
+                );
+                return Err(ServerError::IncompatibleVersion);
+            }
+        };
+
+        tracing::info!("Negotiated {ack:?} with {:?}", conn.client_id);
+        conn.set_version(ack.version).await?;
+        Ok(Reply::ok(ack))
+    }
+}
+
+pub struct PingHandler;
+
+#[async_trait]
+impl<S: Service> Handler<S> for PingHandler {
+    type Request = ();
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::Ping;
+    const RESPONSE: MessageKind = MessageKind::Pong;
+
+    async fn handle(
+        &self,
+        _service: &S,
+        _conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<()>> {
+        Ok(Reply::status(MessageStatus::r#Ok))
+    }
+}
+
+/// The handlers of a service, by the kind of message they handle.
+pub struct Registry<S> {
+    routes: HashMap<MessageKind, Box<dyn Route<S>>>,
+    service: PhantomData<S>,
+}
+
+impl<S: Service> Registry<S> {
+    /// A registry that answers `Hello` and `Ping`, which every service speaks.
+    pub fn new() -> Self {
+        Self {
+            routes: HashMap::new(),
+            service: PhantomData,
+        }
+        .with(HelloHandler)
+        .with(PingHandler)
+    }
+
+    /// Routes messages of `H::REQUEST` to `handler`, replacing any handler
+    /// registered for them before.
+    pub fn with<H: Handler<S> + 'static>(mut self, handler: H) -> Self {
+        self.routes.insert(H::REQUEST, Box::new(handler));
+        self
+    }
+
+    /// Handles the messages read from `reader` until the connection closes or a
+    /// handler closes it.
+    pub async fn serve(
+        &self,
+        service: &S,
+        conn: &mut Connection,
+        mut reader: MessageStream<OwnedReadHalf>,
+    ) -> ServerResult<()> {
+        loop {
+            let msg = match reader.next().await {
+                Some(msg) => msg?,
+                None => {
+                    tracing::warn!("{:?} connection closed", conn.client_id);
+                    return Ok(());
+                }
+            };
+
+            tracing::info!("Received message from {:?}: {msg:?}", conn.client_id);
+
+            if !msg.is_supported_version() {
+                tracing::error!(
+                    "{:?} speaks unsupported protocol version {}",
+                    conn.client_id,
+                    msg.version()
+                );
+                self.reject_version(service, conn).await?;
+                return Err(ServerError::IncompatibleVersion);
+            }
+
+            // Always answer a peer in the header version it spoke to us
+            if msg.version() != conn.version {
+                conn.set_version(msg.version()).await?;
+            }
+
+            service.received(conn).await;
+            conn.request_id = msg.id();
+
+            if self.dispatch(service, conn, &msg).await? == Flow::Close {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn dispatch(
+        &self,
+        service: &S,
+        conn: &mut Connection,
+        msg: &Message,
+    ) -> ServerResult<Flow> {
+        let route = match self.routes.get(msg.kind()) {
+            Some(route) => route,
+            None => {
+                let reply = service
+                    .message(
+                        MessageKind::GenericErrorResponse,
+                        MessageStatus::BadData,
+                        None,
+                    )
+                    .with_id(msg.id());
+                service
+                    .send(&conn.client_id, reply, conn.stream.clone())
+                    .await?;
+                return Err(ServerError::InvalidMessage);
+            }
+        };
+
+        match route.call(service, conn, msg).await {
+            Ok(flow) => Ok(flow),
+            Err(ServerError::IncompatibleVersion) => {
+                self.reject_version(service, conn).await?;
+                Err(ServerError::IncompatibleVersion)
+            }
+            Err(e) => {
+                // Refusals are answered before the connection is dropped
+                if let Some(status) = e.status() {
+                    let reply = service
+                        .message(route.response_kind(), status, None)
+                        .with_id(msg.id());
+                    service
+                        .send(&conn.client_id, reply, conn.stream.clone())
+                        .await?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Tells a peer that we share no protocol version with it, advertising the
+    /// versions we do support so it can report a useful error.
+    async fn reject_version(&self, service: &S, conn: &Connection) -> ServerResult<()> {
+        let reply = service
+            .message(
+                MessageKind::HelloAck,
+                MessageStatus::UpgradeRequired,
+                Some(bincode::serialize(&Hello::local())?),
+            )
+            .with_id(conn.request_id);
+        service
+            .send(&conn.client_id, reply, conn.stream.clone())
+            .await
+    }
+}
+
+impl<S: Service> Default for Registry<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,405 @@
+use crate::{
+    auth::{Authenticator, KeyAuthentication},
+    config::EnrolledClient,
+    ServerError, ServerResult,
+};
+use async_std::sync::RwLock;
+use fs2::FileExt;
+use rand::RngCore;
+use roxi_crypto::decode_public_key;
+use roxi_lib::{types::ClientId, util::sha256};
+use roxi_proto::{
+    AuthenticationChallenge, AuthenticationRequest, EnrollmentRequest, KeyRotationRequest,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Random bytes in an invite token.
+const INVITE_TOKEN_LEN: usize = 24;
+
+/// Prefix marking a string as an invite token.
+const INVITE_TOKEN_PREFIX: &str = "rxi-";
+
+/// A one-time invite minted by `roxi invite create`. Only a hash of its token is
+/// kept.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Invite {
+    pub id: String,
+    token_hash: String,
+    /// Label given to the client that redeems it. Defaults to `invite-<id>`.
+    pub label: Option<String>,
+    /// Tunnel address assigned to the client that redeems it.
+    pub address: Option<String>,
+    pub created_by: String,
+    /// Unix seconds.
+    pub created_at: u64,
+    /// Unix seconds.
+    pub expires_at: u64,
+    pub max_uses: u32,
+    pub uses: u32,
+}
+
+impl Invite {
+    pub fn is_expired(&self) -> bool {
+        now() >= self.expires_at
+    }
+
+    pub fn is_used_up(&self) -> bool {
+        self.uses >= self.max_uses
+    }
+}
+
+/// Invites and the clients that redeemed them, as kept on disk.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct InviteStore {
+    #[serde(default)]
+    invites: Vec<Invite>,
+    #[serde(default)]
+    clients: Vec<EnrolledClient>,
+    /// Keys that were revoked or rotated out, which are never admitted again.
+    #[serde(default)]
+    revoked: Vec<EnrolledClient>,
+}
+
+impl InviteStore {
+    /// Loads the store at `path`, which is empty if the file does not exist yet.
+    pub fn load(path: &Path) -> ServerResult<Self> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_yaml::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the store next to `path` and renames it over `path`, so that a
+    /// crash never leaves a partly written file behind.
+    pub fn save(&self, path: &Path) -> ServerResult<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content = serde_yaml::to_string(self)?;
+        let tmp = sibling(path, "tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Loads the store at `path`, lets `f` change it and saves it back. Holds
+    /// an advisory lock on a file next to it throughout, so that the server
+    /// and `roxi invite` never lose each other's changes. Nothing is saved if
+    /// `f` fails.
+    pub fn update<T>(
+        path: &Path,
+        f: impl FnOnce(&mut Self) -> ServerResult<T>,
+    ) -> ServerResult<(Self, T)> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(sibling(path, "lock"))?;
+        lock.lock_exclusive()?;
+
+        let mut store = Self::load(path)?;
+        let result = f(&mut store)?;
+        store.save(path)?;
+        Ok((store, result))
+    }
+
+    pub fn invites(&self) -> &[Invite] {
+        &self.invites
+    }
+
+    pub fn clients(&self) -> &[EnrolledClient] {
+        &self.clients
+    }
+
+    pub fn revoked(&self) -> &[EnrolledClient] {
+        &self.revoked
+    }
+
+    fn is_known(&self, public_key: &str) -> bool {
+        self.clients
+            .iter()
+            .chain(self.revoked.iter())
+            .any(|c| c.public_key == public_key)
+    }
+
+    /// Revokes `client`, dropping it from the enrolled clients if it is one.
+    pub fn revoke(&mut self, client: EnrolledClient) {
+        self.clients.retain(|c| c.public_key != client.public_key);
+        if !self
+            .revoked
+            .iter()
+            .any(|c| c.public_key == client.public_key)
+        {
+            self.revoked.push(client);
+        }
+    }
+
+    /// Replaces the key of the client enrolled with `old_public_key`, keeping its
+    /// label. The old key is revoked. A client among the `listed` clients of the
+    /// server config is enrolled here under the new key, which the revoked entry
+    /// then overrides the listed one with.
+    pub fn rotate(
+        &mut self,
+        old_public_key: &str,
+        new_public_key: &str,
+        listed: &[EnrolledClient],
+    ) -> ServerResult<EnrolledClient> {
+        if self.is_known(new_public_key) {
+            tracing::error!("Key {new_public_key} was already enrolled");
+            return Err(ServerError::Unauthenticated);
+        }
+
+        let old = match self
+            .clients
+            .iter()
+            .chain(listed.iter())
+            .find(|c| c.public_key == old_public_key)
+        {
+            Some(client) => client.clone(),
+            None => return Err(ServerError::NotEnrolled),
+        };
+        let rotated = EnrolledClient {
+            label: old.label.clone(),
+            public_key: new_public_key.to_string(),
+        };
+
+        self.clients.retain(|c| c.public_key != old_public_key);
+        self.clients.push(rotated.clone());
+        self.revoked.push(old);
+        Ok(rotated)
+    }
+
+    /// Mints an invite valid for `ttl` seconds and `max_uses` enrollments,
+    /// returning its token. The token is not stored and cannot be shown again.
+    pub fn create(
+        &mut self,
+        created_by: &str,
+        ttl: u64,
+        max_uses: u32,
+        label: Option<String>,
+        address: Option<String>,
+    ) -> (String, &Invite) {
+        let mut bytes = [0u8; INVITE_TOKEN_LEN];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = format!(
+            "{INVITE_TOKEN_PREFIX}{}",
+            base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+        );
+        let token_hash = sha256(&token);
+
+        let created_at = now();
+        self.invites.push(Invite {
+            id: token_hash[..8].to_string(),
+            token_hash,
+            label,
+            address,
+            created_by: created_by.to_string(),
+            created_at,
+            expires_at: created_at + ttl,
+            max_uses,
+            uses: 0,
+        });
+        (token, self.invites.last().expect("Invite just added"))
+    }
+
+    /// Redeems an invite for the key in `request`, enrolling it. Returns the
+    /// client and the tunnel address the invite assigned it.
+    pub fn redeem(
+        &mut self,
+        request: &EnrollmentRequest,
+    ) -> ServerResult<(EnrolledClient, Option<String>)> {
+        request.verify().map_err(|_| ServerError::InvalidInvite)?;
+
+        let public_key = base64::encode(&request.public_key);
+        if self.is_known(&public_key) {
+            tracing::error!("Key {public_key} was already enrolled");
+            return Err(ServerError::InvalidInvite);
+        }
+
+        let token_hash = sha256(&request.token);
+        let invite = self
+            .invites
+            .iter_mut()
+            .find(|i| i.token_hash == token_hash)
+            .ok_or(ServerError::InvalidInvite)?;
+        if invite.is_expired() || invite.is_used_up() {
+            tracing::error!("Invite {} is expired or used up", invite.id);
+            return Err(ServerError::InvalidInvite);
+        }
+        invite.uses += 1;
+
+        let label = invite
+            .label
+            .clone()
+            .unwrap_or_else(|| format!("invite-{}", invite.id));
+        let label = match invite.uses {
+            1 => label,
+            n => format!("{label}-{n}"),
+        };
+        let client = EnrolledClient { label, public_key };
+        self.clients.push(client.clone());
+        Ok((client, invite.address.clone()))
+    }
+}
+
+/// Admits clients that redeemed an invite, whichever `auth.kind` is set, and
+/// enrolls new ones. `roxi invite` edits the same file while the server runs,
+/// so each change goes through `InviteStore::update`.
+pub struct InviteManager {
+    path: PathBuf,
+    keys: RwLock<KeyAuthentication>,
+    revoked: RwLock<HashSet<Vec<u8>>>,
+}
+
+impl InviteManager {
+    pub fn new(path: PathBuf) -> ServerResult<Self> {
+        let store = InviteStore::load(&path)?;
+        Ok(Self {
+            keys: RwLock::new(KeyAuthentication::new(store.clients())),
+            revoked: RwLock::new(revoked_keys(&store)),
+            path,
+        })
+    }
+
+    /// Runs `InviteStore::update` off the async runtime, as it may wait for
+    /// `roxi invite` to let go of the file, then picks up the changed store.
+    async fn update<T, F>(&self, f: F) -> ServerResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut InviteStore) -> ServerResult<T> + Send + 'static,
+    {
+        let path = self.path.clone();
+        let (store, result) =
+            tokio::task::spawn_blocking(move || InviteStore::update(&path, f)).await??;
+        self.refresh(&store).await;
+        Ok(result)
+    }
+
+    async fn refresh(&self, store: &InviteStore) {
+        *self.keys.write().await = KeyAuthentication::new(store.clients());
+        *self.revoked.write().await = revoked_keys(store);
+    }
+
+    /// Whether `request` is signed with a key enrolled through an invite.
+    pub async fn is_enrolled(&self, request: &AuthenticationRequest) -> bool {
+        match request.public_key() {
+            Some(key) => self.keys.read().await.contains(key),
+            None => false,
+        }
+    }
+
+    /// Whether `request` is signed with a revoked key.
+    pub async fn is_revoked(&self, request: &AuthenticationRequest) -> bool {
+        match request.public_key() {
+            Some(key) => self.revoked.read().await.contains(key),
+            None => false,
+        }
+    }
+
+    pub async fn enroll(
+        &self,
+        request: &EnrollmentRequest,
+    ) -> ServerResult<(EnrolledClient, Option<String>)> {
+        let request = request.clone();
+        let enrolled = self.update(move |store| store.redeem(&request)).await?;
+        tracing::info!("Enrolled client {}", enrolled.0.label);
+        Ok(enrolled)
+    }
+
+    /// Revokes the client labelled `label`, whether it enrolled through an
+    /// invite or is one of the `listed` clients of the server config.
+    pub async fn revoke(
+        &self,
+        label: &str,
+        listed: &[EnrolledClient],
+    ) -> ServerResult<EnrolledClient> {
+        let (label, listed) = (label.to_string(), listed.to_vec());
+        let client = self
+            .update(move |store| {
+                let client = store
+                    .clients()
+                    .iter()
+                    .chain(listed.iter())
+                    .find(|c| c.label == label)
+                    .cloned()
+                    .ok_or(ServerError::UnknownClient(label))?;
+                store.revoke(client.clone());
+                Ok(client)
+            })
+            .await?;
+        tracing::info!("Revoked client {}", client.label);
+        Ok(client)
+    }
+
+    /// Replaces `old_public_key` with the key `request` was signed with, whether
+    /// it enrolled through an invite or is one of the `listed` clients of the
+    /// server config.
+    pub async fn rotate(
+        &self,
+        old_public_key: &[u8],
+        request: &KeyRotationRequest,
+        listed: &[EnrolledClient],
+    ) -> ServerResult<EnrolledClient> {
+        request
+            .verify(old_public_key)
+            .map_err(|_| ServerError::Unauthenticated)?;
+
+        let old_public_key = base64::encode(old_public_key);
+        let new_public_key = base64::encode(&request.public_key);
+        let listed = listed.to_vec();
+        let client = self
+            .update(move |store| store.rotate(&old_public_key, &new_public_key, &listed))
+            .await?;
+        tracing::info!("Client {} rotated its identity key", client.label);
+        Ok(client)
+    }
+
+    pub async fn authenticate(
+        &self,
+        origin: &ClientId,
+        request: &AuthenticationRequest,
+        challenge: &AuthenticationChallenge,
+    ) -> ServerResult<ClientId> {
+        self.keys
+            .read()
+            .await
+            .authenticate(origin, request, challenge)
+            .await
+    }
+}
+
+fn revoked_keys(store: &InviteStore) -> HashSet<Vec<u8>> {
+    store
+        .revoked()
+        .iter()
+        .filter_map(|c| decode_public_key(&c.public_key).ok())
+        .collect()
+}
+
+/// `path` with `.extension` appended.
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -3,16 +3,40 @@ pub(crate) mod config;
 pub(crate) mod error;
 pub(crate) mod gateway;
 pub(crate) mod handler;
+pub(crate) mod invite;
 pub(crate) mod ip;
+pub(crate) mod outbox;
+pub(crate) mod relay;
+pub(crate) mod seeder;
+pub(crate) mod selector;
 pub(crate) mod server;
 pub(crate) mod session;
+pub(crate) mod store;
+pub(crate) mod ticket;
 pub(crate) mod tun;
 
 pub type ServerResult<T> = core::result::Result<T, error::ServerError>;
 
-pub use config::Config;
+pub use auth::{
+    Authenticator, CommandAuthentication, KeyAuthentication, SharedKeyAuthentication,
+    TokenFileAuthentication,
+};
+pub use config::{AuthKind, Config, EnrolledClient, SelectorKind, StoreKind};
 pub use error::ServerError;
 pub use gateway::Gateway;
+pub use handler::{
+    Authenticated, Connection, Handler, HelloHandler, PingHandler, Privileged, Registry,
+    Reply, Service,
+};
+pub use invite::{Invite, InviteStore};
 pub use ip::IpPoolManager;
+pub use outbox::Outbox;
+pub use relay::RelayManager;
+pub use seeder::Seeder;
+pub use selector::{
+    AffinitySelector, GatewayCandidate, LeastLoadedSelector, LowestRttSelector,
+    PeerSelector, RandomSelector, SelectionRequest, Sticky,
+};
 pub use server::Server;
-pub use session::SessionManager;
+pub use session::{Session, SessionManager};
+pub use store::{MemoryStore, SledStore, Store};
@@ -0,0 +1,75 @@
+use crate::{error::ServerError, ServerResult};
+use futures::SinkExt;
+use roxi_proto::{Message, MessageSink};
+use tokio::{io::AsyncWriteExt, net::tcp::OwnedWriteHalf, sync::mpsc};
+
+/// How many messages may wait for a slow client before pushing to it waits.
+const OUTBOX_CAPACITY: usize = 64;
+
+enum Outbound {
+    Message(Message),
+    /// Header version of the messages queued after it.
+    Version(u8),
+    /// Closes the connection once everything queued before is written.
+    Close,
+}
+
+/// The queue of messages to a connected client.
+///
+/// A writer task owns the connection's write half and drains the queue, so
+/// replies and messages pushed from anywhere in the service, like relayed
+/// punch offers or shutdown notices, never wait on each other's locks.
+/// Cloning an outbox queues onto the same connection.
+#[derive(Clone)]
+pub struct Outbox {
+    tx: mpsc::Sender<Outbound>,
+}
+
+impl Outbox {
+    /// Starts the writer task of `sink`, which ends when the connection is
+    /// closed or every outbox onto it has been dropped.
+    pub fn spawn(sink: MessageSink<OwnedWriteHalf>) -> Self {
+        let (tx, rx) = mpsc::channel(OUTBOX_CAPACITY);
+        tokio::spawn(Self::write(sink, rx));
+        Self { tx }
+    }
+
+    async fn write(
+        mut sink: MessageSink<OwnedWriteHalf>,
+        mut rx: mpsc::Receiver<Outbound>,
+    ) {
+        while let Some(outbound) = rx.recv().await {
+            match outbound {
+                Outbound::Message(msg) => {
+                    if let Err(e) = sink.send(msg).await {
+                        tracing::error!("Failed to write to client: {e}");
+                        break;
+                    }
+                }
+                Outbound::Version(version) => sink.encoder_mut().set_version(version),
+                Outbound::Close => break,
+            }
+        }
+        let _ = AsyncWriteExt::shutdown(sink.get_mut()).await;
+    }
+
+    async fn queue(&self, outbound: Outbound) -> ServerResult<()> {
+        self.tx
+            .send(outbound)
+            .await
+            .map_err(|_| ServerError::ConnectionClosed)
+    }
+
+    pub async fn send(&self, msg: Message) -> ServerResult<()> {
+        self.queue(Outbound::Message(msg)).await
+    }
+
+    pub async fn set_version(&self, version: u8) -> ServerResult<()> {
+        self.queue(Outbound::Version(version)).await
+    }
+
+    /// Closes the connection after the messages already queued.
+    pub async fn close(&self) -> ServerResult<()> {
+        self.queue(Outbound::Close).await
+    }
+}
@@ -0,0 +1,265 @@
+use crate::{config::Config, error::ServerError, ServerResult};
+use async_std::sync::Arc;
+use roxi_lib::types::{Address, ClientId};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::RwLock,
+    task::JoinHandle,
+    time::{timeout, Duration, Instant},
+};
+
+/// Relays with no traffic for this long are torn down.
+const RELAY_IDLE_TIMEOUT_SECS: u64 = 300;
+
+const RELAY_BUFFER_SIZE: usize = 65535;
+
+struct Allocation {
+    peers: (IpAddr, IpAddr),
+    /// Whose tunnel it is, so that it is freed once either side is done.
+    clients: (ClientId, ClientId),
+    handle: JoinHandle<()>,
+}
+
+impl Allocation {
+    fn serves(&self, a: &ClientId, b: &ClientId) -> bool {
+        self.clients == (a.clone(), b.clone()) || self.clients == (b.clone(), a.clone())
+    }
+}
+
+/// Forwards UDP datagrams between two peers that could not punch a direct path.
+///
+/// Each tunnel gets its own port from the configured range, held until either
+/// side's session ends, the tunnel closes or it goes idle. Peers are told apart
+/// by IP address (their `ClientId`), and the port each one sends from is learned
+/// from its traffic, so WireGuard can talk to the relay directly even through a
+/// symmetric NAT. An empty datagram only registers its sender, so that a side
+/// can be forwarded to before its WireGuard has sent anything.
+pub struct RelayManager {
+    config: Config,
+    allocations: Arc<RwLock<HashMap<u16, Allocation>>>,
+}
+
+impl RelayManager {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            allocations: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.relay_enabled()
+    }
+
+    /// Allocates (or reuses) a relay port for a tunnel between the clients
+    /// `a` and `b` connecting from the given IPs, and returns the public relay
+    /// address both should use as their endpoint.
+    pub async fn allocate(
+        &self,
+        (client_a, a): (&ClientId, IpAddr),
+        (client_b, b): (&ClientId, IpAddr),
+    ) -> ServerResult<Address> {
+        if !self.is_enabled() {
+            return Err(ServerError::RelayDisabled);
+        }
+
+        let (a, b) = (a.to_canonical(), b.to_canonical());
+        if a == b {
+            return Err(ServerError::IndistinctRelayPeers);
+        }
+
+        let mut allocations = self.allocations.write().await;
+        if let Some(port) = allocations
+            .iter()
+            .find(|(_, alloc)| {
+                alloc.serves(client_a, client_b)
+                    && (alloc.peers == (a, b) || alloc.peers == (b, a))
+            })
+            .map(|(port, _)| *port)
+        {
+            return Ok(Address::try_from(self.config.relay_remote_addr(port))?);
+        }
+
+        for port in self.config.relay_ports() {
+            if allocations.contains_key(&port) {
+                continue;
+            }
+
+            let socket = match UdpSocket::bind(self.config.relay_addr(port)).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    tracing::warn!("Relay port {port} unavailable: {e}");
+                    continue;
+                }
+            };
+
+            let handle = tokio::spawn(forward(
+                socket,
+                port,
+                (a, b),
+                self.config.relay_max_bandwidth(),
+                Arc::clone(&self.allocations),
+            ));
+            allocations.insert(
+                port,
+                Allocation {
+                    peers: (a, b),
+                    clients: (client_a.clone(), client_b.clone()),
+                    handle,
+                },
+            );
+
+            tracing::info!("Relaying tunnel {a} <-> {b} via port {port}");
+            return Ok(Address::try_from(self.config.relay_remote_addr(port))?);
+        }
+
+        Err(ServerError::NoRelayPortsAvailable)
+    }
+
+    /// Stops relaying on `port`, returning once the port is free again.
+    pub async fn release(&self, port: u16) {
+        let alloc = self.allocations.write().await.remove(&port);
+        if let Some(alloc) = alloc {
+            tracing::info!("Releasing relay port {port}");
+            alloc.handle.abort();
+            let _ = alloc.handle.await;
+        }
+    }
+
+    /// Frees the port relaying the tunnel between `a` and `b`, if any.
+    pub async fn release_between(&self, a: &ClientId, b: &ClientId) {
+        let ports = self.ports(|alloc| alloc.serves(a, b)).await;
+        for port in ports {
+            self.release(port).await;
+        }
+    }
+
+    /// Frees the ports of every tunnel `client_id` is a side of.
+    pub async fn release_client(&self, client_id: &ClientId) {
+        let ports = self
+            .ports(|alloc| alloc.clients.0 == *client_id || alloc.clients.1 == *client_id)
+            .await;
+        for port in ports {
+            self.release(port).await;
+        }
+    }
+
+    async fn ports(&self, f: impl Fn(&Allocation) -> bool) -> Vec<u16> {
+        self.allocations
+            .read()
+            .await
+            .iter()
+            .filter(|(_, alloc)| f(alloc))
+            .map(|(port, _)| *port)
+            .collect()
+    }
+
+    pub async fn clear(&self) {
+        for (_, alloc) in self.allocations.write().await.drain() {
+            alloc.handle.abort();
+        }
+    }
+}
+
+/// Caps the bytes forwarded per one-second window.
+struct BandwidthLimiter {
+    cap: Option<u64>,
+    window: Instant,
+    used: u64,
+}
+
+impl BandwidthLimiter {
+    fn new(cap: Option<u64>) -> Self {
+        Self {
+            cap,
+            window: Instant::now(),
+            used: 0,
+        }
+    }
+
+    fn allow(&mut self, len: usize) -> bool {
+        let Some(cap) = self.cap else {
+            return true;
+        };
+
+        if self.window.elapsed() >= Duration::from_secs(1) {
+            self.window = Instant::now();
+            self.used = 0;
+        }
+
+        if self.used + len as u64 > cap {
+            return false;
+        }
+
+        self.used += len as u64;
+        true
+    }
+}
+
+async fn forward(
+    socket: UdpSocket,
+    port: u16,
+    peers: (IpAddr, IpAddr),
+    max_bandwidth: Option<u64>,
+    allocations: Arc<RwLock<HashMap<u16, Allocation>>>,
+) {
+    let mut endpoints: [Option<SocketAddr>; 2] = [None, None];
+    let mut limiter = BandwidthLimiter::new(max_bandwidth);
+    let mut buff = vec![0u8; RELAY_BUFFER_SIZE];
+
+    loop {
+        let (len, from) = match timeout(
+            Duration::from_secs(RELAY_IDLE_TIMEOUT_SECS),
+            socket.recv_from(&mut buff),
+        )
+        .await
+        {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => {
+                tracing::warn!("Relay port {port} receive error: {e}");
+                continue;
+            }
+            Err(_) => {
+                tracing::info!("Relay port {port} idle, releasing");
+                break;
+            }
+        };
+
+        let ip = from.ip().to_canonical();
+        let (src, dst) = if ip == peers.0 {
+            (0, 1)
+        } else if ip == peers.1 {
+            (1, 0)
+        } else {
+            tracing::debug!("Relay port {port} dropping datagram from stranger {from}");
+            continue;
+        };
+
+        if endpoints[src] != Some(from) {
+            tracing::info!("Relay port {port} learned endpoint {from}");
+            endpoints[src] = Some(from);
+        }
+
+        let Some(to) = endpoints[dst] else {
+            continue;
+        };
+        if len == 0 {
+            continue;
+        }
+
+        if !limiter.allow(len) {
+            tracing::debug!("Relay port {port} over bandwidth cap, dropping datagram");
+            continue;
+        }
+
+        if let Err(e) = socket.send_to(&buff[..len], to).await {
+            tracing::warn!("Relay port {port} failed to forward to {to}: {e}");
+        }
+    }
+
+    allocations.write().await.remove(&port);
+}
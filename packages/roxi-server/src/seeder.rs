@@ -0,0 +1,241 @@
+use crate::{gateway::Gateway, ServerResult};
+use async_std::sync::Arc;
+use futures::{stream::FuturesUnordered, StreamExt};
+use roxi_client::{Client, ClientError, Event};
+use roxi_lib::types::ClientId;
+use roxi_proto::{
+    GatewayInfo, GatewayLoad, MessageStatus, RelayInfo, WireGuardProtoKey,
+    WireGuardProtoPeer,
+};
+use std::{collections::HashMap, future};
+use tokio::{
+    sync::broadcast,
+    task::JoinHandle,
+    time::{self, Duration},
+};
+
+/// Keeps a client seeded and serves as the gateway of the peers the server
+/// assigns to it, launching the local `Gateway` with the first assignment.
+pub struct Seeder {
+    client: Client,
+    gateway: Option<(Arc<Gateway>, JoinHandle<()>)>,
+    /// The peers served, by the WireGuard key they announced.
+    peers: HashMap<String, ClientId>,
+}
+
+impl Seeder {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            gateway: None,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The local gateway, once a peer has been assigned.
+    pub fn gateway(&self) -> Option<&Arc<Gateway>> {
+        self.gateway.as_ref().map(|(gateway, _)| gateway)
+    }
+
+    /// How busy the local gateway is. A gateway that has not been launched
+    /// yet is idle, and one that stopped is unhealthy until it is relaunched.
+    pub async fn load(&self) -> GatewayLoad {
+        match &self.gateway {
+            Some((gateway, handle)) if !handle.is_finished() => gateway.load().await,
+            Some(_) => GatewayLoad {
+                max_clients: self.client.config().max_gateway_clients(),
+                healthy: false,
+                ..Default::default()
+            },
+            None => GatewayLoad {
+                max_clients: self.client.config().max_gateway_clients(),
+                healthy: true,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Seeds, then handles what the server sends until it shuts down or ends
+    /// the session. Each heartbeat reports the gateway's load, which also
+    /// keeps the session from going idle. Punch offers are answered alongside,
+    /// as their connectivity checks take several seconds.
+    pub async fn run(&mut self) -> ServerResult<()> {
+        let seed = self.client.seed().await?.ok_or(ClientError::NoResponse)?;
+        if *seed.status() != MessageStatus::r#Ok {
+            return Err(ClientError::UnexpectedStatus(*seed.status()).into());
+        }
+        tracing::info!("Seeded, waiting for gateway assignments");
+
+        let mut events = self.client.events().await?;
+        let mut heartbeat = time::interval(Duration::from_secs(
+            self.client.config().heartbeat_interval(),
+        ));
+        // The first tick completes immediately
+        heartbeat.tick().await;
+        let mut closed = None;
+        let mut punches = FuturesUnordered::new();
+
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(Event::Gateway(info)) => {
+                        let peer = info.peer.clone();
+                        if let Err(e) = self.serve(info).await {
+                            tracing::error!("Cannot serve as the gateway of {peer:?}: {e}");
+                        }
+                        if closed.is_none() {
+                            closed = self.gateway().map(|gateway| gateway.closed_tunnels());
+                        }
+                    }
+                    Some(Event::Punch(info)) => {
+                        match self.client.answer_punch_detached(info).await {
+                            Ok(punch) => punches.push(punch),
+                            Err(e) => tracing::warn!("Cannot answer NAT punch: {e}"),
+                        }
+                    }
+                    Some(Event::Relay(info)) => {
+                        let peer = info.peer.clone();
+                        if let Err(e) = self.relay(info).await {
+                            tracing::warn!("Cannot relay the tunnel of {peer:?}: {e}");
+                        }
+                    }
+                    Some(Event::SessionEnded(reason)) => {
+                        return Err(ClientError::SessionEnded(reason).into());
+                    }
+                    Some(Event::Shutdown) | None => {
+                        tracing::info!("Server closed the connection");
+                        return Ok(());
+                    }
+                    Some(event) => tracing::debug!("Ignoring {event:?}"),
+                },
+                Some(punched) = punches.next() => {
+                    if let Err(e) = punched {
+                        tracing::warn!("NAT punch failed: {e}");
+                    }
+                }
+                public_key = next_closed(&mut closed) => {
+                    if let Err(e) = self.tunnel_closed(&public_key).await {
+                        tracing::warn!("Cannot report a closed tunnel: {e}");
+                    }
+                }
+                _ = heartbeat.tick() => {
+                    let load = self.load().await;
+                    self.client.report_load(load).await?;
+                }
+            }
+        }
+    }
+
+    /// Makes the local gateway serve the peer in `info` and tells the server
+    /// once it does.
+    async fn serve(&mut self, info: GatewayInfo) -> ServerResult<()> {
+        tracing::info!("Assigned as the gateway of {:?}", info.peer);
+        let gateway = self.launch().await?;
+        match info.wireguard_public_key {
+            Some(key) => {
+                self.peers.insert(key.clone(), info.peer.clone());
+                gateway
+                    .assign_tunnel(
+                        &info.peer,
+                        WireGuardProtoPeer {
+                            public_key: WireGuardProtoKey::from_public(key),
+                            allowed_ips: "".to_string(),
+                            endpoint: None,
+                            persistent_keepalive: Some(1),
+                        },
+                    )
+                    .await?
+            }
+            None => tracing::warn!("{:?} announced no WireGuard key", info.peer),
+        }
+        self.client.report_ready(info.peer).await?;
+        Ok(())
+    }
+
+    /// Points the gateway's WireGuard at the relay the peer in `info` tunnels
+    /// through, and registers our WireGuard port with the relay.
+    async fn relay(&mut self, info: RelayInfo) -> ServerResult<()> {
+        let key = self
+            .peers
+            .iter()
+            .find(|(_, peer)| **peer == info.peer)
+            .map(|(key, _)| key.clone());
+        let endpoint = info.endpoint.to_string();
+        let relayed = match (key, self.gateway()) {
+            (Some(key), Some(gateway)) => gateway.set_endpoint(&key, endpoint).await?,
+            _ => false,
+        };
+        if !relayed {
+            tracing::warn!("Relayed tunnel of {:?}, whom we do not serve", info.peer);
+        }
+        self.client.join_relay(info).await?;
+        Ok(())
+    }
+
+    /// Tells the server that the peer whose tunnel used `public_key` is no
+    /// longer served.
+    async fn tunnel_closed(&mut self, public_key: &str) -> ServerResult<()> {
+        match self.peers.remove(public_key) {
+            Some(peer) => {
+                tracing::info!("No longer the gateway of {peer:?}");
+                self.client.report_tunnel_closed(peer).await?;
+            }
+            None => tracing::debug!("Closed tunnel of a peer the server did not assign"),
+        }
+        Ok(())
+    }
+
+    /// The local gateway, started if it is not running yet.
+    async fn launch(&mut self) -> ServerResult<Arc<Gateway>> {
+        if let Some((gateway, handle)) = &self.gateway {
+            if !handle.is_finished() {
+                return Ok(gateway.clone());
+            }
+            tracing::warn!("Gateway stopped, launching it again");
+        }
+        // Lets go of the listener of a stopped gateway before binding it again
+        self.gateway = None;
+
+        let gateway = Arc::new(Gateway::new(self.client.config().clone()).await?);
+        let handle = tokio::spawn({
+            let gateway = gateway.clone();
+            async move {
+                if let Err(e) = gateway.run().await {
+                    tracing::error!("Failed to run gateway server: {e}");
+                }
+            }
+        });
+        tracing::info!("Launched gateway");
+        self.gateway = Some((gateway.clone(), handle));
+        Ok(gateway)
+    }
+
+    pub async fn stop(&mut self) -> ServerResult<()> {
+        if let Some((gateway, handle)) = self.gateway.take() {
+            handle.abort();
+            gateway.stop().await?;
+        }
+        self.client.stop().await?;
+        Ok(())
+    }
+}
+
+/// The key of the next tunnel the gateway closes. Waits forever without a
+/// gateway, and lets go of the receiver once its gateway is gone so the next
+/// one can be subscribed to.
+async fn next_closed(closed: &mut Option<broadcast::Receiver<String>>) -> String {
+    while let Some(rx) = closed {
+        match rx.recv().await {
+            Ok(public_key) => return public_key,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                tracing::warn!("Missed {n} closed tunnels");
+            }
+            Err(broadcast::error::RecvError::Closed) => *closed = None,
+        }
+    }
+    future::pending().await
+}
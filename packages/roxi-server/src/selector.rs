@@ -0,0 +1,157 @@
+use crate::config::{Config, SelectorKind};
+use async_std::sync::Arc;
+use rand::{seq::SliceRandom, thread_rng};
+use roxi_lib::types::{Address, ClientId};
+use roxi_proto::GatewayLoad;
+use std::{cmp::Reverse, time::Duration};
+
+/// A seeder that could serve as a client's gateway.
+#[derive(Debug, Clone)]
+pub struct GatewayCandidate {
+    pub client_id: ClientId,
+    /// Where its gateway listens.
+    pub gateway: Address,
+    /// How many connected clients it already serves.
+    pub load: usize,
+    /// Round trip to the server, as last measured.
+    pub rtt: Option<Duration>,
+    pub tags: Vec<String>,
+    /// The load it last reported, if any.
+    pub report: Option<GatewayLoad>,
+}
+
+impl GatewayCandidate {
+    /// How many of `tags` the candidate shares.
+    pub fn shared_tags(&self, tags: &[String]) -> usize {
+        self.tags.iter().filter(|tag| tags.contains(tag)).count()
+    }
+}
+
+/// The client a gateway is picked for.
+#[derive(Debug, Clone, Copy)]
+pub struct SelectionRequest<'a> {
+    pub client_id: &'a ClientId,
+    /// Tags the client asked its gateway to match.
+    pub tags: &'a [String],
+    /// The peer that served as its gateway before, if any.
+    pub previous: Option<&'a ClientId>,
+}
+
+/// Decides which seeder serves as a client's gateway.
+pub trait PeerSelector: Send + Sync {
+    /// Picks one of `candidates`, none of which is the client itself, or none
+    /// if no candidate is acceptable.
+    fn select<'a>(
+        &self,
+        request: &SelectionRequest,
+        candidates: &'a [GatewayCandidate],
+    ) -> Option<&'a GatewayCandidate>;
+}
+
+/// Builds the selector chosen by `gateway_selection`.
+pub fn from_config(config: &Config) -> Arc<dyn PeerSelector> {
+    let sticky = config.gateway_selection_sticky();
+    match config.gateway_selection_policy() {
+        SelectorKind::Random => wrap(RandomSelector, sticky),
+        SelectorKind::LeastLoaded => wrap(LeastLoadedSelector, sticky),
+        SelectorKind::LowestRtt => wrap(LowestRttSelector, sticky),
+        SelectorKind::Affinity => wrap(AffinitySelector, sticky),
+    }
+}
+
+fn wrap<S: PeerSelector + 'static>(selector: S, sticky: bool) -> Arc<dyn PeerSelector> {
+    match sticky {
+        true => Arc::new(Sticky(selector)),
+        false => Arc::new(selector),
+    }
+}
+
+/// A random one of the candidates that rank lowest by `key`, so that equally
+/// good gateways share the clients.
+fn lowest_by<K: Ord>(
+    candidates: &[GatewayCandidate],
+    key: impl Fn(&GatewayCandidate) -> K,
+) -> Option<&GatewayCandidate> {
+    let best = candidates.iter().map(&key).min()?;
+    let ties = candidates
+        .iter()
+        .filter(|c| key(c) == best)
+        .collect::<Vec<&GatewayCandidate>>();
+    ties.choose(&mut thread_rng()).copied()
+}
+
+/// Any seeder.
+pub struct RandomSelector;
+
+impl PeerSelector for RandomSelector {
+    fn select<'a>(
+        &self,
+        _request: &SelectionRequest,
+        candidates: &'a [GatewayCandidate],
+    ) -> Option<&'a GatewayCandidate> {
+        candidates.choose(&mut thread_rng())
+    }
+}
+
+/// The seeder serving the fewest clients.
+pub struct LeastLoadedSelector;
+
+impl PeerSelector for LeastLoadedSelector {
+    fn select<'a>(
+        &self,
+        _request: &SelectionRequest,
+        candidates: &'a [GatewayCandidate],
+    ) -> Option<&'a GatewayCandidate> {
+        lowest_by(candidates, |c| c.load)
+    }
+}
+
+/// The seeder closest to the server, the least loaded among equals. Seeders
+/// without a measurement come last.
+pub struct LowestRttSelector;
+
+impl PeerSelector for LowestRttSelector {
+    fn select<'a>(
+        &self,
+        _request: &SelectionRequest,
+        candidates: &'a [GatewayCandidate],
+    ) -> Option<&'a GatewayCandidate> {
+        lowest_by(candidates, |c| (c.rtt.unwrap_or(Duration::MAX), c.load))
+    }
+}
+
+/// The seeder sharing the most tags with the client, the least loaded among
+/// equals.
+pub struct AffinitySelector;
+
+impl PeerSelector for AffinitySelector {
+    fn select<'a>(
+        &self,
+        request: &SelectionRequest,
+        candidates: &'a [GatewayCandidate],
+    ) -> Option<&'a GatewayCandidate> {
+        lowest_by(candidates, |c| {
+            (Reverse(c.shared_tags(request.tags)), c.load)
+        })
+    }
+}
+
+/// Keeps a client on the gateway it had while that one is a candidate, and
+/// leaves the pick to `S` otherwise.
+pub struct Sticky<S>(pub S);
+
+impl<S: PeerSelector> PeerSelector for Sticky<S> {
+    fn select<'a>(
+        &self,
+        request: &SelectionRequest,
+        candidates: &'a [GatewayCandidate],
+    ) -> Option<&'a GatewayCandidate> {
+        let previous = request
+            .previous
+            .and_then(|previous| candidates.iter().find(|c| c.client_id == *previous));
+        match previous {
+            Some(candidate) => Some(candidate),
+            None => self.0.select(request, candidates),
+        }
+    }
+}
@@ -1,29 +1,55 @@
-use crate::{config::Config, error::ServerError, session::SessionManager, ServerResult};
-use async_std::sync::Arc;
-use roxi_client::Config as ClientConfig;
-use roxi_lib::types::{ClientId, InterfaceKind, StunAddressKind, StunInfo};
-use roxi_proto::{Message, MessageKind, MessageStatus};
+use crate::{
+    auth::{self, Authenticator},
+    config::Config,
+    error::ServerError,
+    handler::{Authenticated, Connection, Handler, Privileged, Registry, Reply, Service},
+    outbox::Outbox,
+    relay::RelayManager,
+    session::SessionManager,
+    ServerResult,
+};
+use async_std::sync::{Arc, Weak};
+use async_trait::async_trait;
+use roxi_crypto::{decode_public_key, NoiseKeyPair};
+use roxi_lib::types::{ClientId, InterfaceKind, Ports, StunAddressKind, StunInfo};
+use roxi_proto::{
+    AuthenticationChallenge, AuthenticationRequest, Candidate, CandidateKind,
+    DisconnectReason, Enrollment, EnrollmentRequest, GatewayInfo, GatewayLoad,
+    GatewayReady, GatewayStatus, KeyRotationRequest, Message, MessageKind, MessageSink,
+    MessageStatus, PeerRevocation, PeerTunnelClosed, PunchInfo, PunchRequest, RelayInfo,
+    RelayRequest, ResumeRequest, RevokeRequest, SecureSession, SessionTicket,
+    StunAttribute, StunClass, StunMessage, StunMethod, PUNCH_TOKEN_LEN,
+};
 use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream, UdpSocket},
-    sync::{Mutex, RwLock, Semaphore},
-    time::{timeout, Duration},
+    sync::{RwLock, Semaphore},
+    time::{self, timeout, Duration, Instant},
 };
+use tokio_util::codec::FramedRead;
+
+/// How long a client has to answer an authentication challenge.
+const AUTH_CHALLENGE_TTL_SECS: u64 = 30;
 
-const STUN_BINDING_REQUEST: u16 = 0x0001;
+/// Token and relay candidate of a hole punch awaiting the peer's answer, and
+/// the ID of the request the answer goes back under.
+type PendingPunch = ([u8; PUNCH_TOKEN_LEN], Option<Candidate>, u32);
 
 pub struct Server {
     tcp: TcpListener,
     udp: UdpSocket,
     client_limit: Arc<Semaphore>,
     config: Config,
-    client_streams: Arc<RwLock<HashMap<ClientId, Arc<Mutex<TcpStream>>>>>,
+    keys: NoiseKeyPair,
+    client_streams: Arc<RwLock<HashMap<ClientId, Outbox>>>,
     sessions: SessionManager,
     stun: Arc<RwLock<HashMap<ClientId, StunInfo>>>,
+    relays: RelayManager,
+    punches: Arc<RwLock<HashMap<(ClientId, ClientId), PendingPunch>>>,
+    handlers: Registry<Self>,
 }
 
 impl Server {
@@ -32,254 +58,435 @@ impl Server {
     }
 
     pub async fn new(config: Config) -> ServerResult<Self> {
+        let auth = auth::from_config(&config)?;
+        Self::with_authenticator(config, auth).await
+    }
+
+    /// Starts a server that admits clients through `auth` instead of the source
+    /// named by `auth.kind`.
+    pub async fn with_authenticator(
+        config: Config,
+        auth: Arc<dyn Authenticator>,
+    ) -> ServerResult<Self> {
+        let keys = match config.private_key() {
+            Some(key) => NoiseKeyPair::try_from(key)?,
+            None => {
+                tracing::warn!("No private key configured, generating one for this run");
+                NoiseKeyPair::generate()?
+            }
+        };
+        tracing::info!("Control channel public key: {}", keys.public_base64());
+
         Ok(Self {
             tcp: TcpListener::bind(config.addr(InterfaceKind::Tcp)).await?,
             udp: UdpSocket::bind(config.addr(InterfaceKind::Udp)).await?,
             client_limit: Arc::new(Semaphore::new(config.max_clients().into())),
             config: config.clone(),
+            keys,
             client_streams: Arc::new(RwLock::new(HashMap::new())),
-            sessions: SessionManager::new(config),
+            sessions: SessionManager::with_authenticator(config.clone(), auth)?,
             stun: Arc::new(RwLock::new(HashMap::new())),
+            relays: RelayManager::new(config),
+            punches: Arc::new(RwLock::new(HashMap::new())),
+            handlers: handlers(),
         })
     }
 
-    pub async fn handle_conn(&self, stream: TcpStream) -> ServerResult<()> {
-        tracing::info!("Handling incoming tcp stream");
+    /// Base64 static key clients can pin to verify this listener.
+    pub fn public_key(&self) -> String {
+        self.keys.public_base64()
+    }
 
-        let client_id = ClientId::try_from(&stream)?;
-        let stream = Arc::new(Mutex::new(stream));
+    pub async fn handle_conn(&self, mut stream: TcpStream) -> ServerResult<()> {
+        tracing::info!("Handling incoming tcp stream");
 
-        loop {
-            let mut buff = vec![0u8; 1024];
-            let n = stream.lock().await.read(&mut buff).await?;
-            if n == 0 {
-                tracing::warn!("{client_id:?} connection closed");
-                break;
-            }
+        // Clients that authenticate with an identity key are re-keyed to it
+        let origin = ClientId::try_from(&stream)?;
+        let session = timeout(
+            Duration::from_secs(self.config.response_timeout()),
+            SecureSession::accept(&mut stream, &self.keys),
+        )
+        .await??;
+        // Only the holder of our own static key may administer the server
+        let admin = session.remote_static() == self.keys.public();
+        let codec = session.codec(self.config.max_frame_size());
+        let (reader, writer) = stream.into_split();
+        let reader = FramedRead::new(reader, codec.clone());
+        let stream = Outbox::spawn(MessageSink::new(writer, codec));
 
-            tracing::info!("Read {n} bytes");
+        let mut conn = Connection::new(origin, stream, admin);
+        self.handlers.serve(self, &mut conn, reader).await
+    }
 
-            let msg = Message::deserialize(&buff)?;
+    /// Revokes the enrolled client labelled `label`: its key is no longer
+    /// admitted, its session and connection are closed, and the gateways that
+    /// may have it as a WireGuard peer are told to drop it, even if it is
+    /// offline.
+    pub async fn revoke(&self, label: &str) -> ServerResult<ClientId> {
+        let client = self.sessions.revoke(label).await?;
+        let client_id =
+            ClientId::from_public_key(&decode_public_key(&client.public_key)?);
+        tracing::info!("Revoked {label} ({client_id:?})");
 
-            tracing::info!("Received message from {client_id:?}: {msg:?}");
+        self.sessions.remove(&client_id).await;
+        self.disconnect(&client_id, DisconnectReason::Revoked).await;
 
-            match msg.kind() {
-                MessageKind::Ping => {
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::Pong,
-                            MessageStatus::r#Ok,
-                            self.config.remote_addr(InterfaceKind::Tcp),
-                            None,
-                        ),
-                        stream.clone(),
+        let wireguard_public_key =
+            match self.sessions.last_wireguard_public_key(&client_id).await? {
+                Some(wireguard_public_key) => wireguard_public_key,
+                None => return Ok(client_id),
+            };
+        let revocation = PeerRevocation {
+            peer: client_id.clone(),
+            wireguard_public_key,
+        };
+        // One unreachable gateway must not hold up the others
+        let wait = Duration::from_secs(self.config.response_timeout());
+        let drops = self
+            .sessions
+            .gateways()
+            .await?
+            .into_iter()
+            .map(|(peer, gateway)| {
+                let revocation = &revocation;
+                async move {
+                    let gateway = gateway.to_string();
+                    let dropped = timeout(
+                        wait,
+                        roxi_client::drop_gateway_peer(&gateway, &self.keys, revocation),
                     )
-                    .await?;
-                }
-                MessageKind::AuthenticationRequest => {
-                    let config = ClientConfig::try_from(msg.data())?;
-                    if let Err(_e) = self.sessions.authenticate(&client_id, &config).await
-                    {
-                        self.send(
-                            &client_id,
-                            Message::new(
-                                MessageKind::AuthenticationResponse,
-                                MessageStatus::Unauthorized,
-                                self.config.remote_addr(InterfaceKind::Tcp),
-                                None,
-                            ),
-                            stream.clone(),
-                        )
-                        .await?;
-                        return Err(ServerError::Unauthenticated);
+                    .await
+                    .unwrap_or_else(|e| Err(e.into()));
+                    if let Err(e) = dropped {
+                        tracing::error!(
+                            "Failed to drop {label} from {peer:?}'s gateway: {e}"
+                        );
                     }
-
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::AuthenticationResponse,
-                            MessageStatus::r#Ok,
-                            self.config.remote_addr(InterfaceKind::Tcp),
-                            None,
-                        ),
-                        stream.clone(),
-                    )
-                    .await?;
-
-                    // TODO: Move stream caching into SeedRequest handler
-                    // as authenticating and becoming a peer should be different actions
-                    self.client_streams
-                        .write()
-                        .await
-                        .insert(client_id.clone(), stream.clone());
                 }
-                MessageKind::StunInfoRequest => {
-                    self.ensure_authenticated(
-                        &client_id,
-                        MessageKind::StunInfoResponse,
-                        stream.clone(),
-                    )
-                    .await?;
+            });
+        futures::future::join_all(drops).await;
 
-                    // FIXME: Do we need this?
-                }
-                MessageKind::GatewayRequest => {
-                    self.ensure_authenticated(
-                        &client_id,
-                        MessageKind::GatewayResponse,
-                        stream.clone(),
-                    )
-                    .await?;
+        Ok(client_id)
+    }
 
-                    let peer_addr =
-                        self.sessions.get_peer_for_gateway(&client_id).await?;
+    /// Pings the client on `conn` to time the round trip, which its `Pong`
+    /// completes (see `PongHandler`).
+    async fn probe_rtt(&self, conn: &mut Connection) -> ServerResult<()> {
+        let id = rand::random::<u32>().max(1);
+        conn.probe = Some((id, Instant::now()));
+        self.send(
+            &conn.client_id,
+            self.message(MessageKind::Ping, MessageStatus::Pending, None)
+                .with_id(id),
+            conn.stream.clone(),
+        )
+        .await
+    }
 
-                    let peer_client = ClientId::from(peer_addr.clone());
-                    tracing::info!(
-                        "Peer {peer_client:?} serving GatewayRequest from {client_id:?}"
-                    );
+    /// Tells a client why its session ended and closes its cached stream. The
+    /// relays of its tunnels are freed along with it.
+    async fn disconnect(&self, client_id: &ClientId, reason: DisconnectReason) {
+        self.relays.release_client(client_id).await;
+        let stream = match self.client_streams.write().await.remove(client_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+        let data = match bincode::serialize(&reason) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::error!("Failed to serialize {reason:?}: {e}");
+                return;
+            }
+        };
 
-                    let peer_stream = self
-                        .client_streams
-                        .read()
-                        .await
-                        .get(&peer_client)
-                        .unwrap()
-                        .clone();
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::GatewayResponse,
-                            MessageStatus::r#Ok,
-                            self.config.remote_addr(InterfaceKind::Tcp),
-                            None,
-                        ),
-                        stream.clone(),
-                    )
-                    .await?;
-
-                    self.send(
-                        &peer_client,
-                        Message::new(
-                            MessageKind::GatewayResponse,
-                            MessageStatus::r#Ok,
-                            self.config.remote_addr(InterfaceKind::Tcp),
-                            peer_addr.into(),
-                        ),
-                        peer_stream.clone(),
-                    )
-                    .await?;
-                }
-                MessageKind::SeedRequest => {
-                    self.ensure_authenticated(
-                        &client_id,
-                        MessageKind::SeedResponse,
-                        stream.clone(),
-                    )
-                    .await?;
-
-                    // TODO: Remove stream caching from AuthenticationRequest handler,
-                    // as becoming a seeder and simply authenticating should not be treated
-                    // as the same action
-                    self.client_streams
-                        .write()
-                        .await
-                        .insert(client_id.clone(), stream.clone());
-
-                    let clients = self.client_streams.read().await;
-                    tracing::info!("Seeded clients: {:?}", clients);
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::SeedResponse,
-                            MessageStatus::r#Ok,
-                            self.config.remote_addr(InterfaceKind::Tcp),
-                            None,
-                        ),
-                        stream.clone(),
-                    )
-                    .await?;
-                }
-                _ => {
-                    self.send(
-                        &client_id,
-                        Message::new(
-                            MessageKind::GenericErrorResponse,
-                            MessageStatus::BadData,
-                            self.config.remote_addr(InterfaceKind::Tcp),
-                            None,
-                        ),
-                        stream.clone(),
-                    )
-                    .await?;
-                    return Err(ServerError::InvalidMessage);
-                }
+        if let Err(e) = timeout(
+            Duration::from_secs(self.config.response_timeout()),
+            stream.send(self.message(
+                MessageKind::DisconnectSessionResponse,
+                reason.status(),
+                Some(data),
+            )),
+        )
+        .await
+        {
+            tracing::error!(
+                "{client_id:?} MessageKind::DisconnectSessionResponse timed out: {e}"
+            );
+        }
+        let _ = stream.close().await;
+    }
+
+    /// Queues `msg` for the connected client `client_id`. It is written out
+    /// by the connection's own writer, so this does not wait on the client.
+    pub async fn push(&self, client_id: &ClientId, msg: Message) -> ServerResult<()> {
+        let stream = self
+            .client_streams
+            .read()
+            .await
+            .get(client_id)
+            .cloned()
+            .ok_or_else(|| ServerError::NotConnected(client_id.clone()))?;
+        self.send(client_id, msg, stream).await
+    }
+
+    /// Ends expired and idle sessions, telling their clients why, for as long
+    /// as the server is around. It does not keep the server alive, so that a
+    /// stopped server lets go of its listeners and store.
+    async fn monitor(server: Weak<Self>) {
+        let period = match server.upgrade() {
+            Some(server) => server.sessions.monitor_interval(),
+            None => return,
+        };
+        let mut interval = time::interval(period);
+        loop {
+            interval.tick().await;
+            let server = match server.upgrade() {
+                Some(server) => server,
+                None => return,
+            };
+            for (client_id, reason) in server.sessions.cleanup().await {
+                server.disconnect(&client_id, reason).await;
             }
         }
+    }
 
-        Ok(())
+    /// Forwards a peer's hole punch offer to `request.peer`.
+    ///
+    /// The offered candidates are topped up with the reflexive address we observed
+    /// for the requester and, if the relay is enabled, a relay candidate shared by
+    /// both sides. The requester is answered once the peer sends its own candidates
+    /// back (see `answer_punch`).
+    async fn relay_punch(
+        &self,
+        client_id: &ClientId,
+        request_id: u32,
+        request: PunchRequest,
+    ) -> ServerResult<Reply<PunchInfo>> {
+        let peer = request.peer;
+        let peer_stream = self.client_streams.read().await.get(&peer).cloned();
+        let Some(peer_stream) = peer_stream else {
+            tracing::warn!("Cannot punch {client_id:?} -> {peer:?}: unknown peer");
+            return Ok(Reply::status(MessageStatus::NotFound));
+        };
+
+        let relay = if self.relays.is_enabled() {
+            let ip = self.origin_ip(client_id).await?;
+            let peer_ip = self.origin_ip(&peer).await?;
+            match self
+                .relays
+                .allocate((client_id, ip), (&peer, peer_ip))
+                .await
+            {
+                Ok(addr) => Some(Candidate::new(CandidateKind::Relay, addr, u16::MAX)),
+                Err(e) => {
+                    tracing::warn!(
+                        "No relay candidate for {client_id:?} -> {peer:?}: {e}"
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let token = rand::random::<[u8; PUNCH_TOKEN_LEN]>();
+        let candidates = self
+            .with_server_candidates(client_id, request.candidates, relay.clone())
+            .await;
+        tracing::info!("Punching {client_id:?} -> {peer:?} with {candidates:?}");
+
+        self.punches.write().await.insert(
+            (client_id.clone(), peer.clone()),
+            (token, relay, request_id),
+        );
+
+        self.send(
+            &peer,
+            self.message(
+                MessageKind::NATPunchRequest,
+                MessageStatus::Pending,
+                Some(bincode::serialize(&PunchInfo {
+                    peer: client_id.clone(),
+                    candidates,
+                    token,
+                })?),
+            ),
+            peer_stream,
+        )
+        .await?;
+        Ok(Reply::none())
     }
 
-    async fn ensure_authenticated(
+    /// Forwards a peer's answer to a hole punch offer back to whoever made it.
+    async fn answer_punch(
         &self,
         client_id: &ClientId,
-        kind: MessageKind,
-        stream: Arc<Mutex<TcpStream>>,
+        answer: PunchInfo,
     ) -> ServerResult<()> {
-        if !self.sessions.exists(client_id).await {
-            tracing::error!("Unauthenticated client: {client_id:?}");
-            self.send(
-                client_id,
-                Message::new(
-                    kind,
-                    MessageStatus::Unauthorized,
-                    self.config.remote_addr(InterfaceKind::Tcp),
-                    None,
-                ),
-                stream,
+        let key = (answer.peer.clone(), client_id.clone());
+        let (relay, request_id) = match self.punches.write().await.remove(&key) {
+            Some((token, relay, request_id)) if token == answer.token => {
+                (relay, request_id)
+            }
+            _ => {
+                tracing::warn!(
+                    "{client_id:?} answered unknown punch from {:?}",
+                    answer.peer
+                );
+                return Ok(());
+            }
+        };
+
+        let stream = self.client_streams.read().await.get(&answer.peer).cloned();
+        let Some(stream) = stream else {
+            tracing::warn!("{:?} went away before its punch was answered", answer.peer);
+            return Ok(());
+        };
+
+        let candidates = self
+            .with_server_candidates(client_id, answer.candidates, relay)
+            .await;
+        tracing::info!(
+            "Punching {client_id:?} -> {:?} with {candidates:?}",
+            answer.peer
+        );
+
+        self.send(
+            &answer.peer,
+            self.message(
+                MessageKind::NATPunchResponse,
+                MessageStatus::r#Ok,
+                Some(bincode::serialize(&PunchInfo {
+                    peer: client_id.clone(),
+                    candidates,
+                    token: answer.token,
+                })?),
             )
-            .await?;
+            .with_id(request_id),
+            stream,
+        )
+        .await
+    }
+
+    /// The address-derived ID `client_id` connected from, which is what STUN
+    /// bindings and relays know it by.
+    async fn origin(&self, client_id: &ClientId) -> ClientId {
+        self.sessions
+            .origin(client_id)
+            .await
+            .unwrap_or_else(|| client_id.clone())
+    }
+
+    async fn origin_ip(&self, client_id: &ClientId) -> ServerResult<IpAddr> {
+        Ok(self.origin(client_id).await.to_string().parse()?)
+    }
 
-            return Err(ServerError::Unauthenticated);
+    /// Adds the reflexive address observed for `client_id` (if the client did not
+    /// already offer it) and the relay candidate to a peer's candidates.
+    async fn with_server_candidates(
+        &self,
+        client_id: &ClientId,
+        mut candidates: Vec<Candidate>,
+        relay: Option<Candidate>,
+    ) -> Vec<Candidate> {
+        let origin = self.origin(client_id).await;
+        if let Some(info) = self.stun.read().await.get(&origin) {
+            let addr = info.addr();
+            if !candidates.iter().any(|c| c.addr == addr) {
+                candidates.push(Candidate::new(
+                    CandidateKind::ServerReflexive,
+                    addr,
+                    u16::MAX,
+                ));
+            }
         }
-        Ok(())
+
+        candidates.extend(relay);
+        candidates
     }
 
-    async fn send(
+    /// Sets up a relayed tunnel between two peers and hands both of them the relay
+    /// address, mirroring `relay_punch`.
+    async fn relay_tunnel(
         &self,
         client_id: &ClientId,
-        msg: Message,
-        stream: Arc<Mutex<TcpStream>>,
-    ) -> ServerResult<()> {
-        tracing::info!("Sending message to {client_id:?}: {msg:?}");
-        let data = msg.serialize()?;
-        let _ = stream.lock().await.write_all(&data).await;
-        Ok(())
+        peer: &ClientId,
+    ) -> ServerResult<Reply<RelayInfo>> {
+        let peer_stream = self.client_streams.read().await.get(peer).cloned();
+        let Some(peer_stream) = peer_stream else {
+            tracing::warn!("Cannot relay {client_id:?} -> {peer:?}: unknown peer");
+            return Ok(Reply::status(MessageStatus::NotFound));
+        };
+
+        let ip = self.origin_ip(client_id).await?;
+        let peer_ip = self.origin_ip(peer).await?;
+        let endpoint = match self.relays.allocate((client_id, ip), (peer, peer_ip)).await
+        {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                tracing::warn!("Cannot relay {client_id:?} -> {peer:?}: {e}");
+                return Ok(Reply::status(MessageStatus::ServiceUnavailable));
+            }
+        };
+
+        self.send(
+            peer,
+            self.message(
+                MessageKind::RelayRequest,
+                MessageStatus::Pending,
+                Some(bincode::serialize(&RelayInfo {
+                    peer: client_id.clone(),
+                    endpoint: endpoint.clone(),
+                })?),
+            ),
+            peer_stream,
+        )
+        .await?;
+
+        Ok(Reply::ok(RelayInfo {
+            peer: peer.clone(),
+            endpoint,
+        }))
     }
 
+    /// Answers a STUN Binding request with the reflexive address of the sender,
+    /// recording it so that peers can later be introduced to one another.
     pub async fn handle_udp(&self, buff: &[u8], addr: SocketAddr) -> ServerResult<()> {
-        let msg_type = u16::from_be_bytes([buff[0], buff[1]]);
-        if msg_type != STUN_BINDING_REQUEST {
-            tracing::warn!("Unrecognized stun request");
+        let request = match StunMessage::decode(buff) {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::warn!("Discarding malformed STUN packet from {addr}: {e}");
+                return Ok(());
+            }
+        };
+
+        if request.class() != StunClass::Request {
+            tracing::warn!("Ignoring STUN {:?} from {addr}", request.class());
             return Ok(());
         }
 
-        let ip = match addr.ip() {
-            IpAddr::V4(ip) => ip,
-            _ => {
-                tracing::warn!("Only Ipv4 supported at this time");
-                return Err(ServerError::UnsupportedIpAddrType);
-            }
-        };
+        let tid = *request.transaction_id();
+        let unknown = request.unknown_required_attributes();
+        let response = if !unknown.is_empty() {
+            StunMessage::error_response(request.method(), tid, 420, "Unknown Attribute")
+                .with_attribute(StunAttribute::UnknownAttributes(unknown))
+        } else if request.method() != StunMethod::Binding {
+            StunMessage::error_response(request.method(), tid, 400, "Bad Request")
+        } else {
+            let client_id = ClientId::from(&addr);
+            let info = StunInfo::new(
+                StunAddressKind::Public,
+                addr.ip().to_canonical(),
+                addr.port(),
+            );
 
-        let port = addr.port();
-        let client_id = ClientId::from(&addr);
-        let info = StunInfo::new(StunAddressKind::Public, ip, port);
+            tracing::info!("Adding stun info for {client_id:?}: {info:?}");
+            self.stun.write().await.insert(client_id, info);
 
-        tracing::info!("Adding stun info for {client_id:?}: {info:?}");
-        self.stun.write().await.insert(client_id, info);
+            StunMessage::binding_success(tid, addr)
+        };
 
+        self.udp.send_to(&response.encode(), addr).await?;
         Ok(())
     }
 
@@ -313,18 +520,15 @@ impl Server {
     /// For each incoming connection, it spawns a new task to process the connection
     /// using `handle_conn`. The server instance is shared across tasks using `Arc<Self>`.
     ///
-    /// Additionally, this method spawns a background task to monitor client sessions
-    /// using `sessions.monitor`.
+    /// Additionally, this method spawns a background task that ends expired and
+    /// idle client sessions.
     pub async fn run(self: Arc<Self>) -> ServerResult<()> {
         tracing::info!(
             "Roxi server listening at {}",
             self.config.addr(InterfaceKind::Tcp)
         );
 
-        let server = Arc::clone(&self);
-        tokio::spawn(async move {
-            server.sessions.monitor().await;
-        });
+        tokio::spawn(Self::monitor(Arc::downgrade(&self)));
 
         loop {
             let (stream, _) = self.tcp.accept().await?;
@@ -364,24 +568,13 @@ impl Server {
             for (client_id, stream) in clients.iter() {
                 tracing::info!("Closing connection for client: {:?}", client_id);
 
-                // FIXME: Trying to acquire a lock on the stream here causes lock contention due to
-                // `Server::handle_conn` accessing the lock infinitum, which (for now) allows clients
-                // to send multiple messages on a single stream. Might require a bit of rework later.
-                // <( '.' )>
-                let mut guard = stream.lock().await;
-
                 if let Err(e) = timeout(
                     Duration::from_secs(self.config.response_timeout()),
-                    self.send(
-                        client_id,
-                        Message::new(
-                            MessageKind::ServerShutdown,
-                            MessageStatus::ServiceUnavailable,
-                            self.config.remote_addr(InterfaceKind::Tcp),
-                            None,
-                        ),
-                        stream.clone(),
-                    ),
+                    stream.send(self.message(
+                        MessageKind::ServerShutdown,
+                        MessageStatus::ServiceUnavailable,
+                        None,
+                    )),
                 )
                 .await
                 {
@@ -390,13 +583,15 @@ impl Server {
                     );
                 }
 
-                let _ = AsyncWriteExt::shutdown(&mut *guard).await;
+                let _ = stream.close().await;
             }
             clients.clear();
         }
 
         self.sessions.clear().await?;
         self.stun.write().await.clear();
+        self.relays.clear().await;
+        self.punches.write().await.clear();
 
         drop(self.client_limit.clone());
 
@@ -404,3 +599,627 @@ impl Server {
         Ok(())
     }
 }
+
+#[async_trait]
+impl Service for Server {
+    fn addr(&self) -> String {
+        self.config.remote_addr(InterfaceKind::Tcp)
+    }
+
+    async fn is_authenticated(&self, conn: &Connection) -> bool {
+        self.sessions.exists(&conn.client_id).await
+    }
+
+    /// Any message, heartbeat pings included, keeps the session alive.
+    async fn received(&self, conn: &Connection) {
+        self.sessions.touch(&conn.client_id).await;
+    }
+}
+
+/// How the server answers each kind of message.
+fn handlers() -> Registry<Server> {
+    Registry::new()
+        .with(HeartbeatHandler)
+        .with(PongHandler)
+        .with(ChallengeHandler)
+        .with(AuthenticationHandler)
+        .with(ResumeHandler)
+        .with(EnrollHandler)
+        .with(Privileged(RevokeHandler))
+        .with(Authenticated(KeyRotationHandler))
+        .with(Authenticated(StunInfoHandler))
+        .with(Authenticated(GatewayHandler))
+        .with(Authenticated(GatewayReadyHandler))
+        .with(Authenticated(GatewayLoadHandler))
+        .with(Authenticated(PeerTunnelClosedHandler))
+        .with(Privileged(GatewayStatusHandler))
+        .with(Authenticated(PunchHandler))
+        .with(Authenticated(PunchAnswerHandler))
+        .with(Authenticated(RelayHandler))
+        .with(Authenticated(SeedHandler))
+}
+
+struct ChallengeHandler;
+
+#[async_trait]
+impl Handler<Server> for ChallengeHandler {
+    type Request = ();
+    type Response = AuthenticationChallenge;
+
+    const REQUEST: MessageKind = MessageKind::AuthenticationChallengeRequest;
+    const RESPONSE: MessageKind = MessageKind::AuthenticationChallengeResponse;
+
+    async fn handle(
+        &self,
+        _server: &Server,
+        conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<AuthenticationChallenge>> {
+        let issued = AuthenticationChallenge::new();
+        conn.challenge = Some((issued, Instant::now()));
+        Ok(Reply::ok(issued))
+    }
+}
+
+struct AuthenticationHandler;
+
+#[async_trait]
+impl Handler<Server> for AuthenticationHandler {
+    type Request = AuthenticationRequest;
+    type Response = SessionTicket;
+
+    const REQUEST: MessageKind = MessageKind::AuthenticationRequest;
+    const RESPONSE: MessageKind = MessageKind::AuthenticationResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        request: AuthenticationRequest,
+    ) -> ServerResult<Reply<SessionTicket>> {
+        conn.client_id = match conn.challenge.take() {
+            Some((issued, at))
+                if at.elapsed() < Duration::from_secs(AUTH_CHALLENGE_TTL_SECS) =>
+            {
+                server
+                    .sessions
+                    .authenticate(&conn.origin, &request, &issued)
+                    .await?
+            }
+            _ => {
+                tracing::error!("{:?} has no outstanding challenge", conn.client_id);
+                return Err(ServerError::Unauthenticated);
+            }
+        };
+
+        // TODO: Move stream caching into SeedRequest handler
+        // as authenticating and becoming a peer should be different actions
+        server
+            .client_streams
+            .write()
+            .await
+            .insert(conn.client_id.clone(), conn.stream.clone());
+        server.probe_rtt(conn).await?;
+        Ok(Reply::ok(server.sessions.ticket(&conn.client_id).await?))
+    }
+}
+
+/// Answers a client's heartbeat and, once it is authenticated, pings it back
+/// to keep its round trip time current.
+struct HeartbeatHandler;
+
+#[async_trait]
+impl Handler<Server> for HeartbeatHandler {
+    type Request = ();
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::Ping;
+    const RESPONSE: MessageKind = MessageKind::Pong;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<()>> {
+        if server.sessions.exists(&conn.client_id).await {
+            server.probe_rtt(conn).await?;
+        }
+        Ok(Reply::status(MessageStatus::r#Ok))
+    }
+}
+
+/// Records the round trip of the ping a client answers.
+struct PongHandler;
+
+#[async_trait]
+impl Handler<Server> for PongHandler {
+    type Request = ();
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::Pong;
+    const RESPONSE: MessageKind = MessageKind::Pong;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<()>> {
+        match conn.probe.take() {
+            Some((id, at)) if id == conn.request_id => {
+                server.sessions.set_rtt(&conn.client_id, at.elapsed()).await;
+            }
+            probe => {
+                // An answer to a ping that was since replaced
+                tracing::debug!("{:?} answered a stale ping", conn.client_id);
+                conn.probe = probe;
+            }
+        }
+        Ok(Reply::none())
+    }
+}
+
+struct ResumeHandler;
+
+#[async_trait]
+impl Handler<Server> for ResumeHandler {
+    type Request = ResumeRequest;
+    type Response = SessionTicket;
+
+    const REQUEST: MessageKind = MessageKind::ResumeRequest;
+    const RESPONSE: MessageKind = MessageKind::ResumeResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        request: ResumeRequest,
+    ) -> ServerResult<Reply<SessionTicket>> {
+        conn.client_id = match server.sessions.resume(&conn.origin, &request.token).await
+        {
+            Ok(id) => id,
+            Err(e) => {
+                // The client can still authenticate in full on this connection
+                tracing::error!("{:?} failed to resume: {e}", conn.client_id);
+                return Ok(Reply::status(MessageStatus::Unauthorized));
+            }
+        };
+
+        server
+            .client_streams
+            .write()
+            .await
+            .insert(conn.client_id.clone(), conn.stream.clone());
+        Ok(Reply::ok(server.sessions.ticket(&conn.client_id).await?))
+    }
+}
+
+struct EnrollHandler;
+
+#[async_trait]
+impl Handler<Server> for EnrollHandler {
+    type Request = EnrollmentRequest;
+    type Response = Enrollment;
+
+    const REQUEST: MessageKind = MessageKind::EnrollRequest;
+    const RESPONSE: MessageKind = MessageKind::EnrollResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        request: EnrollmentRequest,
+    ) -> ServerResult<Reply<Enrollment>> {
+        let (client, address) = match server.sessions.enroll(&request).await {
+            Ok(enrolled) => enrolled,
+            Err(e) => {
+                tracing::error!("{:?} failed to enroll: {e}", conn.client_id);
+                return Err(ServerError::InvalidInvite);
+            }
+        };
+
+        let tcp: SocketAddr = server.config.remote_addr(InterfaceKind::Tcp).parse()?;
+        let udp: SocketAddr = server.config.remote_addr(InterfaceKind::Udp).parse()?;
+        Ok(Reply::with_body(
+            MessageStatus::Created,
+            Enrollment {
+                label: client.label,
+                server_ip: tcp.ip(),
+                server_ports: Ports {
+                    tcp: tcp.port(),
+                    udp: udp.port(),
+                },
+                // A generated key changes each run, so is not worth pinning
+                server_public_key: server
+                    .config
+                    .private_key()
+                    .map(|_| server.public_key()),
+                response_timeout: server.config.response_timeout(),
+                address,
+            },
+        ))
+    }
+}
+
+struct RevokeHandler;
+
+#[async_trait]
+impl Handler<Server> for RevokeHandler {
+    type Request = RevokeRequest;
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::RevokeRequest;
+    const RESPONSE: MessageKind = MessageKind::RevokeResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        _conn: &mut Connection,
+        request: RevokeRequest,
+    ) -> ServerResult<Reply<()>> {
+        let status = match server.revoke(&request.label).await {
+            Ok(_) => MessageStatus::r#Ok,
+            Err(ServerError::UnknownClient(label)) => {
+                tracing::error!("No enrolled client labelled {label}");
+                MessageStatus::NotFound
+            }
+            Err(e) => {
+                tracing::error!("Failed to revoke {}: {e}", request.label);
+                MessageStatus::InternalServerError
+            }
+        };
+        Ok(Reply::status(status))
+    }
+}
+
+struct KeyRotationHandler;
+
+#[async_trait]
+impl Handler<Server> for KeyRotationHandler {
+    type Request = KeyRotationRequest;
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::KeyRotationRequest;
+    const RESPONSE: MessageKind = MessageKind::KeyRotationResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        request: KeyRotationRequest,
+    ) -> ServerResult<Reply<()>> {
+        if let Err(e) = server.sessions.rotate(&conn.client_id, &request).await {
+            tracing::error!("{:?} failed to rotate its key: {e}", conn.client_id);
+            return Ok(Reply::status(MessageStatus::Unauthorized));
+        }
+
+        // The session belonged to the old key, so the client has to
+        // authenticate afresh with the new one
+        server.sessions.remove(&conn.client_id).await;
+        server.client_streams.write().await.remove(&conn.client_id);
+        server.relays.release_client(&conn.client_id).await;
+        Ok(Reply::status(MessageStatus::r#Ok).close())
+    }
+}
+
+struct StunInfoHandler;
+
+#[async_trait]
+impl Handler<Server> for StunInfoHandler {
+    type Request = ();
+    type Response = StunInfo;
+
+    const REQUEST: MessageKind = MessageKind::StunInfoRequest;
+    const RESPONSE: MessageKind = MessageKind::StunInfoResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<StunInfo>> {
+        match server.stun.read().await.get(&conn.origin).cloned() {
+            Some(info) => Ok(Reply::ok(info)),
+            None => {
+                tracing::warn!("No STUN binding seen for {:?}", conn.client_id);
+                Ok(Reply::status(MessageStatus::NotFound))
+            }
+        }
+    }
+}
+
+/// Picks a peer to serve as the client's gateway and introduces the two.
+struct GatewayHandler;
+
+#[async_trait]
+impl Handler<Server> for GatewayHandler {
+    type Request = ();
+    type Response = GatewayInfo;
+
+    const REQUEST: MessageKind = MessageKind::GatewayRequest;
+    const RESPONSE: MessageKind = MessageKind::GatewayResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<GatewayInfo>> {
+        let client_id = &conn.client_id;
+        let (peer_client, peer_addr) =
+            server.sessions.select_peer_for_gateway(client_id).await?;
+        tracing::info!("Peer {peer_client:?} serving GatewayRequest from {client_id:?}");
+        server.sessions.assign(client_id, &peer_client).await?;
+
+        let peer_stream = server
+            .client_streams
+            .read()
+            .await
+            .get(&peer_client)
+            .cloned()
+            .ok_or(ServerError::NoAvailablePeers)?;
+        let gateway = server
+            .sessions
+            .gateway(client_id)
+            .await
+            .ok_or(ServerError::Unauthenticated)?;
+        server
+            .send(
+                &peer_client,
+                server.message(
+                    MessageKind::GatewayResponse,
+                    MessageStatus::r#Ok,
+                    Some(bincode::serialize(&GatewayInfo {
+                        peer: client_id.clone(),
+                        gateway,
+                        wireguard_public_key: server
+                            .sessions
+                            .wireguard_public_key(client_id)
+                            .await,
+                        gateway_public_key: server
+                            .sessions
+                            .gateway_public_key(client_id)
+                            .await,
+                    })?),
+                ),
+                peer_stream,
+            )
+            .await?;
+
+        Ok(Reply::ok(GatewayInfo {
+            wireguard_public_key: server
+                .sessions
+                .wireguard_public_key(&peer_client)
+                .await,
+            gateway_public_key: server.sessions.gateway_public_key(&peer_client).await,
+            peer: peer_client,
+            gateway: peer_addr,
+        }))
+    }
+}
+
+/// Takes a seeder's word that its gateway serves a peer it was assigned, and
+/// passes it on to that peer.
+struct GatewayReadyHandler;
+
+#[async_trait]
+impl Handler<Server> for GatewayReadyHandler {
+    type Request = GatewayReady;
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::GatewayReadyRequest;
+    const RESPONSE: MessageKind = MessageKind::GatewayReadyResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        ready: GatewayReady,
+    ) -> ServerResult<Reply<()>> {
+        if server.sessions.assignment(&ready.peer).await?.as_ref()
+            != Some(&conn.client_id)
+        {
+            tracing::error!(
+                "{:?} is not the gateway of {:?}",
+                conn.client_id,
+                ready.peer
+            );
+            return Ok(Reply::status(MessageStatus::Forbidden));
+        }
+
+        tracing::info!("{:?} is ready to serve {:?}", conn.client_id, ready.peer);
+        let msg = server.message(
+            MessageKind::GatewayReadyResponse,
+            MessageStatus::r#Ok,
+            Some(bincode::serialize(&GatewayReady {
+                peer: conn.client_id.clone(),
+            })?),
+        );
+        if let Err(e) = server.push(&ready.peer, msg).await {
+            tracing::warn!("Cannot tell {:?} its gateway is ready: {e}", ready.peer);
+        }
+        Ok(Reply::status(MessageStatus::r#Ok))
+    }
+}
+
+/// Takes a seeder's word that its gateway stopped serving a peer it was
+/// assigned, so the peer is no longer counted against it or kept on it.
+struct PeerTunnelClosedHandler;
+
+#[async_trait]
+impl Handler<Server> for PeerTunnelClosedHandler {
+    type Request = PeerTunnelClosed;
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::PeerTunnelClosedRequest;
+    const RESPONSE: MessageKind = MessageKind::PeerTunnelClosedResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        closed: PeerTunnelClosed,
+    ) -> ServerResult<Reply<()>> {
+        if server.sessions.assignment(&closed.peer).await?.as_ref()
+            != Some(&conn.client_id)
+        {
+            tracing::error!(
+                "{:?} is not the gateway of {:?}",
+                conn.client_id,
+                closed.peer
+            );
+            return Ok(Reply::status(MessageStatus::Forbidden));
+        }
+
+        tracing::info!("{:?} no longer serves {:?}", conn.client_id, closed.peer);
+        server.sessions.release(&closed.peer).await?;
+        server
+            .relays
+            .release_between(&closed.peer, &conn.client_id)
+            .await;
+        Ok(Reply::status(MessageStatus::r#Ok))
+    }
+}
+
+/// Records how busy a seeder's gateway is.
+struct GatewayLoadHandler;
+
+#[async_trait]
+impl Handler<Server> for GatewayLoadHandler {
+    type Request = GatewayLoad;
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::GatewayLoadRequest;
+    const RESPONSE: MessageKind = MessageKind::GatewayLoadResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        load: GatewayLoad,
+    ) -> ServerResult<Reply<()>> {
+        if !load.is_available() {
+            tracing::warn!("{:?} cannot take more peers: {load:?}", conn.client_id);
+        }
+        server.sessions.set_load(&conn.client_id, load).await?;
+        Ok(Reply::status(MessageStatus::r#Ok))
+    }
+}
+
+/// Lists the seeders and their load for the operator.
+struct GatewayStatusHandler;
+
+#[async_trait]
+impl Handler<Server> for GatewayStatusHandler {
+    type Request = ();
+    type Response = Vec<GatewayStatus>;
+
+    const REQUEST: MessageKind = MessageKind::GatewayStatusRequest;
+    const RESPONSE: MessageKind = MessageKind::GatewayStatusResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        _conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<Vec<GatewayStatus>>> {
+        Ok(Reply::ok(server.sessions.gateway_statuses().await?))
+    }
+}
+
+struct PunchHandler;
+
+#[async_trait]
+impl Handler<Server> for PunchHandler {
+    type Request = PunchRequest;
+    type Response = PunchInfo;
+
+    const REQUEST: MessageKind = MessageKind::NATPunchRequest;
+    const RESPONSE: MessageKind = MessageKind::NATPunchResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        request: PunchRequest,
+    ) -> ServerResult<Reply<PunchInfo>> {
+        server
+            .relay_punch(&conn.client_id, conn.request_id, request)
+            .await
+    }
+}
+
+/// Takes a peer's answer to a hole punch offer back to whoever made it.
+struct PunchAnswerHandler;
+
+#[async_trait]
+impl Handler<Server> for PunchAnswerHandler {
+    type Request = PunchInfo;
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::NATPunchResponse;
+    const RESPONSE: MessageKind = MessageKind::NATPunchResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        answer: PunchInfo,
+    ) -> ServerResult<Reply<()>> {
+        server.answer_punch(&conn.client_id, answer).await?;
+        Ok(Reply::none())
+    }
+}
+
+struct RelayHandler;
+
+#[async_trait]
+impl Handler<Server> for RelayHandler {
+    type Request = RelayRequest;
+    type Response = RelayInfo;
+
+    const REQUEST: MessageKind = MessageKind::RelayRequest;
+    const RESPONSE: MessageKind = MessageKind::RelayResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        request: RelayRequest,
+    ) -> ServerResult<Reply<RelayInfo>> {
+        server.relay_tunnel(&conn.client_id, &request.peer).await
+    }
+}
+
+struct SeedHandler;
+
+#[async_trait]
+impl Handler<Server> for SeedHandler {
+    type Request = ();
+    type Response = ();
+
+    const REQUEST: MessageKind = MessageKind::SeedRequest;
+    const RESPONSE: MessageKind = MessageKind::SeedResponse;
+
+    async fn handle(
+        &self,
+        server: &Server,
+        conn: &mut Connection,
+        _: (),
+    ) -> ServerResult<Reply<()>> {
+        // TODO: Remove stream caching from AuthenticationRequest handler,
+        // as becoming a seeder and simply authenticating should not be treated
+        // as the same action
+        server
+            .client_streams
+            .write()
+            .await
+            .insert(conn.client_id.clone(), conn.stream.clone());
+        server.sessions.set_seeded(&conn.client_id).await?;
+
+        let clients = server.client_streams.read().await;
+        tracing::info!("Seeded clients: {:?}", clients.keys());
+        Ok(Reply::status(MessageStatus::r#Ok))
+    }
+}
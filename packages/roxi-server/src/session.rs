@@ -1,111 +1,599 @@
 use crate::{
-    auth::SharedKeyAuthentication, config::Config as ServerConfig, error::ServerError,
+    auth::{self, Authenticator},
+    config::{Config as ServerConfig, EnrolledClient},
+    error::ServerError,
+    invite::InviteManager,
+    selector::{self, GatewayCandidate, PeerSelector, SelectionRequest},
+    store::{self, Store},
+    ticket::TicketIssuer,
     ServerResult,
 };
 use async_std::sync::{Arc, RwLock};
-use rand::{seq::SliceRandom, thread_rng};
-use roxi_client::Config as ClientConfig;
-use roxi_lib::types::{Address, ClientId, InterfaceKind};
+use roxi_lib::types::{Address, ClientId};
+use roxi_proto::{
+    AuthenticationChallenge, AuthenticationRequest, DisconnectReason, EnrollmentRequest,
+    GatewayLoad, GatewayStatus, KeyRotationRequest, SessionTicket,
+};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::time::SystemTime;
-use tokio::time::{self, Duration};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Duration;
+
+/// Upper bound on how often sessions are checked for expiry.
+const SESSION_MONITOR_INTERVAL_SECS: u64 = 30;
 
-#[derive(Debug, Hash, Clone)]
+#[derive(Debug, Serialize, Deserialize, Hash, Clone)]
 pub struct Session {
     time: SystemTime,
+    last_seen: SystemTime,
     expiry: Duration,
-    config: ClientConfig,
+    idle_timeout: Duration,
+    request: AuthenticationRequest,
+    /// The address-derived ID of the connection the client authenticated on.
+    origin: ClientId,
+    seeded: bool,
+    /// Round trip to the client, measured by pinging it when it authenticates
+    /// and on each of its heartbeats.
+    #[serde(skip)]
+    rtt: Option<Duration>,
+    /// The last load its gateway reported, if it seeds.
+    #[serde(skip)]
+    load: Option<GatewayLoad>,
 }
 
 impl Session {
-    pub fn new(session_ttl: u64, config: &ClientConfig) -> Self {
+    pub fn new(
+        session_ttl: u64,
+        idle_timeout: u64,
+        request: &AuthenticationRequest,
+        origin: &ClientId,
+    ) -> Self {
+        let now = SystemTime::now();
         Self {
-            time: SystemTime::now(),
-            config: config.clone(),
+            time: now,
+            last_seen: now,
+            request: request.clone(),
+            origin: origin.clone(),
             expiry: Duration::new(session_ttl, 0),
+            idle_timeout: Duration::new(idle_timeout, 0),
+            seeded: false,
+            rtt: None,
+            load: None,
         }
     }
 
+    /// Unix seconds at which the session expires.
+    pub fn expires_at(&self) -> u64 {
+        (self.time + self.expiry)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    pub fn is_seeded(&self) -> bool {
+        self.seeded
+    }
+
+    /// Records that the client was just heard from.
+    pub fn touch(&mut self) {
+        self.last_seen = SystemTime::now();
+    }
+
     pub fn is_idle(&self) -> bool {
-        // TODO: Implement this
-        false
+        self.last_seen.elapsed().unwrap_or_default() > self.idle_timeout
     }
 
     pub fn gateway_remote_addr(&self) -> ServerResult<Address> {
-        let addr =
-            Address::try_from(self.config.gateway_remote_addr(InterfaceKind::Tcp))?;
-        Ok(addr)
+        Ok(self.request.gateway.clone())
+    }
+
+    pub fn request(&self) -> &AuthenticationRequest {
+        &self.request
     }
 
-    #[allow(unused)]
     pub fn expired(&self) -> bool {
         self.time.elapsed().unwrap_or_default() > self.expiry
     }
+
+    /// Why the session should be ended now, if it should.
+    pub fn end_reason(&self) -> Option<DisconnectReason> {
+        if self.expired() {
+            Some(DisconnectReason::Expired)
+        } else if self.is_idle() {
+            Some(DisconnectReason::Idle)
+        } else {
+            None
+        }
+    }
 }
 
 pub struct SessionManager {
     sessions: Arc<RwLock<HashMap<ClientId, Session>>>,
+    /// Idle sessions a client may still resume with its ticket until they expire.
+    suspended: Arc<RwLock<HashMap<ClientId, Session>>>,
+    tickets: TicketIssuer,
+    store: Arc<dyn Store>,
     config: ServerConfig,
-    auth: SharedKeyAuthentication,
+    auth: Arc<dyn Authenticator>,
+    invites: InviteManager,
+    selector: Arc<dyn PeerSelector>,
 }
 
 impl SessionManager {
-    pub fn new(config: ServerConfig) -> Self {
-        let key = config.shared_key();
-        Self {
+    /// Authenticates clients with the source selected by `auth.kind`.
+    pub fn new(config: ServerConfig) -> ServerResult<Self> {
+        let auth = auth::from_config(&config)?;
+        Self::with_authenticator(config, auth)
+    }
+
+    pub fn with_authenticator(
+        config: ServerConfig,
+        auth: Arc<dyn Authenticator>,
+    ) -> ServerResult<Self> {
+        let store = store::from_config(&config)?;
+        Self::with_store(config, auth, store)
+    }
+
+    /// Keeps sessions in `store` instead of the one selected by `store.kind`.
+    /// Sessions left in it by an earlier run are suspended until their clients
+    /// resume them.
+    pub fn with_store(
+        config: ServerConfig,
+        auth: Arc<dyn Authenticator>,
+        store: Arc<dyn Store>,
+    ) -> ServerResult<Self> {
+        let mut suspended = HashMap::new();
+        for (client_id, session) in store.sessions()? {
+            match session.expired() {
+                true => {
+                    store.remove_session(&client_id)?;
+                    store.unassign(&client_id)?;
+                }
+                false => {
+                    suspended.insert(client_id, session);
+                }
+            }
+        }
+        if !suspended.is_empty() {
+            tracing::info!("Restored {} sessions from the store", suspended.len());
+        }
+
+        Ok(Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
+            suspended: Arc::new(RwLock::new(suspended)),
+            tickets: TicketIssuer::new(store.ticket_key()?),
+            invites: InviteManager::new(config.invites_path())?,
+            selector: selector::from_config(&config),
+            store,
             config,
-            auth: SharedKeyAuthentication::new(&key),
-        }
+            auth,
+        })
     }
 
+    /// Picks gateways with `selector` instead of the policy selected by
+    /// `gateway_selection`.
+    pub fn with_selector(mut self, selector: Arc<dyn PeerSelector>) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    /// Admits the client connected as `origin` and returns the ID its session is
+    /// kept under, as decided by the authenticator. Clients enrolled through an
+    /// invite are admitted by their identity key whichever authenticator is set.
     pub async fn authenticate(
         &self,
-        client_id: &ClientId,
-        client_config: &ClientConfig,
-    ) -> ServerResult<()> {
-        if let Err(e) = self.auth.authenticate(&client_config.shared_key()) {
-            tracing::error!("Failed to authenticate client({client_id}): {e}");
+        origin: &ClientId,
+        request: &AuthenticationRequest,
+        challenge: &AuthenticationChallenge,
+    ) -> ServerResult<ClientId> {
+        if self.invites.is_revoked(request).await {
+            tracing::error!("Client({origin}) authenticated with a revoked key");
             return Err(ServerError::Unauthenticated);
         }
 
+        let result = match self.invites.is_enrolled(request).await {
+            true => self.invites.authenticate(origin, request, challenge).await,
+            false => self.auth.authenticate(origin, request, challenge).await,
+        };
+
+        let client_id = match result {
+            Ok(client_id) => client_id,
+            Err(e) => {
+                tracing::error!("Failed to authenticate client({origin}): {e}");
+                return Err(ServerError::Unauthenticated);
+            }
+        };
+
         tracing::info!("{client_id:?} authenticated. Adding to sessions");
-        self.sessions.write().await.insert(
+        let session = Session::new(
+            self.config.session_ttl(),
+            self.config.session_idle_timeout(),
+            request,
+            origin,
+        );
+        let (id, saved, key) = (
             client_id.clone(),
-            Session::new(self.config.session_ttl(), client_config),
+            session.clone(),
+            request.wireguard_public_key.clone(),
         );
-        Ok(())
+        self.on_store(move |store| {
+            store.save_session(&id, &saved)?;
+            match key {
+                Some(key) => store.save_wireguard_key(&id, &key),
+                None => Ok(()),
+            }
+        })
+        .await?;
+        self.suspended.write().await.remove(&client_id);
+        self.sessions
+            .write()
+            .await
+            .insert(client_id.clone(), session);
+        Ok(client_id)
     }
 
-    pub async fn exists(&self, client_id: &ClientId) -> bool {
-        self.sessions.read().await.contains_key(client_id)
+    /// A ticket the client of `client_id` can resume its session with later.
+    pub async fn ticket(&self, client_id: &ClientId) -> ServerResult<SessionTicket> {
+        let expires_at = self
+            .sessions
+            .read()
+            .await
+            .get(client_id)
+            .map(|s| s.expires_at())
+            .ok_or(ServerError::Unauthenticated)?;
+        self.tickets.issue(client_id, expires_at)
     }
 
-    pub async fn get_peer_for_gateway(&self, other: &ClientId) -> ServerResult<Address> {
-        let items = self
+    /// Picks the session `token` was issued for back up on the connection
+    /// identified as `origin`, returning the ID it is kept under. Sessions that
+    /// went idle are restored, seeded status included, until they expire.
+    pub async fn resume(
+        &self,
+        origin: &ClientId,
+        token: &[u8],
+    ) -> ServerResult<ClientId> {
+        let client_id = self.tickets.verify(token)?;
+
+        let session = self.sessions.write().await.remove(&client_id);
+        let session = match session {
+            Some(session) => Some(session),
+            None => self.suspended.write().await.remove(&client_id),
+        };
+        let mut session = match session {
+            Some(session) if !session.expired() => session,
+            _ => {
+                tracing::error!("No session left for {client_id:?} to resume");
+                return Err(ServerError::Unauthenticated);
+            }
+        };
+        if self.invites.is_revoked(&session.request).await {
+            tracing::error!("{client_id:?} tried to resume with a revoked key");
+            return Err(ServerError::Unauthenticated);
+        }
+
+        tracing::info!("{client_id:?} resumed its session from {origin:?}");
+        session.origin = origin.clone();
+        session.touch();
+        let (id, saved) = (client_id.clone(), session.clone());
+        self.on_store(move |store| store.save_session(&id, &saved))
+            .await?;
+        self.sessions
+            .write()
+            .await
+            .insert(client_id.clone(), session);
+        Ok(client_id)
+    }
+
+    /// Marks the client of `client_id` as a seeder.
+    pub async fn set_seeded(&self, client_id: &ClientId) -> ServerResult<()> {
+        let session = match self.sessions.write().await.get_mut(client_id) {
+            Some(session) => {
+                session.seeded = true;
+                session.clone()
+            }
+            None => return Ok(()),
+        };
+        let id = client_id.clone();
+        self.on_store(move |store| store.save_session(&id, &session))
+            .await
+    }
+
+    /// Records the round trip to the client of `client_id`.
+    pub async fn set_rtt(&self, client_id: &ClientId, rtt: Duration) {
+        if let Some(session) = self.sessions.write().await.get_mut(client_id) {
+            session.rtt = Some(rtt);
+        }
+    }
+
+    /// Records the load the gateway of `client_id` reported.
+    pub async fn set_load(
+        &self,
+        client_id: &ClientId,
+        load: GatewayLoad,
+    ) -> ServerResult<()> {
+        match self.sessions.write().await.get_mut(client_id) {
+            Some(session) => {
+                session.load = Some(load);
+                Ok(())
+            }
+            None => Err(ServerError::Unauthenticated),
+        }
+    }
+
+    /// The seeders, with how many clients each serves and the load it last
+    /// reported.
+    pub async fn gateway_statuses(&self) -> ServerResult<Vec<GatewayStatus>> {
+        let (client_ids, seeders) = {
+            let sessions = self.sessions.read().await;
+            let seeders = sessions
+                .iter()
+                .filter(|(_, v)| v.seeded)
+                .map(|(k, v)| (k.clone(), v.request.gateway.clone(), v.load))
+                .collect::<Vec<_>>();
+            (sessions.keys().cloned().collect(), seeders)
+        };
+        let assigned = self.assigned(client_ids).await?;
+        Ok(seeders
+            .into_iter()
+            .map(|(peer, gateway, load)| GatewayStatus {
+                assigned: assigned.get(&peer).copied().unwrap_or_default() as u32,
+                peer,
+                gateway,
+                load,
+            })
+            .collect())
+    }
+
+    /// How many of `client_ids` each gateway was assigned.
+    async fn assigned(
+        &self,
+        client_ids: Vec<ClientId>,
+    ) -> ServerResult<HashMap<ClientId, usize>> {
+        self.on_store(move |store| {
+            let mut assigned = HashMap::<ClientId, usize>::new();
+            for client_id in client_ids.iter() {
+                if let Some(gateway) = store.assignment(client_id)? {
+                    *assigned.entry(gateway).or_default() += 1;
+                }
+            }
+            Ok(assigned)
+        })
+        .await
+    }
+
+    /// Runs `f` against the store on a blocking thread, as a store may wait on
+    /// the disk.
+    async fn on_store<T, F>(&self, f: F) -> ServerResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Store) -> ServerResult<T> + Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || f(store.as_ref())).await?
+    }
+
+    /// Records that `gateway` serves as the gateway of `client_id`, so that it
+    /// is picked again while it stays connected.
+    pub async fn assign(
+        &self,
+        client_id: &ClientId,
+        gateway: &ClientId,
+    ) -> ServerResult<()> {
+        let (client_id, gateway) = (client_id.clone(), gateway.clone());
+        self.on_store(move |store| store.assign(&client_id, &gateway))
+            .await
+    }
+
+    /// Forgets the gateway of `client_id`, whose tunnel through it closed.
+    pub async fn release(&self, client_id: &ClientId) -> ServerResult<()> {
+        let client_id = client_id.clone();
+        self.on_store(move |store| store.release(&client_id)).await
+    }
+
+    /// The peer assigned to serve as the gateway of `client_id`.
+    pub async fn assignment(
+        &self,
+        client_id: &ClientId,
+    ) -> ServerResult<Option<ClientId>> {
+        let client_id = client_id.clone();
+        self.on_store(move |store| store.assignment(&client_id))
+            .await
+    }
+
+    /// Redeems an invite, returning the enrolled client and the tunnel address
+    /// the invite assigned it.
+    pub async fn enroll(
+        &self,
+        request: &EnrollmentRequest,
+    ) -> ServerResult<(EnrolledClient, Option<String>)> {
+        self.invites.enroll(request).await
+    }
+
+    /// Revokes the enrolled client labelled `label`.
+    pub async fn revoke(&self, label: &str) -> ServerResult<EnrolledClient> {
+        self.invites
+            .revoke(label, self.config.enrolled_clients())
+            .await
+    }
+
+    /// Replaces the identity key the session of `client_id` authenticated with.
+    pub async fn rotate(
+        &self,
+        client_id: &ClientId,
+        request: &KeyRotationRequest,
+    ) -> ServerResult<EnrolledClient> {
+        let old_public_key = self
+            .sessions
+            .read()
+            .await
+            .get(client_id)
+            .and_then(|s| s.request.public_key().map(|key| key.to_vec()))
+            .ok_or(ServerError::NotEnrolled)?;
+        self.invites
+            .rotate(&old_public_key, request, self.config.enrolled_clients())
+            .await
+    }
+
+    /// The address-derived ID a client connected from, if it has a session.
+    pub async fn origin(&self, client_id: &ClientId) -> Option<ClientId> {
+        self.sessions
+            .read()
+            .await
+            .get(client_id)
+            .map(|s| s.origin.clone())
+    }
+
+    pub async fn gateway(&self, client_id: &ClientId) -> Option<Address> {
+        self.sessions
+            .read()
+            .await
+            .get(client_id)
+            .map(|s| s.request.gateway.clone())
+    }
+
+    /// The control channel key `client_id` announced for its gateway.
+    pub async fn gateway_public_key(&self, client_id: &ClientId) -> Option<String> {
+        self.sessions
+            .read()
+            .await
+            .get(client_id)
+            .and_then(|s| s.request.gateway_public_key.clone())
+    }
+
+    /// The WireGuard key `client_id` announced when it authenticated.
+    pub async fn wireguard_public_key(&self, client_id: &ClientId) -> Option<String> {
+        self.sessions
+            .read()
+            .await
+            .get(client_id)
+            .and_then(|s| s.request.wireguard_public_key.clone())
+    }
+
+    /// The WireGuard key `client_id` last announced, whether or not it is
+    /// connected.
+    pub async fn last_wireguard_public_key(
+        &self,
+        client_id: &ClientId,
+    ) -> ServerResult<Option<String>> {
+        let client_id = client_id.clone();
+        self.on_store(move |store| store.wireguard_key(&client_id))
+            .await
+    }
+
+    /// Gateway addresses of the seeders and of the peers serving as someone's
+    /// gateway, the only ones that may have other clients as WireGuard peers.
+    pub async fn gateways(&self) -> ServerResult<Vec<(ClientId, Address)>> {
+        let gateways = self
             .sessions
             .read()
             .await
             .iter()
-            .filter_map(|(k, v)| {
-                if k != other {
-                    return Some(v.clone());
-                }
-                None
-            })
-            .collect::<Vec<Session>>();
-        tracing::info!("Selecting gateway peer from sessions: {items:?}");
-        let mut rng = thread_rng();
-        if let Some(session) = items.choose(&mut rng).cloned() {
-            return session.gateway_remote_addr();
+            .map(|(k, v)| (k.clone(), v.request.gateway.clone(), v.seeded))
+            .collect::<Vec<_>>();
+        let assigned = self
+            .assigned(gateways.iter().map(|(k, _, _)| k.clone()).collect())
+            .await?;
+        Ok(gateways
+            .into_iter()
+            .filter(|(k, _, seeded)| *seeded || assigned.contains_key(k))
+            .map(|(k, gateway, _)| (k, gateway))
+            .collect())
+    }
+
+    /// Keeps the session of `client_id`, if it has one, from going idle.
+    pub async fn touch(&self, client_id: &ClientId) {
+        if let Some(session) = self.sessions.write().await.get_mut(client_id) {
+            session.touch();
         }
+    }
 
-        Err(ServerError::NoAvailablePeers)
+    pub async fn exists(&self, client_id: &ClientId) -> bool {
+        self.sessions.read().await.contains_key(client_id)
     }
 
-    #[allow(unused)]
-    pub async fn remove(&self, client_id: &ClientId) {
-        self.sessions.write().await.remove(client_id);
+    pub async fn get_peer_for_gateway(&self, other: &ClientId) -> ServerResult<Address> {
+        Ok(self.select_peer_for_gateway(other).await?.1)
+    }
+
+    /// Picks a seeder other than `other` to serve as its gateway with the
+    /// configured selector, skipping those that reported being full or
+    /// unhealthy, returning the peer's ID and gateway address.
+    pub async fn select_peer_for_gateway(
+        &self,
+        other: &ClientId,
+    ) -> ServerResult<(ClientId, Address)> {
+        // The store is only asked once the sessions are let go of
+        let (tags, client_ids, mut candidates) = {
+            let sessions = self.sessions.read().await;
+            let session = sessions.get(other).ok_or(ServerError::Unauthenticated)?;
+            let candidates = sessions
+                .iter()
+                .filter(|(k, v)| *k != other && v.seeded)
+                .filter(|(_, v)| v.load.map_or(true, |load| load.is_available()))
+                .map(|(k, v)| GatewayCandidate {
+                    client_id: k.clone(),
+                    gateway: v.request.gateway.clone(),
+                    load: 0,
+                    rtt: v.rtt,
+                    tags: v.request.tags.clone(),
+                    report: v.load,
+                })
+                .collect::<Vec<GatewayCandidate>>();
+            (
+                session.request.tags.clone(),
+                sessions.keys().cloned().collect(),
+                candidates,
+            )
+        };
+
+        let assigned = self.assigned(client_ids).await?;
+        for candidate in candidates.iter_mut() {
+            let assigned = assigned
+                .get(&candidate.client_id)
+                .copied()
+                .unwrap_or_default();
+            // Reports lag behind the assignments made since
+            let reported = candidate
+                .report
+                .map_or(0, |load| load.active_tunnels as usize);
+            candidate.load = assigned.max(reported);
+        }
+        tracing::info!("Selecting gateway peer from candidates: {candidates:?}");
+
+        let previous = self.assignment(other).await?;
+        let request = SelectionRequest {
+            client_id: other,
+            tags: &tags,
+            previous: previous.as_ref(),
+        };
+        match self.selector.select(&request, &candidates) {
+            Some(candidate) => {
+                Ok((candidate.client_id.clone(), candidate.gateway.clone()))
+            }
+            None => Err(ServerError::NoAvailablePeers),
+        }
+    }
+
+    /// Removes the session of `client_id`, whether live or suspended, so that
+    /// it can no longer be resumed either.
+    pub async fn remove(&self, client_id: &ClientId) -> Option<Session> {
+        self.forget(client_id).await;
+        let suspended = self.suspended.write().await.remove(client_id);
+        self.sessions.write().await.remove(client_id).or(suspended)
+    }
+
+    /// Drops what the store keeps about `client_id`.
+    async fn forget(&self, client_id: &ClientId) {
+        let id = client_id.clone();
+        if let Err(e) = self
+            .on_store(move |store| {
+                store.remove_session(&id)?;
+                store.unassign(&id)
+            })
+            .await
+        {
+            tracing::error!("Failed to remove {client_id:?} from the store: {e}");
+        }
     }
 
     #[allow(unused)]
@@ -118,27 +606,60 @@ impl SessionManager {
         self.sessions.read().await.is_empty()
     }
 
-    #[allow(unused)]
-    pub async fn cleanup(&self) {
-        self.sessions
-            .write()
-            .await
-            .retain(|_, session| !session.expired());
-    }
+    /// Ends expired and idle sessions, returning whose ended and why. Idle
+    /// sessions are suspended so their clients can resume them.
+    pub async fn cleanup(&self) -> Vec<(ClientId, DisconnectReason)> {
+        let mut forgotten = vec![];
+        let ended = {
+            let mut sessions = self.sessions.write().await;
+            let mut suspended = self.suspended.write().await;
+            suspended.retain(|client_id, session| {
+                if session.expired() {
+                    forgotten.push(client_id.clone());
+                }
+                !session.expired()
+            });
 
-    pub async fn monitor(&self) {
-        let mut interval = time::interval(Duration::from_secs(30));
-        loop {
-            interval.tick().await;
-            tracing::info!("Pruning idle sessions");
-            self.sessions
-                .write()
-                .await
-                .retain(|_, session| !session.is_idle());
+            let ended = sessions
+                .iter()
+                .filter_map(|(k, v)| v.end_reason().map(|reason| (k.clone(), reason)))
+                .collect::<Vec<(ClientId, DisconnectReason)>>();
+            for (client_id, reason) in ended.iter() {
+                tracing::info!("Ending session of {client_id:?}: {reason:?}");
+                if let Some(session) = sessions.remove(client_id) {
+                    match reason {
+                        DisconnectReason::Idle => {
+                            suspended.insert(client_id.clone(), session);
+                        }
+                        _ => forgotten.push(client_id.clone()),
+                    }
+                }
+            }
+            ended
+        };
+
+        for client_id in forgotten.iter() {
+            self.forget(client_id).await;
         }
+        ended
+    }
+
+    /// How often to look for sessions to end, so that none outlives its ttl or
+    /// idle timeout by much.
+    pub fn monitor_interval(&self) -> Duration {
+        let secs = self
+            .config
+            .session_ttl()
+            .min(self.config.session_idle_timeout())
+            / 2;
+        Duration::from_secs(secs.clamp(1, SESSION_MONITOR_INTERVAL_SECS))
     }
 
+    /// Drops all sessions from memory. The store keeps them, so that a restarted
+    /// server lets their clients resume.
     pub async fn clear(&self) -> ServerResult<()> {
+        self.sessions.write().await.clear();
+        self.suspended.write().await.clear();
         Ok(())
     }
 }
@@ -0,0 +1,262 @@
+use crate::{
+    config::{Config, StoreKind},
+    session::Session,
+    ticket::TICKET_KEY_LEN,
+    ServerResult,
+};
+use async_std::sync::Arc;
+use rand::RngCore;
+use roxi_lib::types::ClientId;
+use std::{collections::HashMap, path::Path, sync::RwLock};
+
+const SESSIONS_TREE: &str = "sessions";
+const ASSIGNMENTS_TREE: &str = "assignments";
+const WIREGUARD_KEYS_TREE: &str = "wireguard_keys";
+const TICKET_KEY: &str = "ticket_key";
+
+/// Where the server keeps what it knows about its clients: their sessions,
+/// which carry whether they seed and where their gateway listens, which peer
+/// serves as whose gateway, the WireGuard keys they announced, and the key
+/// session tickets are signed with.
+///
+/// Calls may block on the disk, so they are made off the async runtime.
+pub trait Store: Send + Sync {
+    /// All sessions, live or suspended, that have not been removed.
+    fn sessions(&self) -> ServerResult<Vec<(ClientId, Session)>>;
+
+    fn save_session(&self, client_id: &ClientId, session: &Session) -> ServerResult<()>;
+
+    fn remove_session(&self, client_id: &ClientId) -> ServerResult<()>;
+
+    /// The peer last assigned to serve as the gateway of `client_id`.
+    fn assignment(&self, client_id: &ClientId) -> ServerResult<Option<ClientId>>;
+
+    fn assign(&self, client_id: &ClientId, gateway: &ClientId) -> ServerResult<()>;
+
+    /// Drops the assignments of `client_id`, as a client or as a gateway.
+    fn unassign(&self, client_id: &ClientId) -> ServerResult<()>;
+
+    /// Drops the gateway assigned to `client_id`, keeping the peers it serves
+    /// as a gateway itself.
+    fn release(&self, client_id: &ClientId) -> ServerResult<()>;
+
+    /// The WireGuard key `client_id` last announced. It outlives the session,
+    /// so that gateways can be told to drop it while the client is away.
+    fn wireguard_key(&self, client_id: &ClientId) -> ServerResult<Option<String>>;
+
+    fn save_wireguard_key(&self, client_id: &ClientId, key: &str) -> ServerResult<()>;
+
+    /// The key session tickets are signed with, generated on first use.
+    fn ticket_key(&self) -> ServerResult<[u8; TICKET_KEY_LEN]>;
+}
+
+/// Builds the store selected by `store.kind`.
+pub fn from_config(config: &Config) -> ServerResult<Arc<dyn Store>> {
+    let store: Arc<dyn Store> = match config.store_kind() {
+        StoreKind::Memory => Arc::new(MemoryStore::new()),
+        StoreKind::Sled => Arc::new(SledStore::open(&config.store_path())?),
+    };
+    Ok(store)
+}
+
+fn generate_ticket_key() -> [u8; TICKET_KEY_LEN] {
+    let mut key = [0u8; TICKET_KEY_LEN];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Keeps everything for as long as the server runs.
+pub struct MemoryStore {
+    sessions: RwLock<HashMap<ClientId, Session>>,
+    assignments: RwLock<HashMap<ClientId, ClientId>>,
+    wireguard_keys: RwLock<HashMap<ClientId, String>>,
+    ticket_key: [u8; TICKET_KEY_LEN],
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            sessions: RwLock::new(HashMap::new()),
+            assignments: RwLock::new(HashMap::new()),
+            wireguard_keys: RwLock::new(HashMap::new()),
+            ticket_key: generate_ticket_key(),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store for MemoryStore {
+    fn sessions(&self) -> ServerResult<Vec<(ClientId, Session)>> {
+        Ok(self
+            .sessions
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn save_session(&self, client_id: &ClientId, session: &Session) -> ServerResult<()> {
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(client_id.clone(), session.clone());
+        Ok(())
+    }
+
+    fn remove_session(&self, client_id: &ClientId) -> ServerResult<()> {
+        self.sessions.write().unwrap().remove(client_id);
+        Ok(())
+    }
+
+    fn assignment(&self, client_id: &ClientId) -> ServerResult<Option<ClientId>> {
+        Ok(self.assignments.read().unwrap().get(client_id).cloned())
+    }
+
+    fn assign(&self, client_id: &ClientId, gateway: &ClientId) -> ServerResult<()> {
+        self.assignments
+            .write()
+            .unwrap()
+            .insert(client_id.clone(), gateway.clone());
+        Ok(())
+    }
+
+    fn unassign(&self, client_id: &ClientId) -> ServerResult<()> {
+        self.assignments
+            .write()
+            .unwrap()
+            .retain(|k, v| k != client_id && v != client_id);
+        Ok(())
+    }
+
+    fn release(&self, client_id: &ClientId) -> ServerResult<()> {
+        self.assignments.write().unwrap().remove(client_id);
+        Ok(())
+    }
+
+    fn wireguard_key(&self, client_id: &ClientId) -> ServerResult<Option<String>> {
+        Ok(self.wireguard_keys.read().unwrap().get(client_id).cloned())
+    }
+
+    fn save_wireguard_key(&self, client_id: &ClientId, key: &str) -> ServerResult<()> {
+        self.wireguard_keys
+            .write()
+            .unwrap()
+            .insert(client_id.clone(), key.to_string());
+        Ok(())
+    }
+
+    fn ticket_key(&self) -> ServerResult<[u8; TICKET_KEY_LEN]> {
+        Ok(self.ticket_key)
+    }
+}
+
+/// Keeps everything in an embedded database on disk, so that a restarted
+/// server picks up where it left off.
+pub struct SledStore {
+    db: sled::Db,
+    sessions: sled::Tree,
+    assignments: sled::Tree,
+    wireguard_keys: sled::Tree,
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> ServerResult<Self> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            sessions: db.open_tree(SESSIONS_TREE)?,
+            assignments: db.open_tree(ASSIGNMENTS_TREE)?,
+            wireguard_keys: db.open_tree(WIREGUARD_KEYS_TREE)?,
+            db,
+        })
+    }
+
+    /// Makes sure writes survive a crash, not just a clean shutdown.
+    fn flush(&self) -> ServerResult<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl Store for SledStore {
+    fn sessions(&self) -> ServerResult<Vec<(ClientId, Session)>> {
+        let mut sessions = vec![];
+        for entry in self.sessions.iter() {
+            let (k, v) = entry?;
+            sessions.push((bincode::deserialize(&k)?, bincode::deserialize(&v)?));
+        }
+        Ok(sessions)
+    }
+
+    fn save_session(&self, client_id: &ClientId, session: &Session) -> ServerResult<()> {
+        self.sessions
+            .insert(bincode::serialize(client_id)?, bincode::serialize(session)?)?;
+        self.flush()
+    }
+
+    fn remove_session(&self, client_id: &ClientId) -> ServerResult<()> {
+        self.sessions.remove(bincode::serialize(client_id)?)?;
+        self.flush()
+    }
+
+    fn assignment(&self, client_id: &ClientId) -> ServerResult<Option<ClientId>> {
+        match self.assignments.get(bincode::serialize(client_id)?)? {
+            Some(gateway) => Ok(Some(bincode::deserialize(&gateway)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn assign(&self, client_id: &ClientId, gateway: &ClientId) -> ServerResult<()> {
+        self.assignments
+            .insert(bincode::serialize(client_id)?, bincode::serialize(gateway)?)?;
+        self.flush()
+    }
+
+    fn unassign(&self, client_id: &ClientId) -> ServerResult<()> {
+        let key = bincode::serialize(client_id)?;
+        self.assignments.remove(&key)?;
+        for entry in self.assignments.iter() {
+            let (k, v) = entry?;
+            if v == key {
+                self.assignments.remove(k)?;
+            }
+        }
+        self.flush()
+    }
+
+    fn release(&self, client_id: &ClientId) -> ServerResult<()> {
+        self.assignments.remove(bincode::serialize(client_id)?)?;
+        self.flush()
+    }
+
+    fn wireguard_key(&self, client_id: &ClientId) -> ServerResult<Option<String>> {
+        match self.wireguard_keys.get(bincode::serialize(client_id)?)? {
+            Some(key) => Ok(Some(String::from_utf8(key.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_wireguard_key(&self, client_id: &ClientId, key: &str) -> ServerResult<()> {
+        self.wireguard_keys
+            .insert(bincode::serialize(client_id)?, key.as_bytes())?;
+        self.flush()
+    }
+
+    fn ticket_key(&self) -> ServerResult<[u8; TICKET_KEY_LEN]> {
+        if let Some(stored) = self.db.get(TICKET_KEY)? {
+            if let Ok(key) = <[u8; TICKET_KEY_LEN]>::try_from(stored.as_ref()) {
+                return Ok(key);
+            }
+            tracing::warn!("Replacing malformed session ticket key");
+        }
+        let key = generate_ticket_key();
+        self.db.insert(TICKET_KEY, &key[..])?;
+        self.flush()?;
+        Ok(key)
+    }
+}
@@ -0,0 +1,67 @@
+use crate::{ServerError, ServerResult};
+use roxi_crypto::{hmac_sha256, verify_hmac_sha256};
+use roxi_lib::types::ClientId;
+use roxi_proto::SessionTicket;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes in the key tickets are signed with.
+pub(crate) const TICKET_KEY_LEN: usize = 32;
+
+/// Bytes of the HMAC-SHA256 tag that ends a token.
+const TICKET_TAG_LEN: usize = 32;
+
+/// What a resumption token vouches for.
+#[derive(Debug, Serialize, Deserialize)]
+struct TicketBody {
+    client_id: ClientId,
+    expires_at: u64,
+}
+
+/// Mints and checks session resumption tokens. Tokens are signed with the
+/// store's key, so they outlive a restart only if the store does.
+pub struct TicketIssuer {
+    key: [u8; TICKET_KEY_LEN],
+}
+
+impl TicketIssuer {
+    pub fn new(key: [u8; TICKET_KEY_LEN]) -> Self {
+        Self { key }
+    }
+
+    /// A ticket resuming the session of `client_id` until `expires_at`.
+    pub fn issue(
+        &self,
+        client_id: &ClientId,
+        expires_at: u64,
+    ) -> ServerResult<SessionTicket> {
+        let mut token = bincode::serialize(&TicketBody {
+            client_id: client_id.clone(),
+            expires_at,
+        })?;
+        let tag = hmac_sha256(&self.key, &token);
+        token.extend_from_slice(&tag);
+        Ok(SessionTicket { token, expires_at })
+    }
+
+    /// Returns the client whose session `token` resumes, if we signed it and it
+    /// has not expired.
+    pub fn verify(&self, token: &[u8]) -> ServerResult<ClientId> {
+        if token.len() <= TICKET_TAG_LEN {
+            return Err(ServerError::InvalidTicket);
+        }
+        let (body, tag) = token.split_at(token.len() - TICKET_TAG_LEN);
+        verify_hmac_sha256(&self.key, body, tag)
+            .map_err(|_| ServerError::InvalidTicket)?;
+
+        let body: TicketBody = bincode::deserialize(body)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now >= body.expires_at {
+            return Err(ServerError::InvalidTicket);
+        }
+        Ok(body.client_id)
+    }
+}
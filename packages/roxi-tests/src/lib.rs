@@ -1,10 +1,12 @@
 pub mod utils {
 
+    use async_std::sync::Arc;
     use rand::Rng;
     use regex::Regex;
     use roxi_client::{Client, Config as ClientConfig};
     use roxi_lib::constant;
-    use roxi_server::{Config as ServerConfig, Server};
+    use roxi_proto::{WireGuardProtoConfig, WireGuardProtoPeer};
+    use roxi_server::{Authenticator, Config as ServerConfig, Gateway, Server};
     use std::{
         env,
         fs::{self, File},
@@ -17,6 +19,11 @@ pub mod utils {
     pub const IP_THREE: &str = "192.168.1.3";
     pub const IP_FOUR: &str = "192.168.1.4";
 
+    pub const UNSPECIFIED: &str = "0.0.0.0";
+    pub const LOOPBACK_TWO: &str = "127.0.0.2";
+    pub const LOOPBACK_THREE: &str = "127.0.0.3";
+    pub const LOOPBACK_FOUR: &str = "127.0.0.4";
+
     pub fn yaml_filename(input: &str) -> String {
         format!("{input}.yaml")
     }
@@ -27,22 +34,229 @@ pub mod utils {
 
     pub async fn setup_server(ip: &str) -> Server {
         let (file, content) = server_config_content(ip);
+        setup_server_from(&file, &content).await
+    }
+
+    /// Sets up a server whose `auth` section sets `auth` in place of the shared
+    /// key, e.g. `kind: keys`.
+    pub async fn setup_server_with_auth(ip: &str, auth: &str) -> Server {
+        let (file, content) = server_config_content(ip);
+        let content = content.replace("  shared_key: \"roxi-XXX\"\n", auth);
+        setup_server_from(&file, &content).await
+    }
+
+    /// Sets up a server whose sessions end after `session_ttl` seconds, or after
+    /// `idle_timeout` seconds without a message.
+    pub async fn setup_server_with_session_limits(
+        ip: &str,
+        session_ttl: u64,
+        idle_timeout: u64,
+    ) -> Server {
+        let (file, content) = server_config_content(ip);
+        let content = content.replace(
+            "  session_ttl: 3600\n",
+            &format!("  session_ttl: {session_ttl}\n  idle_timeout: {idle_timeout}\n"),
+        );
+        setup_server_from(&file, &content).await
+    }
+
+    /// Sets up a server that keeps its sessions in an on-disk store next to its
+    /// config, so that a server set up the same way after it picks them up.
+    pub async fn setup_server_with_store(ip: &str) -> Server {
+        let (file, content) = server_config_content(ip);
+        let content = format!("{content}\nstore:\n  kind: sled\n");
+        setup_server_from(&file, &content).await
+    }
+
+    /// Sets up a server that advertises itself on loopback, so that peers on
+    /// this host can reach its relays.
+    pub async fn setup_loopback_server(ip: &str) -> Server {
+        let (file, content) = server_config_content(ip);
+        let content =
+            content.replace(&format!("    ip: \"{ip}\"\n"), "    ip: \"127.0.0.1\"\n");
+        setup_server_from(&file, &content).await
+    }
+
+    /// Sets up a server holding the control channel key `private_key`.
+    pub async fn setup_server_with_key(ip: &str, private_key: &str) -> Server {
+        let (file, content) = server_config_content(ip);
+        let content = content.replace(
+            "    response_timeout: 1\n",
+            &format!("    response_timeout: 1\n    private_key: \"{private_key}\"\n"),
+        );
+        setup_server_from(&file, &content).await
+    }
+
+    /// Sets up a server without a shared key that only admits the enrolled
+    /// `(label, public_key)` clients.
+    pub async fn setup_enrolling_server(ip: &str, clients: &[(&str, &str)]) -> Server {
+        let mut auth = "  kind: keys\n  clients:\n".to_string();
+        for (label, public_key) in clients {
+            auth.push_str(&format!(
+                "    - label: \"{label}\"\n      public_key: \"{public_key}\"\n"
+            ));
+        }
+        setup_server_with_auth(ip, &auth).await
+    }
+
+    /// Sets up a server that admits clients through `auth`.
+    pub async fn setup_server_with(ip: &str, auth: Arc<dyn Authenticator>) -> Server {
+        let (file, content) = server_config_content(ip);
+        let config = write_server_config(&file, &content);
+        Server::with_authenticator(config, auth).await.unwrap()
+    }
 
-        File::create(&file)
+    async fn setup_server_from(file: &str, content: &str) -> Server {
+        let config = write_server_config(file, content);
+        Server::new(config).await.unwrap()
+    }
+
+    fn write_server_config(file: &str, content: &str) -> ServerConfig {
+        File::create(file)
             .unwrap()
             .write_all(content.as_bytes())
             .unwrap();
 
-        let config = ServerConfig::try_from(Path::new(&file)).unwrap();
+        ServerConfig::try_from(Path::new(file)).unwrap()
+    }
 
-        Server::new(config).await.unwrap()
+    /// Writes `content` to a file in the config directory that
+    /// `cleanup_config_files` removes, returning its path.
+    pub fn write_config_file(ip: &str, name: &str, content: &str) -> String {
+        let path =
+            Path::new(constant::ROXI_CONFIG_DIR_REALPATH).join(format!("{ip}-{name}"));
+        let path = expand_tilde(&path).display().to_string();
+        File::create(&path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+        path
     }
 
     pub async fn setup_peer(ip: &str) -> Client {
+        setup_peer_on(ip, UNSPECIFIED).await
+    }
+
+    /// Sets up a peer whose sockets are bound to `interface`, so that several
+    /// peers on one host present distinct addresses (and client IDs) to a server.
+    pub async fn setup_peer_on(ip: &str, interface: &str) -> Client {
+        let (peer_file, peer_content) = peer_config_content_on(ip, interface);
+        setup_peer_from(ip, &peer_file, &peer_content).await
+    }
+
+    /// Sets up a peer on `interface` that sends a heartbeat every `interval`
+    /// seconds.
+    pub async fn setup_heartbeat_peer_on(
+        ip: &str,
+        interface: &str,
+        interval: u64,
+    ) -> Client {
+        let (peer_file, peer_content) = peer_config_content_on(ip, interface);
+        let peer_content = peer_content.replace(
+            "    response_timeout: 1\n",
+            &format!("    response_timeout: 1\n    heartbeat_interval: {interval}\n"),
+        );
+        setup_peer_from(ip, &peer_file, &peer_content).await
+    }
+
+    /// Sets up a peer on `interface` whose WireGuard public key is `public_key`.
+    pub async fn setup_wireguard_peer_on(
+        ip: &str,
+        interface: &str,
+        public_key: &str,
+    ) -> Client {
+        let (peer_file, peer_content) = peer_config_content_on(ip, interface);
+        let peer_content = with_wireguard_key(&peer_content, public_key);
+        setup_peer_from(ip, &peer_file, &peer_content).await
+    }
+
+    /// Sets up a peer like `setup_wireguard_peer_on` whose gateway is reached
+    /// on loopback, so that other peers on this host can tunnel through it.
+    pub async fn setup_loopback_gateway_peer_on(
+        ip: &str,
+        interface: &str,
+        public_key: &str,
+    ) -> Client {
+        let (peer_file, peer_content) = peer_config_content_on(ip, interface);
+        let peer_content = with_wireguard_key(&peer_content, public_key)
+            .replace(&format!("    ip: \"{ip}\"\n"), "    ip: \"127.0.0.1\"\n")
+            .replace(
+                "    ip: ~\n    port: ~\n",
+                "    ip: \"127.0.0.1\"\n    port: 5675\n",
+            );
+        setup_peer_from(ip, &peer_file, &peer_content).await
+    }
+
+    /// Sets `client` up again with WireGuard listening on `port`.
+    pub async fn with_listen_port(client: Client, port: u16) -> Client {
+        let config = client.config().clone();
+        let path = config.wireguard_filepath();
+        let mut wireguard = WireGuardProtoConfig::try_from(path).unwrap();
+        wireguard.interface.port = port;
+        wireguard.save(path).unwrap();
+        Client::new(config).await.unwrap()
+    }
+
+    fn with_wireguard_key(peer_content: &str, public_key: &str) -> String {
+        peer_content.replace(
+            "\n\nauth:\n",
+            &format!("\n    public_key: \"{public_key}\"\n\nauth:\n"),
+        )
+    }
+
+    /// Sets up a peer that only talks to a server holding `public_key`.
+    pub async fn setup_pinned_peer(ip: &str, public_key: &str) -> Client {
+        let (peer_file, peer_content) = peer_config_content(ip);
+        let peer_content = peer_content.replace(
+            "    response_timeout: 1\n",
+            &format!("    response_timeout: 1\n    public_key: \"{public_key}\"\n"),
+        );
+        setup_peer_from(ip, &peer_file, &peer_content).await
+    }
+
+    /// Sets up a peer that authenticates with the identity key `private_key`.
+    pub async fn setup_identity_peer(ip: &str, private_key: &str) -> Client {
+        let (peer_file, peer_content) = peer_config_content(ip);
+        let peer_content = peer_content.replace(
+            "  shared_key: \"roxi-XXX\"",
+            &format!("  private_key: \"{private_key}\""),
+        );
+        setup_peer_from(ip, &peer_file, &peer_content).await
+    }
+
+    /// Sets up the gateway of a peer pinned to the server holding `public_key`,
+    /// with `peers` already in its WireGuard config.
+    pub async fn setup_pinned_gateway(
+        ip: &str,
+        public_key: &str,
+        peers: Vec<WireGuardProtoPeer>,
+    ) -> Gateway {
         let (peer_file, peer_content) = peer_config_content(ip);
+        let peer_content = peer_content
+            .replace(
+                "    response_timeout: 1\n",
+                &format!("    response_timeout: 1\n    public_key: \"{public_key}\"\n"),
+            )
+            .replace(
+                "    ip: ~\n    port: ~\n",
+                "    ip: \"127.0.0.1\"\n    port: 5675\n",
+            );
+        let client = setup_peer_from(ip, &peer_file, &peer_content).await;
+
+        let path = client.config().wireguard_filepath();
+        let mut wireguard = WireGuardProtoConfig::try_from(path).unwrap();
+        for peer in peers {
+            wireguard.add_peer(peer);
+        }
+        wireguard.save(path).unwrap();
+
+        Gateway::new(client.config().clone()).await.unwrap()
+    }
+
+    async fn setup_peer_from(ip: &str, peer_file: &str, peer_content: &str) -> Client {
         let (wireguard_file, wireguard_content) = peer_wireguard_config_content(ip);
 
-        File::create(&peer_file)
+        File::create(peer_file)
             .unwrap()
             .write_all(peer_content.as_bytes())
             .unwrap();
@@ -52,7 +266,7 @@ pub mod utils {
             .write_all(wireguard_content.as_bytes())
             .unwrap();
 
-        let config = ClientConfig::try_from(Path::new(&peer_file)).unwrap();
+        let config = ClientConfig::try_from(Path::new(peer_file)).unwrap();
         Client::new(config).await.unwrap()
     }
 
@@ -76,6 +290,11 @@ network:
       udp: 5675
     max_clients: 10
     response_timeout: 1
+  relay:
+    enabled: true
+    ports:
+      start: 40000
+      end: 40010
 
 auth:
   shared_key: "roxi-XXX"
@@ -114,6 +333,10 @@ ListenPort = 51820
     }
 
     pub fn peer_config_content(ip: &str) -> (String, String) {
+        peer_config_content_on(ip, UNSPECIFIED)
+    }
+
+    pub fn peer_config_content_on(ip: &str, interface: &str) -> (String, String) {
         let client =
             Path::new(constant::ROXI_CONFIG_DIR_REALPATH).join(yaml_filename(ip));
         let wgconf =
@@ -122,9 +345,7 @@ ListenPort = 51820
         let client = expand_tilde(&client).display().to_string();
         let wgconf = expand_tilde(&wgconf).display().to_string();
 
-        let udp_gen = || rand::thread_rng().gen_range(5675..=5685);
-        let udp = udp_gen();
-        let gateway_udp = udp_gen();
+        let gateway_udp = rand::thread_rng().gen_range(5676..=5685);
 
         (
             client.clone(),
@@ -137,11 +358,11 @@ network:
     attempts: 3
 
   server:
-    interface: "0.0.0.0"
+    interface: "{interface}"
     ip: "127.0.0.1"
     ports:
       tcp: 8080
-      udp: {udp}
+      udp: 5675
     request_timeout: 1
     response_timeout: 1
 
@@ -168,7 +389,7 @@ auth:
     }
 
     pub async fn cleanup_config_files() {
-        let rgx = Regex::new(r"^\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}\.").unwrap();
+        let rgx = Regex::new(r"^\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}[.-]").unwrap();
         let dir = Path::new(constant::ROXI_CONFIG_DIR_REALPATH);
         let dir = expand_tilde(dir);
         for entry in fs::read_dir(dir).unwrap() {
@@ -180,6 +401,8 @@ auth:
                 let path = entry.path();
                 if path.is_file() {
                     fs::remove_file(&path).unwrap();
+                } else if path.is_dir() {
+                    fs::remove_dir_all(&path).unwrap();
                 }
             }
         }
@@ -190,16 +413,31 @@ auth:
 mod integration_tests {
     use crate::utils::*;
     use async_std::sync::Arc;
-    use roxi_client::Config as ClientConfig;
-    use roxi_lib::types::Address;
-    use roxi_proto::{MessageKind, MessageStatus};
-    use roxi_server::{ServerError, SessionManager};
+    use async_trait::async_trait;
+    use roxi_client::{enroll, Client, ClientError, Config as ClientConfig, Event};
+    use roxi_crypto::{IdentityKeyPair, NoiseKeyPair};
+    use roxi_lib::types::{Address, ClientId, InterfaceKind};
+    use roxi_proto::{
+        AuthenticationChallenge, AuthenticationRequest, CandidateKind, DisconnectReason,
+        Features, GatewayLoad, MessageKind, MessageStatus, PeerRevocation, ProtoError,
+        WireGuardProtoConfig, WireGuardProtoKey, WireGuardProtoPeer, PROTOCOL_VERSION,
+    };
+    use roxi_server::{
+        AffinitySelector, Authenticator, Gateway, InviteStore, LeastLoadedSelector,
+        RelayManager, Seeder, ServerError, SessionManager, Sticky,
+    };
     use std::{
+        env,
         fs::{self, File},
         io::Write,
+        net::IpAddr,
         path::Path,
         sync::Once,
     };
+    use tokio::{
+        net::UdpSocket,
+        time::{timeout, Duration},
+    };
 
     static INIT: Once = Once::new();
 
@@ -232,12 +470,19 @@ mod integration_tests {
             init_logging();
 
             let srv = setup_server(IP_ONE).await;
-            let sessions = SessionManager::new(srv.config().clone());
+            let sessions = SessionManager::new(srv.config().clone()).unwrap();
 
             let c1 = setup_peer(IP_TWO).await;
             let c2 = setup_peer(IP_THREE).await;
 
-            let _ = sessions.authenticate(&c1.client_id(), c1.config()).await;
+            let challenge = AuthenticationChallenge::new();
+            let mut request = c1.authentication_request().unwrap();
+            request
+                .sign(&c1.config().shared_key().unwrap(), &challenge)
+                .unwrap();
+            let _ = sessions
+                .authenticate(&c1.client_id(), &request, &challenge)
+                .await;
             assert_eq!(sessions.len().await, 1);
             assert!(sessions.exists(&c1.client_id()).await);
             assert!(!sessions.exists(&c2.client_id()).await);
@@ -245,10 +490,32 @@ mod integration_tests {
             let result = sessions.get_peer_for_gateway(&c1.client_id()).await;
             assert!(matches!(result, Err(ServerError::NoAvailablePeers)));
 
-            let _ = sessions.authenticate(&c2.client_id(), c2.config()).await;
+            let challenge = AuthenticationChallenge::new();
+            let mut request = c2.authentication_request().unwrap();
+            request
+                .sign(&c2.config().shared_key().unwrap(), &challenge)
+                .unwrap();
+            let result = sessions
+                .authenticate(&c2.client_id(), &request, &AuthenticationChallenge::new())
+                .await;
+            assert!(matches!(result, Err(ServerError::Unauthenticated)));
+            assert_eq!(sessions.len().await, 1);
+
+            request
+                .sign(&c2.config().shared_key().unwrap(), &challenge)
+                .unwrap();
+            let _ = sessions
+                .authenticate(&c2.client_id(), &request, &challenge)
+                .await;
             assert_eq!(sessions.len().await, 2);
             assert!(sessions.exists(&c2.client_id()).await);
 
+            // Only seeders serve as gateways
+            let result = sessions.get_peer_for_gateway(&c1.client_id()).await;
+            assert!(matches!(result, Err(ServerError::NoAvailablePeers)));
+            sessions.set_seeded(&c1.client_id()).await.unwrap();
+            sessions.set_seeded(&c2.client_id()).await.unwrap();
+
             let result = sessions
                 .get_peer_for_gateway(&c1.client_id())
                 .await
@@ -266,6 +533,170 @@ mod integration_tests {
             sessions.remove(&c1.client_id()).await;
             assert_eq!(sessions.len().await, 1);
         }
+
+        async fn authenticate_with_tags(
+            sessions: &SessionManager,
+            peer: &Client,
+            tags: &[&str],
+        ) -> ClientId {
+            let challenge = AuthenticationChallenge::new();
+            let mut request = peer
+                .authentication_request()
+                .unwrap()
+                .with_tags(tags.iter().map(|tag| tag.to_string()).collect());
+            request
+                .sign(&peer.config().shared_key().unwrap(), &challenge)
+                .unwrap();
+            sessions
+                .authenticate(&peer.client_id(), &request, &challenge)
+                .await
+                .unwrap()
+        }
+
+        #[tokio::test]
+        async fn test_server_sessions_select_gateways() {
+            init_logging();
+
+            let srv = setup_server(IP_ONE).await;
+            let sessions = SessionManager::new(srv.config().clone())
+                .unwrap()
+                .with_selector(Arc::new(Sticky(AffinitySelector)));
+            let eu =
+                authenticate_with_tags(&sessions, &setup_peer(IP_TWO).await, &["eu"])
+                    .await;
+            let us =
+                authenticate_with_tags(&sessions, &setup_peer(IP_THREE).await, &["us"])
+                    .await;
+            let client =
+                authenticate_with_tags(&sessions, &setup_peer(IP_FOUR).await, &["us"])
+                    .await;
+            sessions.set_seeded(&eu).await.unwrap();
+            sessions.set_seeded(&us).await.unwrap();
+
+            let (peer, _) = sessions.select_peer_for_gateway(&client).await.unwrap();
+            assert_eq!(peer, us);
+
+            // A client keeps the gateway it had while that one seeds
+            sessions.assign(&client, &eu).await.unwrap();
+            let (peer, _) = sessions.select_peer_for_gateway(&client).await.unwrap();
+            assert_eq!(peer, eu);
+
+            let sessions = SessionManager::new(srv.config().clone())
+                .unwrap()
+                .with_selector(Arc::new(LeastLoadedSelector));
+            let busy =
+                authenticate_with_tags(&sessions, &setup_peer(IP_TWO).await, &[]).await;
+            let idle =
+                authenticate_with_tags(&sessions, &setup_peer(IP_THREE).await, &[]).await;
+            let client =
+                authenticate_with_tags(&sessions, &setup_peer(IP_FOUR).await, &[]).await;
+            sessions.set_seeded(&busy).await.unwrap();
+            sessions.set_seeded(&idle).await.unwrap();
+            sessions.assign(&idle, &busy).await.unwrap();
+
+            let (peer, _) = sessions.select_peer_for_gateway(&client).await.unwrap();
+            assert_eq!(peer, idle);
+
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_server_sessions_keep_wireguard_keys() {
+            init_logging();
+
+            let srv = setup_server(IP_ONE).await;
+            let sessions = SessionManager::new(srv.config().clone()).unwrap();
+            let peer = setup_wireguard_peer_on(IP_TWO, UNSPECIFIED, "peer-key").await;
+            let peer = authenticate_with_tags(&sessions, &peer, &[]).await;
+            let seeder =
+                authenticate_with_tags(&sessions, &setup_peer(IP_THREE).await, &[]).await;
+            let client =
+                authenticate_with_tags(&sessions, &setup_peer(IP_FOUR).await, &[]).await;
+
+            // Only seeders and assigned gateways can have other clients as peers
+            assert!(sessions.gateways().await.unwrap().is_empty());
+            sessions.set_seeded(&seeder).await.unwrap();
+            sessions.assign(&client, &peer).await.unwrap();
+            let mut gateways = sessions
+                .gateways()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(peer, _)| peer)
+                .collect::<Vec<ClientId>>();
+            gateways.sort_by_key(|peer| peer.to_string());
+            assert_eq!(gateways, vec![peer.clone(), seeder]);
+
+            // The key is still known once the client is gone
+            sessions.remove(&peer).await;
+            assert_eq!(
+                sessions.last_wireguard_public_key(&peer).await.unwrap(),
+                Some("peer-key".to_string())
+            );
+            assert_eq!(
+                sessions.last_wireguard_public_key(&client).await.unwrap(),
+                None
+            );
+
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_server_sessions_expire() {
+            init_logging();
+
+            let srv = setup_server_with_session_limits(IP_ONE, 1, 60).await;
+            let sessions = SessionManager::new(srv.config().clone()).unwrap();
+            let c1 = setup_peer(IP_TWO).await;
+
+            let challenge = AuthenticationChallenge::new();
+            let mut request = c1.authentication_request().unwrap();
+            request
+                .sign(&c1.config().shared_key().unwrap(), &challenge)
+                .unwrap();
+            sessions
+                .authenticate(&c1.client_id(), &request, &challenge)
+                .await
+                .unwrap();
+            assert_eq!(sessions.monitor_interval(), Duration::from_secs(1));
+            assert!(sessions.cleanup().await.is_empty());
+
+            tokio::time::sleep(Duration::from_millis(1100)).await;
+            let ended = sessions.cleanup().await;
+            assert_eq!(ended, vec![(c1.client_id(), DisconnectReason::Expired)]);
+            assert!(!sessions.exists(&c1.client_id()).await);
+
+            cleanup_config_files().await;
+        }
+        #[tokio::test]
+        async fn test_server_relays_release_ports() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let relays = RelayManager::new(srv.config().clone());
+            let side = |ip: &str| (ClientId::from(ip), ip.parse::<IpAddr>().unwrap());
+            let (a, a_ip) = side(LOOPBACK_TWO);
+            let (b, b_ip) = side(LOOPBACK_THREE);
+            let (c, c_ip) = side(LOOPBACK_FOUR);
+
+            let ab = relays.allocate((&a, a_ip), (&b, b_ip)).await.unwrap();
+            let ac = relays.allocate((&a, a_ip), (&c, c_ip)).await.unwrap();
+            assert_ne!(ab.port(), ac.port());
+
+            // A closed tunnel frees its port for the next one
+            relays.release_between(&b, &a).await;
+            let bc = relays.allocate((&b, b_ip), (&c, c_ip)).await.unwrap();
+            assert_eq!(bc.port(), ab.port());
+
+            // An ended session frees the ports of all its tunnels
+            relays.release_client(&c).await;
+            let ab = relays.allocate((&a, a_ip), (&b, b_ip)).await.unwrap();
+            assert_eq!(ab.port(), bc.port());
+            let ac_again = relays.allocate((&a, a_ip), (&c, c_ip)).await.unwrap();
+            assert_eq!(ac_again.port(), ac.port());
+
+            relays.clear().await;
+            cleanup_config_files().await;
+        }
     }
 
     mod config {
@@ -314,23 +745,64 @@ mod integration_tests {
         }
 
         #[tokio::test]
-        async fn test_peer_server_rpc_authenticate() {
+        async fn test_peer_server_pinned_key() {
             init_logging();
             let srv = setup_server(IP_ONE).await;
-            let mut peer = setup_peer(IP_TWO).await;
+            let mut peer = setup_pinned_peer(IP_TWO, &srv.public_key()).await;
+            let mut impostor = setup_pinned_peer(
+                IP_THREE,
+                "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            )
+            .await;
             let srv = Arc::new(srv);
             let handle = tokio::spawn({
                 let srvc = Arc::clone(&srv);
                 async move { srvc.run().await }
             });
 
-            let auth = peer.authenticate().await;
-            assert!(auth.is_ok(), "Auth failed or timed out.");
-            assert!(auth.as_ref().unwrap().is_some(), "Auth response failed.");
+            let ping = peer.ping().await;
+            assert!(ping.is_ok(), "Ping to pinned server failed.");
+            assert_eq!(*ping.unwrap().unwrap().kind(), MessageKind::Pong);
 
-            let auth = auth.unwrap().unwrap();
+            let ping = impostor.ping().await;
+            assert!(matches!(
+                ping,
+                Err(ClientError::Proto(ProtoError::UntrustedPeerKey))
+            ));
+
+            handle.abort();
+
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_identity_authentication() {
+            init_logging();
+            let laptop = IdentityKeyPair::generate().unwrap();
+            let stranger = IdentityKeyPair::generate().unwrap();
+            let srv =
+                setup_enrolling_server(IP_ONE, &[("laptop", &laptop.public_base64())])
+                    .await;
+            let mut peer = setup_identity_peer(IP_TWO, &laptop.seed_base64()).await;
+            let mut other = setup_identity_peer(IP_THREE, &stranger.seed_base64()).await;
+            let mut shared = setup_peer(IP_FOUR).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            assert_eq!(peer.client_id(), ClientId::from_public_key(laptop.public()));
+            let auth = peer.authenticate().await.unwrap().unwrap();
             assert_eq!(*auth.status(), MessageStatus::r#Ok);
-            assert_eq!(*auth.kind(), MessageKind::AuthenticationResponse);
+
+            let auth = other.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::Unauthorized);
+
+            let auth = shared.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::Unauthorized);
 
             handle.abort();
 
@@ -340,27 +812,70 @@ mod integration_tests {
         }
 
         #[tokio::test]
-        async fn test_peer_server_rpc_stun() {
+        async fn test_peer_server_token_file_authentication() {
             init_logging();
-            let srv = setup_server(IP_ONE).await;
+            let tokens = write_config_file(
+                IP_ONE,
+                "tokens.yaml",
+                "laptop: \"roxi-XXX\"\nphone: \"roxi-YYY\"\n",
+            );
+            let srv = setup_server_with_auth(
+                IP_ONE,
+                &format!("  kind: token_file\n  token_file: \"{tokens}\"\n"),
+            )
+            .await;
+            let identity = IdentityKeyPair::generate().unwrap();
             let mut peer = setup_peer(IP_TWO).await;
+            let mut other = setup_identity_peer(IP_THREE, &identity.seed_base64()).await;
             let srv = Arc::new(srv);
             let handle = tokio::spawn({
                 let srvc = Arc::clone(&srv);
-                async move { tokio::join!(srvc.clone().run(), srvc.run_udp()) }
+                async move { srvc.run().await }
             });
 
-            let stun = peer.stun().await;
-            assert!(stun.is_ok(), "STUN failed or timed out.");
+            let auth = peer.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
 
-            let auth = peer.authenticate().await;
-            assert!(auth.is_ok(), "Auth failed or timed out.");
-            assert!(auth.as_ref().unwrap().is_some(), "Auth response failed.");
-            let auth = auth.unwrap().unwrap();
+            let auth = other.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::Unauthorized);
+
+            handle.abort();
+
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_command_authentication() {
+            init_logging();
+            let mut peer = setup_peer(IP_TWO).await;
+            let mut other = setup_peer(IP_THREE).await;
+            let hook = write_config_file(
+                IP_ONE,
+                "auth.sh",
+                &format!(
+                    "case \"$(cat)\" in\n  *'\"client_id\":\"{}\"'*) echo hooked ;;\n  *) exit 1 ;;\nesac\n",
+                    peer.client_id()
+                ),
+            );
+            let srv = setup_server_with_auth(
+                IP_ONE,
+                &format!("  kind: command\n  command: [\"sh\", \"{hook}\"]\n"),
+            )
+            .await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let auth = peer.authenticate().await.unwrap().unwrap();
             assert_eq!(*auth.status(), MessageStatus::r#Ok);
-            assert_eq!(*auth.kind(), MessageKind::AuthenticationResponse);
-            let stun = peer.stun().await;
-            assert!(stun.is_ok(), "STUN failed or timed out.");
+
+            let auth = other.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::Unauthorized);
+
             handle.abort();
 
             peer.stop().await.unwrap();
@@ -368,34 +883,154 @@ mod integration_tests {
             cleanup_config_files().await;
         }
 
+        /// Stands in for an organization's own identity source.
+        struct Directory {
+            members: Vec<ClientId>,
+        }
+
+        #[async_trait]
+        impl Authenticator for Directory {
+            async fn authenticate(
+                &self,
+                _origin: &ClientId,
+                request: &AuthenticationRequest,
+                _challenge: &AuthenticationChallenge,
+            ) -> Result<ClientId, ServerError> {
+                match self.members.contains(&request.client_id) {
+                    true => Ok(request.client_id.clone()),
+                    false => Err(ServerError::Unauthenticated),
+                }
+            }
+        }
+
         #[tokio::test]
-        async fn test_peer_server_rpc_seed() {
+        async fn test_peer_server_custom_authenticator() {
             init_logging();
-            let srv = setup_server(IP_ONE).await;
             let mut peer = setup_peer(IP_TWO).await;
+            let mut other = setup_peer(IP_THREE).await;
+            let directory = Directory {
+                members: vec![peer.client_id()],
+            };
+            let srv = setup_server_with(IP_ONE, Arc::new(directory)).await;
             let srv = Arc::new(srv);
             let handle = tokio::spawn({
                 let srvc = Arc::clone(&srv);
                 async move { srvc.run().await }
             });
 
-            let seed = peer.seed().await;
-            assert!(seed.is_ok(), "Seed failed or timed out.");
-            assert!(seed.as_ref().unwrap().is_some(), "Seed response failed.");
+            let auth = peer.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
 
-            let auth = peer.authenticate().await;
-            assert!(auth.is_ok(), "Auth failed or timed out.");
-            assert!(auth.as_ref().unwrap().is_some(), "Auth response failed.");
-            let auth = auth.unwrap().unwrap();
+            let auth = other.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::Unauthorized);
+
+            handle.abort();
+
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_invite_store_updates_do_not_interleave() {
+            init_logging();
+            let srv = Arc::new(setup_server(IP_ONE).await);
+            let invites = srv.config().invites_path();
+
+            // Writers that each load, change and save the store, as the server
+            // and `roxi invite` do, lose none of each other's changes
+            let writers = (0..8)
+                .map(|_| {
+                    let invites = invites.clone();
+                    std::thread::spawn(move || {
+                        for _ in 0..5 {
+                            InviteStore::update(&invites, |store| {
+                                store.create("admin", 3600, 1, None, None);
+                                Ok(())
+                            })
+                            .unwrap();
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            assert_eq!(InviteStore::load(&invites).unwrap().invites().len(), 40);
+
+            // A change that fails is not saved
+            let result = InviteStore::update(&invites, |store| {
+                store.create("admin", 3600, 1, None, None);
+                Err::<(), _>(ServerError::InvalidInvite)
+            });
+            assert!(matches!(result, Err(ServerError::InvalidInvite)));
+            assert_eq!(InviteStore::load(&invites).unwrap().invites().len(), 40);
+
+            srv.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_enrollment() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let invites = srv.config().invites_path();
+            let mut store = InviteStore::load(&invites).unwrap();
+            let (token, _) = store.create(
+                "admin",
+                3600,
+                1,
+                Some("laptop".to_string()),
+                Some("10.0.0.2/32".to_string()),
+            );
+            let (expired, _) = store.create("admin", 0, 1, None, None);
+            store.save(&invites).unwrap();
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let server = "127.0.0.1:8080";
+            let laptop = IdentityKeyPair::generate().unwrap();
+            let (enrollment, gateway_ip) =
+                enroll(server, Some(&srv.public_key()), &token, &laptop)
+                    .await
+                    .unwrap();
+            assert_eq!(enrollment.label, "laptop");
+            assert_eq!(enrollment.address.as_deref(), Some("10.0.0.2/32"));
+            assert_eq!(enrollment.server_ports.tcp, 8080);
+            assert_eq!(enrollment.server_ports.udp, 5675);
+
+            let store = InviteStore::load(&invites).unwrap();
+            assert_eq!(store.invites()[0].uses, 1);
+            assert_eq!(store.clients()[0].label, "laptop");
+            assert_eq!(store.clients()[0].public_key, laptop.public_base64());
+
+            // Single use, and only until it expires
+            let phone = IdentityKeyPair::generate().unwrap();
+            let result = enroll(server, None, &token, &phone).await;
+            assert!(matches!(result, Err(ClientError::InviteRejected)));
+            let result = enroll(server, None, &expired, &phone).await;
+            assert!(matches!(result, Err(ClientError::InviteRejected)));
+
+            // The enrolled identity is admitted although the server uses a shared key
+            let mut peer = setup_identity_peer(IP_TWO, &laptop.seed_base64()).await;
+            let auth = peer.authenticate().await.unwrap().unwrap();
             assert_eq!(*auth.status(), MessageStatus::r#Ok);
-            assert_eq!(*auth.kind(), MessageKind::AuthenticationResponse);
-            let seed = peer.seed().await;
-            assert!(seed.is_ok(), "Seed failed or timed out.");
-            assert!(seed.as_ref().unwrap().is_some(), "Seed response failed.");
 
-            let seed = seed.unwrap().unwrap();
-            assert_eq!(*seed.status(), MessageStatus::r#Ok);
-            assert_eq!(*seed.kind(), MessageKind::SeedResponse);
+            let dir = env::temp_dir().join(format!("roxi-enroll-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let config =
+                ClientConfig::from_enrollment(&dir, &enrollment, &laptop, gateway_ip);
+            config.save().unwrap();
+            let loaded = ClientConfig::try_from(config.path()).unwrap();
+            assert_eq!(
+                loaded.identity().unwrap().unwrap().public(),
+                laptop.public()
+            );
+            assert_eq!(loaded.remote_addr(InterfaceKind::Tcp), "192.168.1.1:8080");
+            fs::remove_dir_all(&dir).unwrap();
 
             handle.abort();
 
@@ -403,7 +1038,1145 @@ mod integration_tests {
             srv.clone().stop().await.unwrap();
             cleanup_config_files().await;
         }
-    }
 
-    mod peer_peer_interaction {}
+        #[tokio::test]
+        async fn test_peer_server_revocation() {
+            init_logging();
+            let keys = NoiseKeyPair::generate().unwrap();
+            let srv = setup_server_with_key(IP_ONE, &keys.private_base64()).await;
+            let invites = srv.config().invites_path();
+            let mut store = InviteStore::load(&invites).unwrap();
+            let (token, _) =
+                store.create("admin", 3600, 1, Some("laptop".to_string()), None);
+            store.save(&invites).unwrap();
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let server = "127.0.0.1:8080";
+            let laptop = IdentityKeyPair::generate().unwrap();
+            enroll(server, None, &token, &laptop).await.unwrap();
+            let mut peer = setup_identity_peer(IP_TWO, &laptop.seed_base64()).await;
+            let auth = peer.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
+
+            // Only the holder of the server's key may revoke
+            let stranger = NoiseKeyPair::generate().unwrap();
+            let result = roxi_client::revoke(server, &stranger, "laptop").await;
+            assert!(result.is_err());
+
+            roxi_client::revoke(server, &keys, "laptop").await.unwrap();
+            let result = roxi_client::revoke(server, &keys, "phone").await;
+            assert!(matches!(
+                result,
+                Err(ClientError::UnexpectedStatus(MessageStatus::NotFound))
+            ));
+
+            // The open connection is told, and the key is no longer admitted
+            let result = peer.ping().await;
+            assert!(matches!(
+                result,
+                Err(ClientError::SessionEnded(DisconnectReason::Revoked))
+            ));
+
+            let mut again = setup_identity_peer(IP_THREE, &laptop.seed_base64()).await;
+            let auth = again.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::Unauthorized);
+
+            let store = InviteStore::load(&invites).unwrap();
+            assert!(store.clients().is_empty());
+            assert_eq!(store.revoked()[0].label, "laptop");
+
+            handle.abort();
+
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_key_rotation() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let invites = srv.config().invites_path();
+            let mut store = InviteStore::load(&invites).unwrap();
+            let (token, _) =
+                store.create("admin", 3600, 1, Some("laptop".to_string()), None);
+            store.save(&invites).unwrap();
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let laptop = IdentityKeyPair::generate().unwrap();
+            enroll("127.0.0.1:8080", None, &token, &laptop)
+                .await
+                .unwrap();
+            let mut peer = setup_identity_peer(IP_TWO, &laptop.seed_base64()).await;
+            peer.rotate_identity().await.unwrap();
+            assert_ne!(peer.client_id(), ClientId::from_public_key(laptop.public()));
+
+            // The new key is saved and admitted under the same enrollment
+            let rotated = ClientConfig::try_from(peer.config().path())
+                .unwrap()
+                .identity()
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                peer.client_id(),
+                ClientId::from_public_key(rotated.public())
+            );
+            let auth = peer.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
+
+            let store = InviteStore::load(&invites).unwrap();
+            assert_eq!(store.clients()[0].label, "laptop");
+            assert_eq!(store.clients()[0].public_key, rotated.public_base64());
+
+            let mut old = setup_identity_peer(IP_THREE, &laptop.seed_base64()).await;
+            let auth = old.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::Unauthorized);
+
+            handle.abort();
+
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_listed_key_rotation() {
+            init_logging();
+            let laptop = IdentityKeyPair::generate().unwrap();
+            let srv =
+                setup_enrolling_server(IP_ONE, &[("laptop", &laptop.public_base64())])
+                    .await;
+            let invites = srv.config().invites_path();
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            // A client listed in the server config rotates like an invited one
+            let mut peer = setup_identity_peer(IP_TWO, &laptop.seed_base64()).await;
+            peer.rotate_identity().await.unwrap();
+            let auth = peer.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
+
+            let rotated = peer.config().identity().unwrap().unwrap();
+            let store = InviteStore::load(&invites).unwrap();
+            assert_eq!(store.clients()[0].label, "laptop");
+            assert_eq!(store.clients()[0].public_key, rotated.public_base64());
+
+            // The listed key is overridden although the config still lists it
+            let mut old = setup_identity_peer(IP_THREE, &laptop.seed_base64()).await;
+            let auth = old.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::Unauthorized);
+
+            handle.abort();
+
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_gateway_drops_revoked_peer() {
+            init_logging();
+            let keys = NoiseKeyPair::generate().unwrap();
+            let peer = |key: &str| WireGuardProtoPeer {
+                public_key: WireGuardProtoKey::from_public(key.to_string()),
+                allowed_ips: "10.0.0.3/32".to_string(),
+                endpoint: None,
+                persistent_keepalive: None,
+            };
+            let gateway = setup_pinned_gateway(
+                IP_TWO,
+                &keys.public_base64(),
+                vec![peer("revoked"), peer("kept")],
+            )
+            .await;
+            let gateway = Arc::new(gateway);
+            let handle = tokio::spawn({
+                let gatewayc = Arc::clone(&gateway);
+                async move { gatewayc.run().await }
+            });
+
+            let revocation = PeerRevocation {
+                peer: ClientId::from("rx-revoked"),
+                wireguard_public_key: "revoked".to_string(),
+            };
+            let impostor = NoiseKeyPair::generate().unwrap();
+            let result =
+                roxi_client::drop_gateway_peer("127.0.0.1:8081", &impostor, &revocation)
+                    .await;
+            assert!(matches!(
+                result,
+                Err(ClientError::UnexpectedStatus(MessageStatus::Forbidden))
+            ));
+
+            roxi_client::drop_gateway_peer("127.0.0.1:8081", &keys, &revocation)
+                .await
+                .unwrap();
+            let (path, _) = peer_wireguard_config_content(IP_TWO);
+            let wireguard = WireGuardProtoConfig::try_from(Path::new(&path)).unwrap();
+            let keys = wireguard
+                .peers
+                .unwrap()
+                .iter()
+                .map(|p| p.public_key.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(keys, vec!["kept".to_string()]);
+
+            handle.abort();
+
+            gateway.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_idle_sessions_end() {
+            init_logging();
+            let srv = setup_server_with_session_limits(IP_ONE, 3600, 2).await;
+            let mut idle = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let mut alive = setup_heartbeat_peer_on(IP_THREE, LOOPBACK_THREE, 1).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let auth = idle.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
+            let auth = alive.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
+            let heartbeat = tokio::spawn(async move { alive.heartbeat().await });
+
+            tokio::time::sleep(Duration::from_millis(3500)).await;
+
+            // The silent client is told its session ended, the other kept alive
+            let result = idle.ping().await;
+            assert!(matches!(
+                result,
+                Err(ClientError::SessionEnded(DisconnectReason::Idle))
+            ));
+            assert!(!heartbeat.is_finished());
+
+            heartbeat.abort();
+            handle.abort();
+
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_session_resumption() {
+            init_logging();
+            let srv = setup_server_with_session_limits(IP_ONE, 3600, 2).await;
+            let mut peer = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let auth = peer.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.kind(), MessageKind::AuthenticationResponse);
+            assert!(peer.config().ticket_path().exists());
+            let seed = peer.seed().await.unwrap().unwrap();
+            assert_eq!(*seed.status(), MessageStatus::r#Ok);
+
+            // The session goes idle while the client sleeps, and is picked back
+            // up from another address
+            tokio::time::sleep(Duration::from_millis(3500)).await;
+            let mut moved = setup_peer_on(IP_TWO, LOOPBACK_THREE).await;
+            let resumed = moved.authenticate().await.unwrap().unwrap();
+            assert_eq!(*resumed.kind(), MessageKind::ResumeResponse);
+            assert_eq!(*resumed.status(), MessageStatus::r#Ok);
+            assert!(moved.request_stun_info().await.is_ok());
+
+            // A tampered ticket falls back to a full authentication
+            let path = moved.config().ticket_path();
+            let mut ticket = fs::read(&path).unwrap();
+            ticket[10] ^= 0xff;
+            fs::write(&path, ticket).unwrap();
+            let mut tampered = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let auth = tampered.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.kind(), MessageKind::AuthenticationResponse);
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
+
+            handle.abort();
+
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_restart_resumption() {
+            init_logging();
+            let srv = Arc::new(setup_server_with_store(IP_ONE).await);
+            let mut peer = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let auth = peer.authenticate().await.unwrap().unwrap();
+            assert_eq!(*auth.kind(), MessageKind::AuthenticationResponse);
+            let seed = peer.seed().await.unwrap().unwrap();
+            assert_eq!(*seed.status(), MessageStatus::r#Ok);
+
+            handle.abort();
+            let _ = handle.await;
+            srv.clone().stop().await.unwrap();
+            drop(peer);
+            drop(srv);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            // The restarted server still knows the session, and the key its
+            // ticket was signed with
+            let srv = Arc::new(setup_server_with_store(IP_ONE).await);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+            let mut peer = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let resumed = peer.authenticate().await.unwrap().unwrap();
+            assert_eq!(*resumed.kind(), MessageKind::ResumeResponse);
+            assert_eq!(*resumed.status(), MessageStatus::r#Ok);
+            assert!(peer.request_stun_info().await.is_ok());
+
+            handle.abort();
+
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_rpc_hello() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut peer = setup_peer(IP_TWO).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let ack = peer.hello().await;
+            assert!(ack.is_ok(), "Hello failed or timed out.");
+
+            let ack = ack.unwrap();
+            assert_eq!(ack.version, PROTOCOL_VERSION);
+            assert_eq!(ack.features, Features::local());
+            assert_eq!(peer.protocol(), Some(&ack));
+
+            handle.abort();
+
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_rpc_authenticate() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut peer = setup_peer(IP_TWO).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let auth = peer.authenticate().await;
+            assert!(auth.is_ok(), "Auth failed or timed out.");
+            assert!(auth.as_ref().unwrap().is_some(), "Auth response failed.");
+
+            let auth = auth.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
+            assert_eq!(*auth.kind(), MessageKind::AuthenticationResponse);
+
+            handle.abort();
+
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_rpc_stun() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut peer = setup_peer(IP_TWO).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { tokio::join!(srvc.clone().run(), srvc.run_udp()) }
+            });
+
+            let stun = peer.stun().await;
+            assert!(stun.is_ok(), "STUN failed or timed out.");
+            let stun = stun.unwrap();
+            assert!(stun.ip().is_loopback());
+            assert_eq!(peer.config().stun_addr().unwrap(), stun.to_string());
+
+            let auth = peer.authenticate().await;
+            assert!(auth.is_ok(), "Auth failed or timed out.");
+            assert!(auth.as_ref().unwrap().is_some(), "Auth response failed.");
+            let auth = auth.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
+            assert_eq!(*auth.kind(), MessageKind::AuthenticationResponse);
+
+            let info = peer.request_stun_info().await;
+            assert!(info.is_ok(), "STUN info request failed or timed out.");
+            let info = info.unwrap().expect("Server should have seen a binding");
+            assert_eq!(info.addr(), stun);
+
+            let stun = peer.stun().await;
+            assert!(stun.is_ok(), "STUN failed or timed out.");
+            handle.abort();
+
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_refuses_unauthenticated_requests() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut peer = setup_peer(IP_TWO).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            // Messages every connection may send are answered before authenticating
+            let ack = peer.hello().await.unwrap();
+            assert_eq!(ack.version, PROTOCOL_VERSION);
+
+            let info = peer.request_stun_info().await;
+            assert!(matches!(
+                info,
+                Err(ClientError::UnexpectedStatus(MessageStatus::Unauthorized))
+            ));
+            handle.abort();
+
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_rpc_seed() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut peer = setup_peer(IP_TWO).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let seed = peer.seed().await;
+            assert!(seed.is_ok(), "Seed failed or timed out.");
+            assert!(seed.as_ref().unwrap().is_some(), "Seed response failed.");
+
+            let auth = peer.authenticate().await;
+            assert!(auth.is_ok(), "Auth failed or timed out.");
+            assert!(auth.as_ref().unwrap().is_some(), "Auth response failed.");
+            let auth = auth.unwrap().unwrap();
+            assert_eq!(*auth.status(), MessageStatus::r#Ok);
+            assert_eq!(*auth.kind(), MessageKind::AuthenticationResponse);
+            let seed = peer.seed().await;
+            assert!(seed.is_ok(), "Seed failed or timed out.");
+            assert!(seed.as_ref().unwrap().is_some(), "Seed response failed.");
+
+            let seed = seed.unwrap().unwrap();
+            assert_eq!(*seed.status(), MessageStatus::r#Ok);
+            assert_eq!(*seed.kind(), MessageKind::SeedResponse);
+
+            handle.abort();
+
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_server_pushes_events_to_seeders() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut seeder = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let mut peer = setup_peer_on(IP_THREE, LOOPBACK_THREE).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let seed = seeder.seed().await.unwrap().unwrap();
+            assert_eq!(*seed.status(), MessageStatus::r#Ok);
+            let mut events = seeder.events().await.unwrap();
+
+            peer.authenticate().await.unwrap().unwrap();
+            let gateway = peer.request_gateway().await.unwrap().unwrap();
+            assert_eq!(*gateway.status(), MessageStatus::r#Ok);
+
+            // The seeder learns who it serves while its own requests go on
+            let event = timeout(Duration::from_secs(5), events.next())
+                .await
+                .unwrap();
+            match event {
+                Some(Event::Gateway(info)) => {
+                    assert_eq!(info.peer, ClientId::from(LOOPBACK_THREE))
+                }
+                other => panic!("Expected a gateway assignment, got {other:?}"),
+            }
+            assert!(seeder.ping().await.unwrap().is_some());
+
+            handle.abort();
+            srv.clone().stop().await.unwrap();
+
+            let event = timeout(Duration::from_secs(5), events.next())
+                .await
+                .unwrap();
+            assert!(matches!(event, Some(Event::Shutdown)), "Got {event:?}");
+            let event = timeout(Duration::from_secs(5), events.next())
+                .await
+                .unwrap();
+            assert!(event.is_none(), "Got {event:?}");
+
+            seeder.stop().await.unwrap();
+            peer.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_seeder_serves_assigned_peers() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let seeder = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let mut peer = setup_peer_on(IP_THREE, LOOPBACK_THREE).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let mut seeder = Seeder::new(seeder);
+            let seeding = tokio::spawn(async move {
+                let result = seeder.run().await;
+                (seeder, result)
+            });
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            peer.authenticate().await.unwrap().unwrap();
+            let mut events = peer.events().await.unwrap();
+            let gateway = peer.request_gateway().await.unwrap().unwrap();
+            assert_eq!(*gateway.status(), MessageStatus::r#Ok);
+
+            // The seeder launches its gateway and says so
+            let event = timeout(Duration::from_secs(5), events.next())
+                .await
+                .unwrap();
+            match event {
+                Some(Event::GatewayReady(ready)) => {
+                    assert_eq!(ready.peer, ClientId::from(LOOPBACK_TWO))
+                }
+                other => panic!("Expected the gateway to be ready, got {other:?}"),
+            }
+
+            handle.abort();
+            srv.clone().stop().await.unwrap();
+
+            // Seeding ends with the server
+            let (mut seeder, result) = timeout(Duration::from_secs(5), seeding)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(result.is_ok(), "Seeding failed: {result:?}");
+            assert!(seeder.gateway().is_some());
+
+            seeder.stop().await.unwrap();
+            peer.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_seeder_serves_peers_while_answering_punches() {
+            init_logging();
+            let srv = Arc::new(setup_server(IP_ONE).await);
+            let mut initiator = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let seeder = setup_peer_on(IP_THREE, LOOPBACK_THREE).await;
+            let mut peer = setup_peer_on(IP_FOUR, LOOPBACK_FOUR).await;
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+            let udp = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run_udp().await }
+            });
+
+            let mut seeder = Seeder::new(seeder);
+            let seeding = tokio::spawn(async move {
+                let result = seeder.run().await;
+                (seeder, result)
+            });
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            // With STUN gone quiet, the seeder's answer retransmits its binding
+            // request for several seconds
+            initiator.stun().await.unwrap();
+            initiator.authenticate().await.unwrap().unwrap();
+            udp.abort();
+            let punching = tokio::spawn(async move {
+                let _ = initiator.nat_punch(ClientId::from(LOOPBACK_THREE)).await;
+                initiator
+            });
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            peer.authenticate().await.unwrap().unwrap();
+            let mut events = peer.events().await.unwrap();
+            let gateway = peer.request_gateway().await.unwrap().unwrap();
+            assert_eq!(*gateway.status(), MessageStatus::r#Ok);
+
+            // Meanwhile the seeder goes on serving
+            let event = timeout(Duration::from_secs(2), events.next())
+                .await
+                .expect("Seeder stalled while answering a punch");
+            match event {
+                Some(Event::GatewayReady(ready)) => {
+                    assert_eq!(ready.peer, ClientId::from(LOOPBACK_THREE))
+                }
+                other => panic!("Expected the gateway to be ready, got {other:?}"),
+            }
+
+            handle.abort();
+            srv.clone().stop().await.unwrap();
+            let (mut seeder, result) = timeout(Duration::from_secs(5), seeding)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(result.is_ok(), "Seeding failed: {result:?}");
+
+            seeder.stop().await.unwrap();
+            punching.await.unwrap().stop().await.unwrap();
+            peer.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_server_releases_closed_tunnels() {
+            init_logging();
+            let keys = NoiseKeyPair::generate().unwrap();
+            let srv = setup_server_with_key(IP_ONE, &keys.private_base64()).await;
+            let mut seeder = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let mut peer = setup_peer_on(IP_THREE, LOOPBACK_THREE).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let seed = seeder.seed().await.unwrap().unwrap();
+            assert_eq!(*seed.status(), MessageStatus::r#Ok);
+            peer.authenticate().await.unwrap().unwrap();
+            let gateway = peer.request_gateway().await.unwrap().unwrap();
+            assert_eq!(*gateway.status(), MessageStatus::r#Ok);
+
+            let server = "127.0.0.1:8080";
+            let statuses = roxi_client::gateway_statuses(server, &keys).await.unwrap();
+            assert_eq!(statuses[0].assigned, 1);
+
+            // Only the gateway of a peer may release it
+            let result = peer
+                .report_tunnel_closed(ClientId::from(LOOPBACK_TWO))
+                .await;
+            assert!(matches!(
+                result,
+                Err(ClientError::UnexpectedStatus(MessageStatus::Forbidden))
+            ));
+
+            seeder
+                .report_tunnel_closed(ClientId::from(LOOPBACK_THREE))
+                .await
+                .unwrap();
+            let statuses = roxi_client::gateway_statuses(server, &keys).await.unwrap();
+            assert_eq!(statuses[0].assigned, 0);
+
+            handle.abort();
+            srv.clone().stop().await.unwrap();
+            seeder.stop().await.unwrap();
+            peer.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_seeder_tears_down_closed_tunnels() {
+            init_logging();
+            let keys = NoiseKeyPair::generate().unwrap();
+            let srv = setup_server_with_key(IP_ONE, &keys.private_base64()).await;
+            let seeder =
+                setup_loopback_gateway_peer_on(IP_TWO, LOOPBACK_TWO, "seeder-key").await;
+            let mut peer =
+                setup_wireguard_peer_on(IP_THREE, LOOPBACK_THREE, "peer-key").await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let mut seeder = Seeder::new(seeder);
+            let seeding = tokio::spawn(async move {
+                let result = seeder.run().await;
+                (seeder, result)
+            });
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            let wireguard_keys = || {
+                let (path, _) = peer_wireguard_config_content(IP_TWO);
+                WireGuardProtoConfig::try_from(Path::new(&path))
+                    .unwrap()
+                    .peers
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|p| p.public_key.to_string())
+                    .collect::<Vec<_>>()
+            };
+            let server = "127.0.0.1:8080";
+            let assigned = || async {
+                roxi_client::gateway_statuses(server, &keys).await.unwrap()[0].assigned
+            };
+
+            peer.tunnel().await.unwrap();
+            assert_eq!(wireguard_keys(), vec!["peer-key".to_string()]);
+            assert_eq!(assigned().await, 1);
+
+            // Closing the tunnel removes the peer from the gateway, and the
+            // seeder's report releases it on the server
+            peer.stop().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            assert!(wireguard_keys().is_empty());
+            assert_eq!(assigned().await, 0);
+
+            handle.abort();
+            srv.clone().stop().await.unwrap();
+            let (mut seeder, _) = timeout(Duration::from_secs(5), seeding)
+                .await
+                .unwrap()
+                .unwrap();
+            seeder.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_refuses_unverified_gateway() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut seeder =
+                setup_loopback_gateway_peer_on(IP_TWO, LOOPBACK_TWO, "seeder-key").await;
+            let mut peer =
+                setup_wireguard_peer_on(IP_THREE, LOOPBACK_THREE, "peer-key").await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let seed = seeder.seed().await.unwrap().unwrap();
+            assert_eq!(*seed.status(), MessageStatus::r#Ok);
+
+            // Another gateway answers where the seeder's should
+            let mut config = seeder.config().clone();
+            config.set_gateway_private_key(
+                NoiseKeyPair::generate().unwrap().private_base64(),
+            );
+            let impostor = Arc::new(Gateway::new(config).await.unwrap());
+            let serving = tokio::spawn({
+                let impostor = Arc::clone(&impostor);
+                async move { impostor.run().await }
+            });
+
+            let result = peer.tunnel().await;
+            assert!(
+                matches!(
+                    result,
+                    Err(ClientError::Proto(ProtoError::UntrustedPeerKey))
+                ),
+                "{result:?}"
+            );
+            let (path, _) = peer_wireguard_config_content(IP_TWO);
+            let peers = WireGuardProtoConfig::try_from(Path::new(&path))
+                .unwrap()
+                .peers;
+            assert!(peers.unwrap_or_default().is_empty());
+
+            serving.abort();
+            impostor.stop().await.unwrap();
+            handle.abort();
+            srv.clone().stop().await.unwrap();
+            seeder.stop().await.unwrap();
+            peer.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_server_skips_full_gateways() {
+            init_logging();
+            let keys = NoiseKeyPair::generate().unwrap();
+            let srv = setup_server_with_key(IP_ONE, &keys.private_base64()).await;
+            let mut full = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let mut spare = setup_peer_on(IP_FOUR, LOOPBACK_FOUR).await;
+            let mut peer = setup_peer_on(IP_THREE, LOOPBACK_THREE).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            for (seeder, active_tunnels) in [(&mut full, 2), (&mut spare, 0)] {
+                let seed = seeder.seed().await.unwrap().unwrap();
+                assert_eq!(*seed.status(), MessageStatus::r#Ok);
+                let load = GatewayLoad {
+                    active_tunnels,
+                    max_clients: 2,
+                    healthy: true,
+                    ..Default::default()
+                };
+                seeder.report_load(load).await.unwrap();
+            }
+
+            peer.authenticate().await.unwrap().unwrap();
+            let gateway = peer.request_gateway().await.unwrap().unwrap();
+            assert_eq!(*gateway.status(), MessageStatus::r#Ok);
+
+            // The spare seeder got the peer, and the operator sees what each
+            // seeder reported
+            let server = "127.0.0.1:8080";
+            let mut statuses =
+                roxi_client::gateway_statuses(server, &keys).await.unwrap();
+            statuses.sort_by_key(|status| status.peer.to_string());
+            assert_eq!(statuses.len(), 2);
+            assert_eq!(statuses[0].peer, ClientId::from(LOOPBACK_TWO));
+            assert!(statuses[0].load.unwrap().is_full());
+            assert_eq!(statuses[0].assigned, 0);
+            assert_eq!(statuses[1].peer, ClientId::from(LOOPBACK_FOUR));
+            assert_eq!(statuses[1].assigned, 1);
+
+            let stranger = NoiseKeyPair::generate().unwrap();
+            assert!(roxi_client::gateway_statuses(server, &stranger)
+                .await
+                .is_err());
+
+            handle.abort();
+            srv.clone().stop().await.unwrap();
+            full.stop().await.unwrap();
+            spare.stop().await.unwrap();
+            peer.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_tunnel_fails_when_gateways_are_full() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut full = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let mut peer = setup_peer_on(IP_THREE, LOOPBACK_THREE).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let seed = full.seed().await.unwrap().unwrap();
+            assert_eq!(*seed.status(), MessageStatus::r#Ok);
+            let load = GatewayLoad {
+                active_tunnels: 2,
+                max_clients: 2,
+                healthy: true,
+                ..Default::default()
+            };
+            full.report_load(load).await.unwrap();
+
+            // The peer is told there is no gateway rather than left hanging
+            let result = peer.tunnel().await;
+            assert!(
+                matches!(
+                    result,
+                    Err(ClientError::UnexpectedStatus(
+                        MessageStatus::ServiceUnavailable
+                    ))
+                ),
+                "Expected no gateway to be available, got {result:?}"
+            );
+
+            handle.abort();
+            srv.clone().stop().await.unwrap();
+            full.stop().await.unwrap();
+            peer.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+    }
+
+    mod peer_peer_interaction {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_peer_peer_udp_hole_punch() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut initiator = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let mut seeder = setup_peer_on(IP_THREE, LOOPBACK_THREE).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { tokio::join!(srvc.clone().run(), srvc.run_udp()) }
+            });
+
+            for peer in [&mut initiator, &mut seeder] {
+                assert!(peer.stun().await.is_ok(), "STUN failed or timed out.");
+                let auth = peer.authenticate().await;
+                assert!(auth.is_ok(), "Auth failed or timed out.");
+                assert_eq!(*auth.unwrap().unwrap().status(), MessageStatus::r#Ok);
+            }
+
+            let unknown = initiator.nat_punch(ClientId::from("127.0.0.9")).await;
+            assert!(unknown.is_err(), "Punch to an unknown peer should fail.");
+
+            let seed = seeder.seed().await;
+            assert!(seed.is_ok(), "Seed failed or timed out.");
+
+            let initiator_stun = initiator.config().stun_addr().unwrap();
+            let seeder_stun = seeder.config().stun_addr().unwrap();
+
+            // Without a NAT in between, the reflexive address is the host address
+            let candidates = initiator.gather_candidates().await.unwrap();
+            assert_eq!(candidates.len(), 1);
+            assert_eq!(candidates[0].kind, CandidateKind::Host);
+            assert_eq!(candidates[0].addr.to_string(), initiator_stun);
+
+            // The offer relayed to the seeder must not be taken for the reply
+            // to a request the seeder makes meanwhile
+            let (punched, accepted) = tokio::join!(
+                initiator.nat_punch(ClientId::from(LOOPBACK_THREE)),
+                async {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    let pong = seeder.ping().await.unwrap().unwrap();
+                    assert_eq!(*pong.kind(), MessageKind::Pong);
+                    seeder.accept_punch().await
+                }
+            );
+            assert_eq!(punched.unwrap().to_string(), seeder_stun);
+            assert_eq!(accepted.unwrap().to_string(), initiator_stun);
+
+            handle.abort();
+
+            initiator.stop().await.unwrap();
+            seeder.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_tunnels_to_punched_wireguard_port() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let seeder =
+                setup_loopback_gateway_peer_on(IP_TWO, LOOPBACK_TWO, "seeder-key").await;
+            let seeder = with_listen_port(seeder, 51821).await;
+            let peer =
+                setup_wireguard_peer_on(IP_THREE, LOOPBACK_THREE, "peer-key").await;
+            let mut peer = with_listen_port(peer, 51822).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { tokio::join!(srvc.clone().run(), srvc.run_udp()) }
+            });
+
+            let mut seeder = Seeder::new(seeder);
+            let seeding = tokio::spawn(async move {
+                let result = seeder.run().await;
+                (seeder, result)
+            });
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            peer.tunnel().await.unwrap();
+
+            // The gateway is reached on the port its WireGuard listens on, and
+            // both ports are free for WireGuard again
+            let (path, _) = peer_wireguard_config_content(IP_THREE);
+            let peers = WireGuardProtoConfig::try_from(Path::new(&path))
+                .unwrap()
+                .peers
+                .unwrap_or_default();
+            assert_eq!(peers.len(), 1);
+            assert_eq!(peers[0].public_key.to_string(), "seeder-key");
+            assert_eq!(
+                peers[0].endpoint.as_deref(),
+                Some(format!("{LOOPBACK_TWO}:51821").as_str())
+            );
+            for addr in [
+                format!("{LOOPBACK_TWO}:51821"),
+                format!("{LOOPBACK_THREE}:51822"),
+            ] {
+                assert!(UdpSocket::bind(&addr).await.is_ok(), "{addr} is held");
+            }
+
+            handle.abort();
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            let (mut seeder, _) = timeout(Duration::from_secs(5), seeding)
+                .await
+                .unwrap()
+                .unwrap();
+            seeder.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_seeder_joins_relayed_tunnels() {
+            init_logging();
+            let srv = setup_loopback_server(IP_ONE).await;
+            let seeder =
+                setup_loopback_gateway_peer_on(IP_TWO, LOOPBACK_TWO, "seeder-key").await;
+            let seeder = with_listen_port(seeder, 51821).await;
+            let peer =
+                setup_wireguard_peer_on(IP_THREE, LOOPBACK_THREE, "peer-key").await;
+            let mut peer = with_listen_port(peer, 51822).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            let mut seeder = Seeder::new(seeder);
+            let seeding = tokio::spawn(async move {
+                let result = seeder.run().await;
+                (seeder, result)
+            });
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            peer.authenticate().await.unwrap().unwrap();
+            let gateway = peer.request_gateway().await.unwrap().unwrap();
+            assert_eq!(*gateway.status(), MessageStatus::r#Ok);
+            let relay = peer
+                .request_relay(ClientId::from(LOOPBACK_TWO))
+                .await
+                .unwrap()
+                .to_string();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            // The gateway's WireGuard sends to the relay
+            let (path, _) = peer_wireguard_config_content(IP_TWO);
+            let peers = WireGuardProtoConfig::try_from(Path::new(&path))
+                .unwrap()
+                .peers
+                .unwrap_or_default();
+            assert_eq!(peers.len(), 1);
+            assert_eq!(peers[0].public_key.to_string(), "peer-key");
+            assert_eq!(peers[0].endpoint.as_deref(), Some(relay.as_str()));
+
+            // The seeder registered its WireGuard port, so the peer's first
+            // datagram already reaches it, and the answer makes it back
+            let gateway = UdpSocket::bind(format!("{LOOPBACK_TWO}:51821"))
+                .await
+                .unwrap();
+            let wireguard = UdpSocket::bind(format!("{LOOPBACK_THREE}:51822"))
+                .await
+                .unwrap();
+            let mut buff = [0u8; 64];
+
+            wireguard.send_to(b"hello", &relay).await.unwrap();
+            let (len, from) =
+                timeout(Duration::from_secs(1), gateway.recv_from(&mut buff))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(&buff[..len], b"hello");
+            assert_eq!(from.to_string(), relay);
+
+            gateway.send_to(b"world", &relay).await.unwrap();
+            let (len, _) =
+                timeout(Duration::from_secs(1), wireguard.recv_from(&mut buff))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(&buff[..len], b"world");
+
+            handle.abort();
+            peer.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            let (mut seeder, _) = timeout(Duration::from_secs(5), seeding)
+                .await
+                .unwrap()
+                .unwrap();
+            seeder.stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+
+        #[tokio::test]
+        async fn test_peer_peer_relay_forwards_datagrams() {
+            init_logging();
+            let srv = setup_server(IP_ONE).await;
+            let mut initiator = setup_peer_on(IP_TWO, LOOPBACK_TWO).await;
+            let mut seeder = setup_peer_on(IP_THREE, LOOPBACK_THREE).await;
+            let srv = Arc::new(srv);
+            let handle = tokio::spawn({
+                let srvc = Arc::clone(&srv);
+                async move { srvc.run().await }
+            });
+
+            for peer in [&mut initiator, &mut seeder] {
+                let auth = peer.authenticate().await;
+                assert!(auth.is_ok(), "Auth failed or timed out.");
+            }
+            assert!(seeder.seed().await.is_ok(), "Seed failed or timed out.");
+
+            let (relayed, accepted) = tokio::join!(
+                initiator.request_relay(ClientId::from(LOOPBACK_THREE)),
+                seeder.accept_relay()
+            );
+            let relay = relayed.unwrap();
+            assert_eq!(relay, accepted.unwrap());
+            assert_eq!(relay.ip().to_string(), IP_ONE);
+
+            // The test server advertises IP_ONE, but the relay is bound locally
+            let relay = format!("127.0.0.1:{}", relay.port());
+            let a = UdpSocket::bind(format!("{LOOPBACK_TWO}:0")).await.unwrap();
+            let b = UdpSocket::bind(format!("{LOOPBACK_THREE}:0"))
+                .await
+                .unwrap();
+            let mut buff = [0u8; 64];
+
+            // Nothing to forward to until the other side has been heard from
+            a.send_to(b"hello", &relay).await.unwrap();
+            b.send_to(b"world", &relay).await.unwrap();
+            let (len, from) = timeout(Duration::from_secs(1), a.recv_from(&mut buff))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buff[..len], b"world");
+            assert_eq!(from.to_string(), relay);
+
+            a.send_to(b"hello", &relay).await.unwrap();
+            let (len, _) = timeout(Duration::from_secs(1), b.recv_from(&mut buff))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buff[..len], b"hello");
+
+            handle.abort();
+
+            initiator.stop().await.unwrap();
+            seeder.stop().await.unwrap();
+            srv.clone().stop().await.unwrap();
+            cleanup_config_files().await;
+        }
+    }
 }